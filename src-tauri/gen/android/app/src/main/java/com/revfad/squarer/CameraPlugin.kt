@@ -0,0 +1,46 @@
+package com.revfad.squarer
+
+import android.app.Activity
+import android.content.Intent
+import android.provider.MediaStore
+import androidx.activity.result.ActivityResult
+import androidx.core.content.FileProvider
+import app.tauri.annotation.ActivityCallback
+import app.tauri.annotation.Command
+import app.tauri.annotation.TauriPlugin
+import app.tauri.plugin.Invoke
+import app.tauri.plugin.JSObject
+import app.tauri.plugin.Plugin
+import java.io.File
+
+// Takes photos with the system camera app for `capture_and_detect`. The photo
+// is written to the cache directory through a FileProvider, which needs a
+// `${applicationId}.fileprovider` <provider> (with a <cache-path>) declared in
+// AndroidManifest.xml.
+@TauriPlugin
+class CameraPlugin(private val activity: Activity) : Plugin(activity) {
+    private var pending: File? = null
+
+    @Command
+    fun takePicture(invoke: Invoke) {
+        val file = File.createTempFile("capture", ".jpg", activity.cacheDir)
+        val uri = FileProvider.getUriForFile(activity, "${activity.packageName}.fileprovider", file)
+        val intent = Intent(MediaStore.ACTION_IMAGE_CAPTURE).putExtra(MediaStore.EXTRA_OUTPUT, uri)
+        pending = file
+        startActivityForResult(invoke, intent, "pictureTaken")
+    }
+
+    @ActivityCallback
+    private fun pictureTaken(invoke: Invoke, result: ActivityResult) {
+        val file = pending
+        pending = null
+        if (result.resultCode != Activity.RESULT_OK || file == null) {
+            file?.delete()
+            invoke.reject("No photo was taken")
+            return
+        }
+        val photo = JSObject()
+        photo.put("path", file.absolutePath)
+        invoke.resolve(photo)
+    }
+}
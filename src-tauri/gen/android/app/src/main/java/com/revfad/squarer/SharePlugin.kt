@@ -0,0 +1,107 @@
+package com.revfad.squarer
+
+import android.app.Activity
+import android.content.Intent
+import android.net.Uri
+import android.webkit.WebView
+import androidx.core.content.FileProvider
+import app.tauri.annotation.Command
+import app.tauri.annotation.InvokeArg
+import app.tauri.annotation.TauriPlugin
+import app.tauri.plugin.Channel
+import app.tauri.plugin.Invoke
+import app.tauri.plugin.JSArray
+import app.tauri.plugin.JSObject
+import app.tauri.plugin.Plugin
+import java.io.File
+
+@InvokeArg
+class ShareArgs {
+    lateinit var path: String
+    lateinit var mimeType: String
+}
+
+@InvokeArg
+class ReceiverArgs {
+    lateinit var handler: Channel
+}
+
+// Offers results to other apps through the system share sheet for
+// `share_result`, and passes images shared to Squarer on to Rust. Sharing out
+// goes through the same FileProvider as CameraPlugin; receiving needs
+// <intent-filter>s for ACTION_SEND and ACTION_SEND_MULTIPLE with an image/*
+// <data> on the main activity in AndroidManifest.xml.
+@TauriPlugin
+class SharePlugin(private val activity: Activity) : Plugin(activity) {
+    private var receiver: Channel? = null
+    // Images shared before Rust set the receiver, such as the ones the app
+    // was launched with.
+    private val pending = mutableListOf<String>()
+
+    override fun load(webView: WebView) {
+        super.load(webView)
+        receive(activity.intent)
+    }
+
+    override fun onNewIntent(intent: Intent) {
+        receive(intent)
+    }
+
+    @Command
+    fun setReceiver(invoke: Invoke) {
+        receiver = invoke.parseArgs(ReceiverArgs::class.java).handler
+        deliver()
+        invoke.resolve()
+    }
+
+    @Command
+    fun share(invoke: Invoke) {
+        val args = invoke.parseArgs(ShareArgs::class.java)
+        val uri = FileProvider.getUriForFile(activity, "${activity.packageName}.fileprovider", File(args.path))
+        val intent = Intent(Intent.ACTION_SEND)
+            .setType(args.mimeType)
+            .putExtra(Intent.EXTRA_STREAM, uri)
+            .addFlags(Intent.FLAG_GRANT_READ_URI_PERMISSION)
+        activity.startActivity(Intent.createChooser(intent, null))
+        invoke.resolve()
+    }
+
+    @Suppress("DEPRECATION")
+    private fun receive(intent: Intent?) {
+        val uris: List<Uri> = when (intent?.action) {
+            Intent.ACTION_SEND -> listOfNotNull(intent.getParcelableExtra(Intent.EXTRA_STREAM))
+            Intent.ACTION_SEND_MULTIPLE ->
+                intent.getParcelableArrayListExtra<Uri>(Intent.EXTRA_STREAM).orEmpty()
+            else -> return
+        }
+        // Don't take the same images again if the activity is recreated.
+        intent.action = null
+        for (uri in uris) {
+            // The sharing app's permission to read the image only lasts as
+            // long as this activity, so it's copied somewhere Rust can read.
+            val file = File.createTempFile("shared", null, activity.cacheDir)
+            val copied = activity.contentResolver.openInputStream(uri)?.use { input ->
+                file.outputStream().use { output -> input.copyTo(output) }
+            }
+            if (copied == null) {
+                file.delete()
+                continue
+            }
+            pending.add(file.absolutePath)
+        }
+        deliver()
+    }
+
+    private fun deliver() {
+        val receiver = receiver ?: return
+        if (pending.isEmpty()) {
+            return
+        }
+        val paths = JSArray()
+        pending.forEach { paths.put(it) }
+        pending.clear()
+        val shared = JSObject()
+        shared.put("paths", paths)
+        receiver.send(shared)
+    }
+}
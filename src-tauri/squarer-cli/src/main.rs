@@ -0,0 +1,285 @@
+//! Headless batch squaring, for scripting without the Tauri window. Only
+//! needs `squarer-core`, so it builds and runs without a webview.
+
+use clap::Parser;
+use image::GenericImageView;
+use rayon::prelude::*;
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use squarer_core::adjust::{Inversion, Sharpening};
+use squarer_core::cancel::CancellationToken;
+use squarer_core::cleanup::CleanupMode;
+use squarer_core::decode::DecodeLimits;
+use squarer_core::encode::{self, OutputFormat};
+use squarer_core::naming::{self, Collision, Namer, OutputNaming};
+use squarer_core::{
+    ControlPoint, Error, ImageSquaringError, OutputMode, OutputSize, ProcessingOptions,
+};
+
+#[derive(Debug, Parser)]
+#[command(name = "squarer-cli", version, about = "Square up photos of documents")]
+struct Args {
+    /// Images to square.
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Control points as x1,y1,x2,y2,... in any order: the four corners,
+    /// three of them for a parallelogram, or two along a line to level. Give
+    /// it once to use the same points for every input, or once per input.
+    #[arg(long, value_parser = parse_corners, conflicts_with = "auto_detect")]
+    corners: Vec<Corners>,
+
+    /// Find each image's corners automatically.
+    #[arg(long)]
+    auto_detect: bool,
+
+    /// Directory to write the squared images into.
+    #[arg(short, long)]
+    output_dir: PathBuf,
+
+    /// How to name the outputs, from `{stem}`, `{index}`, `{date}`, `{time}`
+    /// and `{ext}`.
+    #[arg(long, default_value = naming::DEFAULT_TEMPLATE)]
+    name: String,
+
+    /// What to do when an output already exists: overwrite, skip or
+    /// auto-number.
+    #[arg(long, default_value = "overwrite", value_parser = parse_collision)]
+    on_collision: Collision,
+
+    /// png, jpeg or webp.
+    #[arg(short, long, default_value = "png", value_parser = parse_format)]
+    format: OutputFormat,
+
+    /// JPEG quality, 1 to 100.
+    #[arg(short, long, default_value_t = encode::DEFAULT_QUALITY,
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Estimate the document's real proportions instead of keeping the
+    /// bounding box's.
+    #[arg(long)]
+    preserve_aspect_ratio: bool,
+
+    /// Size the output by the quad's longest edges rather than its bounding
+    /// box, keeping all of the source's detail.
+    #[arg(long)]
+    max_edge: bool,
+
+    /// Flatten the background and binarize, for receipts and forms.
+    #[arg(long)]
+    document: bool,
+
+    /// Even out lighting across the page, such as a shadow cast by the phone.
+    #[arg(long)]
+    remove_shadows: bool,
+
+    /// Neutralize a color cast so that the paper comes out white.
+    #[arg(long)]
+    auto_color: bool,
+
+    /// Paste the squared quad back into the whole photo rather than writing
+    /// it on its own.
+    #[arg(long)]
+    in_place: bool,
+
+    /// Invert the output, for negatives and blueprints.
+    #[arg(long)]
+    invert: bool,
+
+    /// Sharpen text that shrinking the quad softened.
+    #[arg(long)]
+    sharpen: bool,
+
+    /// Even out a whiteboard photo to white and bring up the pen strokes.
+    #[arg(long, conflicts_with = "document")]
+    whiteboard: bool,
+
+    /// Copy capture date, camera and location metadata from the source.
+    #[arg(long)]
+    copy_metadata: bool,
+
+    /// Resolution to record in the output, e.g. 300 for a 300 dpi scan.
+    #[arg(long)]
+    dpi: Option<f32>,
+
+    /// Lower the JPEG quality as far as needed to keep each output within
+    /// this many KB.
+    #[arg(long)]
+    max_size_kb: Option<u32>,
+}
+
+// A newtype so that clap doesn't treat `Vec<Vec<ControlPoint>>` as grouped
+// occurrences of single points.
+#[derive(Debug, Clone)]
+struct Corners(Vec<ControlPoint>);
+
+fn parse_corners(value: &str) -> Result<Corners, String> {
+    let coordinates = value
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    if coordinates.len() < 4 || coordinates.len() % 2 != 0 {
+        return Err(String::from(
+            "expected comma-separated x,y pairs for at least two points",
+        ));
+    }
+    Ok(Corners(
+        coordinates
+            .chunks(2)
+            .map(|xy| ControlPoint::new(xy[0], xy[1]))
+            .collect(),
+    ))
+}
+
+fn parse_format(value: &str) -> Result<OutputFormat, String> {
+    OutputFormat::from_extension(value).ok_or_else(|| format!("unknown format '{value}'"))
+}
+
+fn parse_collision(value: &str) -> Result<Collision, String> {
+    match value {
+        "overwrite" => Ok(Collision::Overwrite),
+        "skip" => Ok(Collision::Skip),
+        "auto-number" => Ok(Collision::AutoNumber),
+        _ => Err(format!("unknown collision handling '{value}'")),
+    }
+}
+
+/// Squares `input` into the path `namer` gives it, returning None if it was
+/// skipped.
+fn process_file(
+    input: &Path,
+    index: usize,
+    corners: Option<&[ControlPoint]>,
+    namer: &Namer,
+    options: &ProcessingOptions,
+) -> Result<Option<PathBuf>, Error> {
+    let Some(output_path) = namer.output_path(input, index, options.output_format) else {
+        return Ok(None);
+    };
+    let source = squarer_core::decode::read_image_file(input, &DecodeLimits::default())?;
+    let corners = match corners {
+        Some(corners) => corners.to_vec(),
+        None => squarer_core::detect::detect_quad(&source.image)
+            .ok_or_else(|| Error::Squaring(ImageSquaringError::new("No quadrilateral found")))?
+            .into_iter()
+            .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+            .collect(),
+    };
+    let quad = squarer_core::quad_from_points(corners, source.image.dimensions())?;
+    let squared = squarer_core::square_quad(
+        &source.image,
+        quad.clone(),
+        options,
+        &CancellationToken::default(),
+    )?;
+    let bytes = squarer_core::encode_output_with_metadata(
+        &squared,
+        options,
+        source.exif.as_deref(),
+        &quad,
+    )?;
+    std::fs::write(&output_path, bytes)?;
+    Ok(Some(output_path))
+}
+
+/// Prints one line per input and exits unsuccessfully if any of them failed.
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if !args.auto_detect && args.corners.is_empty() {
+        eprintln!("error: either --corners or --auto-detect is required");
+        return ExitCode::from(2);
+    }
+    if args.corners.len() > 1 && args.corners.len() != args.inputs.len() {
+        eprintln!(
+            "error: got {} --corners for {} inputs",
+            args.corners.len(),
+            args.inputs.len()
+        );
+        return ExitCode::from(2);
+    }
+    if let Err(e) = std::fs::create_dir_all(&args.output_dir) {
+        eprintln!("error: {}: {e}", args.output_dir.display());
+        return ExitCode::FAILURE;
+    }
+    let naming = OutputNaming {
+        template: args.name.clone(),
+        on_collision: args.on_collision,
+    };
+    let namer = match Namer::new(naming, &args.output_dir) {
+        Ok(namer) => namer,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    let options = ProcessingOptions {
+        output_format: args.format,
+        quality: args.quality,
+        preserve_aspect_ratio: args.preserve_aspect_ratio,
+        output_size: if args.max_edge {
+            OutputSize::MaxEdge
+        } else {
+            OutputSize::BoundingBox
+        },
+        copy_metadata: args.copy_metadata,
+        remove_shadows: args.remove_shadows,
+        auto_color: args.auto_color,
+        invert: args.invert.then(Inversion::default),
+        output_mode: if args.in_place {
+            OutputMode::InPlace
+        } else {
+            OutputMode::Standalone
+        },
+        sharpening: if args.sharpen {
+            Sharpening::Auto
+        } else {
+            Sharpening::None
+        },
+        dpi: args.dpi,
+        max_file_size_kb: args.max_size_kb,
+        cleanup_mode: if args.document {
+            CleanupMode::Document
+        } else if args.whiteboard {
+            CleanupMode::Whiteboard
+        } else {
+            CleanupMode::None
+        },
+        ..ProcessingOptions::default()
+    };
+
+    let failures = args
+        .inputs
+        .par_iter()
+        .enumerate()
+        .filter(|(index, input)| {
+            let corners = match args.corners.len() {
+                0 => None,
+                1 => Some(args.corners[0].0.as_slice()),
+                _ => Some(args.corners[*index].0.as_slice()),
+            };
+            match process_file(input, index + 1, corners, &namer, &options) {
+                Ok(Some(output_path)) => {
+                    println!("{} -> {}", input.display(), output_path.display());
+                    false
+                }
+                Ok(None) => {
+                    println!("{}: skipped, its output already exists", input.display());
+                    false
+                }
+                Err(e) => {
+                    eprintln!("{}: {e}", input.display());
+                    true
+                }
+            }
+        })
+        .count();
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,357 @@
+use image::RgbaImage;
+use imageproc::filter::gaussian_blur_f32;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+// The paper is taken to be the brightest pixels, lighter than this fraction
+// of the rest.
+const PAPER_FRACTION: f64 = 0.9;
+// Gains are kept within this factor of 1, so that a page that really is
+// colored doesn't get wildly recolored.
+const MAX_GAIN: f32 = 2.0;
+
+/// Basic exposure and color fixes, applied after warping. The defaults
+/// change nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Adjustments {
+    /// Added to every channel, as a fraction of the full range (-1 to 1).
+    pub brightness: f32,
+    /// Multiplies each channel's distance from mid-gray; 0 flattens the
+    /// image to gray.
+    pub contrast: f32,
+    /// Values above 1 brighten the midtones, below 1 darken them.
+    pub gamma: f32,
+    /// Multiplies each pixel's difference from its gray; 0 gives grayscale.
+    pub saturation: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Adjustments {
+            brightness: 0.0,
+            contrast: 1.0,
+            gamma: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+impl Adjustments {
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::InvalidInput(message));
+        if !(-1.0..=1.0).contains(&self.brightness) {
+            return invalid(format!(
+                "Brightness must be between -1 and 1, got {}",
+                self.brightness
+            ));
+        }
+        if !(self.contrast >= 0.0 && self.contrast.is_finite()) {
+            return invalid(format!(
+                "Contrast must be zero or more, got {}",
+                self.contrast
+            ));
+        }
+        if !(self.gamma > 0.0 && self.gamma.is_finite()) {
+            return invalid(format!("Gamma must be positive, got {}", self.gamma));
+        }
+        if !(self.saturation >= 0.0 && self.saturation.is_finite()) {
+            return invalid(format!(
+                "Saturation must be zero or more, got {}",
+                self.saturation
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Applies the adjustments: brightness, then contrast, then gamma (as one
+/// lookup table), then saturation. Alpha is left alone.
+pub fn adjust(mut image: RgbaImage, adjustments: &Adjustments) -> RgbaImage {
+    let table: Vec<u8> = (0..256)
+        .map(|level| {
+            let value = level as f32 / 255.0 + adjustments.brightness;
+            let value = (value - 0.5) * adjustments.contrast + 0.5;
+            let value = value.clamp(0.0, 1.0).powf(1.0 / adjustments.gamma);
+            (value * 255.0).round() as u8
+        })
+        .collect();
+    for pixel in image.pixels_mut() {
+        let [r, g, b] = [0, 1, 2].map(|channel| table[pixel[channel] as usize] as f32);
+        if adjustments.saturation == 1.0 {
+            pixel.0[..3].copy_from_slice(&[r as u8, g as u8, b as u8]);
+            continue;
+        }
+        let gray = 0.299 * r + 0.587 * g + 0.114 * b;
+        let [r, g, b] = [r, g, b].map(|value| {
+            (gray + (value - gray) * adjustments.saturation)
+                .round()
+                .clamp(0.0, 255.0) as u8
+        });
+        pixel.0[..3].copy_from_slice(&[r, g, b]);
+    }
+    image
+}
+
+// `Sharpening::Auto` starts sharpening once the warp shrinks the quad by
+// more than this (as a linear factor)...
+const AUTO_SHARPEN_MIN_SCALE: f64 = 1.2;
+// ...with an amount growing with the factor, up to a limit...
+const AUTO_SHARPEN_AMOUNT_PER_SCALE: f64 = 0.5;
+const AUTO_SHARPEN_MAX_AMOUNT: f64 = 1.0;
+// ...over a fixed radius, for detail about a pixel across.
+const AUTO_SHARPEN_RADIUS: f32 = 1.0;
+const MAX_SHARPEN_RADIUS: f32 = 50.0;
+
+/// Unsharp masking, to bring back the crispness of text that the warp
+/// softened by shrinking it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sharpening {
+    #[default]
+    None,
+    /// As much as the warp shrinks the quad calls for, and none if it
+    /// doesn't.
+    Auto,
+    /// `amount` times the detail finer than `radius` (the blur's standard
+    /// deviation, in output pixels) is added back.
+    Unsharp { amount: f32, radius: f32 },
+}
+
+impl Sharpening {
+    pub fn validate(&self) -> Result<(), Error> {
+        match *self {
+            Sharpening::Unsharp { amount, radius } => {
+                if !(amount >= 0.0 && amount.is_finite()) {
+                    return Err(Error::InvalidInput(format!(
+                        "Sharpening amount must be zero or more, got {amount}"
+                    )));
+                }
+                if !(radius > 0.0 && radius <= MAX_SHARPEN_RADIUS) {
+                    return Err(Error::InvalidInput(format!(
+                        "Sharpening radius must be between 0 and {MAX_SHARPEN_RADIUS}, got {radius}"
+                    )));
+                }
+                Ok(())
+            }
+            Sharpening::None | Sharpening::Auto => Ok(()),
+        }
+    }
+
+    /// The amount and radius to sharpen with, given how many source pixels
+    /// the warp squeezed into each output pixel along each axis; None for no
+    /// sharpening.
+    pub fn parameters(&self, scale: f64) -> Option<(f32, f32)> {
+        match *self {
+            Sharpening::None => None,
+            Sharpening::Auto => (scale > AUTO_SHARPEN_MIN_SCALE).then(|| {
+                let amount =
+                    ((scale - 1.0) * AUTO_SHARPEN_AMOUNT_PER_SCALE).min(AUTO_SHARPEN_MAX_AMOUNT);
+                (amount as f32, AUTO_SHARPEN_RADIUS)
+            }),
+            Sharpening::Unsharp { amount, radius } => (amount > 0.0).then_some((amount, radius)),
+        }
+    }
+}
+
+/// Unsharp mask: adds `amount` times the difference between the image and a
+/// Gaussian blur of it with standard deviation `radius`. Alpha is left alone.
+pub fn unsharp_mask(mut image: RgbaImage, amount: f32, radius: f32) -> RgbaImage {
+    let blurred = gaussian_blur_f32(&image, radius);
+    for (pixel, blurred) in image.pixels_mut().zip(blurred.pixels()) {
+        for (value, &smooth) in pixel.0[..3].iter_mut().zip(&blurred.0[..3]) {
+            let detail = *value as f32 - smooth as f32;
+            *value = (*value as f32 + amount * detail).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    image
+}
+
+/// Turns negatives (and blueprints) positive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Inversion {
+    /// Which of red, green and blue to invert.
+    pub channels: [bool; 3],
+    /// The color of the bare film (e.g. the orange mask of color negatives,
+    /// picked from the unexposed edge of the film), which is divided out of
+    /// each inverted channel before inverting so that it comes out black
+    /// rather than blue.
+    pub film_base: Option<[u8; 3]>,
+}
+
+impl Default for Inversion {
+    fn default() -> Self {
+        Inversion {
+            channels: [true; 3],
+            film_base: None,
+        }
+    }
+}
+
+impl Inversion {
+    pub fn validate(&self) -> Result<(), Error> {
+        match self.film_base {
+            Some(base) if base.contains(&0) => Err(Error::InvalidInput(format!(
+                "Film base color channels must be above zero, got {base:?}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Inverts the chosen channels, after dividing out the film base. Alpha is
+/// left alone.
+pub fn invert(mut image: RgbaImage, inversion: &Inversion) -> RgbaImage {
+    let base = inversion.film_base.unwrap_or([255; 3]);
+    let tables: Vec<Vec<u8>> = (0..3)
+        .map(|channel| {
+            (0..256u32)
+                .map(|level| {
+                    if !inversion.channels[channel] {
+                        return level as u8;
+                    }
+                    let relative = (level * 255 / base[channel] as u32).min(255);
+                    255 - relative as u8
+                })
+                .collect()
+        })
+        .collect();
+    for pixel in image.pixels_mut() {
+        for (value, table) in pixel.0[..3].iter_mut().zip(&tables) {
+            *value = table[*value as usize];
+        }
+    }
+    image
+}
+
+/// Removes ink of one color (e.g. yellow highlighter or a red stamp), as
+/// forms processing does before binarizing, so that only the text is left.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ColorDropout {
+    /// The middle of the hue range, in degrees: 0 for red, 60 for yellow,
+    /// 120 for green, 240 for blue.
+    pub hue: f32,
+    /// How far either side of `hue` (in degrees) still counts.
+    pub tolerance: f32,
+    /// Pixels with less chroma than this (0 to 1, the spread between their
+    /// largest and smallest channel) are kept whatever their hue, which
+    /// spares black text under a highlighter.
+    pub min_chroma: f32,
+}
+
+impl Default for ColorDropout {
+    fn default() -> Self {
+        ColorDropout {
+            hue: 60.0,
+            tolerance: 30.0,
+            min_chroma: 0.25,
+        }
+    }
+}
+
+impl ColorDropout {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.hue.is_finite() {
+            return Err(Error::InvalidInput(format!(
+                "Dropout hue must be a number of degrees, got {}",
+                self.hue
+            )));
+        }
+        if !(0.0..=180.0).contains(&self.tolerance) {
+            return Err(Error::InvalidInput(format!(
+                "Dropout tolerance must be between 0 and 180 degrees, got {}",
+                self.tolerance
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.min_chroma) {
+            return Err(Error::InvalidInput(format!(
+                "Dropout chroma must be between 0 and 1, got {}",
+                self.min_chroma
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Hue (in degrees) and chroma (0 to 1) of an RGB color.
+fn hue_chroma([r, g, b]: [u8; 3]) -> (f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    if chroma == 0.0 {
+        return (0.0, 0.0);
+    }
+    let hue = if max == r {
+        ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    (hue * 60.0, chroma / 255.0)
+}
+
+/// Turns pixels in the dropout's hue range white. Alpha is left alone.
+pub fn drop_out_color(mut image: RgbaImage, dropout: &ColorDropout) -> RgbaImage {
+    for pixel in image.pixels_mut() {
+        let (hue, chroma) = hue_chroma([pixel[0], pixel[1], pixel[2]]);
+        // Distance around the color wheel.
+        let distance = (hue - dropout.hue).rem_euclid(360.0);
+        let distance = distance.min(360.0 - distance);
+        if chroma >= dropout.min_chroma && distance <= dropout.tolerance {
+            pixel.0[..3].fill(255);
+        }
+    }
+    image
+}
+
+/// Removes a color cast (e.g. from warm indoor light) by scaling each
+/// channel so that the paper comes out neutral. The paper is the brightest
+/// part of the image (white patch), ignoring transparent pixels; if there's
+/// none to go on, the image's average is made gray instead (gray world).
+pub fn auto_color(mut image: RgbaImage) -> RgbaImage {
+    let luma = |p: &[u8]| (299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32) / 1000;
+    let mut histogram = [0u64; 256];
+    for pixel in image.pixels().filter(|p| p[3] > 0) {
+        histogram[luma(&pixel.0) as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return image;
+    }
+    // The darkest level of the brightest pixels.
+    let mut seen = 0;
+    let threshold = (0..256)
+        .find(|&level| {
+            seen += histogram[level];
+            seen as f64 >= total as f64 * PAPER_FRACTION
+        })
+        .unwrap_or(255) as u32;
+    let sums = |paper_only: bool| {
+        let (mut sums, mut count) = ([0u64; 3], 0u64);
+        for pixel in image.pixels().filter(|p| p[3] > 0) {
+            if !paper_only || luma(&pixel.0) >= threshold {
+                for (sum, &value) in sums.iter_mut().zip(&pixel.0[..3]) {
+                    *sum += value as u64;
+                }
+                count += 1;
+            }
+        }
+        (count > 0).then(|| sums.map(|sum| sum as f32 / count as f32))
+    };
+    let Some(reference) = sums(true).or_else(|| sums(false)) else {
+        return image;
+    };
+    let neutral = (reference[0] + reference[1] + reference[2]) / 3.0;
+    let gains =
+        reference.map(|channel| (neutral / channel.max(1.0)).clamp(1.0 / MAX_GAIN, MAX_GAIN));
+    for pixel in image.pixels_mut() {
+        for (value, gain) in pixel.0[..3].iter_mut().zip(gains) {
+            *value = (*value as f32 * gain).round().min(255.0) as u8;
+        }
+    }
+    image
+}
@@ -0,0 +1,602 @@
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+use imageproc::binary_descriptors::brief::{brief, BriefDescriptor, TestPair};
+use imageproc::binary_descriptors::match_binary_descriptors;
+use imageproc::corners::{corners_fast9, Corner};
+use imageproc::point::Point;
+use imageproc::suppress::local_maxima;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::CancellationToken;
+use crate::matrix::{self, Matrix, PointPair};
+use crate::{
+    encode, has_transparency, match_color_type, project, warp_bands, Error, Fill, InterpolationMode,
+};
+
+// Features are found with the longer side scaled down to this.
+const WORK_SIZE: u32 = 1000;
+// FAST corner threshold, and how many of the strongest corners (after
+// suppressing those with a stronger neighbour) to describe.
+const FAST_THRESHOLD: u8 = 20;
+const MAX_FEATURES: usize = 1500;
+const SUPPRESSION_RADIUS: u32 = 4;
+// BRIEF descriptors, and the most bits two may differ by to match.
+const DESCRIPTOR_BITS: usize = 256;
+const MATCH_THRESHOLD: u32 = 48;
+// BRIEF patches reach this far (in pixels) around a corner.
+const PATCH_MARGIN: u32 = 17;
+const PATCH_DIAMETER: u32 = 31;
+// RANSAC: hypotheses tried, how close (in working pixels) a match must land
+// to agree with one, and how many must agree for the images to count as
+// aligned.
+const RANSAC_ITERATIONS: usize = 2000;
+const INLIER_DISTANCE: f64 = 3.0;
+const MIN_INLIERS: usize = 12;
+// Largest panorama `stitch` will make.
+const MAX_STITCHED_PIXELS: u64 = 250_000_000;
+// Fixed so that alignment gives the same result every time.
+const SEED: u64 = 0x5eed_1234_abcd_ef01;
+
+/// How aligned shots are combined.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StackBlend {
+    /// Averages every shot, which reduces noise most.
+    Mean,
+    /// Takes the middle value, which also drops anything (a hand, a patch
+    /// of glare) that only some of the shots have.
+    #[default]
+    Median,
+}
+
+/// A xorshift generator; alignment only needs cheap, reproducible choices.
+pub(crate) struct Random(u64);
+
+impl Random {
+    pub(crate) fn new() -> Self {
+        Random(SEED)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Normally distributed, by the Box-Muller transform.
+    fn normal(&mut self, mean: f64, deviation: f64) -> f64 {
+        let u1 = ((self.next() >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let u2 = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        mean + deviation * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+}
+
+/// BRIEF's pixel comparisons, clustered around the middle of the patch as
+/// in the original paper. Every image must be described with the same ones.
+pub(crate) fn test_pairs(random: &mut Random) -> Vec<TestPair> {
+    let mut coordinate = || loop {
+        let value = random.normal(PATCH_DIAMETER as f64 / 2.0, 6.6);
+        if (0.0..PATCH_DIAMETER as f64).contains(&value) {
+            return value as u32;
+        }
+    };
+    (0..DESCRIPTOR_BITS)
+        .map(|_| TestPair {
+            p0: Point::new(coordinate(), coordinate()),
+            p1: Point::new(coordinate(), coordinate()),
+        })
+        .collect()
+}
+
+/// An image's features, found at the working size.
+pub(crate) struct Features {
+    descriptors: Vec<BriefDescriptor>,
+    // Working pixels per full-resolution pixel.
+    scale: f64,
+}
+
+impl Features {
+    pub(crate) fn find(image: &DynamicImage, test_pairs: &Vec<TestPair>) -> Self {
+        let (width, height) = image.dimensions();
+        let scale = (WORK_SIZE as f64 / width.max(height).max(1) as f64).min(1.0);
+        let gray: GrayImage = if scale < 1.0 {
+            image
+                .thumbnail(
+                    (width as f64 * scale).round().max(1.0) as u32,
+                    (height as f64 * scale).round().max(1.0) as u32,
+                )
+                .to_luma8()
+        } else {
+            image.to_luma8()
+        };
+        let (work_width, work_height) = gray.dimensions();
+        let inside = |c: &Corner| {
+            c.x >= PATCH_MARGIN
+                && c.y >= PATCH_MARGIN
+                && c.x + PATCH_MARGIN < work_width
+                && c.y + PATCH_MARGIN < work_height
+        };
+        let corners: Vec<Corner> = corners_fast9(&gray, FAST_THRESHOLD)
+            .into_iter()
+            .filter(inside)
+            .collect();
+        let mut corners = local_maxima(&corners, SUPPRESSION_RADIUS);
+        corners.sort_by(|a, b| b.score.total_cmp(&a.score));
+        corners.truncate(MAX_FEATURES);
+        let keypoints: Vec<Point<u32>> = corners.iter().map(|c| Point::new(c.x, c.y)).collect();
+        let descriptors = brief(&gray, &keypoints, DESCRIPTOR_BITS, Some(test_pairs))
+            .map(|(descriptors, _)| descriptors)
+            .unwrap_or_default();
+        Features { descriptors, scale }
+    }
+}
+
+fn reprojection_error(h: &Matrix, ((x, y), target): PointPair) -> f64 {
+    let (u, v) = matrix::transform(h, (x, y));
+    (u - target.0).hypot(v - target.1)
+}
+
+/// The homography taking `from`'s image onto `to`'s, in full-resolution
+/// pixels, with the number of feature matches that agree with it; None if
+/// too few do for the images to be of the same thing.
+pub(crate) fn estimate_homography(
+    from: &Features,
+    to: &Features,
+    random: &mut Random,
+) -> Option<(Matrix, usize)> {
+    let position = |d: &BriefDescriptor| (d.corner.x as f64, d.corner.y as f64);
+    let matches: Vec<PointPair> = match_binary_descriptors(
+        &from.descriptors,
+        &to.descriptors,
+        MATCH_THRESHOLD,
+        Some(SEED),
+    )
+    .into_iter()
+    .map(|(a, b)| (position(a), position(b)))
+    .collect();
+    if matches.len() < MIN_INLIERS {
+        return None;
+    }
+    let inliers_of = |h: &Matrix| -> Vec<PointPair> {
+        matches
+            .iter()
+            .copied()
+            .filter(|&pair| reprojection_error(h, pair) <= INLIER_DISTANCE)
+            .collect()
+    };
+    let mut best: Option<(Matrix, usize)> = None;
+    for _ in 0..RANSAC_ITERATIONS {
+        let sample: Vec<PointPair> = (0..4)
+            .map(|_| matches[random.below(matches.len())])
+            .collect();
+        let Some(h) = matrix::fit_homography(&sample) else {
+            continue;
+        };
+        let count = inliers_of(&h).len();
+        if best.is_none_or(|(_, best_count)| count > best_count) {
+            best = Some((h, count));
+        }
+    }
+    let (h, _) = best?;
+    // Refit to every match that agreed, then count again.
+    let inliers = inliers_of(&h);
+    let h = matrix::fit_homography(&inliers).unwrap_or(h);
+    let count = inliers_of(&h).len();
+    if count < MIN_INLIERS {
+        return None;
+    }
+    // Full resolution -> working pixels -> the other image's working pixels
+    // -> its full resolution.
+    let full = matrix::multiply(
+        &matrix::scale(1.0 / to.scale as f32, 1.0 / to.scale as f32),
+        &matrix::multiply(&h, &matrix::scale(from.scale as f32, from.scale as f32)),
+    );
+    Some((full, count))
+}
+
+/// Warps `image` into a `size` frame, given the homography from the image
+/// to the frame; uncovered pixels are transparent.
+pub(crate) fn warp_into_frame(
+    image: &DynamicImage,
+    to_frame: &Matrix,
+    size: (u32, u32),
+    cancel: &CancellationToken,
+) -> Result<RgbaImage, Error> {
+    let from_frame = matrix::invert(to_frame).ok_or_else(|| {
+        Error::InvalidInput(String::from("The images don't align to a valid projection"))
+    })?;
+    warp_bands(
+        &image.to_rgba8(),
+        &|x: f32, y: f32| project(&from_frame, x, y),
+        InterpolationMode::Bilinear,
+        Fill::Transparent,
+        size,
+        cancel,
+    )
+}
+
+/// Combines layers of the same size, leaving out each layer's transparent
+/// pixels; pixels no layer covers stay transparent.
+fn blend(layers: &[RgbaImage], blend: StackBlend) -> RgbaImage {
+    let (width, height) = layers[0].dimensions();
+    let mut blended = RgbaImage::new(width, height);
+    blended
+        .par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let mut values: Vec<[u8; 4]> = Vec::with_capacity(layers.len());
+            for (x, pixel) in row.chunks_mut(4).enumerate() {
+                values.clear();
+                values.extend(
+                    layers
+                        .iter()
+                        .map(|layer| layer.get_pixel(x as u32, y as u32).0)
+                        .filter(|p| p[3] > u8::MAX / 2),
+                );
+                if values.is_empty() {
+                    continue;
+                }
+                for channel in 0..3 {
+                    pixel[channel] = match blend {
+                        StackBlend::Mean => {
+                            let sum: u32 = values.iter().map(|p| p[channel] as u32).sum();
+                            (sum as f32 / values.len() as f32).round() as u8
+                        }
+                        StackBlend::Median => {
+                            let mut channel_values: Vec<u8> =
+                                values.iter().map(|p| p[channel]).collect();
+                            channel_values.sort_unstable();
+                            channel_values[channel_values.len() / 2]
+                        }
+                    };
+                }
+                pixel[3] = u8::MAX;
+            }
+        });
+    blended
+}
+
+/// Aligns several shots of the same page to the first one (matching
+/// features between them) and blends them, to reduce noise or, with
+/// `StackBlend::Median` and at least three shots, remove things only some of
+/// them have. The result is the first shot's size and (at 8 bits) color type.
+pub fn stack_align(
+    images: &[&DynamicImage],
+    blend_mode: StackBlend,
+    cancel: &CancellationToken,
+) -> Result<DynamicImage, Error> {
+    let Some((reference, others)) = images.split_first() else {
+        return Err(Error::InvalidInput(String::from(
+            "Need at least one image to align",
+        )));
+    };
+    let mut random = Random::new();
+    let pairs = test_pairs(&mut random);
+    let reference_features = Features::find(reference, &pairs);
+    let size = reference.dimensions();
+    let mut layers = vec![reference.to_rgba8()];
+    for (index, image) in others.iter().enumerate() {
+        cancel.check()?;
+        let features = Features::find(image, &pairs);
+        let (to_reference, _) = estimate_homography(&features, &reference_features, &mut random)
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "Couldn't align image {} with the first one",
+                    index + 2
+                ))
+            })?;
+        layers.push(warp_into_frame(image, &to_reference, size, cancel)?);
+    }
+    cancel.check()?;
+    let blended = blend(&layers, blend_mode);
+    Ok(match_color_type(
+        DynamicImage::ImageRgba8(blended),
+        reference.color(),
+        false,
+        false,
+        encode::DEFAULT_BACKGROUND,
+    ))
+}
+
+/// Bilinear sample of `image` at (x, y), or None outside it.
+fn sample(image: &RgbaImage, x: f64, y: f64) -> Option<[f32; 4]> {
+    let (width, height) = image.dimensions();
+    if !(x >= -0.5 && y >= -0.5 && x <= width as f64 - 0.5 && y <= height as f64 - 0.5) {
+        return None;
+    }
+    let x = x.clamp(0.0, width as f64 - 1.0);
+    let y = y.clamp(0.0, height as f64 - 1.0);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+    let mut value = [0.0; 4];
+    for (px, py, weight) in [
+        (x0, y0, (1.0 - tx) * (1.0 - ty)),
+        (x1, y0, tx * (1.0 - ty)),
+        (x0, y1, (1.0 - tx) * ty),
+        (x1, y1, tx * ty),
+    ] {
+        for (v, &channel) in value.iter_mut().zip(&image.get_pixel(px, py).0) {
+            *v += weight * channel as f32;
+        }
+    }
+    Some(value)
+}
+
+/// Joins overlapping photos (e.g. squared sections of a big whiteboard or
+/// poster) into one image. Each tile is placed by matching features with a
+/// tile already placed, starting from the first, which keeps its own
+/// perspective; overlaps are feathered, each tile counting for less towards
+/// its edges. Uncovered parts of the result are transparent.
+pub fn stitch(images: &[&DynamicImage], cancel: &CancellationToken) -> Result<DynamicImage, Error> {
+    let Some(first) = images.first() else {
+        return Err(Error::InvalidInput(String::from(
+            "Need at least one image to stitch",
+        )));
+    };
+    let mut random = Random::new();
+    let pairs = test_pairs(&mut random);
+    let features: Vec<Features> = images
+        .par_iter()
+        .map(|image| Features::find(image, &pairs))
+        .collect();
+    cancel.check()?;
+    // Each tile's homography into the first tile's frame.
+    let mut placements: Vec<Option<Matrix>> = vec![None; images.len()];
+    placements[0] = Some(matrix::scale(1.0, 1.0));
+    for _ in 1..images.len() {
+        let mut best: Option<(usize, Matrix, usize)> = None;
+        for (tile, placement) in placements.iter().enumerate() {
+            if placement.is_some() {
+                continue;
+            }
+            for (placed, to_first) in placements.iter().enumerate() {
+                let Some(to_first) = to_first else {
+                    continue;
+                };
+                if let Some((to_placed, count)) =
+                    estimate_homography(&features[tile], &features[placed], &mut random)
+                {
+                    if best.is_none_or(|(_, _, best_count)| count > best_count) {
+                        best = Some((tile, matrix::multiply(to_first, &to_placed), count));
+                    }
+                }
+            }
+        }
+        cancel.check()?;
+        let Some((tile, to_first, _)) = best else {
+            let unplaced = placements.iter().position(Option::is_none).unwrap_or(0);
+            return Err(Error::InvalidInput(format!(
+                "Couldn't find any overlap between image {} and the others",
+                unplaced + 1
+            )));
+        };
+        placements[tile] = Some(to_first);
+    }
+    let placements: Vec<Matrix> = placements.into_iter().flatten().collect();
+    // The panorama's extent, in the first tile's frame.
+    let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+    let (mut max_x, mut max_y) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for (image, placement) in images.iter().zip(&placements) {
+        let (width, height) = (image.width() as f64, image.height() as f64);
+        for corner in [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)] {
+            let (x, y) = matrix::transform(placement, corner);
+            min_x = min_x.min(x);
+            max_x = max_x.max(x);
+            min_y = min_y.min(y);
+            max_y = max_y.max(y);
+        }
+    }
+    let (origin_x, origin_y) = (min_x.floor(), min_y.floor());
+    let width = (max_x.ceil() - origin_x).max(1.0);
+    let height = (max_y.ceil() - origin_y).max(1.0);
+    let area = width * height;
+    if !area.is_finite() || area > MAX_STITCHED_PIXELS as f64 {
+        return Err(Error::ImageTooLarge(format!(
+            "The stitched image would be {width}x{height}"
+        )));
+    }
+    let (width, height) = (width as u32, height as u32);
+    // Panorama pixels -> each tile's pixels.
+    let from_panorama: Vec<Matrix> = placements
+        .iter()
+        .map(|placement| {
+            let placement = matrix::multiply(
+                &matrix::translate(-origin_x as f32, -origin_y as f32),
+                placement,
+            );
+            matrix::invert(&placement).ok_or_else(|| {
+                Error::InvalidInput(String::from("The images don't align to a valid projection"))
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    let tiles: Vec<RgbaImage> = images.iter().map(|image| image.to_rgba8()).collect();
+    let mut panorama = RgbaImage::new(width, height);
+    panorama
+        .par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            if y % 256 == 0 {
+                cancel.check()?;
+            }
+            for (x, pixel) in row.chunks_mut(4).enumerate() {
+                let (mut sum, mut total) = ([0.0f32; 3], 0.0f32);
+                for (tile, m) in tiles.iter().zip(&from_panorama) {
+                    let (tx, ty) = matrix::transform(m, (x as f64, y as f64));
+                    let Some(value) = sample(tile, tx, ty) else {
+                        continue;
+                    };
+                    // Feathering: the distance to the tile's nearest edge.
+                    let edge = (tx + 0.5)
+                        .min(tile.width() as f64 - 0.5 - tx)
+                        .min(ty + 0.5)
+                        .min(tile.height() as f64 - 0.5 - ty)
+                        .max(0.0) as f32
+                        + 1.0;
+                    let weight = edge * value[3] / u8::MAX as f32;
+                    for (s, v) in sum.iter_mut().zip(&value) {
+                        *s += weight * v;
+                    }
+                    total += weight;
+                }
+                if total > 0.0 {
+                    for (channel, s) in pixel.iter_mut().zip(&sum) {
+                        *channel = (s / total).round().clamp(0.0, 255.0) as u8;
+                    }
+                    pixel[3] = u8::MAX;
+                }
+            }
+            Ok::<(), Error>(())
+        })?;
+    let panorama = DynamicImage::ImageRgba8(panorama);
+    let keep_alpha = has_transparency(&panorama);
+    Ok(match_color_type(
+        panorama,
+        first.color(),
+        keep_alpha,
+        false,
+        encode::DEFAULT_BACKGROUND,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{imageops, RgbImage};
+
+    /// A scene of overlapping rectangles in random greys, sharp-cornered
+    /// enough everywhere for features to be found and matched.
+    fn scene(width: u32, height: u32) -> RgbImage {
+        let mut random = Random::new();
+        let mut scene = RgbImage::from_pixel(width, height, image::Rgb([128, 128, 128]));
+        for _ in 0..(width * height / 400) {
+            let (x, y) = (random.below(width as usize), random.below(height as usize));
+            let (w, h) = (4 + random.below(40), 4 + random.below(40));
+            let value = random.below(256) as u8;
+            for py in y..(y + h).min(height as usize) {
+                for px in x..(x + w).min(width as usize) {
+                    scene.put_pixel(px as u32, py as u32, image::Rgb([value, value, value]));
+                }
+            }
+        }
+        scene
+    }
+
+    fn crop(scene: &RgbImage, x: u32, y: u32, width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(imageops::crop_imm(scene, x, y, width, height).to_image())
+    }
+
+    /// How far apart two images of the same size are on average, ignoring a
+    /// `border` the alignment may leave uncovered.
+    fn mean_difference(a: &DynamicImage, b: &DynamicImage, border: u32) -> f64 {
+        let (a, b) = (a.to_luma8(), b.to_luma8());
+        let (width, height) = a.dimensions();
+        let (mut sum, mut count) = (0.0, 0.0);
+        for y in border..height - border {
+            for x in border..width - border {
+                sum += (a.get_pixel(x, y)[0] as f64 - b.get_pixel(x, y)[0] as f64).abs();
+                count += 1.0;
+            }
+        }
+        sum / count
+    }
+
+    #[test]
+    fn stack_align_recovers_a_known_shift() {
+        let scene = scene(640, 480);
+        let reference = crop(&scene, 40, 30, 500, 380);
+        // The same page shot 25 pixels further right and 14 further down.
+        let shifted = crop(&scene, 65, 44, 500, 380);
+
+        let mut random = Random::new();
+        let pairs = test_pairs(&mut random);
+        let (to_reference, inliers) = estimate_homography(
+            &Features::find(&shifted, &pairs),
+            &Features::find(&reference, &pairs),
+            &mut random,
+        )
+        .expect("the shots should align");
+        assert!(inliers >= MIN_INLIERS);
+        for corner in [(0.0, 0.0), (499.0, 0.0), (0.0, 379.0), (250.0, 190.0)] {
+            let (x, y) = matrix::transform(&to_reference, corner);
+            assert!(
+                (x - corner.0 - 25.0).abs() < 1.0 && (y - corner.1 - 14.0).abs() < 1.0,
+                "{corner:?} maps to ({x}, {y})"
+            );
+        }
+
+        let stacked = stack_align(
+            &[&reference, &shifted],
+            StackBlend::Mean,
+            &CancellationToken::default(),
+        )
+        .unwrap();
+        assert_eq!(stacked.dimensions(), reference.dimensions());
+        let difference = mean_difference(&stacked, &reference, 30);
+        assert!(
+            difference < 4.0,
+            "stacked differs by {difference} on average"
+        );
+    }
+
+    #[test]
+    fn stack_align_rejects_shots_that_barely_overlap() {
+        let scene = scene(1200, 400);
+        let reference = crop(&scene, 0, 0, 500, 400);
+        let elsewhere = crop(&scene, 480, 0, 500, 400);
+        let result = stack_align(
+            &[&reference, &elsewhere],
+            StackBlend::Median,
+            &CancellationToken::default(),
+        );
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "shots 20 pixels apart aligned"
+        );
+    }
+
+    #[test]
+    fn stitch_joins_tiles_at_their_overlap() {
+        let scene = scene(900, 400);
+        let left = crop(&scene, 0, 0, 500, 400);
+        // Overlapping the left tile by 200 pixels.
+        let right = crop(&scene, 300, 0, 500, 400);
+        let stitched = stitch(&[&left, &right], &CancellationToken::default()).unwrap();
+        let (width, height) = stitched.dimensions();
+        assert!(
+            width.abs_diff(800) <= 2 && height.abs_diff(400) <= 2,
+            "stitched into {width}x{height}"
+        );
+        // Rounding the extent out may leave a pixel or two above and to the
+        // left of the first tile.
+        let (width, height) = (796, 396);
+        let expected = crop(&scene, 0, 0, width, height);
+        let stitched = stitched.to_rgb8();
+        let difference = (0..=2)
+            .flat_map(|dy| (0..=2).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| mean_difference(&crop(&stitched, dx, dy, width, height), &expected, 4))
+            .fold(f64::INFINITY, f64::min);
+        assert!(
+            difference < 2.0,
+            "stitched differs by {difference} on average"
+        );
+    }
+
+    #[test]
+    fn stitch_rejects_tiles_that_barely_overlap() {
+        let scene = scene(1200, 400);
+        let left = crop(&scene, 0, 0, 500, 400);
+        let right = crop(&scene, 480, 0, 500, 400);
+        let result = stitch(&[&left, &right], &CancellationToken::default());
+        assert!(
+            matches!(result, Err(Error::InvalidInput(_))),
+            "tiles overlapping by 20 pixels stitched"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::png::PngDecoder;
+use image::error::{EncodingError, ImageFormatHint};
+use image::{AnimationDecoder, DynamicImage, Frame, Frames, ImageError, ImageFormat, RgbaImage};
+use imageproc::point::Point;
+
+use std::io::Cursor;
+
+use crate::cancel::CancellationToken;
+use crate::decode::DecodeLimits;
+use crate::{square_quad, Error, ProcessingOptions};
+
+/// The animated formats that can be squared frame by frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+/// The frames of `bytes` if it's an animation (with more than one frame),
+/// composited onto the full canvas, along with its format. Returns None for
+/// anything else, including still GIFs and PNGs.
+pub fn decode_frames(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+) -> Result<Option<(AnimationFormat, Vec<Frame>)>, Error> {
+    let (format, frames): (_, Frames) = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => (
+            AnimationFormat::Gif,
+            GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            (AnimationFormat::Apng, decoder.apng()?.into_frames())
+        }
+        _ => return Ok(None),
+    };
+    let mut decoded = Vec::new();
+    let mut total_bytes = 0;
+    for frame in frames {
+        let frame = frame?;
+        let (width, height) = frame.buffer().dimensions();
+        total_bytes += frame.buffer().as_raw().len() as u64;
+        limits.check(width, height, total_bytes)?;
+        decoded.push(frame);
+    }
+    Ok((decoded.len() > 1).then_some((format, decoded)))
+}
+
+fn png_error(error: png::EncodingError) -> Error {
+    Error::Image(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        error,
+    )))
+}
+
+fn encode_apng(frames: Vec<Frame>) -> Result<Vec<u8>, Error> {
+    let (width, height) = frames[0].buffer().dimensions();
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    // Zero plays means looping forever.
+    encoder
+        .set_animated(frames.len() as u32, 0)
+        .map_err(png_error)?;
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for frame in &frames {
+        // APNG delays are in seconds; the numerator and denominator only
+        // have 16 bits each, so go via whole milliseconds if they overflow.
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        let (numerator, denominator) = match (
+            u16::try_from(numerator),
+            u16::try_from(denominator as u64 * 1000),
+        ) {
+            (Ok(n), Ok(d)) => (n, d),
+            _ => (
+                (numerator / denominator.max(1)).min(u16::MAX as u32) as u16,
+                1000,
+            ),
+        };
+        writer
+            .set_frame_delay(numerator, denominator)
+            .map_err(png_error)?;
+        writer
+            .write_image_data(frame.buffer().as_raw())
+            .map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)?;
+    Ok(bytes)
+}
+
+/// Squares each frame with the same quad (full-canvas frames all share the
+/// source's dimensions) and re-encodes the animation in its original format,
+/// keeping each frame's delay. `options.output_format` doesn't apply.
+pub fn square_animation(
+    format: AnimationFormat,
+    frames: Vec<Frame>,
+    corners: &[Point<f64>],
+    options: &ProcessingOptions,
+    cancel: &CancellationToken,
+) -> Result<Vec<u8>, Error> {
+    let squared = frames
+        .into_iter()
+        .map(|frame| {
+            cancel.check()?;
+            let delay = frame.delay();
+            let source = DynamicImage::ImageRgba8(frame.into_buffer());
+            let squared: RgbaImage =
+                square_quad(&source, corners.to_vec(), options, cancel)?.to_rgba8();
+            Ok(Frame::from_parts(squared, 0, 0, delay))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    match format {
+        AnimationFormat::Gif => {
+            let mut bytes = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut bytes);
+                encoder.set_repeat(Repeat::Infinite)?;
+                encoder.encode_frames(squared)?;
+            }
+            Ok(bytes)
+        }
+        AnimationFormat::Apng => encode_apng(squared),
+    }
+}
@@ -0,0 +1,72 @@
+// Estimates the real-world aspect ratio of a photographed rectangle, following
+// Zhang & He, "Whiteboard scanning and image enhancement" (2007). The camera is
+// assumed to have square pixels and its principal point at the image center;
+// the focal length is recovered from the vanishing points of the quad's edges.
+
+type Vec3 = [f64; 3];
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Returns width / height of the rectangle whose image is the quadrilateral
+/// with the given corners (top-left, top-right, bottom-right, bottom-left, in
+/// pixels), or None if the corners are degenerate.
+///
+/// `center` is the center of the full photo (not of any crop), which stands in
+/// for the camera's principal point. `fallback_focal_length` (in pixels) is used
+/// when the focal length can't be recovered from the corners.
+pub fn estimate_aspect_ratio(
+    corners: &[(f32, f32)],
+    center: (f32, f32),
+    fallback_focal_length: f32,
+) -> Option<f32> {
+    let [tl, tr, br, bl] = corners else {
+        return None;
+    };
+    let homogeneous =
+        |&(x, y): &(f32, f32)| -> Vec3 { [(x - center.0) as f64, (y - center.1) as f64, 1.0] };
+    let (m1, m2, m3, m4) = (
+        homogeneous(tl),
+        homogeneous(tr),
+        homogeneous(bl),
+        homogeneous(br),
+    );
+
+    let m1_x_m4 = cross(m1, m4);
+    let k2_denominator = dot(cross(m2, m4), m3);
+    let k3_denominator = dot(cross(m3, m4), m2);
+    if k2_denominator.abs() < f64::EPSILON || k3_denominator.abs() < f64::EPSILON {
+        return None;
+    }
+    let k2 = dot(m1_x_m4, m3) / k2_denominator;
+    let k3 = dot(m1_x_m4, m2) / k3_denominator;
+    let n2: Vec3 = std::array::from_fn(|i| k2 * m2[i] - m1[i]);
+    let n3: Vec3 = std::array::from_fn(|i| k3 * m3[i] - m1[i]);
+
+    // n2 and n3 are the images of the rectangle's edge directions. With
+    // calibration matrix A = diag(f, f, 1), the ratio of their lengths on the
+    // actual plane is |A^-1 n2| / |A^-1 n3|.
+    let focal_denominator = n2[2] * n3[2];
+    let focal_sq = Some(-(n2[0] * n3[0] + n2[1] * n3[1]) / focal_denominator)
+        .filter(|f_sq| focal_denominator.abs() > 1e-9 && f_sq.is_finite() && *f_sq > 0.0)
+        // If either pair of opposite edges is parallel in the photo there's only
+        // one vanishing point, which doesn't pin down the focal length.
+        .unwrap_or((fallback_focal_length as f64).powi(2));
+    let width_sq = (n2[0] * n2[0] + n2[1] * n2[1]) / focal_sq + n2[2] * n2[2];
+    let height_sq = (n3[0] * n3[0] + n3[1] * n3[1]) / focal_sq + n3[2] * n3[2];
+    let ratio = (width_sq / height_sq).sqrt();
+    if ratio.is_finite() && ratio > 0.0 {
+        Some(ratio as f32)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,125 @@
+use image::{DynamicImage, GenericImageView, GrayImage};
+use imageproc::filter::box_filter;
+
+use crate::Error;
+
+/// How far past the gutter each page extends by default, as a fraction of
+/// the spread's width, so that text running into the fold isn't cut off.
+pub const DEFAULT_OVERLAP: f32 = 0.02;
+
+// The gutter is looked for at this working width.
+const WORK_WIDTH: u32 = 600;
+// ...and only in this middle part of the spread.
+const SEARCH_START: f64 = 0.35;
+const SEARCH_END: f64 = 0.65;
+// Radius of the vertical box filter a pixel is compared against to count as
+// ink (text lines stand out from the rows around them, where the fold's
+// shadow doesn't), and how much darker than the local mean it must be.
+const INK_RADIUS: u32 = 10;
+const INK_OFFSET: u8 = 12;
+// Columns with less than this fraction of ink count as blank.
+const BLANK_INK: f64 = 0.01;
+// How much darker (in gray levels) than the rest of the blank band the fold's
+// shadow must be to be taken as the gutter rather than the band's middle.
+const GUTTER_SHADOW: f64 = 8.0;
+
+/// Finds the x coordinate of the fold between the two pages of a squared
+/// open-book spread: the fold's shadow within the blank band between the two
+/// pages' text if there is one, otherwise the middle of that band.
+pub fn find_gutter(spread: &DynamicImage) -> u32 {
+    let (width, height) = spread.dimensions();
+    let gray: GrayImage = if width > WORK_WIDTH {
+        spread.thumbnail(WORK_WIDTH, height).to_luma8()
+    } else {
+        spread.to_luma8()
+    };
+    let (work_width, work_height) = gray.dimensions();
+    let mean = box_filter(&gray, 0, INK_RADIUS);
+    let start = (work_width as f64 * SEARCH_START) as u32;
+    let end = ((work_width as f64 * SEARCH_END) as u32).max(start + 1);
+    // Ink fraction and mean brightness of each column in the search band.
+    let columns: Vec<(f64, f64)> = (start..end)
+        .map(|x| {
+            let (mut ink, mut total) = (0u32, 0u64);
+            for y in 0..work_height {
+                let value = gray.get_pixel(x, y)[0];
+                total += value as u64;
+                if value.saturating_add(INK_OFFSET) < mean.get_pixel(x, y)[0] {
+                    ink += 1;
+                }
+            }
+            let rows = work_height.max(1) as f64;
+            (ink as f64 / rows, total as f64 / rows)
+        })
+        .collect();
+    let center = columns.len() / 2;
+    let mut best_run: Option<(usize, usize)> = None;
+    let mut run_start = None;
+    for i in 0..=columns.len() {
+        let blank = i < columns.len() && columns[i].0 < BLANK_INK;
+        match (blank, run_start) {
+            (true, None) => run_start = Some(i),
+            (false, Some(first)) => {
+                if best_run.is_none_or(|(a, b)| i - first > b - a) {
+                    best_run = Some((first, i));
+                }
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    let column = match best_run {
+        Some((first, last)) => {
+            let run = &columns[first..last];
+            let (darkest, darkest_mean) = run
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (i, c.1))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap_or((0, 0.0));
+            let brightest = run.iter().map(|c| c.1).fold(0.0, f64::max);
+            if brightest - darkest_mean >= GUTTER_SHADOW {
+                first + darkest
+            } else {
+                (first + last) / 2
+            }
+        }
+        // Text runs into the fold: go by the column with least ink, nearest
+        // the middle on a tie.
+        None => (0..columns.len())
+            .min_by(|&a, &b| {
+                columns[a]
+                    .0
+                    .total_cmp(&columns[b].0)
+                    .then(a.abs_diff(center).cmp(&b.abs_diff(center)))
+            })
+            .unwrap_or(center),
+    };
+    let x = (start as usize + column) as f64 + 0.5;
+    ((x * width as f64 / work_width as f64) as u32).min(width.saturating_sub(1))
+}
+
+/// Splits a squared open-book spread at its gutter into the left and right
+/// pages, each extending `overlap` (a fraction of the spread's width) past
+/// the gutter.
+pub fn split_pages(spread: &DynamicImage, overlap: f32) -> Result<[DynamicImage; 2], Error> {
+    if !(0.0..=0.5).contains(&overlap) {
+        return Err(Error::InvalidInput(format!(
+            "Overlap must be between 0 and 0.5, got {overlap}"
+        )));
+    }
+    let (width, height) = spread.dimensions();
+    if width < 2 {
+        return Err(Error::InvalidInput(String::from(
+            "The spread is too narrow to split",
+        )));
+    }
+    let gutter = find_gutter(spread).clamp(1, width - 1);
+    let extra = (width as f32 * overlap).round() as u32;
+    let left_end = (gutter + extra).min(width);
+    let right_start = gutter.saturating_sub(extra);
+    Ok([
+        spread.crop_imm(0, 0, left_end, height),
+        spread.crop_imm(right_start, 0, width - right_start, height),
+    ])
+}
@@ -0,0 +1,251 @@
+use image::imageops::FilterType;
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::morphology::{grayscale_dilate, Mask};
+use imageproc::region_labelling::{connected_components, Connectivity};
+use serde::{Deserialize, Serialize};
+
+/// Post-warp cleanup applied to the squared image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupMode {
+    #[default]
+    None,
+    /// Bilevel output for receipts and forms: flattened background, Sauvola
+    /// thresholding and despeckling.
+    Document,
+    /// For photos of whiteboards: the board evened out to white (glare
+    /// included), and pen strokes darkened and made more saturated.
+    Whiteboard,
+}
+
+// Background estimation works at this fraction of the full resolution; text is
+// removed by a max filter at this scale and lighting varies smoothly anyway.
+const BACKGROUND_SCALE: u32 = 8;
+// Sauvola's k: how far below the local mean (relative to local contrast) a
+// pixel must be to count as ink.
+const SAUVOLA_K: f64 = 0.2;
+// Sauvola's R: the dynamic range of the standard deviation.
+const SAUVOLA_R: f64 = 128.0;
+// Ink blobs with fewer pixels than this are treated as noise.
+const SPECKLE_SIZE: u32 = 4;
+// Whiteboard colors at or above this fraction of the local board color
+// become white.
+const WHITEBOARD_WHITE_POINT: f32 = 0.9;
+// Gamma applied to whiteboard colors, which darkens faint strokes while
+// leaving the board white.
+const WHITEBOARD_GAMMA: f32 = 1.6;
+// How much a whiteboard pixel's difference from gray is amplified.
+const WHITEBOARD_SATURATION: f32 = 1.5;
+
+pub fn apply(image: RgbaImage, mode: CleanupMode) -> RgbaImage {
+    match mode {
+        CleanupMode::None => image,
+        CleanupMode::Document => {
+            let gray = image::imageops::grayscale(&image);
+            let flattened = flatten_background(&gray);
+            // Windows of roughly a couple of text lines.
+            let radius = std::cmp::max(7, std::cmp::min(gray.width(), gray.height()) / 60);
+            let mut binary = sauvola_threshold(&flattened, radius);
+            despeckle(&mut binary, SPECKLE_SIZE);
+            with_alpha_of(&binary, &image)
+        }
+        CleanupMode::Whiteboard => enhance_whiteboard(image),
+    }
+}
+
+// Shadow removal brightens each pixel's background up to this percentile of
+// the background across the image, so the page keeps its overall brightness
+// rather than turning pure white.
+const PAPER_PERCENTILE: f64 = 0.99;
+
+/// Divides out uneven lighting (such as a phone's shadow falling across the
+/// page) using the estimated background, keeping colors' hues.
+pub fn remove_shadows(image: RgbaImage) -> RgbaImage {
+    let gray = image::imageops::grayscale(&image);
+    let background = estimate_background(&gray);
+    let mut levels: Vec<u8> = background.iter().copied().collect();
+    levels.sort_unstable();
+    let index = ((levels.len() as f64 * PAPER_PERCENTILE) as usize).min(levels.len() - 1);
+    let paper = levels[index].max(1) as f32;
+    let mut image = image;
+    for (pixel, local) in image.pixels_mut().zip(background.pixels()) {
+        let gain = paper / local[0].max(1) as f32;
+        for value in &mut pixel.0[..3] {
+            *value = (*value as f32 * gain).round().min(255.0) as u8;
+        }
+    }
+    image
+}
+
+/// Estimates the paper color at each pixel: the local brightest value after
+/// removing ink, smoothed out.
+fn estimate_background(gray: &GrayImage) -> GrayImage {
+    let (width, height) = gray.dimensions();
+    let small = image::imageops::resize(
+        gray,
+        std::cmp::max(1, width / BACKGROUND_SCALE),
+        std::cmp::max(1, height / BACKGROUND_SCALE),
+        FilterType::Triangle,
+    );
+    let without_ink = grayscale_dilate(&small, &Mask::square(2));
+    let smooth = gaussian_blur_f32(&without_ink, 2.0);
+    image::imageops::resize(&smooth, width, height, FilterType::Triangle)
+}
+
+/// Divides out the estimated background so that paper is uniformly white,
+/// whatever the lighting across the page.
+fn flatten_background(gray: &GrayImage) -> GrayImage {
+    let background = estimate_background(gray);
+    GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let value = gray.get_pixel(x, y)[0] as u32;
+        let paper = std::cmp::max(1, background.get_pixel(x, y)[0] as u32);
+        Luma([std::cmp::min(255, value * 255 / paper) as u8])
+    })
+}
+
+/// Divides each channel by its own estimated background, so that the board
+/// is white even where lighting or glare made it brighter or tinted; then
+/// whitens what's nearly white, and darkens and saturates the rest.
+fn enhance_whiteboard(image: RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let backgrounds: Vec<GrayImage> = (0..3)
+        .map(|channel| {
+            let plane =
+                GrayImage::from_fn(width, height, |x, y| Luma([image.get_pixel(x, y)[channel]]));
+            estimate_background(&plane)
+        })
+        .collect();
+    RgbaImage::from_fn(width, height, |x, y| {
+        let pixel = image.get_pixel(x, y);
+        let [r, g, b] = [0, 1, 2].map(|channel| {
+            let board = backgrounds[channel].get_pixel(x, y)[0].max(1) as f32;
+            let value = (pixel[channel] as f32 / board / WHITEBOARD_WHITE_POINT).min(1.0);
+            value.powf(WHITEBOARD_GAMMA)
+        });
+        let gray = 0.299 * r + 0.587 * g + 0.114 * b;
+        let [r, g, b] = [r, g, b].map(|value| {
+            let boosted = gray + (value - gray) * WHITEBOARD_SATURATION;
+            (boosted.clamp(0.0, 1.0) * 255.0).round() as u8
+        });
+        Rgba([r, g, b, pixel[3]])
+    })
+}
+
+/// Sauvola's adaptive threshold: ink (0) where a pixel is darker than
+/// mean * (1 + k * (stddev / R - 1)) over the surrounding window, else paper (255).
+fn sauvola_threshold(gray: &GrayImage, radius: u32) -> GrayImage {
+    let (width, height) = gray.dimensions();
+    let stride = width as usize + 1;
+    // Summed-area tables with an extra leading row and column of zeros.
+    let mut sums = vec![0u64; stride * (height as usize + 1)];
+    let mut squares = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        let (mut row_sum, mut row_squares) = (0u64, 0u64);
+        for x in 0..width as usize {
+            let value = gray.get_pixel(x as u32, y as u32)[0] as u64;
+            row_sum += value;
+            row_squares += value * value;
+            sums[(y + 1) * stride + x + 1] = sums[y * stride + x + 1] + row_sum;
+            squares[(y + 1) * stride + x + 1] = squares[y * stride + x + 1] + row_squares;
+        }
+    }
+    let window_sum = |table: &[u64], x0: usize, y0: usize, x1: usize, y1: usize| {
+        table[y1 * stride + x1] + table[y0 * stride + x0]
+            - table[y0 * stride + x1]
+            - table[y1 * stride + x0]
+    };
+
+    GrayImage::from_fn(width, height, |x, y| {
+        let x0 = x.saturating_sub(radius) as usize;
+        let y0 = y.saturating_sub(radius) as usize;
+        let x1 = std::cmp::min(width, x + radius + 1) as usize;
+        let y1 = std::cmp::min(height, y + radius + 1) as usize;
+        let count = ((x1 - x0) * (y1 - y0)) as f64;
+        let mean = window_sum(&sums, x0, y0, x1, y1) as f64 / count;
+        let variance = window_sum(&squares, x0, y0, x1, y1) as f64 / count - mean * mean;
+        let threshold = mean * (1.0 + SAUVOLA_K * (variance.max(0.0).sqrt() / SAUVOLA_R - 1.0));
+        if (gray.get_pixel(x, y)[0] as f64) < threshold {
+            Luma([0])
+        } else {
+            Luma([255])
+        }
+    })
+}
+
+/// Turns ink blobs smaller than `max_size` pixels into paper.
+fn despeckle(binary: &mut GrayImage, max_size: u32) {
+    let labels = connected_components(&*binary, Connectivity::Eight, Luma([255u8]));
+    let mut sizes: Vec<u32> = Vec::new();
+    for label in labels.pixels() {
+        let label = label[0] as usize;
+        if label >= sizes.len() {
+            sizes.resize(label + 1, 0);
+        }
+        sizes[label] += 1;
+    }
+    for (pixel, label) in binary.pixels_mut().zip(labels.pixels()) {
+        let label = label[0] as usize;
+        if label != 0 && sizes[label] < max_size {
+            *pixel = Luma([255]);
+        }
+    }
+}
+
+/// Expands a grayscale result back to RGBA, keeping the original's alpha (so
+/// areas outside the source stay transparent).
+fn with_alpha_of(gray: &GrayImage, original: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let value = gray.get_pixel(x, y)[0];
+        Rgba([value, value, value, original.get_pixel(x, y)[3]])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 480;
+    const HEIGHT: u32 = 320;
+
+    /// Lines of "words" made of strokes a few pixels thick.
+    fn is_ink(x: u32, y: u32) -> bool {
+        let (column, row) = (x % 24, y % 16);
+        (4..7).contains(&row) && column < 18 || (2..5).contains(&column) && row < 10
+    }
+
+    /// A page lit unevenly, from dim at the bottom left to bright at the top
+    /// right, with the ink always a third as bright as the paper around it:
+    /// ink in the light is brighter than paper in the shade, so no single
+    /// threshold would do.
+    fn page() -> RgbaImage {
+        RgbaImage::from_fn(WIDTH, HEIGHT, |x, y| {
+            let light =
+                0.25 + 0.5 * x as f32 / WIDTH as f32 + 0.25 * (HEIGHT - y) as f32 / HEIGHT as f32;
+            let paper = 240.0 * light;
+            let value = if is_ink(x, y) { paper / 3.0 } else { paper };
+            let value = value.round() as u8;
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    #[test]
+    fn document_cleanup_separates_ink_from_unevenly_lit_paper() {
+        let cleaned = apply(page(), CleanupMode::Document);
+        let (mut ink, mut ink_found, mut paper, mut paper_found) = (0, 0, 0, 0);
+        for (x, y, pixel) in cleaned.enumerate_pixels() {
+            assert!(pixel[0] == 0 || pixel[0] == 255, "({x}, {y}) isn't bilevel");
+            if is_ink(x, y) {
+                ink += 1;
+                ink_found += (pixel[0] == 0) as u32;
+            } else {
+                paper += 1;
+                paper_found += (pixel[0] == 255) as u32;
+            }
+        }
+        let ink_rate = ink_found as f64 / ink as f64;
+        let paper_rate = paper_found as f64 / paper as f64;
+        assert!(ink_rate > 0.95, "only {ink_rate} of the ink is black");
+        assert!(paper_rate > 0.98, "only {paper_rate} of the paper is white");
+    }
+}
@@ -0,0 +1,218 @@
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Seek};
+use std::path::Path;
+
+use crate::{heif, metadata, raw, Error};
+
+/// A decoded image along with its raw EXIF block, if it had one.
+pub struct SourceImage {
+    pub image: DynamicImage,
+    pub exif: Option<Vec<u8>>,
+}
+
+/// Caps on what will be decoded, so that a huge (or maliciously crafted)
+/// image fails up front instead of exhausting memory.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// The most memory, in bytes, the decoder may allocate for one image.
+    pub max_alloc: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: 32_768,
+            max_height: 32_768,
+            max_alloc: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Fails with `Error::ImageTooLarge` if an image of the given size, which
+    /// takes `bytes` to decode, is over the limits.
+    pub fn check(&self, width: u32, height: u32, bytes: u64) -> Result<(), Error> {
+        if width > self.max_width || height > self.max_height || bytes > self.max_alloc {
+            return Err(Error::ImageTooLarge(format!(
+                "{width}x{height} exceeds the limits of {}x{} and {} MiB",
+                self.max_width,
+                self.max_height,
+                self.max_alloc / (1024 * 1024)
+            )));
+        }
+        Ok(())
+    }
+
+    fn to_image_limits(self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
+/// Decodes the image, applying any EXIF orientation so that the pixels match
+/// what a browser displays (and so where the user placed the control points).
+/// Images beyond `limits` fail with `Error::ImageTooLarge`.
+pub fn read_image<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    limits: &DecodeLimits,
+) -> Result<SourceImage, Error> {
+    let mut decoder = reader.with_guessed_format()?.into_decoder()?;
+    // Checked up front for a clearer error; the decoder enforces the limits
+    // again on what it allocates while decoding.
+    let (width, height) = decoder.dimensions();
+    let bytes = decoder.total_bytes();
+    limits.check(width, height, bytes)?;
+    decoder
+        .set_limits(limits.to_image_limits())
+        .map_err(too_large)?;
+    let orientation = decoder.orientation()?;
+    let exif = decoder.exif_metadata()?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(too_large)?;
+    image.apply_orientation(orientation);
+    Ok(SourceImage { image, exif })
+}
+
+/// What's known about an image from its headers, without decoding its pixels.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    /// The size as displayed, with the EXIF orientation applied.
+    pub width: u32,
+    pub height: u32,
+    /// The format's usual extension, e.g. "png" or "heic".
+    pub format: String,
+    /// The pixel layout stored in the file, e.g. "rgb8", "la16" or "cmyk8".
+    pub color_type: String,
+    /// Bits per channel.
+    pub bit_depth: u16,
+    pub has_alpha: bool,
+    /// The EXIF orientation still to be applied, from 1 (none) to 8.
+    pub orientation: u8,
+    /// The physical resolution the file records, if any.
+    pub dpi: Option<f32>,
+}
+
+/// Reads the image's metadata from its headers. `header` is the first bytes
+/// of the file, where any JFIF density or pHYs chunk is looked for.
+pub fn probe_image<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+    header: &[u8],
+) -> Result<ImageInfo, Error> {
+    let reader = reader.with_guessed_format()?;
+    let format = reader
+        .format()
+        .and_then(|format| format.extensions_str().first())
+        .map_or_else(String::new, |extension| extension.to_string());
+    let mut decoder = reader.into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    let color = decoder.original_color_type();
+    let orientation = decoder.orientation()?.to_exif();
+    let exif = decoder.exif_metadata()?;
+    // Orientations 5 to 8 turn the image a quarter.
+    let (width, height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    Ok(ImageInfo {
+        width,
+        height,
+        format,
+        color_type: format!("{color:?}").to_lowercase(),
+        bit_depth: color.bits_per_pixel() / color.channel_count().max(1) as u16,
+        has_alpha: decoder.color_type().has_alpha(),
+        orientation,
+        dpi: metadata::read_dpi(header, exif.as_deref()),
+    })
+}
+
+pub fn probe_image_bytes(bytes: &[u8]) -> Result<ImageInfo, Error> {
+    if heif::is_heif(bytes) {
+        return heif::probe(bytes);
+    }
+    if raw::might_be_raw(bytes) {
+        if let Some(info) = raw::try_probe(bytes) {
+            return Ok(info);
+        }
+    }
+    probe_image(ImageReader::new(Cursor::new(bytes)), bytes)
+}
+
+pub fn probe_image_file(path: &Path) -> Result<ImageInfo, Error> {
+    if raw::has_raw_extension(path) {
+        return raw::probe(&std::fs::read(path)?);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let header = reader.fill_buf()?.to_vec();
+    if heif::is_heif(&header) {
+        return heif::probe(&std::fs::read(path)?);
+    }
+    probe_image(ImageReader::new(reader), &header)
+}
+
+/// Reports the decoder running into its limits as `Error::ImageTooLarge`.
+fn too_large(error: image::ImageError) -> Error {
+    match error {
+        image::ImageError::Limits(e) => Error::ImageTooLarge(e.to_string()),
+        e => Error::Image(e),
+    }
+}
+
+/// Extensions (in lowercase) of the files this build can decode, e.g. for
+/// the filters of a file picker.
+pub fn supported_extensions() -> Vec<&'static str> {
+    let mut extensions: Vec<&str> = ImageFormat::all()
+        .filter(ImageFormat::reading_enabled)
+        .flat_map(|format| format.extensions_str().iter().copied())
+        .collect();
+    if cfg!(feature = "heif") {
+        extensions.extend(heif::HEIF_EXTENSIONS);
+    }
+    if cfg!(feature = "raw") {
+        extensions.extend(raw::RAW_EXTENSIONS);
+    }
+    extensions
+}
+
+/// Whether `path`'s extension is one of `supported_extensions`, in any case.
+/// The file itself isn't looked at.
+pub fn is_supported_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        let extension = extension.to_string_lossy().to_lowercase();
+        supported_extensions().contains(&extension.as_str())
+    })
+}
+
+#[tracing::instrument(name = "decode", skip_all, err, fields(bytes = bytes.len()))]
+pub fn read_image_bytes(bytes: Vec<u8>, limits: &DecodeLimits) -> Result<SourceImage, Error> {
+    if heif::is_heif(&bytes) {
+        return heif::read(&bytes, limits);
+    }
+    if raw::might_be_raw(&bytes) {
+        if let Some(source) = raw::try_read(&bytes, limits)? {
+            return Ok(source);
+        }
+    }
+    read_image(ImageReader::new(Cursor::new(bytes)), limits)
+}
+
+#[tracing::instrument(name = "decode", skip_all, err, fields(path = %path.display()))]
+pub fn read_image_file(path: &Path, limits: &DecodeLimits) -> Result<SourceImage, Error> {
+    if raw::has_raw_extension(path) {
+        return raw::read(&std::fs::read(path)?, limits);
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    if heif::is_heif(reader.fill_buf()?) {
+        return heif::read(&std::fs::read(path)?, limits);
+    }
+    read_image(ImageReader::new(reader), limits)
+}
@@ -0,0 +1,491 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+use imageproc::contours::find_contours;
+use imageproc::distance_transform::Norm;
+use imageproc::edges::canny;
+use imageproc::filter::gaussian_blur_f32;
+use imageproc::geometry::{
+    approximate_polygon_dp, arc_length, contour_area, convex_hull, min_area_rect,
+};
+use imageproc::gradients::{horizontal_sobel, vertical_sobel};
+use imageproc::morphology::dilate;
+use imageproc::point::Point;
+
+// Detection runs on a downscaled copy; document borders survive this fine and
+// it keeps Canny + contour tracing fast on large phone photos.
+const DETECTION_MAX_DIMENSION: u32 = 512;
+// Canny thresholds used for detection, on gradients of images blurred by
+// `EDGE_BLUR_SIGMA`.
+pub const CANNY_LOW: f32 = 20.0;
+pub const CANNY_HIGH: f32 = 60.0;
+const EDGE_BLUR_SIGMA: f32 = 1.5;
+// Ignore quadrilaterals covering less than this fraction of the image.
+const MIN_AREA_FRACTION: f64 = 0.1;
+// Candidates can be much smaller, e.g. one of several receipts.
+const CANDIDATE_MIN_AREA_FRACTION: f64 = 0.01;
+// Candidates whose corners are all within this fraction of the image's
+// diagonal of a better candidate's are duplicates of it (typically the inner
+// and outer outlines of the same border).
+const DUPLICATE_TOLERANCE: f64 = 0.03;
+// How far either side of a candidate's outline its contrast is measured, in
+// pixels of the downscaled copy.
+const CONTRAST_OFFSET: f64 = 3.0;
+// The usual sensitivity for the Harris corner response.
+const HARRIS_K: f32 = 0.04;
+// Half the width of the neighborhood whose gradients place a corner to a
+// fraction of a pixel.
+const SUBPIXEL_HALF_WINDOW: i64 = 5;
+const SUBPIXEL_ITERATIONS: usize = 10;
+// Gradients right at the corner mix both edges, so the pixels within this
+// distance of it are left out.
+const SUBPIXEL_DEAD_ZONE: i64 = 1;
+// Sub-pixel refinement stops once it moves the corner less than this.
+const SUBPIXEL_EPSILON: f64 = 0.01;
+
+/// A quadrilateral found by `detect`.
+#[derive(Debug, Clone)]
+pub struct Detection {
+    /// Corners in the coordinates of the full-size image.
+    pub corners: Vec<Point<i32>>,
+    /// The fraction (0 to 1) of the quad's outline that runs along edges in
+    /// the image; low values suggest the quad only roughly follows the border.
+    pub confidence: f64,
+}
+
+/// Finds the largest convex quadrilateral outlined by edges in the image,
+/// e.g. a sheet of paper on a contrasting background.
+///
+/// Returns the corners in the coordinates of the full-size image, or None if
+/// nothing plausible was found.
+pub fn detect_quad(image: &DynamicImage) -> Option<Vec<Point<i32>>> {
+    detect(image).map(|detection| detection.corners)
+}
+
+/// Like `detect_quad`, but also says how confident the detection is.
+#[tracing::instrument(skip_all, fields(width = image.width(), height = image.height()))]
+pub fn detect(image: &DynamicImage) -> Option<Detection> {
+    let prepared = EdgeImage::new(image)?;
+    let min_area = MIN_AREA_FRACTION * prepared.pixel_count();
+    let (_, quad) = prepared
+        .quads()
+        .map(|quad| (contour_area(&quad), quad))
+        .filter(|(area, _)| *area >= min_area)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))?;
+    Some(Detection {
+        confidence: edge_support(&prepared.edges, &quad),
+        corners: prepared.to_full_size(&quad, image),
+    })
+}
+
+/// A possible document found by `detect_candidates`, with the scores that
+/// rank it. All scores are from 0 to 1.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// Corners in the coordinates of the full-size image.
+    pub corners: Vec<Point<i32>>,
+    /// The fraction of the image the quad covers.
+    pub area: f64,
+    /// How much of its smallest bounding rectangle the quad fills; close to 1
+    /// for documents, even in perspective, and lower for odd shapes.
+    pub rectangularity: f64,
+    /// The mean difference in brightness across the outline.
+    pub contrast: f64,
+    /// As `Detection::confidence`.
+    pub edge_support: f64,
+    /// What candidates are ranked by, combining the other scores.
+    pub score: f64,
+}
+
+/// Every plausible quadrilateral in the image (e.g. several receipts on a
+/// table, or a document and a window behind it), best first, at most
+/// `max_candidates` of them. Quads that are nearly the same as a better one
+/// are left out.
+#[tracing::instrument(skip_all, fields(width = image.width(), height = image.height()))]
+pub fn detect_candidates(image: &DynamicImage, max_candidates: usize) -> Vec<Candidate> {
+    let Some(prepared) = EdgeImage::new(image) else {
+        return Vec::new();
+    };
+    let image_area = prepared.pixel_count();
+    let mut scored: Vec<(Candidate, Vec<Point<i32>>)> = prepared
+        .quads()
+        .filter_map(|quad| {
+            let area = contour_area(&quad) / image_area;
+            if area < CANDIDATE_MIN_AREA_FRACTION {
+                return None;
+            }
+            let rectangularity =
+                (contour_area(&quad) / contour_area(&min_area_rect(&quad)).max(1.0)).min(1.0);
+            let contrast = outline_contrast(&prepared.gray, &quad);
+            let edge_support = edge_support(&prepared.edges, &quad);
+            // Multiplying means a candidate has to do reasonably on every
+            // count; the square root keeps small documents in contention.
+            let score = area.sqrt() * rectangularity * (contrast + edge_support) / 2.0;
+            let candidate = Candidate {
+                corners: prepared.to_full_size(&quad, image),
+                area,
+                rectangularity,
+                contrast,
+                edge_support,
+                score,
+            };
+            Some((candidate, quad))
+        })
+        .collect();
+    scored.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+
+    let (width, height) = prepared.edges.dimensions();
+    let tolerance = DUPLICATE_TOLERANCE * (width as f64).hypot(height as f64);
+    let mut kept: Vec<(Candidate, Vec<Point<i32>>)> = Vec::new();
+    for (candidate, quad) in scored {
+        if kept.len() == max_candidates {
+            break;
+        }
+        if !kept
+            .iter()
+            .any(|(_, other)| same_quad(&quad, other, tolerance))
+        {
+            kept.push((candidate, quad));
+        }
+    }
+    kept.into_iter().map(|(candidate, _)| candidate).collect()
+}
+
+/// The downscaled copy of an image that detection works on.
+struct EdgeImage {
+    gray: GrayImage,
+    /// Edges, dilated to close small gaps so that a document's border traces
+    /// as a single contour.
+    edges: GrayImage,
+    /// Full-size pixels per pixel of the copy.
+    scale: f64,
+}
+
+impl EdgeImage {
+    fn new(image: &DynamicImage) -> Option<EdgeImage> {
+        let long_edge = std::cmp::max(image.width(), image.height());
+        if long_edge == 0 {
+            return None;
+        }
+        let scale = if long_edge > DETECTION_MAX_DIMENSION {
+            long_edge as f64 / DETECTION_MAX_DIMENSION as f64
+        } else {
+            1.0
+        };
+        let gray = image
+            .resize(
+                (image.width() as f64 / scale).round() as u32,
+                (image.height() as f64 / scale).round() as u32,
+                FilterType::Triangle,
+            )
+            .to_luma8();
+        let edges = dilate(&canny_edges(&gray, CANNY_LOW, CANNY_HIGH), Norm::LInf, 1);
+        Some(EdgeImage { gray, edges, scale })
+    }
+
+    fn pixel_count(&self) -> f64 {
+        (self.edges.width() * self.edges.height()) as f64
+    }
+
+    /// The convex quadrilaterals that the edges' contours simplify to.
+    fn quads(&self) -> impl Iterator<Item = Vec<Point<i32>>> + '_ {
+        find_contours::<i32>(&self.edges)
+            .into_iter()
+            .filter_map(|contour| {
+                if contour.points.len() < 4 {
+                    return None;
+                }
+                let hull = convex_hull(contour.points);
+                if hull.len() < 4 {
+                    return None;
+                }
+                let quad = simplify_closed_polygon(&hull, 0.02 * arc_length(&hull, true));
+                (quad.len() == 4).then_some(quad)
+            })
+    }
+
+    fn to_full_size(&self, quad: &[Point<i32>], image: &DynamicImage) -> Vec<Point<i32>> {
+        quad.iter()
+            .map(|p| {
+                Point::new(
+                    ((p.x as f64 * self.scale).round() as i32).clamp(0, image.width() as i32 - 1),
+                    ((p.y as f64 * self.scale).round() as i32).clamp(0, image.height() as i32 - 1),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The mean difference (from 0 to 1) between the brightness just either side
+/// of the closed polygon's outline.
+fn outline_contrast(gray: &GrayImage, polygon: &[Point<i32>]) -> f64 {
+    let (mut total, mut samples) = (0.0, 0u32);
+    let brightness = |x: f64, y: f64| {
+        gray.get_pixel_checked(x.round() as u32, y.round() as u32)
+            .filter(|_| x >= 0.0 && y >= 0.0)
+            .map(|p| p[0] as f64)
+    };
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+        let length = dx.hypot(dy);
+        if length == 0.0 {
+            continue;
+        }
+        let (normal_x, normal_y) = (
+            dy / length * CONTRAST_OFFSET,
+            -dx / length * CONTRAST_OFFSET,
+        );
+        for step in 0..length.ceil() as u32 {
+            let t = step as f64 / length.ceil();
+            let (x, y) = (a.x as f64 + t * dx, a.y as f64 + t * dy);
+            if let (Some(one_side), Some(other_side)) = (
+                brightness(x + normal_x, y + normal_y),
+                brightness(x - normal_x, y - normal_y),
+            ) {
+                total += (one_side - other_side).abs();
+                samples += 1;
+            }
+        }
+    }
+    total / 255.0 / samples.max(1) as f64
+}
+
+/// Whether every corner of `a` is within `tolerance` of some corner of `b`.
+fn same_quad(a: &[Point<i32>], b: &[Point<i32>], tolerance: f64) -> bool {
+    a.iter().all(|p| {
+        b.iter()
+            .any(|q| ((p.x - q.x) as f64).hypot((p.y - q.y) as f64) <= tolerance)
+    })
+}
+
+/// Canny edges (255 on an edge, 0 elsewhere) found with the given hysteresis
+/// thresholds after the smoothing that detection uses.
+pub fn edge_map(image: &DynamicImage, low: f32, high: f32) -> GrayImage {
+    canny_edges(&image.to_luma8(), low, high)
+}
+
+fn canny_edges(gray: &GrayImage, low: f32, high: f32) -> GrayImage {
+    canny(&gaussian_blur_f32(gray, EDGE_BLUR_SIGMA), low, high)
+}
+
+type GradientImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Finds the strongest corner (e.g. where two document edges meet) within
+/// `radius` pixels of (`x`, `y`) and returns its sub-pixel position, or None
+/// if there's nothing corner-like nearby.
+///
+/// The Harris response picks the pixel. Around it, every gradient should be
+/// perpendicular to the line from its pixel to the true corner, since it lies
+/// on one of the edges through it; solving for the point that best satisfies
+/// that (the least-squares intersection of the edges) gives the fraction.
+pub fn refine_corner(image: &DynamicImage, x: f64, y: f64, radius: u32) -> Option<Point<f64>> {
+    // Nothing farther than `radius` outside the image can be in reach, and
+    // leaving such points out keeps the window's bounds from overflowing.
+    let reach = radius as f64;
+    let inside = |value: f64, size: u32| (-reach..=size as f64 + reach).contains(&value);
+    if !(inside(x, image.width()) && inside(y, image.height())) {
+        return None;
+    }
+    let margin = radius as i64 + SUBPIXEL_HALF_WINDOW + 2;
+    let (center_x, center_y) = (x.round() as i64, y.round() as i64);
+    let (x0, y0) = ((center_x - margin).max(0), (center_y - margin).max(0));
+    let x1 = (center_x + margin + 1).min(image.width() as i64);
+    let y1 = (center_y + margin + 1).min(image.height() as i64);
+    if x1 - x0 < 3 || y1 - y0 < 3 {
+        return None;
+    }
+    let window = image
+        .crop_imm(x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32)
+        .to_luma8();
+    let (width, height) = window.dimensions();
+    // Unblurred gradients, which keep the edges' positions most precisely;
+    // the Harris products are smoothed anyway.
+    let (gx, gy) = (horizontal_sobel(&window), vertical_sobel(&window));
+    let gradient = |i: u32, j: u32| (gx.get_pixel(i, j)[0] as f32, gy.get_pixel(i, j)[0] as f32);
+    let product = |f: fn((f32, f32)) -> f32| -> GradientImage {
+        let raw = ImageBuffer::from_fn(width, height, |i, j| Luma([f(gradient(i, j))]));
+        gaussian_blur_f32(&raw, 1.5)
+    };
+    let (xx, xy, yy) = (
+        product(|(gx, _)| gx * gx),
+        product(|(gx, gy)| gx * gy),
+        product(|(_, gy)| gy * gy),
+    );
+
+    // Sobel is unreliable on the window's outermost pixels.
+    let mut best: Option<(f32, u32, u32)> = None;
+    for j in 1..height - 1 {
+        for i in 1..width - 1 {
+            let (dx, dy) = ((x0 + i as i64) as f64 - x, (y0 + j as i64) as f64 - y);
+            if dx.hypot(dy) > radius as f64 {
+                continue;
+            }
+            let (a, b, c) = (
+                xx.get_pixel(i, j)[0],
+                xy.get_pixel(i, j)[0],
+                yy.get_pixel(i, j)[0],
+            );
+            let response = a * c - b * b - HARRIS_K * (a + c) * (a + c);
+            if response > best.map_or(0.0, |(r, _, _)| r) {
+                best = Some((response, i, j));
+            }
+        }
+    }
+    let (_, peak_x, peak_y) = best?;
+
+    let sigma = SUBPIXEL_HALF_WINDOW as f64 / 2.0;
+    let (mut corner_x, mut corner_y) = (peak_x as f64, peak_y as f64);
+    for _ in 0..SUBPIXEL_ITERATIONS {
+        let (mut a, mut b, mut c, mut rhs_x, mut rhs_y) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        let (around_x, around_y) = (corner_x.round() as i64, corner_y.round() as i64);
+        for dy in -SUBPIXEL_HALF_WINDOW..=SUBPIXEL_HALF_WINDOW {
+            for dx in -SUBPIXEL_HALF_WINDOW..=SUBPIXEL_HALF_WINDOW {
+                let (i, j) = (around_x + dx, around_y + dy);
+                if (dx.abs() <= SUBPIXEL_DEAD_ZONE && dy.abs() <= SUBPIXEL_DEAD_ZONE)
+                    || i < 1
+                    || j < 1
+                    || i >= width as i64 - 1
+                    || j >= height as i64 - 1
+                {
+                    continue;
+                }
+                let (g1, g2) = gradient(i as u32, j as u32);
+                let (g1, g2) = (g1 as f64, g2 as f64);
+                let weight = (-((dx * dx + dy * dy) as f64) / (2.0 * sigma * sigma)).exp();
+                a += weight * g1 * g1;
+                b += weight * g1 * g2;
+                c += weight * g2 * g2;
+                rhs_x += weight * (g1 * g1 * i as f64 + g1 * g2 * j as f64);
+                rhs_y += weight * (g1 * g2 * i as f64 + g2 * g2 * j as f64);
+            }
+        }
+        // A single straight edge (or flat area) doesn't pin down a point.
+        let determinant = a * c - b * b;
+        if determinant <= 1e-6 * (a + c) * (a + c) {
+            break;
+        }
+        let next_x = (c * rhs_x - b * rhs_y) / determinant;
+        let next_y = (a * rhs_y - b * rhs_x) / determinant;
+        let shift = (next_x - corner_x).hypot(next_y - corner_y);
+        (corner_x, corner_y) = (next_x, next_y);
+        if shift < SUBPIXEL_EPSILON {
+            break;
+        }
+    }
+    // Don't let refinement wander off to some other feature.
+    if (corner_x - peak_x as f64).hypot(corner_y - peak_y as f64) > SUBPIXEL_HALF_WINDOW as f64 {
+        (corner_x, corner_y) = (peak_x as f64, peak_y as f64);
+    }
+    Some(Point::new(x0 as f64 + corner_x, y0 as f64 + corner_y))
+}
+
+/// The fraction of points along the closed polygon that land on an edge
+/// pixel, sampling about once per pixel.
+fn edge_support(edges: &GrayImage, polygon: &[Point<i32>]) -> f64 {
+    let (mut on_edge, mut samples) = (0u32, 0u32);
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+        let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as u32;
+        for step in 0..steps {
+            let t = step as f64 / steps as f64;
+            let x = (a.x as f64 + t * dx).round() as u32;
+            let y = (a.y as f64 + t * dy).round() as u32;
+            samples += 1;
+            if edges.get_pixel_checked(x, y).is_some_and(|p| p[0] > 0) {
+                on_edge += 1;
+            }
+        }
+    }
+    on_edge as f64 / samples.max(1) as f64
+}
+
+/// Douglas-Peucker simplification of a closed polygon.
+///
+/// `approximate_polygon_dp` expects an open curve, so split the polygon at its
+/// first vertex and the vertex farthest from it and simplify each half
+/// separately. The split points are always kept by that, so afterwards drop
+/// either of them if it turns out to lie on a straight edge.
+fn simplify_closed_polygon(polygon: &[Point<i32>], epsilon: f64) -> Vec<Point<i32>> {
+    let start = polygon[0];
+    let distance_sq = |p: &Point<i32>| {
+        let dx = (p.x - start.x) as i64;
+        let dy = (p.y - start.y) as i64;
+        dx * dx + dy * dy
+    };
+    let (far, _) = polygon
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, p)| distance_sq(p))
+        .unwrap();
+    if far == 0 {
+        return vec![start];
+    }
+    let mut second_half = polygon[far..].to_vec();
+    second_half.push(start);
+    let mut simplified = approximate_polygon_dp(&polygon[..=far], epsilon, false);
+    simplified.pop();
+    simplified.extend(approximate_polygon_dp(&second_half, epsilon, false));
+    simplified.pop();
+    let mut i = 0;
+    while simplified.len() > 3 && i < simplified.len() {
+        let n = simplified.len();
+        let prev = simplified[(i + n - 1) % n];
+        let next = simplified[(i + 1) % n];
+        if distance_to_line(simplified[i], prev, next) < epsilon {
+            simplified.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+    simplified
+}
+
+/// Distance from `p` to the line through `a` and `b`.
+fn distance_to_line(p: Point<i32>, a: Point<i32>, b: Point<i32>) -> f64 {
+    let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+    let length = dx.hypot(dy);
+    if length == 0.0 {
+        return ((p.x - a.x) as f64).hypot((p.y - a.y) as f64);
+    }
+    (dy * (p.x - a.x) as f64 - dx * (p.y - a.y) as f64).abs() / length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A light square from (40, 30) to (120, 90) on a dark background.
+    fn square() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(160, 120, |x, y| {
+            let inside = (40..120).contains(&x) && (30..90).contains(&y);
+            Luma([if inside { 220 } else { 30 }])
+        }))
+    }
+
+    #[test]
+    fn refine_corner_finds_a_nearby_corner() {
+        let corner = refine_corner(&square(), 44.0, 33.0, 10).unwrap();
+        assert!(
+            (corner.x - 39.5).abs() < 1.0 && (corner.y - 29.5).abs() < 1.0,
+            "found ({}, {})",
+            corner.x,
+            corner.y
+        );
+    }
+
+    #[test]
+    fn refine_corner_ignores_points_out_of_reach() {
+        let image = square();
+        for (x, y) in [
+            (f64::NAN, 10.0),
+            (10.0, f64::INFINITY),
+            (1e300, 10.0),
+            (10.0, -1e300),
+            (-20.0, 10.0),
+            (10.0, 150.0),
+        ] {
+            assert!(refine_corner(&image, x, y, 10).is_none(), "({x}, {y})");
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Pixel};
+use imageproc::filter::{box_filter, gaussian_blur_f32};
+use imageproc::gradients::vertical_sobel;
+
+use crate::matrix::{self, Matrix};
+
+// The page is analysed flattened to at most this width; text lines and page
+// edges are still well resolved.
+const WORK_WIDTH: u32 = 800;
+// How far (as a fraction of the page's height) above and below the flat
+// quad to look for the curved page edges.
+const EDGE_SEARCH: f64 = 0.08;
+// Columns this close (as a fraction of the width) to the corners are left
+// out; the edges there are pinned by the corners anyway.
+const SIDE_MARGIN: f64 = 0.05;
+// Vertical gradient (Sobel, on the blurred page) an edge needs to count.
+const EDGE_THRESHOLD: i16 = 60;
+// Text lines are looked for in this part of the page's height, clear of the
+// edges.
+const TEXT_TOP: f64 = 0.1;
+const TEXT_BOTTOM: f64 = 0.9;
+// Radius of the box filter a pixel is compared against to count as ink, and
+// how much darker than the local mean it must be.
+const INK_RADIUS: u32 = 15;
+const INK_OFFSET: u8 = 12;
+// The page is cut into this many vertical strips, each with its own row
+// profile, whose peaks are linked across strips into text lines.
+const STRIPS: usize = 16;
+// Profile peaks must reach this fraction of the strip's highest.
+const PEAK_FRACTION: f32 = 0.3;
+// Closest (in working pixels) two lines' peaks can be in one strip.
+const MIN_LINE_SPACING: usize = 4;
+// How far (as a fraction of the page's height) a line may stray from where
+// it was heading between neighbouring strips.
+const LINK_TOLERANCE: f64 = 0.008;
+// Edge samples are direct measurements, so count for more than text.
+const EDGE_WEIGHT: f64 = 2.0;
+// Pull towards a flat page, so that the fit falls back to one when there's
+// little to go on.
+const RIDGE: f64 = 1e-3;
+const MIN_OBSERVATIONS: usize = 10;
+// Rounds of dropping outliers and refitting.
+const ROBUST_ROUNDS: usize = 3;
+const MIN_RESIDUAL: f64 = 0.003;
+// The largest displacement (as a fraction of the page's height) the fit may
+// give the page's edges.
+const MAX_OFFSET: f64 = 0.2;
+// Resolution of the table used to unroll the page's width.
+const ARC_SAMPLES: usize = 256;
+
+// Each edge's displacement is a combination of these, which all vanish at
+// the corners: a bulge, a tilt towards one side and a bulge near the sides.
+const BASIS: usize = 3;
+const PARAMETERS: usize = 2 * BASIS;
+
+fn basis(u: f64) -> [f64; BASIS] {
+    let bulge = 4.0 * u * (1.0 - u);
+    let side = 2.0 * u - 1.0;
+    [bulge, bulge * side, bulge * side * side]
+}
+
+fn offset(coefficients: &[f64], u: f64) -> f64 {
+    basis(u).iter().zip(coefficients).map(|(b, c)| b * c).sum()
+}
+
+/// A curved page, as the vertical displacement of its top and bottom edges
+/// across the flat (perspective-only) squaring, with (between them) every
+/// line of text displaced in proportion. The page's width is unrolled along
+/// its middle, so text that curves away from the camera isn't squashed.
+pub(crate) struct CurvedPage {
+    // Coefficients of the top then bottom edge's displacement, as fractions
+    // of the flat height.
+    coefficients: [f64; PARAMETERS],
+    flat_size: (u32, u32),
+    output_width: u32,
+    // Arc length along the middle of the page at evenly spaced positions
+    // across it, normalized to end at 1.
+    arc: Vec<f64>,
+}
+
+/// One measurement of where the page curves to: `row` times the
+/// coefficients should come to `value`.
+struct Observation {
+    row: [f64; PARAMETERS],
+    value: f64,
+    weight: f64,
+}
+
+impl CurvedPage {
+    /// Fits the page's curve from its edges and text lines, given the flat
+    /// squaring (`flat_to_source` maps pixels of its `flat_size` output to
+    /// `image`). A page with nothing to fit it to comes out flat.
+    pub(crate) fn fit(
+        image: &DynamicImage,
+        flat_to_source: &Matrix,
+        flat_size: (u32, u32),
+    ) -> Self {
+        let page = FlattenedPage::render(image, flat_to_source, flat_size);
+        let mut observations = page.edge_observations();
+        observations.extend(page.text_observations());
+        let coefficients = if observations.len() < MIN_OBSERVATIONS {
+            [0.0; PARAMETERS]
+        } else {
+            robust_fit(&mut observations)
+        };
+        CurvedPage::new(limit(coefficients), flat_size)
+    }
+
+    fn new(coefficients: [f64; PARAMETERS], flat_size: (u32, u32)) -> Self {
+        let (width, height) = (flat_size.0 as f64, flat_size.1 as f64);
+        let middle =
+            |u: f64| (offset(&coefficients[..BASIS], u) + offset(&coefficients[BASIS..], u)) / 2.0;
+        let mut arc = Vec::with_capacity(ARC_SAMPLES + 1);
+        arc.push(0.0);
+        let mut length = 0.0;
+        for i in 0..ARC_SAMPLES {
+            let (u0, u1) = (
+                i as f64 / ARC_SAMPLES as f64,
+                (i + 1) as f64 / ARC_SAMPLES as f64,
+            );
+            let (dx, dy) = ((u1 - u0) * width, (middle(u1) - middle(u0)) * height);
+            length += dx.hypot(dy);
+            arc.push(length);
+        }
+        for value in &mut arc {
+            *value /= length.max(f64::EPSILON);
+        }
+        CurvedPage {
+            coefficients,
+            flat_size,
+            output_width: (length.round() as u32).max(1),
+            arc,
+        }
+    }
+
+    /// The width of the page unrolled; its height stays the flat height.
+    pub(crate) fn output_width(&self) -> u32 {
+        self.output_width
+    }
+
+    /// Position across the flat page (0 to 1) of the point the given
+    /// fraction of the way along the unrolled page.
+    fn unroll(&self, fraction: f64) -> f64 {
+        let index = self
+            .arc
+            .partition_point(|&a| a < fraction)
+            .clamp(1, ARC_SAMPLES);
+        let (a0, a1) = (self.arc[index - 1], self.arc[index]);
+        let t = if a1 > a0 {
+            (fraction - a0) / (a1 - a0)
+        } else {
+            0.0
+        };
+        ((index - 1) as f64 + t.clamp(0.0, 1.0)) / ARC_SAMPLES as f64
+    }
+
+    /// Maps an output pixel to the flat squaring's pixels.
+    pub(crate) fn to_flat(&self, x: f32, y: f32) -> (f32, f32) {
+        let (width, height) = (self.flat_size.0 as f64, self.flat_size.1 as f64);
+        let u = self.unroll((x as f64 + 0.5) / self.output_width as f64);
+        let v = (y as f64 + 0.5) / height;
+        let displacement = (1.0 - v) * offset(&self.coefficients[..BASIS], u)
+            + v * offset(&self.coefficients[BASIS..], u);
+        (
+            (u * width - 0.5) as f32,
+            ((v + displacement) * height - 0.5) as f32,
+        )
+    }
+}
+
+/// Scales the fitted curve down if it puts an edge implausibly far out.
+fn limit(coefficients: [f64; PARAMETERS]) -> [f64; PARAMETERS] {
+    let largest = (0..=32)
+        .map(|i| i as f64 / 32.0)
+        .flat_map(|u| {
+            [
+                offset(&coefficients[..BASIS], u).abs(),
+                offset(&coefficients[BASIS..], u).abs(),
+            ]
+        })
+        .fold(0.0, f64::max);
+    if largest > MAX_OFFSET {
+        coefficients.map(|c| c * MAX_OFFSET / largest)
+    } else {
+        coefficients
+    }
+}
+
+/// Weighted least squares with a pull towards zero, dropping observations
+/// far from the fit and refitting a few times.
+fn robust_fit(observations: &mut Vec<Observation>) -> [f64; PARAMETERS] {
+    let mut coefficients = least_squares(observations);
+    for _ in 0..ROBUST_ROUNDS {
+        let residual = |o: &Observation| {
+            (o.row
+                .iter()
+                .zip(&coefficients)
+                .map(|(r, c)| r * c)
+                .sum::<f64>()
+                - o.value)
+                .abs()
+        };
+        let mut residuals: Vec<f64> = observations.iter().map(residual).collect();
+        residuals.sort_by(f64::total_cmp);
+        let cutoff = (3.0 * residuals[residuals.len() / 2]).max(MIN_RESIDUAL);
+        observations.retain(|o| residual(o) <= cutoff);
+        if observations.len() < MIN_OBSERVATIONS {
+            break;
+        }
+        coefficients = least_squares(observations);
+    }
+    coefficients
+}
+
+fn least_squares(observations: &[Observation]) -> [f64; PARAMETERS] {
+    let mut normal = [[0.0; PARAMETERS]; PARAMETERS];
+    let mut rhs = [0.0; PARAMETERS];
+    for o in observations {
+        for ((normal_row, rhs), &r) in normal.iter_mut().zip(&mut rhs).zip(&o.row) {
+            *rhs += o.weight * r * o.value;
+            for (n, &r2) in normal_row.iter_mut().zip(&o.row) {
+                *n += o.weight * r * r2;
+            }
+        }
+    }
+    let total_weight: f64 = observations.iter().map(|o| o.weight).sum();
+    for (i, row) in normal.iter_mut().enumerate() {
+        row[i] += RIDGE * total_weight.max(1.0);
+    }
+    matrix::solve(normal, rhs).unwrap_or([0.0; PARAMETERS])
+}
+
+/// The flat squaring in grayscale at a working resolution, extended above
+/// and below so that edges bowing out of the quad are still in view.
+struct FlattenedPage {
+    gray: GrayImage,
+    // Rows above the flat page, and the flat page's height, in working pixels.
+    margin: u32,
+    height: u32,
+}
+
+impl FlattenedPage {
+    fn render(
+        image: &DynamicImage,
+        flat_to_source: &Matrix,
+        (flat_width, flat_height): (u32, u32),
+    ) -> Self {
+        let width = flat_width.clamp(1, WORK_WIDTH);
+        let scale = width as f64 / flat_width.max(1) as f64;
+        let height = ((flat_height as f64 * scale).round() as u32).max(1);
+        let margin = (height as f64 * EDGE_SEARCH).ceil() as u32;
+        let (source_width, source_height) = image.dimensions();
+        let gray = GrayImage::from_fn(width, height + 2 * margin, |x, y| {
+            let flat = (
+                (x as f64 + 0.5) / scale - 0.5,
+                (y as f64 - margin as f64 + 0.5) / scale - 0.5,
+            );
+            let (sx, sy) = matrix::transform(flat_to_source, flat);
+            if !(sx.is_finite() && sy.is_finite()) {
+                return Luma([0]);
+            }
+            // Outside the source, repeat its edge.
+            let sx = (sx.round().max(0.0) as u32).min(source_width - 1);
+            let sy = (sy.round().max(0.0) as u32).min(source_height - 1);
+            image.get_pixel(sx, sy).to_luma()
+        });
+        FlattenedPage {
+            gray,
+            margin,
+            height,
+        }
+    }
+
+    /// Columns sampled for the page edges, as working x and position across
+    /// the page.
+    fn columns(&self, count: usize) -> impl Iterator<Item = (u32, f64)> + '_ {
+        let width = self.gray.width() as f64;
+        (0..count).map(move |i| {
+            let u = SIDE_MARGIN + (1.0 - 2.0 * SIDE_MARGIN) * (i as f64 + 0.5) / count as f64;
+            ((u * width) as u32, u)
+        })
+    }
+
+    /// Where the page's top and bottom edges are in each column: the
+    /// strongest step from dark to light going into the page, which assumes
+    /// the page is lighter than what's around it (as detection does).
+    fn edge_observations(&self) -> Vec<Observation> {
+        let blurred = gaussian_blur_f32(&self.gray, 1.5);
+        let gradient = vertical_sobel(&blurred);
+        let height = self.height as f64;
+        let mut observations = Vec::new();
+        let search = 2 * self.margin;
+        for (x, u) in self.columns(self.gray.width().min(100) as usize) {
+            let b = basis(u);
+            // Going down at the top, up at the bottom.
+            for (bottom, start, sign) in [(false, 0, 1), (true, self.height, -1)] {
+                let strongest = (start..=start + search)
+                    .filter(|&y| y < gradient.height())
+                    .map(|y| (y, sign * gradient.get_pixel(x, y)[0]))
+                    .max_by_key(|&(_, g)| g);
+                let Some((y, _)) = strongest.filter(|&(_, g)| g >= EDGE_THRESHOLD) else {
+                    continue;
+                };
+                let mut row = [0.0; PARAMETERS];
+                let part = if bottom { BASIS } else { 0 };
+                row[part..part + BASIS].copy_from_slice(&b);
+                let rest = if bottom {
+                    self.margin as f64 + height
+                } else {
+                    self.margin as f64
+                };
+                observations.push(Observation {
+                    row,
+                    value: (y as f64 - rest) / height,
+                    weight: EDGE_WEIGHT,
+                });
+            }
+        }
+        observations
+    }
+
+    /// Text lines traced across the page. Each line's mean height is unknown
+    /// (it's where the line would be on a flat page), so each is measured
+    /// relative to its own mean.
+    fn text_observations(&self) -> Vec<Observation> {
+        let height = self.height as f64;
+        let top = self.margin + (height * TEXT_TOP) as u32;
+        let bottom = self.margin + (height * TEXT_BOTTOM) as u32;
+        if bottom <= top + 2 {
+            return Vec::new();
+        }
+        let mean = box_filter(&self.gray, INK_RADIUS, INK_RADIUS);
+        let is_ink = |x: u32, y: u32| {
+            self.gray.get_pixel(x, y)[0].saturating_add(INK_OFFSET) < mean.get_pixel(x, y)[0]
+        };
+        let width = self.gray.width() as f64;
+        let left = (width * SIDE_MARGIN) as u32;
+        let strip_width = ((width * (1.0 - 2.0 * SIDE_MARGIN)) as u32 / STRIPS as u32).max(1);
+        let strips: Vec<(f64, Vec<usize>)> = (0..STRIPS as u32)
+            .map(|strip| {
+                let x0 = left + strip * strip_width;
+                let x1 = (x0 + strip_width).min(self.gray.width());
+                let profile: Vec<f32> = (top..bottom)
+                    .map(|y| (x0..x1).filter(|&x| is_ink(x, y)).count() as f32)
+                    .collect();
+                let u = (x0 + x1) as f64 / 2.0 / width;
+                (u, profile_peaks(&profile))
+            })
+            .collect();
+        let tolerance = (LINK_TOLERANCE * height).max(2.0);
+        let lines = link_lines(&strips, tolerance);
+        let mut observations = Vec::new();
+        for line in lines.iter().filter(|line| line.len() >= STRIPS / 2) {
+            // Rows relative to the flat page's top, as fractions of its height.
+            let points: Vec<(f64, f64)> = line
+                .iter()
+                .map(|&(u, row)| (u, (row as f64 + (top - self.margin) as f64) / height))
+                .collect();
+            let count = points.len() as f64;
+            let mean_v = points.iter().map(|p| p.1).sum::<f64>() / count;
+            let mut mean_basis = [0.0; BASIS];
+            for &(u, _) in &points {
+                for (m, b) in mean_basis.iter_mut().zip(basis(u)) {
+                    *m += b / count;
+                }
+            }
+            for &(u, v) in &points {
+                let b = basis(u);
+                let mut row = [0.0; PARAMETERS];
+                for k in 0..BASIS {
+                    row[k] = (1.0 - mean_v) * (b[k] - mean_basis[k]);
+                    row[BASIS + k] = mean_v * (b[k] - mean_basis[k]);
+                }
+                observations.push(Observation {
+                    row,
+                    value: v - mean_v,
+                    weight: 1.0,
+                });
+            }
+        }
+        observations
+    }
+}
+
+/// Rows of a strip's ink profile (smoothed) where text lines run, strongest
+/// first and at least `MIN_LINE_SPACING` apart.
+fn profile_peaks(profile: &[f32]) -> Vec<usize> {
+    let smoothed: Vec<f32> = (0..profile.len())
+        .map(|i| {
+            let previous = profile[i.saturating_sub(1)];
+            let next = profile[(i + 1).min(profile.len() - 1)];
+            (previous + 2.0 * profile[i] + next) / 4.0
+        })
+        .collect();
+    let highest = smoothed.iter().copied().fold(0.0, f32::max);
+    let threshold = (highest * PEAK_FRACTION).max(1.0);
+    let mut candidates: Vec<usize> = (1..smoothed.len().saturating_sub(1))
+        .filter(|&i| {
+            smoothed[i] >= threshold
+                && smoothed[i] >= smoothed[i - 1]
+                && smoothed[i] > smoothed[i + 1]
+        })
+        .collect();
+    candidates.sort_by(|&a, &b| smoothed[b].total_cmp(&smoothed[a]));
+    let mut peaks: Vec<usize> = Vec::new();
+    for candidate in candidates {
+        if peaks
+            .iter()
+            .all(|&p| p.abs_diff(candidate) >= MIN_LINE_SPACING)
+        {
+            peaks.push(candidate);
+        }
+    }
+    peaks
+}
+
+/// Chains peaks in neighbouring strips into lines, each peak continuing the
+/// line it's closest to where that line was heading (if within `tolerance`).
+fn link_lines(strips: &[(f64, Vec<usize>)], tolerance: f64) -> Vec<Vec<(f64, usize)>> {
+    let mut finished: Vec<Vec<(f64, usize)>> = Vec::new();
+    let mut open: Vec<Vec<(f64, usize)>> = Vec::new();
+    for (u, peaks) in strips {
+        let mut unclaimed: Vec<usize> = peaks.clone();
+        let mut continued = Vec::new();
+        for line in open.drain(..) {
+            let last = line[line.len() - 1].1 as f64;
+            let predicted = match line.len() {
+                1 => last,
+                n => 2.0 * last - line[n - 2].1 as f64,
+            };
+            let closest = unclaimed
+                .iter()
+                .enumerate()
+                .map(|(i, &row)| (i, (row as f64 - predicted).abs()))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .filter(|&(_, distance)| distance <= tolerance);
+            match closest {
+                Some((i, _)) => {
+                    let mut line = line;
+                    line.push((*u, unclaimed.swap_remove(i)));
+                    continued.push(line);
+                }
+                None => finished.push(line),
+            }
+        }
+        continued.extend(unclaimed.into_iter().map(|row| vec![(*u, row)]));
+        open = continued;
+    }
+    finished.extend(open);
+    finished
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::RgbImage;
+
+    const WIDTH: u32 = 600;
+    const HEIGHT: u32 = 400;
+    // Rows of table above and below the page in the photos.
+    const MARGIN: u32 = 60;
+
+    /// A photo of a page `WIDTH` by `HEIGHT`, squared already but for its
+    /// top and bottom (and every line of text) sagging by `sag` of its height
+    /// in the middle, framed by darker table.
+    fn photo(sag: f64) -> DynamicImage {
+        let total_height = HEIGHT + 2 * MARGIN;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(WIDTH, total_height, |x, y| {
+            let u = (x as f64 + 0.5) / WIDTH as f64;
+            let v = (y as f64 - MARGIN as f64 + 0.5) / HEIGHT as f64 - sag * basis(u)[0];
+            let value = if !(0.0..1.0).contains(&v) {
+                60
+            } else if (0.15..0.85).contains(&v)
+                && (v * HEIGHT as f64) as u32 % 20 < 4
+                && (0.08..0.92).contains(&u)
+                && x % 50 < 42
+            {
+                30
+            } else {
+                235
+            };
+            image::Rgb([value, value, value])
+        }))
+    }
+
+    fn fit(sag: f64) -> CurvedPage {
+        CurvedPage::fit(
+            &photo(sag),
+            &matrix::translate(0.0, MARGIN as f32),
+            (WIDTH, HEIGHT),
+        )
+    }
+
+    #[test]
+    fn a_flat_page_comes_back_unchanged() {
+        let page = fit(0.0);
+        assert_eq!(page.output_width(), WIDTH);
+        for (x, y) in [(0.0, 0.0), (300.0, 200.0), (599.0, 399.0), (150.0, 50.0)] {
+            let (flat_x, flat_y) = page.to_flat(x, y);
+            assert!(
+                (flat_x - x).abs() < 0.5 && (flat_y - y).abs() < 0.5,
+                "({x}, {y}) maps to ({flat_x}, {flat_y})"
+            );
+        }
+    }
+
+    #[test]
+    fn a_sagging_page_is_followed_within_a_few_pixels() {
+        let sag = 0.06;
+        let page = fit(sag);
+        for x in [60.0, 150.0, 300.0, 450.0, 540.0] {
+            let u = (x as f64 + 0.5) / WIDTH as f64;
+            let expected = sag * basis(u)[0] * HEIGHT as f64;
+            for y in [0.0, 200.0, HEIGHT as f32 - 1.0] {
+                let (_, flat_y) = page.to_flat(x, y);
+                let drop = flat_y as f64 - y as f64;
+                assert!(
+                    (drop - expected).abs() < 3.0,
+                    "({x}, {y}) drops {drop} instead of {expected}"
+                );
+            }
+        }
+        // Unrolled, the sagging middle makes the page a little wider.
+        assert!(page.output_width() >= WIDTH);
+    }
+}
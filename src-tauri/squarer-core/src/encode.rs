@@ -0,0 +1,219 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{
+    ColorType, DynamicImage, ImageBuffer, ImageEncoder, ImageResult, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::io::Write;
+use std::path::Path;
+
+type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+pub const DEFAULT_QUALITY: u8 = 90;
+pub const DEFAULT_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// The `image` crate only has a lossless WebP encoder, so quality is
+    /// ignored for this format.
+    Webp,
+}
+
+impl OutputFormat {
+    /// Guesses the format from a file name's extension.
+    pub fn from_path(path: &Path) -> Option<OutputFormat> {
+        OutputFormat::from_extension(path.extension()?.to_str()?)
+    }
+
+    pub fn from_extension(extension: &str) -> Option<OutputFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// All the extensions `from_extension` accepts for this format.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            OutputFormat::Png => &["png"],
+            OutputFormat::Jpeg => &["jpg", "jpeg"],
+            OutputFormat::Webp => &["webp"],
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+        }
+    }
+}
+
+/// Composites the image over an opaque background color.
+pub fn flatten_alpha(image: &RgbaImage, background: [u8; 3]) -> RgbImage {
+    let mut flattened = RgbImage::new(image.width(), image.height());
+    flattened
+        .par_chunks_mut(3)
+        .zip(image.par_chunks(4))
+        .for_each(|(output, pixel)| {
+            let alpha = pixel[3] as u32;
+            for channel in 0..3 {
+                output[channel] = ((pixel[channel] as u32 * alpha
+                    + background[channel] as u32 * (255 - alpha)
+                    + 127)
+                    / 255) as u8;
+            }
+        });
+    flattened
+}
+
+/// Composites a 16-bit image over an opaque background color.
+fn flatten_alpha16(image: &Rgba16Image, background: [u8; 3]) -> Rgb16Image {
+    let mut flattened = Rgb16Image::new(image.width(), image.height());
+    flattened
+        .par_chunks_mut(3)
+        .zip(image.par_chunks(4))
+        .for_each(|(output, pixel)| {
+            let alpha = pixel[3] as u64;
+            for channel in 0..3 {
+                let background = background[channel] as u64 * 257;
+                output[channel] = ((pixel[channel] as u64 * alpha
+                    + background * (65535 - alpha)
+                    + 32767)
+                    / 65535) as u16;
+            }
+        });
+    flattened
+}
+
+/// Drops the alpha channel (if any), compositing onto `background`. Keeps
+/// grayscale images grayscale and 16-bit images 16-bit.
+pub fn remove_alpha(image: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let color = image.color();
+    if !color.has_alpha() {
+        return image;
+    }
+    let sixteen_bit = color.bytes_per_pixel() > color.channel_count();
+    let flattened = if sixteen_bit {
+        DynamicImage::ImageRgb16(flatten_alpha16(&image.to_rgba16(), background))
+    } else {
+        DynamicImage::ImageRgb8(flatten_alpha(&image.to_rgba8(), background))
+    };
+    match (color.has_color(), sixteen_bit) {
+        (true, _) => flattened,
+        (false, false) => DynamicImage::ImageLuma8(flattened.to_luma8()),
+        (false, true) => DynamicImage::ImageLuma16(flattened.to_luma16()),
+    }
+}
+
+/// Converts to 8 bits per channel, keeping the channel layout.
+fn to_eight_bit(image: &DynamicImage) -> DynamicImage {
+    match image.color() {
+        ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => image.clone(),
+        ColorType::L16 => DynamicImage::ImageLuma8(image.to_luma8()),
+        ColorType::La16 => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        color if color.has_alpha() => DynamicImage::ImageRgba8(image.to_rgba8()),
+        _ => DynamicImage::ImageRgb8(image.to_rgb8()),
+    }
+}
+
+/// Encodes the image in the given format, flattening it onto `background`
+/// first if the format can't store an alpha channel. PNG keeps 16-bit depth;
+/// the other formats are 8-bit only.
+///
+/// `quality` is in 1..=100 and only affects lossy formats.
+///
+/// Encoding itself runs on one thread: the `image` crate's PNG, JPEG and WebP
+/// encoders are single-threaded, and splitting the entropy coding up would
+/// take a different encoder. Only flattening the alpha channel is parallel.
+/// Batches get their parallelism by encoding several images at once
+/// instead.
+pub fn encode(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+    background: [u8; 3],
+) -> ImageResult<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    encode_to(image, format, quality, background, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Like `encode`, but writes to `writer` as the encoder goes rather than
+/// collecting the output first.
+#[tracing::instrument(
+    name = "encode",
+    skip_all,
+    err,
+    fields(format = ?format, width = image.width(), height = image.height())
+)]
+pub fn encode_to<W: Write>(
+    image: &DynamicImage,
+    format: OutputFormat,
+    quality: u8,
+    background: [u8; 3],
+    mut bytes: W,
+) -> ImageResult<()> {
+    let (width, height) = (image.width(), image.height());
+    match format {
+        OutputFormat::Png => {
+            // PNG has no floating-point samples.
+            let converted;
+            let image = match image.color() {
+                ColorType::Rgb32F => {
+                    converted = DynamicImage::ImageRgb16(image.to_rgb16());
+                    &converted
+                }
+                ColorType::Rgba32F => {
+                    converted = DynamicImage::ImageRgba16(image.to_rgba16());
+                    &converted
+                }
+                _ => image,
+            };
+            PngEncoder::new(&mut bytes).write_image(
+                image.as_bytes(),
+                width,
+                height,
+                image.color().into(),
+            )?
+        }
+        OutputFormat::Webp => {
+            let image = to_eight_bit(image);
+            WebPEncoder::new_lossless(&mut bytes).write_image(
+                image.as_bytes(),
+                width,
+                height,
+                image.color().into(),
+            )?
+        }
+        OutputFormat::Jpeg => {
+            let image = to_eight_bit(&remove_alpha(image.clone(), background));
+            JpegEncoder::new_with_quality(&mut bytes, quality.clamp(1, 100)).write_image(
+                image.as_bytes(),
+                width,
+                height,
+                image.color().into(),
+            )?
+        }
+    }
+    Ok(())
+}
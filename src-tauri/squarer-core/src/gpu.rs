@@ -0,0 +1,324 @@
+use image::RgbaImage;
+
+use crate::matrix::Matrix;
+use crate::{Fill, InterpolationMode};
+
+#[cfg(feature = "gpu")]
+mod wgpu_warp {
+    use bytemuck::{Pod, Zeroable};
+    use image::RgbaImage;
+    use wgpu::util::DeviceExt;
+
+    use std::sync::OnceLock;
+
+    use crate::matrix::Matrix;
+    use crate::Fill;
+
+    const SHADER: &str = r#"
+struct Params {
+    row0: vec4<f32>,
+    row1: vec4<f32>,
+    row2: vec4<f32>,
+    fill: vec4<f32>,
+    output_size: vec2<u32>,
+    nearest: u32,
+    clamp_edges: u32,
+}
+
+@group(0) @binding(0) var source: texture_2d<f32>;
+@group(0) @binding(1) var output: texture_storage_2d<rgba8unorm, write>;
+@group(0) @binding(2) var<uniform> params: Params;
+
+fn texel(position: vec2<i32>) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(source));
+    if any(position < vec2<i32>(0)) || any(position >= size) {
+        return params.fill;
+    }
+    return textureLoad(source, position, 0);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.output_size.x || id.y >= params.output_size.y {
+        return;
+    }
+    let p = vec3<f32>(f32(id.x), f32(id.y), 1.0);
+    let q = vec3<f32>(dot(params.row0.xyz, p), dot(params.row1.xyz, p), dot(params.row2.xyz, p));
+    var s = q.xy / q.z;
+    // As on the CPU: samples within half a pixel of the source are clamped
+    // into it, as are all samples when clamping to the edges.
+    let size = vec2<f32>(textureDimensions(source));
+    let inside = all(s >= vec2<f32>(-0.5)) && all(s <= size - vec2<f32>(0.5));
+    if inside || params.clamp_edges != 0u {
+        s = clamp(s, vec2<f32>(0.0), size - vec2<f32>(1.001));
+    }
+    var color: vec4<f32>;
+    if params.nearest != 0u {
+        color = texel(vec2<i32>(round(s)));
+    } else {
+        let base = floor(s);
+        let t = s - base;
+        let i = vec2<i32>(base);
+        color = mix(
+            mix(texel(i), texel(i + vec2<i32>(1, 0)), t.x),
+            mix(texel(i + vec2<i32>(0, 1)), texel(i + vec2<i32>(1, 1)), t.x),
+            t.y,
+        );
+    }
+    textureStore(output, vec2<i32>(id.xy), color);
+}
+"#;
+    const WORKGROUP_SIZE: u32 = 8;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Pod, Zeroable)]
+    struct Params {
+        rows: [[f32; 4]; 3],
+        fill: [f32; 4],
+        output_size: [u32; 2],
+        nearest: u32,
+        clamp_edges: u32,
+    }
+
+    struct Gpu {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        pipeline: wgpu::ComputePipeline,
+    }
+
+    /// The device is set up on first use and kept for the life of the process;
+    /// None if there's no usable adapter.
+    fn gpu() -> Option<&'static Gpu> {
+        static GPU: OnceLock<Option<Gpu>> = OnceLock::new();
+        GPU.get_or_init(|| pollster::block_on(init())).as_ref()
+    }
+
+    async fn init() -> Option<Gpu> {
+        let instance = wgpu::Instance::new(&wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                ..Default::default()
+            })
+            .await?;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("squarer"),
+                    required_features: wgpu::Features::empty(),
+                    // Photos need textures and buffers as large as the adapter allows.
+                    required_limits: adapter.limits(),
+                    memory_hints: wgpu::MemoryHints::default(),
+                },
+                None,
+            )
+            .await
+            .ok()?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("warp"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("warp"),
+            layout: None,
+            module: &module,
+            entry_point: Some("main"),
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        Some(Gpu {
+            device,
+            queue,
+            pipeline,
+        })
+    }
+
+    pub fn warp(
+        source: &RgbaImage,
+        output_to_source: Matrix,
+        (width, height): (u32, u32),
+        nearest: bool,
+        fill: Fill,
+    ) -> Option<RgbaImage> {
+        let gpu = gpu()?;
+        let limits = gpu.device.limits();
+        let max_dimension = limits.max_texture_dimension_2d;
+        if [source.width(), source.height(), width, height]
+            .iter()
+            .any(|&d| d == 0 || d > max_dimension)
+        {
+            return None;
+        }
+        // Rows in a texture-to-buffer copy must be 256-byte aligned.
+        let row_bytes = width * 4;
+        let padded_row_bytes = row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let buffer_size = padded_row_bytes as u64 * height as u64;
+        if buffer_size > limits.max_buffer_size {
+            return None;
+        }
+
+        let device = &gpu.device;
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        let source_texture = device.create_texture_with_data(
+            &gpu.queue,
+            &wgpu::TextureDescriptor {
+                label: Some("source"),
+                size: wgpu::Extent3d {
+                    width: source.width(),
+                    height: source.height(),
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            source.as_raw(),
+        );
+        let output_size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let output_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("output"),
+            size: output_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let m = output_to_source;
+        let params = Params {
+            rows: [
+                [m[0], m[1], m[2], 0.0],
+                [m[3], m[4], m[5], 0.0],
+                [m[6], m[7], m[8], 0.0],
+            ],
+            fill: match fill {
+                Fill::Color([r, g, b]) => [r, g, b, 255].map(|c| c as f32 / 255.0),
+                Fill::Transparent | Fill::EdgeClamp => [0.0; 4],
+            },
+            output_size: [width, height],
+            nearest: nearest as u32,
+            clamp_edges: (fill == Fill::EdgeClamp) as u32,
+        };
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("params"),
+            contents: bytemuck::bytes_of(&params),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: buffer_size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("warp"),
+            layout: &gpu.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &source_texture.create_view(&Default::default()),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(
+                        &output_texture.create_view(&Default::default()),
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: params_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&gpu.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                width.div_ceil(WORKGROUP_SIZE),
+                height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+        }
+        encoder.copy_texture_to_buffer(
+            output_texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &readback,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: Some(height),
+                },
+            },
+            output_size,
+        );
+        gpu.queue.submit([encoder.finish()]);
+        let out_of_memory = pollster::block_on(device.pop_error_scope());
+        let invalid = pollster::block_on(device.pop_error_scope());
+        if out_of_memory.is_some() || invalid.is_some() {
+            return None;
+        }
+
+        let slice = readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv().ok()?.ok()?;
+        let mapped = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((row_bytes * height) as usize);
+        for row in mapped.chunks(padded_row_bytes as usize) {
+            pixels.extend_from_slice(&row[..row_bytes as usize]);
+        }
+        drop(mapped);
+        readback.unmap();
+        RgbaImage::from_raw(width, height, pixels)
+    }
+}
+
+/// Warps `source` on the GPU, given the matrix mapping output pixels back to
+/// source pixels. Returns None whenever the GPU can't do it (no adapter, too
+/// large for its limits, an interpolation mode the shader doesn't implement),
+/// in which case the caller should warp on the CPU instead.
+#[cfg(feature = "gpu")]
+pub fn warp(
+    source: &RgbaImage,
+    output_to_source: Matrix,
+    size: (u32, u32),
+    interpolation: InterpolationMode,
+    fill: Fill,
+) -> Option<RgbaImage> {
+    let nearest = match interpolation {
+        InterpolationMode::Nearest => true,
+        InterpolationMode::Bilinear => false,
+        InterpolationMode::Bicubic => return None,
+    };
+    wgpu_warp::warp(source, output_to_source, size, nearest, fill)
+}
+
+#[cfg(not(feature = "gpu"))]
+pub fn warp(
+    _source: &RgbaImage,
+    _output_to_source: Matrix,
+    _size: (u32, u32),
+    _interpolation: InterpolationMode,
+    _fill: Fill,
+) -> Option<RgbaImage> {
+    None
+}
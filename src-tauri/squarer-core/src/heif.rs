@@ -0,0 +1,147 @@
+use crate::decode::{DecodeLimits, ImageInfo, SourceImage};
+use crate::Error;
+
+pub const HEIF_EXTENSIONS: &[&str] = &["heic", "heif"];
+
+/// Whether `header` (the first bytes of a file) is an ISO base media file
+/// with one of the HEIF brands used for still images.
+pub fn is_heif(header: &[u8]) -> bool {
+    header.len() >= 12
+        && &header[4..8] == b"ftyp"
+        && matches!(
+            &header[8..12],
+            b"heic" | b"heix" | b"heim" | b"heis" | b"mif1" | b"msf1"
+        )
+}
+
+#[cfg(feature = "heif")]
+mod libheif {
+    use super::*;
+    use image::error::{DecodingError, ImageFormatHint};
+    use image::{DynamicImage, ImageBuffer, ImageError};
+    use libheif_rs::{ColorSpace, HeifContext, HeifError, ImageHandle, LibHeif, RgbChroma};
+
+    fn decoding_error(error: HeifError) -> Error {
+        Error::Image(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name(String::from("HEIF")),
+            error,
+        )))
+    }
+
+    /// The primary image's EXIF block, without the offset HEIF puts before
+    /// the TIFF header.
+    fn exif(handle: &ImageHandle) -> Option<Vec<u8>> {
+        let mut ids = [0; 1];
+        if handle.metadata_block_ids(&mut ids, b"Exif") == 0 {
+            return None;
+        }
+        let block = handle.metadata(ids[0]).ok()?;
+        let offset = u32::from_be_bytes(block.get(..4)?.try_into().ok()?) as usize;
+        block.get(4 + offset..).map(<[u8]>::to_vec)
+    }
+
+    /// Decodes the primary image. libheif applies the file's rotation and
+    /// mirroring itself, so unlike other formats there's no EXIF orientation
+    /// left to apply.
+    pub fn read(bytes: &[u8], limits: &DecodeLimits) -> Result<SourceImage, Error> {
+        let context = HeifContext::read_from_bytes(bytes).map_err(decoding_error)?;
+        let handle = context.primary_image_handle().map_err(decoding_error)?;
+        let (width, height) = (handle.width(), handle.height());
+        let alpha = handle.has_alpha_channel();
+        let high_bit_depth = handle.luma_bits_per_pixel() > 8;
+        let channels: u64 = if alpha { 4 } else { 3 };
+        let bytes_needed =
+            width as u64 * height as u64 * channels * if high_bit_depth { 2 } else { 1 };
+        limits.check(width, height, bytes_needed)?;
+        let chroma = match (high_bit_depth, alpha) {
+            (false, false) => RgbChroma::Rgb,
+            (false, true) => RgbChroma::Rgba,
+            (true, false) => RgbChroma::HdrRgbLe,
+            (true, true) => RgbChroma::HdrRgbaLe,
+        };
+        let decoded = LibHeif::new()
+            .decode(&handle, ColorSpace::Rgb(chroma), None)
+            .map_err(decoding_error)?;
+        let plane = decoded.planes().interleaved.ok_or_else(|| {
+            Error::InvalidInput(String::from("HEIF decoder returned no pixel data"))
+        })?;
+        let row_bytes =
+            plane.width as usize * channels as usize * if high_bit_depth { 2 } else { 1 };
+        let rows = plane.data.chunks(plane.stride).map(|row| &row[..row_bytes]);
+        let image = if high_bit_depth {
+            // Samples are `bits_per_pixel` wide; stretch them to the full
+            // 16-bit range.
+            let max = ((1u32 << plane.bits_per_pixel) - 1) as f32;
+            let samples: Vec<u16> = rows
+                .flat_map(|row| row.chunks_exact(2))
+                .map(|s| {
+                    let value = u16::from_le_bytes([s[0], s[1]]) as f32;
+                    (value * u16::MAX as f32 / max).round() as u16
+                })
+                .collect();
+            if alpha {
+                ImageBuffer::from_raw(plane.width, plane.height, samples)
+                    .map(DynamicImage::ImageRgba16)
+            } else {
+                ImageBuffer::from_raw(plane.width, plane.height, samples)
+                    .map(DynamicImage::ImageRgb16)
+            }
+        } else {
+            let samples: Vec<u8> = rows.flatten().copied().collect();
+            if alpha {
+                ImageBuffer::from_raw(plane.width, plane.height, samples)
+                    .map(DynamicImage::ImageRgba8)
+            } else {
+                ImageBuffer::from_raw(plane.width, plane.height, samples)
+                    .map(DynamicImage::ImageRgb8)
+            }
+        }
+        .ok_or_else(|| Error::InvalidInput(String::from("HEIF image has unexpected layout")))?;
+        Ok(SourceImage {
+            image,
+            exif: exif(&handle),
+        })
+    }
+
+    /// Reads the primary image's metadata without decoding it.
+    pub fn probe(bytes: &[u8]) -> Result<ImageInfo, Error> {
+        let context = HeifContext::read_from_bytes(bytes).map_err(decoding_error)?;
+        let handle = context.primary_image_handle().map_err(decoding_error)?;
+        let alpha = handle.has_alpha_channel();
+        let bit_depth = handle.luma_bits_per_pixel() as u16;
+        let color_type = match (bit_depth > 8, alpha) {
+            (false, false) => "rgb8",
+            (false, true) => "rgba8",
+            (true, false) => "rgb16",
+            (true, true) => "rgba16",
+        };
+        Ok(ImageInfo {
+            width: handle.width(),
+            height: handle.height(),
+            format: String::from("heic"),
+            color_type: String::from(color_type),
+            bit_depth,
+            has_alpha: alpha,
+            // Already applied by libheif, as when reading.
+            orientation: 1,
+            dpi: crate::metadata::read_dpi(&[], exif(&handle).as_deref()),
+        })
+    }
+}
+
+#[cfg(feature = "heif")]
+pub use libheif::{probe, read};
+
+#[cfg(not(feature = "heif"))]
+pub fn probe(_bytes: &[u8]) -> Result<ImageInfo, Error> {
+    Err(Error::Unsupported(String::from(
+        "HEIC/HEIF images need a build with the `heif` feature",
+    )))
+}
+
+#[cfg(not(feature = "heif"))]
+pub fn read(_bytes: &[u8], _limits: &DecodeLimits) -> Result<SourceImage, Error> {
+    Err(Error::Unsupported(String::from(
+        "HEIC/HEIF images need a build with the `heif` feature",
+    )))
+}
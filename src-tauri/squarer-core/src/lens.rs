@@ -0,0 +1,319 @@
+use image::{DynamicImage, GrayImage};
+use imageproc::point::Point;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+/// Radial lens distortion, as in the Brown-Conrady model: a point at
+/// distance r from the image's center (as a fraction of half its diagonal)
+/// appears at r * (1 + k1 r^2 + k2 r^4). Wide-angle phone lenses usually have
+/// barrel distortion, with a negative `k1`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LensDistortion {
+    pub k1: f64,
+    pub k2: f64,
+}
+
+// Coefficients beyond this far from zero are taken as a mistake rather than
+// any real lens.
+const MAX_COEFFICIENT: f64 = 1.0;
+// Iterations of the fixed-point search for an undistorted position.
+const UNDISTORT_ITERATIONS: usize = 20;
+
+impl LensDistortion {
+    pub fn validate(&self) -> Result<(), Error> {
+        for (name, k) in [("k1", self.k1), ("k2", self.k2)] {
+            if !(-MAX_COEFFICIENT..=MAX_COEFFICIENT).contains(&k) {
+                return Err(Error::InvalidInput(format!(
+                    "Lens distortion {name} must be between -{MAX_COEFFICIENT} and {MAX_COEFFICIENT}, got {k}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.k1 == 0.0 && self.k2 == 0.0
+    }
+
+    fn factor(&self, r2: f64) -> f64 {
+        1.0 + self.k1 * r2 + self.k2 * r2 * r2
+    }
+
+    /// Where a point of the ideal (undistorted) image appears in a photo of
+    /// the given size.
+    pub fn distort(&self, (x, y): (f64, f64), (width, height): (u32, u32)) -> (f64, f64) {
+        let (cx, cy, norm) = frame(width, height);
+        let (dx, dy) = ((x - cx) / norm, (y - cy) / norm);
+        let factor = self.factor(dx * dx + dy * dy);
+        (cx + dx * factor * norm, cy + dy * factor * norm)
+    }
+
+    /// Where a point of the photo belongs in the ideal image: the inverse of
+    /// `distort`.
+    pub fn undistort(&self, (x, y): (f64, f64), (width, height): (u32, u32)) -> (f64, f64) {
+        let (cx, cy, norm) = frame(width, height);
+        let (px, py) = ((x - cx) / norm, (y - cy) / norm);
+        let (mut ux, mut uy) = (px, py);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let factor = self.factor(ux * ux + uy * uy);
+            if factor.abs() < f64::EPSILON {
+                break;
+            }
+            (ux, uy) = (px / factor, py / factor);
+        }
+        (cx + ux * norm, cy + uy * norm)
+    }
+}
+
+/// A camera's lens distortion, for correcting its photos automatically.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LensProfile {
+    /// The camera's EXIF make; profiles without one match any make.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    /// The camera's EXIF model, e.g. "Pixel 7".
+    pub model: String,
+    pub distortion: LensDistortion,
+}
+
+impl LensProfile {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.model.trim().is_empty() {
+            return Err(Error::InvalidInput(String::from(
+                "A lens profile needs a camera model",
+            )));
+        }
+        self.distortion.validate()
+    }
+
+    /// Whether this is the profile for the camera with the given EXIF make
+    /// and model, ignoring case and surrounding spaces.
+    pub fn matches(&self, make: Option<&str>, model: &str) -> bool {
+        let same = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+        same(&self.model, model)
+            && match (&self.make, make) {
+                (Some(ours), Some(theirs)) => same(ours, theirs),
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+}
+
+/// The best profile for a photo with the given EXIF block: one for its exact
+/// make and model if there is one, otherwise one for its model alone.
+pub fn find_profile<'a>(profiles: &'a [LensProfile], exif: &[u8]) -> Option<&'a LensProfile> {
+    let (make, model) = crate::metadata::camera(exif);
+    let model = model?;
+    let matching = || {
+        profiles
+            .iter()
+            .filter(|profile| profile.matches(make.as_deref(), &model))
+    };
+    matching()
+        .find(|profile| profile.make.is_some())
+        .or_else(|| matching().next())
+}
+
+/// The image's center and half its diagonal, which distances are measured
+/// in.
+fn frame(width: u32, height: u32) -> (f64, f64, f64) {
+    let (width, height) = (width as f64, height as f64);
+    (
+        width / 2.0,
+        height / 2.0,
+        (width.hypot(height) / 2.0).max(1.0),
+    )
+}
+
+// Positions sampled along each of the document's edges, and how far either
+// side of the straight line between its corners (as a fraction of the
+// image's diagonal) the edge is looked for.
+const EDGE_SAMPLES: usize = 40;
+const EDGE_SEARCH: f64 = 0.03;
+// The least step in brightness across an edge for it to count.
+const MIN_EDGE_CONTRAST: f32 = 12.0;
+// Below this many edge points found, there's too little to go on.
+const MIN_EDGE_POINTS: usize = 40;
+// Range and steps of the search for coefficients, refined around the best
+// so far.
+const K1_RANGE: f64 = 0.5;
+const K2_RANGE: f64 = 0.3;
+const SEARCH_STEPS: i32 = 20;
+const REFINEMENTS: usize = 3;
+// Keeps `k2` from trading off against `k1` when the edges can't tell them
+// apart.
+const K2_PENALTY: f64 = 1e-3;
+
+fn sample(gray: &GrayImage, x: f64, y: f64) -> Option<f32> {
+    let (width, height) = gray.dimensions();
+    if x < 0.0 || y < 0.0 || x >= (width - 1) as f64 || y >= (height - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (fx, fy) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+    let at = |dx: u32, dy: u32| gray.get_pixel(x0 + dx, y0 + dy)[0] as f32;
+    let top = at(0, 0) + (at(1, 0) - at(0, 0)) * fx;
+    let bottom = at(0, 1) + (at(1, 1) - at(0, 1)) * fx;
+    Some(top + (bottom - top) * fy)
+}
+
+/// Points along each of the quad's edges where the image's brightness steps
+/// most sharply across it.
+fn edge_points(gray: &GrayImage, corners: &[Point<f64>]) -> Vec<Vec<(f64, f64)>> {
+    let (width, height) = gray.dimensions();
+    let search = (width as f64).hypot(height as f64) * EDGE_SEARCH;
+    (0..4)
+        .map(|side| {
+            let (start, end) = (corners[side], corners[(side + 1) % 4]);
+            let (dx, dy) = (end.x - start.x, end.y - start.y);
+            let length = dx.hypot(dy).max(f64::EPSILON);
+            let (nx, ny) = (-dy / length, dx / length);
+            (1..EDGE_SAMPLES)
+                .filter_map(|i| {
+                    // Skip the ends, where the neighbouring edges interfere.
+                    let t = 0.1 + 0.8 * i as f64 / EDGE_SAMPLES as f64;
+                    let (x, y) = (start.x + dx * t, start.y + dy * t);
+                    let mut best: Option<(f32, f64)> = None;
+                    let mut offset = -search;
+                    while offset <= search {
+                        let (px, py) = (x + nx * offset, y + ny * offset);
+                        if let (Some(before), Some(after)) = (
+                            sample(gray, px - nx, py - ny),
+                            sample(gray, px + nx, py + ny),
+                        ) {
+                            let step = (after - before).abs();
+                            if best.is_none_or(|(best_step, _)| step > best_step) {
+                                best = Some((step, offset));
+                            }
+                        }
+                        offset += 0.5;
+                    }
+                    let (step, offset) = best?;
+                    (step >= MIN_EDGE_CONTRAST).then_some((x + nx * offset, y + ny * offset))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The mean squared distance of the points from their best-fitting line.
+fn line_residual(points: impl Iterator<Item = (f64, f64)> + Clone) -> f64 {
+    let count = points.clone().count() as f64;
+    let (sx, sy) = points
+        .clone()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (mx, my) = (sx / count, sy / count);
+    let (mut xx, mut xy, mut yy) = (0.0, 0.0, 0.0);
+    for (x, y) in points {
+        let (dx, dy) = (x - mx, y - my);
+        xx += dx * dx;
+        xy += dx * dy;
+        yy += dy * dy;
+    }
+    // The smaller eigenvalue of the scatter matrix.
+    let half_trace = (xx + yy) / 2.0;
+    let spread = (((xx - yy) / 2.0).powi(2) + xy * xy).sqrt();
+    (half_trace - spread).max(0.0) / count
+}
+
+/// Estimates the lens distortion that bowed the straight edges of the
+/// document with the given corners (as found by `detect`), by finding the
+/// coefficients that straighten them best. None if too little of the edges
+/// can be made out.
+pub fn estimate_distortion(image: &DynamicImage, corners: &[Point<f64>]) -> Option<LensDistortion> {
+    if corners.len() != 4 {
+        return None;
+    }
+    let gray = image.to_luma8();
+    let size = gray.dimensions();
+    let edges: Vec<Vec<(f64, f64)>> = edge_points(&gray, corners)
+        .into_iter()
+        .filter(|points| points.len() >= 3)
+        .collect();
+    if edges.iter().map(Vec::len).sum::<usize>() < MIN_EDGE_POINTS {
+        return None;
+    }
+    let (_, _, norm) = frame(size.0, size.1);
+    let cost = |lens: &LensDistortion| {
+        let residual: f64 = edges
+            .iter()
+            .map(|points| {
+                line_residual(points.iter().map(|&p| lens.undistort(p, size))) / (norm * norm)
+            })
+            .sum();
+        residual + K2_PENALTY * lens.k2 * lens.k2
+    };
+    let mut best = LensDistortion::default();
+    let mut best_cost = cost(&best);
+    let (mut range_k1, mut range_k2) = (K1_RANGE, K2_RANGE);
+    let (mut center_k1, mut center_k2) = (0.0, 0.0);
+    for _ in 0..REFINEMENTS {
+        for i in -SEARCH_STEPS..=SEARCH_STEPS {
+            for j in -SEARCH_STEPS..=SEARCH_STEPS {
+                let lens = LensDistortion {
+                    k1: center_k1 + range_k1 * i as f64 / SEARCH_STEPS as f64,
+                    k2: center_k2 + range_k2 * j as f64 / SEARCH_STEPS as f64,
+                };
+                if lens.validate().is_err() {
+                    continue;
+                }
+                let lens_cost = cost(&lens);
+                if lens_cost < best_cost {
+                    (best, best_cost) = (lens, lens_cost);
+                }
+            }
+        }
+        (center_k1, center_k2) = (best.k1, best.k2);
+        range_k1 /= SEARCH_STEPS as f64 / 2.0;
+        range_k2 /= SEARCH_STEPS as f64 / 2.0;
+    }
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (4000, 3000);
+
+    /// Points across a photo of `SIZE`, out to its corners.
+    fn points() -> impl Iterator<Item = (f64, f64)> {
+        (0..=8).flat_map(|j| (0..=8).map(move |i| (i as f64 * 500.0, j as f64 * 375.0)))
+    }
+
+    #[test]
+    fn undistorting_a_distorted_point_returns_it() {
+        for lens in [
+            LensDistortion {
+                k1: -0.12,
+                k2: 0.02,
+            },
+            LensDistortion {
+                k1: 0.08,
+                k2: -0.01,
+            },
+        ] {
+            for p in points() {
+                let distorted = lens.distort(p, SIZE);
+                let (x, y) = lens.undistort(distorted, SIZE);
+                assert!(
+                    (x - p.0).abs() < 0.01 && (y - p.1).abs() < 0.01,
+                    "{lens:?} takes {p:?} to {distorted:?} and back to ({x}, {y})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn zero_coefficients_leave_points_alone() {
+        let lens = LensDistortion::default();
+        assert!(lens.is_identity());
+        for p in points() {
+            assert_eq!(lens.distort(p, SIZE), p);
+            assert_eq!(lens.undistort(p, SIZE), p);
+        }
+    }
+}
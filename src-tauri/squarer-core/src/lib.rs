@@ -0,0 +1,2087 @@
+//! The image pipeline behind Squarer: finding, validating and warping a
+//! document's corners into an upright rectangle, then cleaning up and encoding
+//! the result.
+
+pub mod adjust;
+pub mod align;
+pub mod animation;
+pub mod aspect;
+pub mod book;
+pub mod cancel;
+pub mod cleanup;
+pub mod decode;
+pub mod detect;
+mod dewarp;
+pub mod encode;
+mod gpu;
+mod heif;
+pub mod lens;
+pub mod matrix;
+pub mod mesh;
+pub mod metadata;
+pub mod naming;
+mod raw;
+pub mod tiff;
+
+use cancel::CancellationToken;
+use cleanup::CleanupMode;
+use encode::OutputFormat;
+use image::{ColorType, DynamicImage, GenericImageView, Pixel, Primitive};
+use imageproc::definitions::{Clamp, Image};
+use imageproc::geometric_transformations;
+use imageproc::point::Point;
+use matrix::Matrix;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use std::fmt;
+use std::io::Write;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlPoint {
+    /// Pixel coordinates in the source image; fractional values are kept all
+    /// the way through to the warp.
+    pub x: f64,
+    pub y: f64,
+    /// Which corner of the output this point becomes. Without labels the
+    /// orientation is chosen automatically; labelling lets the caller rotate
+    /// (or mirror) the output deliberately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<CornerRole>,
+    /// Where this point lies on the page, in any units (grid squares on
+    /// graph paper, say). Giving every point a target lets more than four be
+    /// used, with the projection fitted to all of them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<[f64; 2]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CornerRole {
+    TopLeft,
+    TopRight,
+    BottomRight,
+    BottomLeft,
+}
+
+impl ControlPoint {
+    pub fn new(x: f64, y: f64) -> Self {
+        ControlPoint {
+            x,
+            y,
+            role: None,
+            target: None,
+        }
+    }
+}
+
+/// How control points' positions are given.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoordinateSpace {
+    /// Pixels of the decoded image, after any EXIF rotation.
+    #[default]
+    Pixels,
+    /// Fractions of the image's width and height, from 0 to 1, as a frontend
+    /// tracking points over a scaled display of the image has them.
+    Normalized,
+}
+
+impl CoordinateSpace {
+    /// The control points in pixels of an image of the given size.
+    pub fn to_pixels(
+        self,
+        control_points: Vec<ControlPoint>,
+        (width, height): (u32, u32),
+    ) -> Vec<ControlPoint> {
+        match self {
+            CoordinateSpace::Pixels => control_points,
+            CoordinateSpace::Normalized => control_points
+                .into_iter()
+                .map(|cp| ControlPoint {
+                    x: cp.x * width as f64,
+                    y: cp.y * height as f64,
+                    ..cp
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InterpolationMode {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+}
+
+impl From<InterpolationMode> for geometric_transformations::Interpolation {
+    fn from(mode: InterpolationMode) -> Self {
+        match mode {
+            InterpolationMode::Nearest => geometric_transformations::Interpolation::Nearest,
+            InterpolationMode::Bilinear => geometric_transformations::Interpolation::Bilinear,
+            InterpolationMode::Bicubic => geometric_transformations::Interpolation::Bicubic,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ImageSquaringError {
+    message: String,
+}
+
+impl ImageSquaringError {
+    pub fn new(message: impl Into<String>) -> Self {
+        ImageSquaringError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ImageSquaringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{0}", self.message)
+    }
+}
+
+impl std::error::Error for ImageSquaringError {}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Squaring(#[from] ImageSquaringError),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Exif(#[from] exif::Error),
+    #[error("Image too large: {0}")]
+    ImageTooLarge(String),
+    #[error("{0}")]
+    Unsupported(String),
+    /// The control points outline a concave quad: the one at index `point`
+    /// lies inside the triangle of the other three.
+    #[error("Non-convex quadrilateral: control point {point} is inside the other three")]
+    Concave { point: usize },
+    /// The corner labels take the outline across itself: the edges between
+    /// each pair of control points (by index) cross.
+    #[error(
+        "Self-intersecting quadrilateral: the edge from control point {} to {} crosses the edge from {} to {}",
+        edges[0][0], edges[0][1], edges[1][0], edges[1][1]
+    )]
+    SelfIntersecting { edges: [[usize; 2]; 2] },
+}
+
+/// The matrix mapping the unit square onto the quad with the given (scaled)
+/// corners.
+fn scaled_control_points_to_matrix(points: &Vec<(f32, f32)>) -> Option<[f32; 9]> {
+    // From Oleksandr Kaleniuk's "Geometry for Programmers", pp. 118 - 119.
+    match points.as_slice() {
+        [(xt1, yt1), (xt2, yt2), (xt3, yt3), (xt4, yt4)] => {
+            let g =
+                (xt1 * yt3 - xt1 * yt4 - xt2 * yt3 + xt2 * yt4 - xt3 * yt1 + xt3 * yt2 + xt4 * yt1
+                    - xt4 * yt2)
+                    / (xt2 * yt3 - xt2 * yt4 - xt3 * yt2 + xt3 * yt4 + xt4 * yt2 - xt4 * yt3);
+            let h =
+                (xt1 * yt2 - xt1 * yt3 - xt2 * yt1 + xt2 * yt4 + xt3 * yt1 - xt3 * yt4 - xt4 * yt2
+                    + xt4 * yt3)
+                    / (xt2 * yt3 - xt2 * yt4 - xt3 * yt2 + xt3 * yt4 + xt4 * yt2 - xt4 * yt3);
+            let e = h * yt4 - yt1 + yt4;
+            let d = g * yt2 - yt1 + yt2;
+            let b = h * xt4 - xt1 + xt4;
+            let a = g * xt2 - xt1 + xt2;
+            let c = 0.0 + xt1;
+            let f = 0.0 + yt1;
+            let i: f32 = 1.0;
+            let matrix = [a, b, c, d, e, f, g, h, i];
+            // Degenerate inputs show up as division by zero above.
+            if !matrix.iter().all(|v| v.is_finite()) {
+                return None;
+            }
+            Some(matrix)
+        }
+        _ => None,
+    }
+}
+
+/// What goes in output pixels that map to outside the source image.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fill {
+    #[default]
+    Transparent,
+    /// An opaque color.
+    Color([u8; 3]),
+    /// Repeat the source's nearest edge pixel.
+    EdgeClamp,
+}
+
+/// How the output's resolution is chosen (before any aspect ratio correction).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputSize {
+    /// The size of the quad's bounding box in the source (or, for a
+    /// parallelogram, its edges' lengths).
+    #[default]
+    BoundingBox,
+    /// The longer of each pair of opposite edges, so that even the most
+    /// foreshortened part of the quad isn't undersampled.
+    MaxEdge,
+}
+
+/// How much care goes into sampling the source.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderQuality {
+    /// Nearest-neighbor sampling, whatever `interpolation` says.
+    Draft,
+    /// One sample per output pixel with the chosen interpolation.
+    #[default]
+    Normal,
+    /// Renders at several times the output resolution and box-filters down,
+    /// so that fine detail (such as small text) doesn't alias when the quad
+    /// is shrunk.
+    High,
+}
+
+/// What `square_quad` gives back.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    /// Just the squared quad.
+    #[default]
+    Standalone,
+    /// The whole source, with the squared quad pasted over where it was
+    /// (centered on its bounding box, which it exactly covers with
+    /// `OutputSize::BoundingBox`), e.g. to straighten a crooked poster or
+    /// screen within a larger photo.
+    InPlace,
+}
+
+/// The shape the page is assumed to have when it's squared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PageModel {
+    /// A flat sheet, squared by a single perspective projection.
+    #[default]
+    Flat,
+    /// A page curving away from the camera, like one side of an open book.
+    /// The curve is fitted from the page's top and bottom edges and its
+    /// lines of text, which come out straight, and the page's width is
+    /// unrolled. The corners should be the page's.
+    Curved,
+}
+
+impl RenderQuality {
+    /// Samples per output pixel along each axis.
+    fn supersampling(self) -> u32 {
+        match self {
+            RenderQuality::Draft | RenderQuality::Normal => 1,
+            RenderQuality::High => 3,
+        }
+    }
+}
+
+/// Options controlling how the selected quadrilateral is squared and encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProcessingOptions {
+    pub interpolation: InterpolationMode,
+    pub output_format: OutputFormat,
+    pub quality: u8,
+    /// Color that transparency is flattened onto for formats without alpha.
+    pub background: [u8; 3],
+    pub fill: Fill,
+    pub preserve_aspect_ratio: bool,
+    pub output_size: OutputSize,
+    /// Not to be confused with `quality`, which is for JPEG compression.
+    pub render_quality: RenderQuality,
+    /// Warp on the GPU when possible (bilinear and nearest interpolation
+    /// only), falling back to the CPU otherwise. Ignored in builds without
+    /// the `gpu` feature.
+    pub use_gpu: bool,
+    /// Copy capture date, camera details and (unless `strip_gps`) location
+    /// from the source photo's EXIF into the output.
+    pub copy_metadata: bool,
+    pub strip_gps: bool,
+    pub cleanup_mode: CleanupMode,
+    /// Even out the lighting across the squared image (e.g. a shadow cast
+    /// by the phone) before cleanup and export.
+    pub remove_shadows: bool,
+    /// Invert negatives and blueprints, before any other adjustment.
+    pub invert: Option<adjust::Inversion>,
+    /// Neutralize a color cast (e.g. from warm indoor light) so that the
+    /// paper comes out white.
+    pub auto_color: bool,
+    /// Brightness, contrast, gamma and saturation, applied after any color
+    /// correction.
+    pub adjustments: Option<adjust::Adjustments>,
+    /// Remove a color of ink, before sharpening and cleanup.
+    pub dropout: Option<adjust::ColorDropout>,
+    pub sharpening: adjust::Sharpening,
+    /// Physical resolution to record in the output so that it prints at the
+    /// right size; without it most software assumes 72 dpi.
+    pub dpi: Option<f32>,
+    /// Keep the encoded output within this many KB (e.g. for upload portals)
+    /// by lowering the JPEG quality as far as needed; `quality` becomes the
+    /// most it can be.
+    pub max_file_size_kb: Option<u32>,
+    /// Squaring a curved page gives a wider output than `warp_geometry`
+    /// describes, and doesn't use the GPU.
+    pub page_model: PageModel,
+    /// The camera lens's distortion, corrected for as the quad is squared so
+    /// that edges bowed by a wide-angle lens come out straight. Not for
+    /// curved pages; doesn't use the GPU.
+    pub lens_distortion: Option<lens::LensDistortion>,
+    pub output_mode: OutputMode,
+    /// How the control points passed along with these options are given.
+    pub coordinate_space: CoordinateSpace,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        ProcessingOptions {
+            interpolation: InterpolationMode::default(),
+            output_format: OutputFormat::default(),
+            quality: encode::DEFAULT_QUALITY,
+            background: encode::DEFAULT_BACKGROUND,
+            fill: Fill::default(),
+            preserve_aspect_ratio: false,
+            output_size: OutputSize::default(),
+            render_quality: RenderQuality::default(),
+            use_gpu: false,
+            copy_metadata: false,
+            strip_gps: true,
+            cleanup_mode: CleanupMode::default(),
+            remove_shadows: false,
+            invert: None,
+            auto_color: false,
+            adjustments: None,
+            dropout: None,
+            sharpening: adjust::Sharpening::default(),
+            dpi: None,
+            max_file_size_kb: None,
+            page_model: PageModel::default(),
+            lens_distortion: None,
+            output_mode: OutputMode::default(),
+            coordinate_space: CoordinateSpace::default(),
+        }
+    }
+}
+
+// Corners closer than this (in pixels) are treated as duplicates.
+const MIN_CORNER_DISTANCE: f64 = 2.0;
+// Corners whose edges meet at an angle with a smaller sine than this (about
+// 0.6 degrees) are treated as lying on a straight line.
+const MIN_CORNER_SINE: f64 = 0.01;
+
+/// Checks that the control points form a non-degenerate convex quadrilateral
+/// and returns its corners in output order: top left, top right, bottom right,
+/// bottom left. That's the order given by the points' roles if they're all
+/// labelled, otherwise clockwise starting from `top_left_index`. Points with
+/// targets are handled by `fitted_corners` instead, and three points by
+/// `parallelogram_corners`.
+pub fn convex_quad(control_points: Vec<ControlPoint>) -> Result<Vec<Point<f64>>, Error> {
+    if control_points.iter().any(|cp| cp.target.is_some()) {
+        return convex_quad(fitted_corners(&control_points)?);
+    }
+    if let Some(cp) = control_points
+        .iter()
+        .find(|cp| !cp.x.is_finite() || !cp.y.is_finite())
+    {
+        return Err(Error::InvalidInput(format!(
+            "Control point ({}, {}) isn't a finite position",
+            cp.x, cp.y
+        )));
+    }
+    if control_points.len() == 3 {
+        return convex_quad(parallelogram_corners(&control_points)?);
+    }
+    if control_points.len() != 4 {
+        return Err(Error::InvalidInput(format!(
+            "Expected 3 or 4 control points, got {}",
+            control_points.len()
+        )));
+    }
+    let labelled = labelled_corners(&control_points)?;
+    let point = |i: usize| Point::new(control_points[i].x, control_points[i].y);
+    // Going by angle around the centroid visits the corners clockwise (on
+    // screen, with y pointing down) if the quad is convex.
+    let centroid_x = control_points.iter().map(|cp| cp.x).sum::<f64>() / 4.0;
+    let centroid_y = control_points.iter().map(|cp| cp.y).sum::<f64>() / 4.0;
+    let mut hull_order = [0, 1, 2, 3];
+    hull_order.sort_by(|&a, &b| {
+        let angle = |i: usize| (point(i).y - centroid_y).atan2(point(i).x - centroid_x);
+        angle(a).total_cmp(&angle(b))
+    });
+    for i in 0..4 {
+        let corner = point(hull_order[i]);
+        let (prev, next) = (
+            point(hull_order[(i + 3) % 4]),
+            point(hull_order[(i + 1) % 4]),
+        );
+        let (ux, uy) = (prev.x - corner.x, prev.y - corner.y);
+        let (vx, vy) = (next.x - corner.x, next.y - corner.y);
+        let (u_length, v_length) = (ux.hypot(uy), vx.hypot(vy));
+        // A reflex corner (one inside the triangle of the other three) turns
+        // the other way.
+        if ux * vy - uy * vx > 0.0 {
+            return Err(Error::Concave {
+                point: hull_order[i],
+            });
+        }
+        if v_length < MIN_CORNER_DISTANCE {
+            return Err(Error::Squaring(ImageSquaringError {
+                message: String::from("Control points are too close together"),
+            }));
+        }
+        if (ux * vy - uy * vx).abs() / (u_length * v_length) < MIN_CORNER_SINE {
+            return Err(Error::Squaring(ImageSquaringError {
+                message: String::from("Control points are collinear"),
+            }));
+        }
+    }
+    match labelled {
+        Some(order) => {
+            // In a (convex) quad, labels must go around it one way or the other;
+            // anything else would twist the output into a bow tie, with one
+            // pair of opposite edges crossing.
+            let around = |order: &[usize; 4]| {
+                (0..4).any(|shift| (0..4).all(|i| order[i] == hull_order[(i + shift) % 4]))
+            };
+            let mut reversed = order;
+            reversed.reverse();
+            if !around(&order) && !around(&reversed) {
+                let edge = |i: usize| [order[i], order[(i + 1) % 4]];
+                let crossing = |a: [usize; 2], b: [usize; 2]| {
+                    segments_cross((point(a[0]), point(a[1])), (point(b[0]), point(b[1])))
+                };
+                let edges = if crossing(edge(0), edge(2)) {
+                    [edge(0), edge(2)]
+                } else {
+                    [edge(1), edge(3)]
+                };
+                return Err(Error::SelfIntersecting { edges });
+            }
+            Ok(order.iter().map(|&i| point(i)).collect())
+        }
+        None => {
+            let mut convex_hull: Vec<Point<f64>> = hull_order.iter().map(|&i| point(i)).collect();
+            let first_point = top_left_index(&convex_hull);
+            convex_hull.rotate_left(first_point);
+            Ok(convex_hull)
+        }
+    }
+}
+
+/// Whether two line segments cross each other (rather than just touching).
+fn segments_cross((a, b): (Point<f64>, Point<f64>), (c, d): (Point<f64>, Point<f64>)) -> bool {
+    let side = |p: Point<f64>, q: Point<f64>, r: Point<f64>| {
+        (q.x - p.x) * (r.y - p.y) - (q.y - p.y) * (r.x - p.x)
+    };
+    side(a, b, c) * side(a, b, d) < 0.0 && side(c, d, a) * side(c, d, b) < 0.0
+}
+
+/// Like `convex_quad`, but two points (without targets) are also accepted:
+/// they mark a line that should be level (or plumb, whichever it's nearer),
+/// and the result is the largest rectangle with the image's proportions that
+/// fits in the image when it's rotated to match.
+pub fn quad_from_points(
+    control_points: Vec<ControlPoint>,
+    (width, height): (u32, u32),
+) -> Result<Vec<Point<f64>>, Error> {
+    if control_points.len() != 2 || control_points.iter().any(|cp| cp.target.is_some()) {
+        return convex_quad(control_points);
+    }
+    let (start, end) = (&control_points[0], &control_points[1]);
+    if ![start.x, start.y, end.x, end.y]
+        .iter()
+        .all(|c| c.is_finite())
+    {
+        return Err(Error::InvalidInput(String::from(
+            "Control points must be finite positions",
+        )));
+    }
+    let (dx, dy) = (end.x - start.x, end.y - start.y);
+    if dx.hypot(dy) < MIN_CORNER_DISTANCE {
+        return Err(Error::Squaring(ImageSquaringError {
+            message: String::from("Control points are too close together"),
+        }));
+    }
+    // Turn by at most 45 degrees either way.
+    let quarter_turn = std::f64::consts::FRAC_PI_2;
+    let angle = dy.atan2(dx);
+    let angle = angle - (angle / quarter_turn).round() * quarter_turn;
+    let (sin, cos) = angle.sin_cos();
+    let (image_width, image_height) = (width as f64, height as f64);
+    let scale = (image_width / (image_width * cos + image_height * sin.abs()))
+        .min(image_height / (image_width * sin.abs() + image_height * cos));
+    let (half_width, half_height) = (image_width * scale / 2.0, image_height * scale / 2.0);
+    let (center_x, center_y) = (image_width / 2.0, image_height / 2.0);
+    Ok([(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+        .iter()
+        .map(|&(sx, sy)| {
+            let (x, y) = (sx * half_width, sy * half_height);
+            // Rounding can put the corners a hair outside the image.
+            Point::new(
+                (center_x + x * cos - y * sin).clamp(0.0, image_width),
+                (center_y + x * sin + y * cos).clamp(0.0, image_height),
+            )
+        })
+        .collect())
+}
+
+/// Runs every check on the control points that squaring an image of the
+/// given size would, without touching any pixels: that they're in the image,
+/// form a convex quad that isn't degenerate or twisted by its labels, and
+/// give a usable projection.
+pub fn validate_points(
+    control_points: Vec<ControlPoint>,
+    (width, height): (u32, u32),
+) -> Result<(), Error> {
+    let quad = quad_from_points(control_points, (width, height))?;
+    warp_geometry((width, height), &quad, &ProcessingOptions::default())?;
+    Ok(())
+}
+
+/// Completes three corners of the quad into a parallelogram, so that squaring
+/// it is an affine correction (skew, rotation and scale, but no perspective).
+/// Labelled points say which corner is missing; otherwise it's the one
+/// opposite the point with the widest angle, which is taken as the corner
+/// between the other two.
+fn parallelogram_corners(control_points: &[ControlPoint]) -> Result<Vec<ControlPoint>, Error> {
+    let roles = [
+        CornerRole::TopLeft,
+        CornerRole::TopRight,
+        CornerRole::BottomRight,
+        CornerRole::BottomLeft,
+    ];
+    if control_points.iter().any(|cp| cp.role.is_some()) {
+        let by_role: Vec<Option<&ControlPoint>> = roles
+            .iter()
+            .map(|&role| control_points.iter().find(|cp| cp.role == Some(role)))
+            .collect();
+        let missing = match by_role.iter().position(Option::is_none) {
+            Some(missing) if by_role.iter().flatten().count() == 3 => missing,
+            _ => {
+                return Err(Error::InvalidInput(String::from(
+                    "Label three points with distinct corner roles, or none of them",
+                )));
+            }
+        };
+        let corner = |offset: usize| by_role[(missing + offset) % 4].unwrap_or(&control_points[0]);
+        let (previous, opposite, next) = (corner(3), corner(2), corner(1));
+        let mut corners = control_points.to_vec();
+        corners.push(ControlPoint {
+            role: Some(roles[missing]),
+            ..ControlPoint::new(
+                previous.x + next.x - opposite.x,
+                previous.y + next.y - opposite.y,
+            )
+        });
+        return Ok(corners);
+    }
+    let angle = |i: usize| {
+        let (corner, a, b) = (
+            &control_points[i],
+            &control_points[(i + 1) % 3],
+            &control_points[(i + 2) % 3],
+        );
+        let (ux, uy) = (a.x - corner.x, a.y - corner.y);
+        let (vx, vy) = (b.x - corner.x, b.y - corner.y);
+        (ux * vy - uy * vx).abs().atan2(ux * vx + uy * vy)
+    };
+    let shared = (0..3)
+        .max_by(|&a, &b| angle(a).total_cmp(&angle(b)))
+        .unwrap_or(0);
+    let (corner, a, b) = (
+        &control_points[shared],
+        &control_points[(shared + 1) % 3],
+        &control_points[(shared + 2) % 3],
+    );
+    let mut corners = control_points.to_vec();
+    corners.push(ControlPoint::new(
+        a.x + b.x - corner.x,
+        a.y + b.y - corner.y,
+    ));
+    Ok(corners)
+}
+
+/// The corners, labelled with their roles, of the rectangle spanned by the
+/// control points' targets, placed in the image by the homography that best
+/// maps the targets onto the points (see `matrix::fit_homography`). With more
+/// than four points, a single misplaced one only pulls the fit slightly.
+fn fitted_corners(control_points: &[ControlPoint]) -> Result<Vec<ControlPoint>, Error> {
+    if control_points.len() < 4 {
+        return Err(Error::InvalidInput(format!(
+            "Expected at least 4 control points with targets, got {}",
+            control_points.len()
+        )));
+    }
+    let mut pairs = Vec::with_capacity(control_points.len());
+    for cp in control_points {
+        let Some([u, v]) = cp.target else {
+            return Err(Error::InvalidInput(String::from(
+                "Give every control point a target, or none of them",
+            )));
+        };
+        if cp.role.is_some() {
+            return Err(Error::InvalidInput(String::from(
+                "Control points with targets can't also have corner roles",
+            )));
+        }
+        if ![cp.x, cp.y, u, v].iter().all(|c| c.is_finite()) {
+            return Err(Error::InvalidInput(format!(
+                "Control point ({}, {}) with target ({u}, {v}) isn't a finite position",
+                cp.x, cp.y
+            )));
+        }
+        pairs.push(((u, v), (cp.x, cp.y)));
+    }
+    let invalid_projection = || {
+        Error::Squaring(ImageSquaringError {
+            message: String::from("Control points don't define a valid projection"),
+        })
+    };
+    let target_to_source = matrix::fit_homography(&pairs).ok_or_else(invalid_projection)?;
+    let (mut min_u, mut min_v) = (f64::INFINITY, f64::INFINITY);
+    let (mut max_u, mut max_v) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for &((u, v), _) in &pairs {
+        min_u = min_u.min(u);
+        max_u = max_u.max(u);
+        min_v = min_v.min(v);
+        max_v = max_v.max(v);
+    }
+    [
+        ((min_u, min_v), CornerRole::TopLeft),
+        ((max_u, min_v), CornerRole::TopRight),
+        ((max_u, max_v), CornerRole::BottomRight),
+        ((min_u, max_v), CornerRole::BottomLeft),
+    ]
+    .into_iter()
+    .map(|((u, v), role)| {
+        let m = &target_to_source;
+        // Corners beyond the projection's horizon have no place in the image.
+        if (m[6] as f64 * u + m[7] as f64 * v + m[8] as f64) <= 0.0 {
+            return Err(invalid_projection());
+        }
+        let (x, y) = matrix::transform(m, (u, v));
+        Ok(ControlPoint {
+            role: Some(role),
+            ..ControlPoint::new(x, y)
+        })
+    })
+    .collect()
+}
+
+/// The indices of the points in output order if every point has a distinct
+/// role, or None if none do.
+fn labelled_corners(control_points: &[ControlPoint]) -> Result<Option<[usize; 4]>, Error> {
+    if control_points.iter().all(|cp| cp.role.is_none()) {
+        return Ok(None);
+    }
+    let mut order = [0; 4];
+    for (index, role) in order.iter_mut().zip([
+        CornerRole::TopLeft,
+        CornerRole::TopRight,
+        CornerRole::BottomRight,
+        CornerRole::BottomLeft,
+    ]) {
+        let mut matching =
+            (0..control_points.len()).filter(|&i| control_points[i].role == Some(role));
+        match (matching.next(), matching.next()) {
+            (Some(i), None) => *index = i,
+            _ => {
+                return Err(Error::InvalidInput(String::from(
+                    "Label all four corners with distinct roles, or none of them",
+                )))
+            }
+        }
+    }
+    Ok(Some(order))
+}
+
+/// Picks which corner of a clockwise quadrilateral becomes the output's top
+/// left: the start of the edge pointing most nearly left-to-right, so that the
+/// output is rotated as little as possible from how the quad appears. Ties
+/// (a quad at exactly 45 degrees) go to the corner nearest the image's origin.
+fn top_left_index(quad: &[Point<f64>]) -> usize {
+    let edge_score = |i: usize| {
+        let (start, end) = (quad[i], quad[(i + 1) % 4]);
+        let (dx, dy) = (end.x - start.x, end.y - start.y);
+        dx / dx.hypot(dy)
+    };
+    (0..4)
+        .max_by(|&a, &b| {
+            edge_score(a)
+                .total_cmp(&edge_score(b))
+                .then((quad[b].x + quad[b].y).total_cmp(&(quad[a].x + quad[a].y)))
+        })
+        .unwrap_or(0)
+}
+
+/// Whether the corners (in output order) rotate the output by about 90
+/// degrees, so that its width runs up or down the source image.
+fn is_sideways(corners: &[Point<f64>]) -> bool {
+    let (top_left, top_right) = (corners[0], corners[1]);
+    (top_right.y - top_left.y).abs() > (top_right.x - top_left.x).abs()
+}
+
+/// Width and height for `OutputSize::MaxEdge`, from corners in output order.
+fn max_edge_size(corners: &[Point<f64>]) -> (f32, f32) {
+    let length = |a: Point<f64>, b: Point<f64>| (b.x - a.x).hypot(b.y - a.y);
+    let [top_left, top_right, bottom_right, bottom_left] = [0, 1, 2, 3].map(|i| corners[i]);
+    let width = length(top_left, top_right).max(length(bottom_left, bottom_right));
+    let height = length(top_left, bottom_left).max(length(top_right, bottom_right));
+    (
+        width.round().max(1.0) as f32,
+        height.round().max(1.0) as f32,
+    )
+}
+
+// How far (in pixels) a quad's diagonals' midpoints can be apart for it to
+// count as a parallelogram.
+const PARALLELOGRAM_TOLERANCE: f64 = 1e-6;
+
+fn is_parallelogram(corners: &[Point<f64>]) -> bool {
+    let (a, b, c, d) = (corners[0], corners[1], corners[2], corners[3]);
+    (a.x + c.x - b.x - d.x).hypot(a.y + c.y - b.y - d.y) < PARALLELOGRAM_TOLERANCE
+}
+
+fn quad_area(corners: &[Point<f64>]) -> f64 {
+    let twice_area: f64 = (0..corners.len())
+        .map(|i| {
+            let (a, b) = (corners[i], corners[(i + 1) % corners.len()]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum();
+    twice_area.abs() / 2.0
+}
+
+// Upper limit on the size of a supersampled render, beyond which
+// `RenderQuality::High` takes fewer samples per pixel.
+const MAX_SUPERSAMPLED_PIXELS: u64 = 64_000_000;
+// Rows warped between checks for cancellation.
+const WARP_BAND_HEIGHT: u32 = 256;
+
+/// Where a quad's pixels come from and go to when it's squared.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarpGeometry {
+    pub output_width: u32,
+    pub output_height: u32,
+    /// Maps source image pixels to output pixels (row-major, homogeneous).
+    pub matrix: Matrix,
+    /// Maps output pixels back to source image pixels.
+    pub inverse: Matrix,
+    pub quality: WarpQuality,
+    /// The part of the source the warp reads from: x, y, width, height.
+    #[serde(skip)]
+    crop: (u32, u32, u32, u32),
+}
+
+/// Beyond this much stretching, part of the output is blurry enough that the
+/// photo is worth retaking more squarely.
+pub const UPSCALE_WARNING_FACTOR: f64 = 2.0;
+// Points along each side of the output at which the stretching is measured.
+const QUALITY_SAMPLES: u32 = 9;
+
+/// How well the source supports the output, so that the UI can advise
+/// retaking a photo shot at too steep an angle.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WarpQuality {
+    /// How far (in source pixels) each of the output's corners, in output
+    /// order, lands from the control point it should: round-off in the
+    /// projection, which should be well under a pixel.
+    pub corner_errors: [f64; 4],
+    /// The least area of the source (in pixels) that any output pixel is
+    /// sampled from; under 1, the output is upscaled there.
+    pub min_sampling_density: f64,
+    /// How many output pixels a source pixel is stretched across at most, in
+    /// any direction.
+    pub max_upscale: f64,
+    /// Whether `max_upscale` is over `UPSCALE_WARNING_FACTOR`.
+    pub upscale_warning: bool,
+}
+
+impl WarpQuality {
+    fn measure(
+        output_to_source: &Matrix,
+        corners: &[Point<f64>],
+        (width, height): (u32, u32),
+    ) -> Self {
+        let m = output_to_source.map(|v| v as f64);
+        let (width, height) = (width as f64, height as f64);
+        let mut corner_errors = [0.0; 4];
+        for ((error, corner), output) in corner_errors.iter_mut().zip(corners).zip([
+            (0.0, 0.0),
+            (width, 0.0),
+            (width, height),
+            (0.0, height),
+        ]) {
+            let (x, y) = matrix::transform(output_to_source, output);
+            *error = (x - corner.x).hypot(y - corner.y);
+        }
+        let (mut min_sampling_density, mut max_upscale) = (f64::INFINITY, 0.0_f64);
+        let steps = (QUALITY_SAMPLES - 1) as f64;
+        for i in 0..QUALITY_SAMPLES {
+            for j in 0..QUALITY_SAMPLES {
+                let (x, y) = (width * i as f64 / steps, height * j as f64 / steps);
+                let w = m[6] * x + m[7] * y + m[8];
+                let (sx, sy) = matrix::transform(output_to_source, (x, y));
+                // The projection's Jacobian there.
+                let (a, b) = ((m[0] - sx * m[6]) / w, (m[1] - sx * m[7]) / w);
+                let (c, d) = ((m[3] - sy * m[6]) / w, (m[4] - sy * m[7]) / w);
+                min_sampling_density = min_sampling_density.min((a * d - b * c).abs());
+                // Its smaller singular value is the least distance in the
+                // source covered by a step of one output pixel.
+                let (p, q, r) = (a * a + c * c, a * b + c * d, b * b + d * d);
+                let smallest = ((p + r) / 2.0 - (((p - r) / 2.0).powi(2) + q * q).sqrt())
+                    .max(0.0)
+                    .sqrt();
+                max_upscale = max_upscale.max(1.0 / smallest);
+            }
+        }
+        WarpQuality {
+            corner_errors,
+            min_sampling_density,
+            max_upscale,
+            upscale_warning: max_upscale > UPSCALE_WARNING_FACTOR,
+        }
+    }
+}
+
+/// Which way `WarpGeometry::map_point` goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MapDirection {
+    SourceToOutput,
+    OutputToSource,
+}
+
+impl WarpGeometry {
+    /// Maps a position between the source image and the output. None for
+    /// points on the far side of the projection's horizon, which have no image.
+    pub fn map_point(&self, point: (f64, f64), direction: MapDirection) -> Option<(f64, f64)> {
+        let m = match direction {
+            MapDirection::SourceToOutput => &self.matrix,
+            MapDirection::OutputToSource => &self.inverse,
+        };
+        let w = m[6] as f64 * point.0 + m[7] as f64 * point.1 + m[8] as f64;
+        if w <= 0.0 {
+            return None;
+        }
+        let (x, y) = matrix::transform(m, point);
+        (x.is_finite() && y.is_finite()).then_some((x, y))
+    }
+}
+
+/// The matrix mapping pixels of a `width` x `height` rectangle onto the quad
+/// with the given corners (in output order), which lie within the crop
+/// (x, y, width, height) that the projection is worked out in.
+fn rectangle_to_quad(
+    corners: &[Point<f64>],
+    (crop_x, crop_y, crop_width, crop_height): (f64, f64, f32, f32),
+    (width, height): (f32, f32),
+) -> Option<Matrix> {
+    let scaled_hull_vec: Vec<(f32, f32)> = corners
+        .iter()
+        .map(|p| -> (f32, f32) {
+            (
+                ((p.x - crop_x) as f32) / crop_width,
+                ((p.y - crop_y) as f32) / crop_height,
+            )
+        })
+        .collect();
+    let unit_to_scaled = scaled_control_points_to_matrix(&scaled_hull_vec)?;
+    // Rectangle pixels -> the unit square -> the quad in the crop -> source
+    // pixels.
+    Some(
+        [
+            matrix::translate(crop_x as f32, crop_y as f32),
+            matrix::scale(crop_width, crop_height),
+            unit_to_scaled,
+            matrix::scale(1.0 / width, 1.0 / height),
+        ]
+        .iter()
+        .fold(matrix::scale(1.0, 1.0), |product, m| {
+            matrix::multiply(&product, m)
+        }),
+    )
+}
+
+/// Works out the output size and projection for squaring the quad with the
+/// given corners, in output order (see `convex_quad`), from an image of the
+/// given size.
+pub fn warp_geometry(
+    (width, height): (u32, u32),
+    corners: &[Point<f64>],
+    options: &ProcessingOptions,
+) -> Result<WarpGeometry, Error> {
+    let (image_width, image_height) = (width as f64, height as f64);
+    if let Some(p) = corners
+        .iter()
+        .find(|p| p.x < 0.0 || p.y < 0.0 || p.x > image_width || p.y > image_height)
+    {
+        return Err(Error::InvalidInput(format!(
+            "Control point ({}, {}) is outside the {}x{} image",
+            p.x, p.y, width, height
+        )));
+    }
+    // Both in JavaScript and these Rust image packages, (0, 0) = top-left corner
+    // and increasing y goes *down* the page.
+    let (mut min_x, mut max_x) = (image_width, 0.0_f64);
+    let (mut min_y, mut max_y) = (image_height, 0.0_f64);
+    for &Point { x, y } in corners {
+        min_x = min_x.min(x);
+        max_x = max_x.max(x);
+        min_y = min_y.min(y);
+        max_y = max_y.max(y);
+    }
+    // The output is as big as the quad's bounding box; the crop is that box
+    // widened out to whole pixels.
+    let (bounding_width, bounding_height) = (
+        (max_x - min_x).round().max(1.0) as f32,
+        (max_y - min_y).round().max(1.0) as f32,
+    );
+    let (crop_x, crop_y) = (min_x.floor(), min_y.floor());
+    let new_width = (max_x.ceil() - crop_x) as f32;
+    let new_height = (max_y.ceil() - crop_y) as f32;
+    let (base_width, base_height) = match options.output_size {
+        // A parallelogram has no perspective to undo, so its edges are
+        // already true to size, where the bounding box would stretch it.
+        OutputSize::BoundingBox if is_parallelogram(corners) => max_edge_size(corners),
+        OutputSize::BoundingBox if is_sideways(corners) => (bounding_height, bounding_width),
+        OutputSize::BoundingBox => (bounding_width, bounding_height),
+        OutputSize::MaxEdge => max_edge_size(corners),
+    };
+    let (output_width, output_height) = if options.preserve_aspect_ratio {
+        let float_corners: Vec<(f32, f32)> =
+            corners.iter().map(|p| (p.x as f32, p.y as f32)).collect();
+        let center = (width as f32 / 2.0, height as f32 / 2.0);
+        // Roughly a 35mm-equivalent lens, typical of phone cameras.
+        let fallback_focal_length = std::cmp::max(width, height) as f32;
+        let aspect = aspect::estimate_aspect_ratio(&float_corners, center, fallback_focal_length)
+            .ok_or_else(|| {
+            Error::Squaring(ImageSquaringError {
+                message: String::from("Unable to estimate aspect ratio"),
+            })
+        })?;
+        // Keep roughly the same number of pixels as `output_size` asks for.
+        let area = base_width * base_height;
+        (
+            (area * aspect).sqrt().round(),
+            (area / aspect).sqrt().round(),
+        )
+    } else {
+        (base_width, base_height)
+    };
+    let invalid_projection = || {
+        Error::Squaring(ImageSquaringError {
+            message: String::from("Control points don't define a valid projection"),
+        })
+    };
+    let inverse = rectangle_to_quad(
+        corners,
+        (crop_x, crop_y, new_width, new_height),
+        (output_width, output_height),
+    )
+    .ok_or_else(invalid_projection)?;
+    let matrix = matrix::invert(&inverse).ok_or_else(invalid_projection)?;
+    let (output_width, output_height) = (output_width as u32, output_height as u32);
+    Ok(WarpGeometry {
+        output_width,
+        output_height,
+        quality: WarpQuality::measure(&inverse, corners, (output_width, output_height)),
+        matrix,
+        inverse,
+        crop: (
+            crop_x as u32,
+            crop_y as u32,
+            new_width as u32,
+            new_height as u32,
+        ),
+    })
+}
+
+/// Warps an RGBA `source` into a `width` x `height` image, given the mapping
+/// from output pixels to source pixels. Bands of rows are warped in
+/// parallel, each straight into its part of the output, with `cancel` checked
+/// between bands so that a cancelled job stops promptly.
+pub(crate) fn warp_bands<P, F>(
+    source: &Image<P>,
+    output_to_source: &F,
+    interpolation: InterpolationMode,
+    fill: Fill,
+    (width, height): (u32, u32),
+    cancel: &CancellationToken,
+) -> Result<Image<P>, Error>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync + Into<f32> + Clamp<f32>,
+    F: Fn(f32, f32) -> (f32, f32) + Sync,
+{
+    let max: f32 = <P::Subpixel as Primitive>::DEFAULT_MAX_VALUE.into();
+    let channel = |value: f32| <P::Subpixel as Clamp<f32>>::clamp(value);
+    let default = match fill {
+        Fill::Color([r, g, b]) => {
+            let scale = |c: u8| channel(c as f32 * max / 255.0);
+            *P::from_slice(&[scale(r), scale(g), scale(b), channel(max)])
+        }
+        Fill::Transparent | Fill::EdgeClamp => *P::from_slice(&[channel(0.0); 4]),
+    };
+    // Each interpolation mode reads a neighborhood around the sample point,
+    // and gives the default pixel unless all of it lies inside the source.
+    let (margin_low, margin_high) = match interpolation {
+        InterpolationMode::Nearest => (0.0, 1.0),
+        InterpolationMode::Bilinear => (0.0, 1.001),
+        InterpolationMode::Bicubic => (1.0, 3.001),
+    };
+    let (source_width, source_height) = (source.width() as f32, source.height() as f32);
+    let clamp_x = |x: f32| x.min(source_width - margin_high).max(margin_low);
+    let clamp_y = |y: f32| y.min(source_height - margin_high).max(margin_low);
+    let row_length = width as usize * P::CHANNEL_COUNT as usize;
+    let mut squared = Image::<P>::new(width, height);
+    squared
+        .par_chunks_mut(row_length * WARP_BAND_HEIGHT as usize)
+        .enumerate()
+        .try_for_each(|(index, chunk)| {
+            cancel.check()?;
+            let band_top = (index as u32 * WARP_BAND_HEIGHT) as f32;
+            let mapping = |x: f32, y: f32| {
+                let (sx, sy) = output_to_source(x, y + band_top);
+                // Pixels extend half a pixel past their centers, so samples
+                // that close to the edge of the source are still inside it.
+                let inside = (-0.5..=source_width - 0.5).contains(&sx)
+                    && (-0.5..=source_height - 0.5).contains(&sy);
+                if inside || fill == Fill::EdgeClamp {
+                    (clamp_x(sx), clamp_y(sy))
+                } else {
+                    (sx, sy)
+                }
+            };
+            let mut band = Image::<P>::new(width, (chunk.len() / row_length) as u32);
+            geometric_transformations::warp_into_with(
+                source,
+                mapping,
+                interpolation.into(),
+                default,
+                &mut band,
+            );
+            chunk.copy_from_slice(&band);
+            Ok::<(), Error>(())
+        })?;
+    Ok(squared)
+}
+
+/// Averages each `factor` x `factor` block of `image` into one pixel.
+fn box_downsample<P>(image: &Image<P>, factor: u32) -> Image<P>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync + Into<f32> + Clamp<f32>,
+{
+    let (width, height) = (image.width() / factor, image.height() / factor);
+    let channels = P::CHANNEL_COUNT as usize;
+    let block_area = (factor * factor) as f32;
+    let mut downsampled = Image::<P>::new(width, height);
+    downsampled
+        .par_chunks_mut(width as usize * channels)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let mut sums = vec![0.0f32; channels];
+            for (x, pixel) in row.chunks_mut(channels).enumerate() {
+                sums.fill(0.0);
+                for dy in 0..factor {
+                    for dx in 0..factor {
+                        let source =
+                            image.get_pixel(x as u32 * factor + dx, y as u32 * factor + dy);
+                        for (sum, &value) in sums.iter_mut().zip(source.channels()) {
+                            *sum += value.into();
+                        }
+                    }
+                }
+                for (value, sum) in pixel.iter_mut().zip(&sums) {
+                    *value = <P::Subpixel as Clamp<f32>>::clamp((sum / block_area).round());
+                }
+            }
+        });
+    downsampled
+}
+
+/// Applies a projective matrix to a point.
+pub(crate) fn project(m: &Matrix, x: f32, y: f32) -> (f32, f32) {
+    let w = m[6] * x + m[7] * y + m[8];
+    (
+        (m[0] * x + m[1] * y + m[2]) / w,
+        (m[3] * x + m[4] * y + m[5]) / w,
+    )
+}
+
+/// Warps RGBA `source` at `factor` times the output resolution and averages
+/// the result down to `size`; with a factor of 1 this is just `warp_bands`.
+pub(crate) fn warp_supersampled<P, F>(
+    source: &Image<P>,
+    output_to_source: &F,
+    interpolation: InterpolationMode,
+    fill: Fill,
+    size: (u32, u32),
+    factor: u32,
+    cancel: &CancellationToken,
+) -> Result<Image<P>, Error>
+where
+    P: Pixel + Send + Sync,
+    P::Subpixel: Send + Sync + Into<f32> + Clamp<f32>,
+    F: Fn(f32, f32) -> (f32, f32) + Sync,
+{
+    if factor == 1 {
+        return warp_bands(source, output_to_source, interpolation, fill, size, cancel);
+    }
+    // Supersample X lies at output coordinate (X + 0.5) / f - 0.5, as in
+    // `supersampled_matrix`.
+    let f = factor as f32;
+    let supersampled = warp_bands(
+        source,
+        &|x: f32, y: f32| output_to_source((x + 0.5) / f - 0.5, (y + 0.5) / f - 0.5),
+        interpolation,
+        fill,
+        (size.0 * factor, size.1 * factor),
+        cancel,
+    )?;
+    cancel.check()?;
+    Ok(box_downsample(&supersampled, factor))
+}
+
+/// Adapts an output-to-source matrix to an output `factor` times larger,
+/// with each output pixel's samples spread evenly across it.
+fn supersampled_matrix(output_to_source: &Matrix, factor: u32) -> Matrix {
+    let f = factor as f32;
+    // Supersample X lies at output coordinate (X + 0.5) / f - 0.5.
+    let offset = 0.5 / f - 0.5;
+    matrix::multiply(
+        output_to_source,
+        &matrix::multiply(
+            &matrix::translate(offset, offset),
+            &matrix::scale(1.0 / f, 1.0 / f),
+        ),
+    )
+}
+
+/// Reduces `factor` until the supersampled output stays within
+/// `MAX_SUPERSAMPLED_PIXELS`.
+pub(crate) fn supersampling_factor(factor: u32, (width, height): (u32, u32)) -> u32 {
+    let pixels = width as u64 * height as u64;
+    (1..=factor)
+        .rev()
+        .find(|f| pixels * (f * f) as u64 <= MAX_SUPERSAMPLED_PIXELS)
+        .unwrap_or(1)
+}
+
+pub(crate) fn has_transparency(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|p| p[3] < u8::MAX),
+        DynamicImage::ImageRgba16(rgba) => rgba.pixels().any(|p| p[3] < u16::MAX),
+        _ => image.color().has_alpha(),
+    }
+}
+
+/// Converts the squared (RGBA) image to the source's color type where
+/// possible: without alpha unless the source had it or `keep_alpha` is set
+/// (flattening whatever remains transparent onto `background`), and grayscale
+/// if the source was or `grayscale` is set. Bit depth is whatever the squared
+/// image has.
+pub(crate) fn match_color_type(
+    squared: DynamicImage,
+    source: ColorType,
+    keep_alpha: bool,
+    grayscale: bool,
+    background: [u8; 3],
+) -> DynamicImage {
+    let keep_alpha = keep_alpha || source.has_alpha();
+    let squared = if keep_alpha {
+        squared
+    } else {
+        encode::remove_alpha(squared, background)
+    };
+    if source.has_color() && !grayscale {
+        return squared;
+    }
+    let sixteen_bit = squared.color().bytes_per_pixel() > squared.color().channel_count();
+    match (keep_alpha, sixteen_bit) {
+        (false, false) => DynamicImage::ImageLuma8(squared.to_luma8()),
+        (false, true) => DynamicImage::ImageLuma16(squared.to_luma16()),
+        (true, false) => DynamicImage::ImageLumaA8(squared.to_luma_alpha8()),
+        (true, true) => DynamicImage::ImageLumaA16(squared.to_luma_alpha16()),
+    }
+}
+
+/// Samples the quad described by `geometry` out of `image`, giving an RGBA
+/// image with the source's bit depth (8 or 16 bits).
+fn warp_quad(
+    image: &DynamicImage,
+    geometry: &WarpGeometry,
+    options: &ProcessingOptions,
+    cancel: &CancellationToken,
+) -> Result<DynamicImage, Error> {
+    let flat_size = (geometry.output_width, geometry.output_height);
+    let curved = match options.page_model {
+        PageModel::Flat => None,
+        PageModel::Curved => Some(dewarp::CurvedPage::fit(image, &geometry.inverse, flat_size)),
+    };
+    let lens = options.lens_distortion.filter(|lens| !lens.is_identity());
+    // A curved page's edges can bow out past the quad, as can a straight
+    // edge through a distorting lens, so they read from the whole source.
+    let (crop_x, crop_y, crop_width, crop_height) = if curved.is_some() || lens.is_some() {
+        (0, 0, image.width(), image.height())
+    } else {
+        geometry.crop
+    };
+    let cropped = image.crop_imm(crop_x, crop_y, crop_width, crop_height);
+    // The warps below work within the crop rather than the whole source.
+    let output_to_crop = matrix::multiply(
+        &matrix::translate(-(crop_x as f32), -(crop_y as f32)),
+        &geometry.inverse,
+    );
+    let mapping = |x: f32, y: f32| {
+        let (x, y) = match &curved {
+            Some(page) => page.to_flat(x, y),
+            None => (x, y),
+        };
+        let (x, y) = project(&output_to_crop, x, y);
+        match &lens {
+            Some(lens) => {
+                let (x, y) = lens.distort((x as f64, y as f64), image.dimensions());
+                (x as f32, y as f32)
+            }
+            None => (x, y),
+        }
+    };
+    let size = match &curved {
+        Some(page) => (page.output_width(), geometry.output_height),
+        None => flat_size,
+    };
+    let interpolation = match options.render_quality {
+        RenderQuality::Draft => InterpolationMode::Nearest,
+        _ => options.interpolation,
+    };
+    let factor = supersampling_factor(options.render_quality.supersampling(), size);
+    cancel.check()?;
+    let sixteen_bit = image.color().bytes_per_pixel() > image.color().channel_count();
+    Ok(if sixteen_bit {
+        DynamicImage::ImageRgba16(warp_supersampled(
+            &cropped.to_rgba16(),
+            &mapping,
+            interpolation,
+            options.fill,
+            size,
+            factor,
+            cancel,
+        )?)
+    } else {
+        let source = cropped.to_rgba8();
+        let on_gpu = if options.use_gpu && curved.is_none() && lens.is_none() {
+            gpu::warp(
+                &source,
+                supersampled_matrix(&output_to_crop, factor),
+                (size.0 * factor, size.1 * factor),
+                interpolation,
+                options.fill,
+            )
+            .map(|squared| box_downsample(&squared, factor))
+        } else {
+            None
+        };
+        DynamicImage::ImageRgba8(match on_gpu {
+            Some(squared) => squared,
+            None => warp_supersampled(
+                &source,
+                &mapping,
+                interpolation,
+                options.fill,
+                size,
+                factor,
+                cancel,
+            )?,
+        })
+    })
+}
+
+// How far (in pixels) the corners may be from forming an upright rectangle
+// for squaring to be a plain crop.
+const CROP_TOLERANCE: f64 = 1.0;
+
+/// The whole-pixel rectangle (x, y, width, height) that the corners, in
+/// output order, already form to within `CROP_TOLERANCE`, if they do and
+/// it's upright (rather than rotated or mirrored).
+fn axis_aligned_crop(
+    corners: &[Point<f64>],
+    (width, height): (u32, u32),
+) -> Option<(u32, u32, u32, u32)> {
+    let [top_left, top_right, bottom_right, bottom_left] = corners else {
+        return None;
+    };
+    let aligned = (top_left.y - top_right.y).abs() <= CROP_TOLERANCE
+        && (bottom_left.y - bottom_right.y).abs() <= CROP_TOLERANCE
+        && (top_left.x - bottom_left.x).abs() <= CROP_TOLERANCE
+        && (top_right.x - bottom_right.x).abs() <= CROP_TOLERANCE;
+    if !aligned || top_left.x >= top_right.x || top_left.y >= bottom_left.y {
+        return None;
+    }
+    let edge = |a: f64, b: f64, limit: u32| ((a + b) / 2.0).round().clamp(0.0, limit as f64) as u32;
+    let (left, right) = (
+        edge(top_left.x, bottom_left.x, width),
+        edge(top_right.x, bottom_right.x, width),
+    );
+    let (top, bottom) = (
+        edge(top_left.y, top_right.y, height),
+        edge(bottom_left.y, bottom_right.y, height),
+    );
+    (right > left && bottom > top).then(|| (left, top, right - left, bottom - top))
+}
+
+/// Warps the quadrilateral with the given corners, in output order (see
+/// `convex_quad`), into an upright rectangle, bailing out with
+/// `Error::Cancelled` if `cancel` is triggered along the way.
+///
+/// The result keeps the source's bit depth, and only has color and alpha
+/// channels if the source did.
+#[tracing::instrument(
+    name = "warp",
+    skip_all,
+    err,
+    fields(width = image.width(), height = image.height(), interpolation = ?options.interpolation)
+)]
+pub fn square_quad(
+    image: &DynamicImage,
+    corners: Vec<Point<f64>>,
+    options: &ProcessingOptions,
+    cancel: &CancellationToken,
+) -> Result<DynamicImage, Error> {
+    check_options(options)?;
+    let lens = options.lens_distortion.filter(|lens| !lens.is_identity());
+    let geometry = match &lens {
+        Some(lens) => {
+            lens.validate()?;
+            if options.page_model == PageModel::Curved {
+                return Err(Error::InvalidInput(String::from(
+                    "Lens distortion can't be corrected on a curved page",
+                )));
+            }
+            undistorted_geometry(image.dimensions(), &corners, lens, options)?
+        }
+        None => warp_geometry(image.dimensions(), &corners, options)?,
+    };
+    let size = (geometry.output_width, geometry.output_height);
+    // An upright rectangle needs no resampling at all, as long as the output
+    // is meant to be the same size (which aspect ratio correction or
+    // `OutputSize::MaxEdge` might not leave it), the page is flat and the
+    // lens needs no correcting.
+    let squared = match axis_aligned_crop(&corners, image.dimensions()) {
+        Some((x, y, width, height))
+            if (width, height) == size
+                && options.page_model == PageModel::Flat
+                && lens.is_none() =>
+        {
+            image.crop_imm(x, y, width, height)
+        }
+        _ => warp_quad(image, &geometry, options, cancel)?,
+    };
+    cancel.check()?;
+    Ok(finish(image, squared, &corners, options))
+}
+
+/// Like `warp_geometry`, but for corners in a photo taken through a lens
+/// with the given distortion: the projection is worked out for where they'd
+/// be without it, and maps between the output and that ideal image.
+fn undistorted_geometry(
+    size: (u32, u32),
+    corners: &[Point<f64>],
+    lens: &lens::LensDistortion,
+    options: &ProcessingOptions,
+) -> Result<WarpGeometry, Error> {
+    let ideal: Vec<Point<f64>> = corners
+        .iter()
+        .map(|p| {
+            let (x, y) = lens.undistort((p.x, p.y), size);
+            Point::new(x, y)
+        })
+        .collect();
+    // Barrel distortion pushes the ideal corners out past the photo's edges,
+    // where `warp_geometry` won't have them, so it works on a canvas padded
+    // evenly all round (keeping the center where it was).
+    let overshoot = |value: f64, limit: u32| (-value).max(value - limit as f64).max(0.0);
+    let pad_x = ideal
+        .iter()
+        .map(|p| overshoot(p.x, size.0))
+        .fold(0.0, f64::max)
+        .ceil();
+    let pad_y = ideal
+        .iter()
+        .map(|p| overshoot(p.y, size.1))
+        .fold(0.0, f64::max)
+        .ceil();
+    let padded: Vec<Point<f64>> = ideal
+        .iter()
+        .map(|p| Point::new(p.x + pad_x, p.y + pad_y))
+        .collect();
+    let padded_size = (size.0 + 2 * pad_x as u32, size.1 + 2 * pad_y as u32);
+    let mut geometry = warp_geometry(padded_size, &padded, options)?;
+    let (pad_x, pad_y) = (pad_x as f32, pad_y as f32);
+    geometry.matrix = matrix::multiply(&geometry.matrix, &matrix::translate(pad_x, pad_y));
+    geometry.inverse = matrix::multiply(&matrix::translate(-pad_x, -pad_y), &geometry.inverse);
+    Ok(geometry)
+}
+
+/// Checks the options that apply after the warp, before any work is done.
+pub(crate) fn check_options(options: &ProcessingOptions) -> Result<(), Error> {
+    if let Some(adjustments) = &options.adjustments {
+        adjustments.validate()?;
+    }
+    options.sharpening.validate()?;
+    if let Some(inversion) = &options.invert {
+        inversion.validate()?;
+    }
+    if let Some(dropout) = &options.dropout {
+        dropout.validate()?;
+    }
+    Ok(())
+}
+
+/// Everything after the warp: color and cleanup per `options`, conversion to
+/// the source's color type, and (for `OutputMode::InPlace`) compositing back
+/// over the source. `corners` outline the part of `image` that was warped.
+pub(crate) fn finish(
+    image: &DynamicImage,
+    squared: DynamicImage,
+    corners: &[Point<f64>],
+    options: &ProcessingOptions,
+) -> DynamicImage {
+    // Inversion, shadow removal, color correction and cleanup work on 8-bit
+    // images.
+    let squared = match &options.invert {
+        Some(inversion) => DynamicImage::ImageRgba8(adjust::invert(squared.to_rgba8(), inversion)),
+        None => squared,
+    };
+    let squared = if options.remove_shadows {
+        DynamicImage::ImageRgba8(cleanup::remove_shadows(squared.to_rgba8()))
+    } else {
+        squared
+    };
+    let squared = if options.auto_color {
+        DynamicImage::ImageRgba8(adjust::auto_color(squared.to_rgba8()))
+    } else {
+        squared
+    };
+    let squared = match &options.adjustments {
+        Some(adjustments) => {
+            DynamicImage::ImageRgba8(adjust::adjust(squared.to_rgba8(), adjustments))
+        }
+        None => squared,
+    };
+    let squared = match &options.dropout {
+        Some(dropout) => {
+            DynamicImage::ImageRgba8(adjust::drop_out_color(squared.to_rgba8(), dropout))
+        }
+        None => squared,
+    };
+    // How much the warp shrank the quad, on average along each axis.
+    let scale = (quad_area(corners) / (squared.width() as f64 * squared.height() as f64)).sqrt();
+    let squared = match options.sharpening.parameters(scale) {
+        Some((amount, radius)) => {
+            DynamicImage::ImageRgba8(adjust::unsharp_mask(squared.to_rgba8(), amount, radius))
+        }
+        None => squared,
+    };
+    let squared = match options.cleanup_mode {
+        CleanupMode::None => squared,
+        mode => DynamicImage::ImageRgba8(cleanup::apply(squared.to_rgba8(), mode)),
+    };
+    // Only keep an alpha channel for an opaque source if the fill actually
+    // left some of the output uncovered.
+    let keep_alpha = options.fill == Fill::Transparent && has_transparency(&squared);
+    let squared = match_color_type(
+        squared,
+        image.color(),
+        keep_alpha,
+        options.cleanup_mode == CleanupMode::Document,
+        options.background,
+    );
+    match options.output_mode {
+        OutputMode::Standalone => squared,
+        OutputMode::InPlace => composite_in_place(image, &squared, corners, options.background),
+    }
+}
+
+/// Pastes `squared` back over `image`, centered on the quad's bounding box,
+/// with any transparency in it showing the original underneath.
+fn composite_in_place(
+    image: &DynamicImage,
+    squared: &DynamicImage,
+    corners: &[Point<f64>],
+    background: [u8; 3],
+) -> DynamicImage {
+    let (mut min_x, mut max_x) = (f64::INFINITY, f64::NEG_INFINITY);
+    let (mut min_y, mut max_y) = (f64::INFINITY, f64::NEG_INFINITY);
+    for p in corners {
+        min_x = min_x.min(p.x);
+        max_x = max_x.max(p.x);
+        min_y = min_y.min(p.y);
+        max_y = max_y.max(p.y);
+    }
+    let x = ((min_x + max_x - squared.width() as f64) / 2.0).round() as i64;
+    let y = ((min_y + max_y - squared.height() as f64) / 2.0).round() as i64;
+    let sixteen_bit = image.color().bytes_per_pixel() > image.color().channel_count();
+    let canvas = if sixteen_bit {
+        let mut canvas = image.to_rgba16();
+        image::imageops::overlay(&mut canvas, &squared.to_rgba16(), x, y);
+        DynamicImage::ImageRgba16(canvas)
+    } else {
+        let mut canvas = image.to_rgba8();
+        image::imageops::overlay(&mut canvas, &squared.to_rgba8(), x, y);
+        DynamicImage::ImageRgba8(canvas)
+    };
+    match_color_type(canvas, image.color(), false, false, background)
+}
+
+/// Squares the quadrilateral outlined by `control_points` (in any order) into
+/// an upright rectangle, or levels the image by two (see `quad_from_points`).
+pub fn square_image(
+    image: &DynamicImage,
+    control_points: Vec<ControlPoint>,
+    options: &ProcessingOptions,
+) -> Result<DynamicImage, Error> {
+    let quad = quad_from_points(control_points, image.dimensions())?;
+    square_quad(image, quad, options, &CancellationToken::default())
+}
+
+/// The inverse of squaring: warps all of `overlay` into the quad with the
+/// given corners (in output order) of `base`, e.g. to put a flat document or
+/// screenshot into a photographed scene. Uses `options`' interpolation and
+/// render quality; the result has `base`'s size and bit depth, and is in
+/// color if either image is.
+pub fn project_into_quad(
+    base: &DynamicImage,
+    overlay: &DynamicImage,
+    corners: &[Point<f64>],
+    options: &ProcessingOptions,
+    cancel: &CancellationToken,
+) -> Result<DynamicImage, Error> {
+    let (mut min_x, mut max_x) = (f64::INFINITY, f64::NEG_INFINITY);
+    let (mut min_y, mut max_y) = (f64::INFINITY, f64::NEG_INFINITY);
+    for p in corners {
+        min_x = min_x.min(p.x);
+        max_x = max_x.max(p.x);
+        min_y = min_y.min(p.y);
+        max_y = max_y.max(p.y);
+    }
+    let (crop_x, crop_y) = (min_x.floor(), min_y.floor());
+    let crop = (
+        crop_x,
+        crop_y,
+        ((max_x.ceil() - crop_x) as f32).max(1.0),
+        ((max_y.ceil() - crop_y) as f32).max(1.0),
+    );
+    let overlay_size = (overlay.width() as f32, overlay.height() as f32);
+    let base_to_overlay = rectangle_to_quad(corners, crop, overlay_size)
+        .and_then(|overlay_to_base| matrix::invert(&overlay_to_base))
+        .ok_or_else(|| {
+            Error::Squaring(ImageSquaringError {
+                message: String::from("Control points don't define a valid projection"),
+            })
+        })?;
+    // Only the part of the base the quad covers is rendered.
+    let left = crop_x.clamp(0.0, base.width() as f64) as u32;
+    let top = crop_y.clamp(0.0, base.height() as f64) as u32;
+    let right = max_x.ceil().clamp(0.0, base.width() as f64) as u32;
+    let bottom = max_y.ceil().clamp(0.0, base.height() as f64) as u32;
+    if right <= left || bottom <= top {
+        return Ok(base.clone());
+    }
+    let size = (right - left, bottom - top);
+    let region_to_overlay = matrix::multiply(
+        &base_to_overlay,
+        &matrix::translate(left as f32, top as f32),
+    );
+    let mapping = |x: f32, y: f32| project(&region_to_overlay, x, y);
+    let interpolation = match options.render_quality {
+        RenderQuality::Draft => InterpolationMode::Nearest,
+        _ => options.interpolation,
+    };
+    let factor = supersampling_factor(options.render_quality.supersampling(), size);
+    // Past the overlay's edges the base shows through.
+    let fill = Fill::Transparent;
+    let sixteen_bit = base.color().bytes_per_pixel() > base.color().channel_count();
+    let composited = if sixteen_bit {
+        let warped = warp_supersampled(
+            &overlay.to_rgba16(),
+            &mapping,
+            interpolation,
+            fill,
+            size,
+            factor,
+            cancel,
+        )?;
+        let mut canvas = base.to_rgba16();
+        image::imageops::overlay(&mut canvas, &warped, left as i64, top as i64);
+        DynamicImage::ImageRgba16(canvas)
+    } else {
+        let warped = warp_supersampled(
+            &overlay.to_rgba8(),
+            &mapping,
+            interpolation,
+            fill,
+            size,
+            factor,
+            cancel,
+        )?;
+        let mut canvas = base.to_rgba8();
+        image::imageops::overlay(&mut canvas, &warped, left as i64, top as i64);
+        DynamicImage::ImageRgba8(canvas)
+    };
+    // A color overlay keeps a grayscale base in color.
+    let color = match (base.color().has_color(), overlay.color().has_color()) {
+        (false, true) if base.color().has_alpha() => ColorType::Rgba8,
+        (false, true) => ColorType::Rgb8,
+        _ => base.color(),
+    };
+    Ok(match_color_type(
+        composited,
+        color,
+        false,
+        false,
+        options.background,
+    ))
+}
+
+/// Encodes with `options` at the given quality, recording its DPI if set.
+fn encode_at_quality(
+    image: &DynamicImage,
+    options: &ProcessingOptions,
+    quality: u8,
+) -> Result<Vec<u8>, Error> {
+    let bytes = encode::encode(image, options.output_format, quality, options.background)?;
+    match options.dpi {
+        Some(dpi) => metadata::embed_dpi(bytes, options.output_format, dpi),
+        None => Ok(bytes),
+    }
+}
+
+/// Runs `encode` at `options.quality`, or, given `max_file_size_kb`, at the
+/// highest JPEG quality up to that whose output fits, found by binary search.
+/// Lossless formats can't be shrunk, so they fail if they're too big.
+fn encode_within_budget(
+    options: &ProcessingOptions,
+    encode: impl Fn(u8) -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let Some(max_kb) = options.max_file_size_kb else {
+        return encode(options.quality);
+    };
+    let max_bytes = max_kb as usize * 1024;
+    if options.output_format != OutputFormat::Jpeg {
+        let bytes = encode(options.quality)?;
+        if bytes.len() > max_bytes {
+            return Err(Error::InvalidInput(format!(
+                "The {} output is {} KB, over the {max_kb} KB limit, and lossless \
+                 formats can't be compressed further; use JPEG",
+                options.output_format.extension(),
+                bytes.len().div_ceil(1024)
+            )));
+        }
+        return Ok(bytes);
+    }
+    let (mut low, mut high) = (1, options.quality.clamp(1, 100));
+    let mut best = None;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let bytes = encode(quality)?;
+        if bytes.len() <= max_bytes {
+            best = Some(bytes);
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+    best.ok_or_else(|| {
+        Error::InvalidInput(format!(
+            "The output doesn't fit in {max_kb} KB, even at the lowest JPEG quality"
+        ))
+    })
+}
+
+pub fn encode_output(image: &DynamicImage, options: &ProcessingOptions) -> Result<Vec<u8>, Error> {
+    encode_within_budget(options, |quality| {
+        encode_at_quality(image, options, quality)
+    })
+}
+
+/// Like `encode_output`, but also carries over the source's metadata if
+/// `options.copy_metadata` is set, recording how the image was squared.
+pub fn encode_output_with_metadata(
+    image: &DynamicImage,
+    options: &ProcessingOptions,
+    source_exif: Option<&[u8]>,
+    quad: &[Point<f64>],
+) -> Result<Vec<u8>, Error> {
+    if !options.copy_metadata {
+        return encode_output(image, options);
+    }
+    let corners: Vec<String> = quad.iter().map(|p| format!("({},{})", p.x, p.y)).collect();
+    let history = format!(
+        "Squared corners {} to {}x{} with {:?} interpolation",
+        corners.join(" "),
+        image.width(),
+        image.height(),
+        options.interpolation
+    );
+    let exif = metadata::build_exif(source_exif, options.strip_gps, &history)?;
+    // The metadata counts towards `max_file_size_kb` too.
+    encode_within_budget(options, |quality| {
+        metadata::embed_exif(
+            encode_at_quality(image, options, quality)?,
+            options.output_format,
+            image.width(),
+            image.height(),
+            &exif,
+        )
+    })
+}
+
+/// Like `encode_output_with_metadata`, but writes the result to `writer`. If
+/// nothing has to be patched into the finished file (no `dpi`,
+/// `max_file_size_kb` or `copy_metadata`), it's written as the encoder
+/// produces it, without holding the whole file in memory.
+pub fn write_output<W: Write>(
+    image: &DynamicImage,
+    options: &ProcessingOptions,
+    source_exif: Option<&[u8]>,
+    quad: &[Point<f64>],
+    mut writer: W,
+) -> Result<(), Error> {
+    if options.dpi.is_none() && options.max_file_size_kb.is_none() && !options.copy_metadata {
+        encode::encode_to(
+            image,
+            options.output_format,
+            options.quality,
+            options.background,
+            writer,
+        )?;
+    } else {
+        writer.write_all(&encode_output_with_metadata(
+            image,
+            options,
+            source_exif,
+            quad,
+        )?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(coordinates: &[(f64, f64)]) -> Vec<ControlPoint> {
+        coordinates
+            .iter()
+            .map(|&(x, y)| ControlPoint::new(x, y))
+            .collect()
+    }
+
+    fn assert_near((x, y): (f64, f64), (expected_x, expected_y): (f64, f64), tolerance: f64) {
+        assert!(
+            (x - expected_x).abs() < tolerance && (y - expected_y).abs() < tolerance,
+            "({x}, {y}) isn't near ({expected_x}, {expected_y})"
+        );
+    }
+
+    fn assert_corners(quad: &[Point<f64>], expected: &[(f64, f64)]) {
+        assert_eq!(quad.len(), expected.len());
+        for (corner, &expected) in quad.iter().zip(expected) {
+            assert_near((corner.x, corner.y), expected, 1e-9);
+        }
+    }
+
+    #[test]
+    fn convex_quad_orders_corners_from_the_top_left() {
+        let quad = convex_quad(points(&[
+            (100.0, 10.0),
+            (10.0, 110.0),
+            (10.0, 10.0),
+            (110.0, 100.0),
+        ]))
+        .unwrap();
+        assert_corners(
+            &quad,
+            &[(10.0, 10.0), (100.0, 10.0), (110.0, 100.0), (10.0, 110.0)],
+        );
+    }
+
+    #[test]
+    fn convex_quad_follows_labels() {
+        let roles = [
+            CornerRole::BottomRight,
+            CornerRole::BottomLeft,
+            CornerRole::TopLeft,
+            CornerRole::TopRight,
+        ];
+        let control_points = points(&[(0.0, 0.0), (100.0, 0.0), (100.0, 80.0), (0.0, 80.0)])
+            .into_iter()
+            .zip(roles)
+            .map(|(cp, role)| ControlPoint {
+                role: Some(role),
+                ..cp
+            })
+            .collect();
+        let quad = convex_quad(control_points).unwrap();
+        // Upside down.
+        assert_corners(
+            &quad,
+            &[(100.0, 80.0), (0.0, 80.0), (0.0, 0.0), (100.0, 0.0)],
+        );
+    }
+
+    #[test]
+    fn convex_quad_rejects_a_concave_quad() {
+        let error = convex_quad(points(&[
+            (0.0, 0.0),
+            (100.0, 0.0),
+            (30.0, 30.0),
+            (0.0, 100.0),
+        ]))
+        .unwrap_err();
+        assert!(matches!(error, Error::Concave { point: 2 }), "{error:?}");
+    }
+
+    #[test]
+    fn convex_quad_rejects_labels_that_cross() {
+        let roles = [
+            CornerRole::TopLeft,
+            CornerRole::BottomRight,
+            CornerRole::TopRight,
+            CornerRole::BottomLeft,
+        ];
+        let control_points = points(&[(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)])
+            .into_iter()
+            .zip(roles)
+            .map(|(cp, role)| ControlPoint {
+                role: Some(role),
+                ..cp
+            })
+            .collect();
+        let error = convex_quad(control_points).unwrap_err();
+        assert!(matches!(error, Error::SelfIntersecting { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn convex_quad_completes_three_points_into_a_parallelogram() {
+        // The widest angle is at (10, 10), so the missing corner is across
+        // from it.
+        let quad = convex_quad(points(&[(10.0, 10.0), (110.0, 20.0), (20.0, 70.0)])).unwrap();
+        assert_corners(
+            &quad,
+            &[(10.0, 10.0), (110.0, 20.0), (120.0, 80.0), (20.0, 70.0)],
+        );
+    }
+
+    #[test]
+    fn convex_quad_rejects_the_wrong_number_of_points() {
+        let error = convex_quad(points(&[(0.0, 0.0), (100.0, 0.0)])).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{error:?}");
+    }
+
+    #[test]
+    fn quad_from_points_takes_a_level_line_as_the_whole_image() {
+        let quad = quad_from_points(points(&[(20.0, 50.0), (180.0, 50.0)]), (200, 100)).unwrap();
+        assert_corners(
+            &quad,
+            &[(0.0, 0.0), (200.0, 0.0), (200.0, 100.0), (0.0, 100.0)],
+        );
+    }
+
+    #[test]
+    fn quad_from_points_fits_the_rotated_rectangle_in_the_image() {
+        // Tilted about 5.7 degrees, and nearer plumb than level.
+        let quad = quad_from_points(points(&[(100.0, 0.0), (110.0, 100.0)]), (200, 100)).unwrap();
+        let edge = |a: &Point<f64>, b: &Point<f64>| (b.x - a.x).hypot(b.y - a.y);
+        let (width, height) = (edge(&quad[0], &quad[1]), edge(&quad[1], &quad[2]));
+        assert!((width / height - 2.0).abs() < 1e-9, "{quad:?}");
+        assert!(quad
+            .iter()
+            .all(|p| (0.0..=200.0).contains(&p.x) && (0.0..=100.0).contains(&p.y)));
+        // Turned by as much as the line is off plumb.
+        let angle = (quad[1].y - quad[0].y).atan2(quad[1].x - quad[0].x);
+        assert!((angle + 0.1_f64.atan()).abs() < 1e-9, "{angle}");
+    }
+
+    #[test]
+    fn quad_from_points_rejects_points_too_close_together() {
+        let error =
+            quad_from_points(points(&[(20.0, 50.0), (21.0, 50.0)]), (200, 100)).unwrap_err();
+        assert!(matches!(error, Error::Squaring(_)), "{error:?}");
+    }
+
+    #[test]
+    fn warp_geometry_maps_the_corners_onto_the_output() {
+        let quad = convex_quad(points(&[
+            (40.0, 30.0),
+            (260.0, 50.0),
+            (280.0, 190.0),
+            (20.0, 170.0),
+        ]))
+        .unwrap();
+        let geometry = warp_geometry((300, 200), &quad, &ProcessingOptions::default()).unwrap();
+        let (width, height) = (geometry.output_width as f64, geometry.output_height as f64);
+        let output = [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)];
+        for (corner, output) in quad.iter().zip(output) {
+            assert_near(
+                matrix::transform(&geometry.matrix, (corner.x, corner.y)),
+                output,
+                0.05,
+            );
+            assert_near(
+                matrix::transform(&geometry.inverse, output),
+                (corner.x, corner.y),
+                0.05,
+            );
+        }
+        assert!(geometry.quality.corner_errors.iter().all(|&e| e < 0.05));
+    }
+
+    #[test]
+    fn warp_geometry_rejects_corners_outside_the_image() {
+        let quad = convex_quad(points(&[
+            (0.0, 0.0),
+            (120.0, 0.0),
+            (120.0, 80.0),
+            (0.0, 80.0),
+        ]))
+        .unwrap();
+        let error = warp_geometry((100, 100), &quad, &ProcessingOptions::default()).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{error:?}");
+    }
+
+    // Stands in for an encoder whose output shrinks with the quality: a KB
+    // per quality point.
+    fn fake_encode(quality: u8) -> Result<Vec<u8>, Error> {
+        Ok(vec![0; quality as usize * 1024])
+    }
+
+    #[test]
+    fn encode_within_budget_finds_the_highest_quality_that_fits() {
+        let options = ProcessingOptions {
+            output_format: OutputFormat::Jpeg,
+            quality: 90,
+            max_file_size_kb: Some(42),
+            ..ProcessingOptions::default()
+        };
+        let bytes = encode_within_budget(&options, fake_encode).unwrap();
+        assert_eq!(bytes.len(), 42 * 1024);
+    }
+
+    #[test]
+    fn encode_within_budget_keeps_the_quality_when_it_fits() {
+        let options = ProcessingOptions {
+            output_format: OutputFormat::Jpeg,
+            quality: 30,
+            max_file_size_kb: Some(100),
+            ..ProcessingOptions::default()
+        };
+        let bytes = encode_within_budget(&options, fake_encode).unwrap();
+        assert_eq!(bytes.len(), 30 * 1024);
+    }
+
+    #[test]
+    fn encode_within_budget_fails_when_nothing_fits() {
+        let options = ProcessingOptions {
+            output_format: OutputFormat::Jpeg,
+            max_file_size_kb: Some(0),
+            ..ProcessingOptions::default()
+        };
+        let error = encode_within_budget(&options, fake_encode).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{error:?}");
+    }
+
+    #[test]
+    fn encode_within_budget_fails_for_lossless_formats_over_it() {
+        let options = ProcessingOptions {
+            output_format: OutputFormat::Png,
+            quality: 90,
+            max_file_size_kb: Some(50),
+            ..ProcessingOptions::default()
+        };
+        let error = encode_within_budget(&options, fake_encode).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{error:?}");
+    }
+
+    const BLUE: image::Rgb<u8> = image::Rgb([20, 40, 200]);
+    const RED: image::Rgb<u8> = image::Rgb([220, 30, 30]);
+
+    #[test]
+    fn project_into_quad_covers_the_quad_and_nothing_else() {
+        let base = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(200, 150, BLUE));
+        let overlay = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(50, 40, RED));
+        let corners = [
+            Point::new(40.0, 30.0),
+            Point::new(150.0, 40.0),
+            Point::new(140.0, 120.0),
+            Point::new(30.0, 110.0),
+        ];
+        let projected = project_into_quad(
+            &base,
+            &overlay,
+            &corners,
+            &ProcessingOptions::default(),
+            &CancellationToken::default(),
+        )
+        .unwrap();
+        assert_eq!(projected.dimensions(), base.dimensions());
+        assert_eq!(projected.color(), ColorType::Rgb8);
+        let projected = projected.to_rgb8();
+        // Just inside each corner, and the middle.
+        for (x, y) in [(45, 35), (144, 45), (135, 114), (36, 105), (90, 75)] {
+            assert_eq!(*projected.get_pixel(x, y), RED, "({x}, {y})");
+        }
+        // Just outside each edge, and far from the quad.
+        for (x, y) in [(95, 31), (148, 80), (85, 119), (32, 70), (5, 5), (195, 145)] {
+            assert_eq!(*projected.get_pixel(x, y), BLUE, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn composite_in_place_centers_on_the_quad_showing_the_original_through() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(100, 80, BLUE));
+        // Transparent down its left column, as a warp's fill might leave it.
+        let squared = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(30, 20, |x, _| {
+            if x == 0 {
+                image::Rgba([0, 0, 0, 0])
+            } else {
+                RED.to_rgba()
+            }
+        }));
+        let corners = [
+            Point::new(20.0, 10.0),
+            Point::new(60.0, 12.0),
+            Point::new(58.0, 50.0),
+            Point::new(22.0, 48.0),
+        ];
+        let composited = composite_in_place(&image, &squared, &corners, [255, 255, 255]);
+        assert_eq!(composited.color(), ColorType::Rgb8);
+        let composited = composited.to_rgb8();
+        // The bounding box is centered on (40, 30), so the 30x20 paste
+        // spans (25, 20) to (54, 39).
+        for (x, y) in [(26, 20), (54, 20), (54, 39), (26, 39), (40, 30)] {
+            assert_eq!(*composited.get_pixel(x, y), RED, "({x}, {y})");
+        }
+        for (x, y) in [(25, 30), (24, 30), (55, 30), (40, 19), (40, 40), (5, 5)] {
+            assert_eq!(*composited.get_pixel(x, y), BLUE, "({x}, {y})");
+        }
+    }
+}
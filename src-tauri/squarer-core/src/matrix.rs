@@ -0,0 +1,218 @@
+//! Row-major 3x3 matrices acting on homogeneous 2D points, as taken by
+//! `Projection::from_matrix`.
+
+pub type Matrix = [f32; 9];
+
+/// A point and the point it should map to.
+pub type PointPair = ((f64, f64), (f64, f64));
+
+pub fn scale(sx: f32, sy: f32) -> Matrix {
+    [sx, 0.0, 0.0, 0.0, sy, 0.0, 0.0, 0.0, 1.0]
+}
+
+pub fn translate(tx: f32, ty: f32) -> Matrix {
+    [1.0, 0.0, tx, 0.0, 1.0, ty, 0.0, 0.0, 1.0]
+}
+
+/// `a * b`, i.e. the transform applying `b` first, then `a`.
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [0.0; 9];
+    for row in 0..3 {
+        for column in 0..3 {
+            product[row * 3 + column] = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + column]).sum();
+        }
+    }
+    product
+}
+
+/// The inverse, normalized so that its last entry is 1 where possible, or
+/// None if the matrix is singular.
+pub fn invert(m: &Matrix) -> Option<Matrix> {
+    let m: [f64; 9] = m.map(|v| v as f64);
+    let cofactors = [
+        m[4] * m[8] - m[5] * m[7],
+        m[2] * m[7] - m[1] * m[8],
+        m[1] * m[5] - m[2] * m[4],
+        m[5] * m[6] - m[3] * m[8],
+        m[0] * m[8] - m[2] * m[6],
+        m[2] * m[3] - m[0] * m[5],
+        m[3] * m[7] - m[4] * m[6],
+        m[1] * m[6] - m[0] * m[7],
+        m[0] * m[4] - m[1] * m[3],
+    ];
+    let determinant = m[0] * cofactors[0] + m[1] * cofactors[3] + m[2] * cofactors[6];
+    if determinant.abs() < f64::EPSILON {
+        return None;
+    }
+    // A homography is only defined up to scale.
+    let normalizer = if cofactors[8].abs() > f64::EPSILON {
+        cofactors[8]
+    } else {
+        determinant
+    };
+    let inverse = cofactors.map(|v| (v / normalizer) as f32);
+    inverse.iter().all(|v| v.is_finite()).then_some(inverse)
+}
+
+/// Applies the matrix to a point.
+pub fn transform(m: &Matrix, (x, y): (f64, f64)) -> (f64, f64) {
+    let m = m.map(|v| v as f64);
+    let w = m[6] * x + m[7] * y + m[8];
+    (
+        (m[0] * x + m[1] * y + m[2]) / w,
+        (m[3] * x + m[4] * y + m[5]) / w,
+    )
+}
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting, or None
+/// if `a` is (nearly) singular.
+pub(crate) fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for column in 0..N {
+        let pivot =
+            (column..N).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let (pivot_row, pivot_b) = (a[column], b[column]);
+        for (row, b) in a.iter_mut().zip(&mut b).skip(column + 1) {
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot_value) in row.iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+            *b -= factor * pivot_b;
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Moves the points' centroid to the origin and scales them to an average
+/// distance of sqrt(2) from it, which keeps the DLT well conditioned.
+fn normalizing_transform(points: impl Iterator<Item = (f64, f64)> + Clone) -> [f64; 9] {
+    let count = points.clone().count().max(1) as f64;
+    let (cx, cy) = points
+        .clone()
+        .fold((0.0, 0.0), |(sx, sy), (x, y)| (sx + x, sy + y));
+    let (cx, cy) = (cx / count, cy / count);
+    let spread = points.map(|(x, y)| (x - cx).hypot(y - cy)).sum::<f64>() / count;
+    let s = if spread > f64::EPSILON {
+        std::f64::consts::SQRT_2 / spread
+    } else {
+        1.0
+    };
+    [s, 0.0, -s * cx, 0.0, s, -s * cy, 0.0, 0.0, 1.0]
+}
+
+fn multiply_f64(a: &[f64; 9], b: &[f64; 9]) -> [f64; 9] {
+    let mut product = [0.0; 9];
+    for row in 0..3 {
+        for column in 0..3 {
+            product[row * 3 + column] = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + column]).sum();
+        }
+    }
+    product
+}
+
+/// The homography best mapping each pair's first point onto its second, in
+/// the least-squares sense: the direct linear transform (with the last entry
+/// fixed at 1) on normalized coordinates. Needs at least four pairs, no three
+/// of them on a line; with exactly four the fit is exact.
+pub fn fit_homography(pairs: &[PointPair]) -> Option<Matrix> {
+    if pairs.len() < 4 {
+        return None;
+    }
+    let from = normalizing_transform(pairs.iter().map(|p| p.0));
+    let to = normalizing_transform(pairs.iter().map(|p| p.1));
+    let apply = |t: &[f64; 9], (x, y): (f64, f64)| (t[0] * x + t[2], t[4] * y + t[5]);
+    let mut normal = [[0.0; 8]; 8];
+    let mut rhs = [0.0; 8];
+    for &(source, target) in pairs {
+        let (x, y) = apply(&from, source);
+        let (u, v) = apply(&to, target);
+        let rows = [
+            ([x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], u),
+            ([0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y], v),
+        ];
+        for (row, value) in rows {
+            for ((normal_row, rhs), &r) in normal.iter_mut().zip(&mut rhs).zip(&row) {
+                *rhs += r * value;
+                for (n, &r2) in normal_row.iter_mut().zip(&row) {
+                    *n += r * r2;
+                }
+            }
+        }
+    }
+    let h = solve(normal, rhs)?;
+    let normalized = [h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0];
+    // Undo the normalization: to^-1 * H * from.
+    let (s, tx, ty) = (to[0], to[2], to[5]);
+    let to_inverse = [1.0 / s, 0.0, -tx / s, 0.0, 1.0 / s, -ty / s, 0.0, 0.0, 1.0];
+    let homography = multiply_f64(&to_inverse, &multiply_f64(&normalized, &from));
+    let last = homography[8];
+    if last.abs() < f64::EPSILON {
+        return None;
+    }
+    let homography = homography.map(|v| (v / last) as f32);
+    homography
+        .iter()
+        .all(|v| v.is_finite())
+        .then_some(homography)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near((x, y): (f64, f64), (expected_x, expected_y): (f64, f64)) {
+        assert!(
+            (x - expected_x).abs() < 1e-2 && (y - expected_y).abs() < 1e-2,
+            "({x}, {y}) isn't near ({expected_x}, {expected_y})"
+        );
+    }
+
+    const PAIRS: [PointPair; 4] = [
+        ((0.0, 0.0), (40.0, 30.0)),
+        ((100.0, 0.0), (260.0, 50.0)),
+        ((100.0, 100.0), (280.0, 190.0)),
+        ((0.0, 100.0), (20.0, 170.0)),
+    ];
+
+    #[test]
+    fn fit_homography_maps_four_points_exactly() {
+        let h = fit_homography(&PAIRS).unwrap();
+        for (from, to) in PAIRS {
+            assert_near(transform(&h, from), to);
+        }
+    }
+
+    #[test]
+    fn invert_undoes_the_fit() {
+        let h = fit_homography(&PAIRS).unwrap();
+        let inverse = invert(&h).unwrap();
+        for (from, to) in PAIRS {
+            assert_near(transform(&inverse, to), from);
+        }
+        let identity = multiply(&h, &inverse);
+        let scale = identity[8];
+        for (i, value) in identity.iter().enumerate() {
+            let expected = if i % 4 == 0 { scale } else { 0.0 };
+            assert!((value - expected).abs() < 1e-4, "{identity:?}");
+        }
+    }
+
+    #[test]
+    fn fit_homography_needs_four_points() {
+        assert!(fit_homography(&PAIRS[..3]).is_none());
+    }
+
+    #[test]
+    fn invert_rejects_a_singular_matrix() {
+        assert!(invert(&scale(0.0, 1.0)).is_none());
+    }
+}
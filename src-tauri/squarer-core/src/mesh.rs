@@ -0,0 +1,345 @@
+use image::DynamicImage;
+use imageproc::point::Point;
+use rayon::prelude::*;
+
+use crate::cancel::CancellationToken;
+use crate::matrix::PointPair;
+use crate::{
+    check_options, finish, supersampling_factor, warp_supersampled, ControlPoint, Error,
+    ImageSquaringError, InterpolationMode, ProcessingOptions, RenderQuality,
+};
+
+// More points than this make the spline's dense system slow to solve.
+const MAX_MESH_POINTS: usize = 1000;
+// The spline is evaluated every this many output pixels, and interpolated in
+// between; it's smooth enough that this is indistinguishable from evaluating
+// it everywhere.
+const GRID_STEP: u32 = 8;
+
+/// A thin-plate spline: the smoothest mapping that takes each of a set of
+/// points exactly onto its partner, bending like a thin sheet of metal would.
+struct ThinPlateSpline {
+    // Normalized positions of the points the spline is anchored at.
+    centers: Vec<(f64, f64)>,
+    // Per output coordinate: a weight for each center, then the affine part
+    // (constant, x and y terms).
+    weights: [Vec<f64>; 2],
+    affine: [[f64; 3]; 2],
+    // Maps positions into the normalized space `centers` are given in.
+    offset: (f64, f64),
+    scale: f64,
+}
+
+/// The spline's radial basis function, of the squared distance.
+fn kernel(r2: f64) -> f64 {
+    if r2 > 0.0 {
+        r2 * r2.ln()
+    } else {
+        0.0
+    }
+}
+
+impl ThinPlateSpline {
+    /// Fits the spline mapping each pair's first point onto its second, or
+    /// None if that's impossible (repeated or collinear first points).
+    fn fit(pairs: &[PointPair]) -> Option<ThinPlateSpline> {
+        // Work on coordinates of about unit size, which keeps the system
+        // well conditioned.
+        let (min_x, min_y, max_x, max_y) = bounds(pairs.iter().map(|p| p.0));
+        let extent = (max_x - min_x).max(max_y - min_y);
+        if extent <= f64::EPSILON {
+            return None;
+        }
+        let scale = 1.0 / extent;
+        let centers: Vec<(f64, f64)> = pairs
+            .iter()
+            .map(|&((x, y), _)| ((x - min_x) * scale, (y - min_y) * scale))
+            .collect();
+        let n = centers.len();
+        let size = n + 3;
+        let mut system = vec![vec![0.0; size]; size];
+        for (i, &(xi, yi)) in centers.iter().enumerate() {
+            for (j, &(xj, yj)) in centers.iter().enumerate() {
+                system[i][j] = kernel((xi - xj).powi(2) + (yi - yj).powi(2));
+            }
+            for (k, value) in [1.0, xi, yi].into_iter().enumerate() {
+                system[i][n + k] = value;
+                system[n + k][i] = value;
+            }
+        }
+        let mut right: [Vec<f64>; 2] = [vec![0.0; size], vec![0.0; size]];
+        for (i, &(_, (x, y))) in pairs.iter().enumerate() {
+            right[0][i] = x;
+            right[1][i] = y;
+        }
+        let [x, y] = solve(system, right)?;
+        let affine = [[x[n], x[n + 1], x[n + 2]], [y[n], y[n + 1], y[n + 2]]];
+        Some(ThinPlateSpline {
+            centers,
+            weights: [x[..n].to_vec(), y[..n].to_vec()],
+            affine,
+            offset: (min_x, min_y),
+            scale,
+        })
+    }
+
+    fn map(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (x, y) = (
+            (x - self.offset.0) * self.scale,
+            (y - self.offset.1) * self.scale,
+        );
+        let [mx, my] = [0, 1].map(|axis| {
+            let [c, ax, ay] = self.affine[axis];
+            let bend: f64 = self
+                .centers
+                .iter()
+                .zip(&self.weights[axis])
+                .map(|(&(cx, cy), w)| w * kernel((x - cx).powi(2) + (y - cy).powi(2)))
+                .sum();
+            c + ax * x + ay * y + bend
+        });
+        (mx, my)
+    }
+}
+
+/// Solves `a x = b` for two right-hand sides at once by Gaussian elimination
+/// with partial pivoting, or None if `a` is (nearly) singular.
+fn solve(mut a: Vec<Vec<f64>>, mut b: [Vec<f64>; 2]) -> Option<[Vec<f64>; 2]> {
+    let n = a.len();
+    for column in 0..n {
+        let pivot =
+            (column..n).max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))?;
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        for b in &mut b {
+            b.swap(column, pivot);
+        }
+        let pivot_row = a[column].clone();
+        let pivot_b = [b[0][column], b[1][column]];
+        for row in column + 1..n {
+            let factor = a[row][column] / pivot_row[column];
+            if factor == 0.0 {
+                continue;
+            }
+            for (value, pivot_value) in a[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+            for (b, pivot_b) in b.iter_mut().zip(pivot_b) {
+                b[row] -= factor * pivot_b;
+            }
+        }
+    }
+    let mut x = [vec![0.0; n], vec![0.0; n]];
+    for (x, b) in x.iter_mut().zip(&b) {
+        for row in (0..n).rev() {
+            let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+            x[row] = (b[row] - sum) / a[row][row];
+        }
+    }
+    Some(x)
+}
+
+/// The points' bounding box: min x, min y, max x, max y.
+fn bounds(points: impl Iterator<Item = (f64, f64)>) -> (f64, f64, f64, f64) {
+    let start = (
+        f64::INFINITY,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::NEG_INFINITY,
+    );
+    points.fold(start, |(x0, y0, x1, y1), (x, y)| {
+        (x0.min(x), y0.min(y), x1.max(x), y1.max(y))
+    })
+}
+
+/// Flattens a gently curved subject (a label on a bottle, a bowed poster)
+/// with a thin-plate spline through the control points, each of which must
+/// have a target: where it should end up on the flattened page, in any units.
+/// The output spans the targets' bounding box, with about as many pixels as
+/// the points' bounding box covers in the source, and is cleaned up like a
+/// squared quad.
+pub fn warp_mesh(
+    image: &DynamicImage,
+    control_points: &[ControlPoint],
+    options: &ProcessingOptions,
+    cancel: &CancellationToken,
+) -> Result<DynamicImage, Error> {
+    check_options(options)?;
+    if !(3..=MAX_MESH_POINTS).contains(&control_points.len()) {
+        return Err(Error::InvalidInput(format!(
+            "Expected between 3 and {MAX_MESH_POINTS} control points, got {}",
+            control_points.len()
+        )));
+    }
+    let mut pairs = Vec::with_capacity(control_points.len());
+    for cp in control_points {
+        let Some([u, v]) = cp.target else {
+            return Err(Error::InvalidInput(String::from(
+                "Every control point needs a target for a mesh warp",
+            )));
+        };
+        if ![cp.x, cp.y, u, v].iter().all(|c| c.is_finite()) {
+            return Err(Error::InvalidInput(format!(
+                "Control point ({}, {}) with target ({u}, {v}) isn't a finite position",
+                cp.x, cp.y
+            )));
+        }
+        pairs.push(((u, v), (cp.x, cp.y)));
+    }
+    let (min_u, min_v, max_u, max_v) = bounds(pairs.iter().map(|p| p.0));
+    let (min_x, min_y, max_x, max_y) = bounds(pairs.iter().map(|p| p.1));
+    let (target_width, target_height) = (max_u - min_u, max_v - min_v);
+    let source_area = (max_x - min_x) * (max_y - min_y);
+    if target_width * target_height <= f64::EPSILON || source_area < 1.0 {
+        return Err(Error::Squaring(ImageSquaringError {
+            message: String::from("Control points don't span an area"),
+        }));
+    }
+    // Output pixels per target unit.
+    let scale = (source_area / (target_width * target_height)).sqrt();
+    let (width, height) = (
+        (target_width * scale).round().max(1.0) as u32,
+        (target_height * scale).round().max(1.0) as u32,
+    );
+    // Fit in output pixels, from the outputs' top left.
+    let output_pairs: Vec<PointPair> = pairs
+        .iter()
+        .map(|&((u, v), source)| (((u - min_u) * scale, (v - min_v) * scale), source))
+        .collect();
+    let spline = ThinPlateSpline::fit(&output_pairs).ok_or_else(|| {
+        Error::Squaring(ImageSquaringError {
+            message: String::from("Control points don't define a valid mesh"),
+        })
+    })?;
+    // Source positions at every `GRID_STEP`th output pixel, from which the
+    // rest are interpolated.
+    let (grid_width, grid_height) = (
+        width.div_ceil(GRID_STEP) + 1,
+        height.div_ceil(GRID_STEP) + 1,
+    );
+    let grid: Vec<(f32, f32)> = (0..grid_width * grid_height)
+        .into_par_iter()
+        .map(|i| {
+            let (gx, gy) = ((i % grid_width) * GRID_STEP, (i / grid_width) * GRID_STEP);
+            let (x, y) = spline.map((gx as f64, gy as f64));
+            (x as f32, y as f32)
+        })
+        .collect();
+    cancel.check()?;
+    let step = GRID_STEP as f32;
+    let mapping = |x: f32, y: f32| {
+        let (gx, gy) = (
+            (x / step).clamp(0.0, (grid_width - 2) as f32),
+            (y / step).clamp(0.0, (grid_height - 2) as f32),
+        );
+        let (column, row) = (gx.floor() as u32, gy.floor() as u32);
+        let (fx, fy) = (x / step - column as f32, y / step - row as f32);
+        let at = |dx: u32, dy: u32| grid[((row + dy) * grid_width + column + dx) as usize];
+        let lerp =
+            |a: (f32, f32), b: (f32, f32), t: f32| (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+        lerp(
+            lerp(at(0, 0), at(1, 0), fx),
+            lerp(at(0, 1), at(1, 1), fx),
+            fy,
+        )
+    };
+    let interpolation = match options.render_quality {
+        RenderQuality::Draft => InterpolationMode::Nearest,
+        _ => options.interpolation,
+    };
+    let factor = supersampling_factor(options.render_quality.supersampling(), (width, height));
+    let sixteen_bit = image.color().bytes_per_pixel() > image.color().channel_count();
+    let warped = if sixteen_bit {
+        DynamicImage::ImageRgba16(warp_supersampled(
+            &image.to_rgba16(),
+            &mapping,
+            interpolation,
+            options.fill,
+            (width, height),
+            factor,
+            cancel,
+        )?)
+    } else {
+        DynamicImage::ImageRgba8(warp_supersampled(
+            &image.to_rgba8(),
+            &mapping,
+            interpolation,
+            options.fill,
+            (width, height),
+            factor,
+            cancel,
+        )?)
+    };
+    cancel.check()?;
+    // Where the output's corners came from, for sharpening and compositing.
+    let outline: Vec<Point<f64>> = [(0, 0), (width, 0), (width, height), (0, height)]
+        .iter()
+        .map(|&(x, y)| {
+            let (x, y) = spline.map((x as f64, y as f64));
+            Point::new(x, y)
+        })
+        .collect();
+    Ok(finish(image, warped, &outline, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A scattering of points over a few hundred pixels, not on any grid.
+    const POINTS: [(f64, f64); 7] = [
+        (10.0, 20.0),
+        (310.0, 15.0),
+        (320.0, 240.0),
+        (5.0, 230.0),
+        (160.0, 120.0),
+        (90.0, 60.0),
+        (250.0, 180.0),
+    ];
+
+    #[test]
+    fn control_points_land_exactly_on_their_targets() {
+        let pairs: Vec<PointPair> = POINTS
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| {
+                let bulge = if i % 2 == 0 { 12.0 } else { -9.0 };
+                ((x, y), (x * 1.1 + bulge, y * 0.9 - bulge + 30.0))
+            })
+            .collect();
+        let spline = ThinPlateSpline::fit(&pairs).unwrap();
+        for &(from, (x, y)) in &pairs {
+            let (mx, my) = spline.map(from);
+            assert!(
+                (mx - x).abs() < 1e-6 && (my - y).abs() < 1e-6,
+                "{from:?} maps to ({mx}, {my}) instead of ({x}, {y})"
+            );
+        }
+    }
+
+    #[test]
+    fn points_kept_in_place_give_the_identity() {
+        let pairs: Vec<PointPair> = POINTS.iter().map(|&p| (p, p)).collect();
+        let spline = ThinPlateSpline::fit(&pairs).unwrap();
+        for y in (0..=260).step_by(20) {
+            for x in (0..=340).step_by(20) {
+                let (x, y) = (x as f64, y as f64);
+                let (mx, my) = spline.map((x, y));
+                assert!(
+                    (mx - x).abs() < 1e-6 && (my - y).abs() < 1e-6,
+                    "({x}, {y}) maps to ({mx}, {my})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn collinear_points_dont_fit() {
+        let pairs: Vec<PointPair> = (0..5)
+            .map(|i| ((i as f64 * 10.0, i as f64 * 5.0), (i as f64, 0.0)))
+            .collect();
+        assert!(ThinPlateSpline::fit(&pairs).is_none());
+    }
+}
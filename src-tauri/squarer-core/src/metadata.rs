@@ -0,0 +1,457 @@
+use exif::experimental::Writer;
+use exif::{Context, Field, In, Reader, Tag, Value};
+
+use std::io::Cursor;
+
+use crate::encode::OutputFormat;
+use crate::Error;
+
+// TIFF/EP ImageHistory, which kamadak-exif doesn't name.
+const IMAGE_HISTORY: Tag = Tag(Context::Tiff, 0x9213);
+
+const METRES_PER_INCH: f64 = 0.0254;
+
+// Tags copied over from the source photo, besides GPS.
+const COPIED_TAGS: &[Tag] = &[
+    Tag::DateTime,
+    Tag::DateTimeOriginal,
+    Tag::DateTimeDigitized,
+    Tag::OffsetTime,
+    Tag::OffsetTimeOriginal,
+    Tag::OffsetTimeDigitized,
+    Tag::Make,
+    Tag::Model,
+    Tag::LensMake,
+    Tag::LensModel,
+];
+
+fn ascii_field(tag: Tag, text: &str) -> Field {
+    Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Ascii(vec![text.as_bytes().to_vec()]),
+    }
+}
+
+/// Builds the EXIF block (a TIFF structure) for the output: capture date and
+/// camera details from `source_exif`, its GPS position unless `strip_gps`, and
+/// `history` describing what was done to the image.
+///
+/// The orientation tag is deliberately dropped, since it has already been
+/// applied to the pixels.
+pub fn build_exif(
+    source_exif: Option<&[u8]>,
+    strip_gps: bool,
+    history: &str,
+) -> Result<Vec<u8>, Error> {
+    let source = match source_exif {
+        // Unreadable metadata in the source shouldn't stop the export.
+        Some(raw) => Reader::new().read_raw(raw.to_vec()).ok(),
+        None => None,
+    };
+    let mut fields: Vec<Field> = Vec::new();
+    let mut little_endian = false;
+    if let Some(source) = &source {
+        little_endian = source.little_endian();
+        for field in source.fields().filter(|f| f.ifd_num == In::PRIMARY) {
+            let Tag(context, _) = field.tag;
+            if COPIED_TAGS.contains(&field.tag) || (context == Context::Gps && !strip_gps) {
+                fields.push(field.clone());
+            }
+        }
+    }
+    fields.push(ascii_field(
+        Tag::Software,
+        concat!("Squarer ", env!("CARGO_PKG_VERSION")),
+    ));
+    fields.push(ascii_field(IMAGE_HISTORY, history));
+
+    let mut writer = Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    writer.write(&mut buffer, little_endian)?;
+    Ok(buffer.into_inner())
+}
+
+/// The camera's make and model from an EXIF block, as far as they're given.
+pub fn camera(exif: &[u8]) -> (Option<String>, Option<String>) {
+    let Ok(exif) = Reader::new().read_raw(exif.to_vec()) else {
+        return (None, None);
+    };
+    let text = |tag: Tag| {
+        let field = exif.get_field(tag, In::PRIMARY)?;
+        match &field.value {
+            Value::Ascii(values) => values
+                .first()
+                .map(|value| String::from_utf8_lossy(value).trim().to_string())
+                .filter(|value| !value.is_empty()),
+            _ => None,
+        }
+    };
+    (text(Tag::Make), text(Tag::Model))
+}
+
+/// The physical resolution an image records, in dots per inch: its EXIF
+/// resolution if it has one, otherwise the JFIF density or pHYs chunk found
+/// in `header` (the first bytes of the file).
+pub fn read_dpi(header: &[u8], exif: Option<&[u8]>) -> Option<f32> {
+    exif.and_then(exif_dpi)
+        .or_else(|| jfif_dpi(header))
+        .or_else(|| png_dpi(header))
+        .filter(|dpi| *dpi > 0.0 && dpi.is_finite())
+}
+
+fn exif_dpi(exif: &[u8]) -> Option<f32> {
+    let exif = Reader::new().read_raw(exif.to_vec()).ok()?;
+    let resolution = match &exif.get_field(Tag::XResolution, In::PRIMARY)?.value {
+        Value::Rational(values) => values.first()?.to_f64(),
+        _ => return None,
+    };
+    let unit = exif
+        .get_field(Tag::ResolutionUnit, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0));
+    // Inches by default; 1 means there's no unit, only an aspect ratio.
+    match unit {
+        None | Some(2) => Some(resolution as f32),
+        Some(3) => Some((resolution * 2.54) as f32),
+        _ => None,
+    }
+}
+
+pub fn embed_exif(
+    encoded: Vec<u8>,
+    format: OutputFormat,
+    width: u32,
+    height: u32,
+    exif: &[u8],
+) -> Result<Vec<u8>, Error> {
+    match format {
+        OutputFormat::Jpeg => embed_exif_jpeg(encoded, exif),
+        OutputFormat::Png => embed_exif_png(encoded, exif),
+        OutputFormat::Webp => embed_exif_webp(encoded, width, height, exif),
+    }
+}
+
+fn malformed(format: &str) -> Error {
+    Error::InvalidInput(format!("Unexpected {format} structure from encoder"))
+}
+
+/// Adds an APP1 segment, after the JFIF APP0 segment if there is one.
+fn embed_exif_jpeg(encoded: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>, Error> {
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
+    if encoded.len() < 4 || encoded[0..2] != [0xFF, 0xD8] {
+        return Err(malformed("JPEG"));
+    }
+    let segment_length = 2 + EXIF_HEADER.len() + exif.len();
+    if segment_length > u16::MAX as usize {
+        return Err(Error::InvalidInput(String::from(
+            "Metadata too large for a JPEG APP1 segment",
+        )));
+    }
+    let mut insert_at = 2;
+    if encoded[2..4] == [0xFF, 0xE0] && encoded.len() >= 6 {
+        insert_at += 2 + u16::from_be_bytes([encoded[4], encoded[5]]) as usize;
+    }
+    let mut output = Vec::with_capacity(encoded.len() + segment_length + 2);
+    output.extend_from_slice(&encoded[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&(segment_length as u16).to_be_bytes());
+    output.extend_from_slice(EXIF_HEADER);
+    output.extend_from_slice(exif);
+    output.extend_from_slice(&encoded[insert_at..]);
+    Ok(output)
+}
+
+/// Adds a chunk right after IHDR, where eXIf and pHYs must come (before the
+/// image data).
+fn insert_png_chunk(encoded: Vec<u8>, kind: &[u8; 4], data: &[u8]) -> Result<Vec<u8>, Error> {
+    const SIGNATURE_LENGTH: usize = 8;
+    if encoded.len() < SIGNATURE_LENGTH + 8
+        || &encoded[SIGNATURE_LENGTH + 4..SIGNATURE_LENGTH + 8] != b"IHDR"
+    {
+        return Err(malformed("PNG"));
+    }
+    let ihdr_length = u32::from_be_bytes(
+        encoded[SIGNATURE_LENGTH..SIGNATURE_LENGTH + 4]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    // Length, type, data and CRC.
+    let insert_at = SIGNATURE_LENGTH + 4 + 4 + ihdr_length + 4;
+    if insert_at > encoded.len() {
+        return Err(malformed("PNG"));
+    }
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let crc = crc32fast::hash(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+
+    let mut output = Vec::with_capacity(encoded.len() + chunk.len());
+    output.extend_from_slice(&encoded[..insert_at]);
+    output.extend_from_slice(&chunk);
+    output.extend_from_slice(&encoded[insert_at..]);
+    Ok(output)
+}
+
+fn embed_exif_png(encoded: Vec<u8>, exif: &[u8]) -> Result<Vec<u8>, Error> {
+    insert_png_chunk(encoded, b"eXIf", exif)
+}
+
+/// Adds an EXIF chunk, converting the file to the extended (VP8X) layout first
+/// if needed since simple WebP files can't carry metadata.
+/// Whether a simple-format WebP's chunks (its body after the RIFF header)
+/// have alpha: the VP8L header's alpha bit, or an ALPH chunk.
+fn webp_has_alpha(body: &[u8]) -> bool {
+    // The VP8L signature byte, then 14 bits each of width and height less
+    // one, then the alpha bit.
+    const VP8L_ALPHA_BIT: u32 = 1 << 28;
+    let mut chunks = body;
+    while chunks.len() >= 8 {
+        let (fourcc, length) = (&chunks[0..4], &chunks[4..8]);
+        let length = u32::from_le_bytes([length[0], length[1], length[2], length[3]]) as usize;
+        let data = &chunks[8..chunks.len().min(8 + length)];
+        match fourcc {
+            b"ALPH" => return true,
+            b"VP8L" if data.len() >= 5 && data[0] == 0x2F => {
+                let header = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+                if header & VP8L_ALPHA_BIT != 0 {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        chunks = &chunks[chunks.len().min(8 + length + length % 2)..];
+    }
+    false
+}
+
+fn embed_exif_webp(
+    encoded: Vec<u8>,
+    width: u32,
+    height: u32,
+    exif: &[u8],
+) -> Result<Vec<u8>, Error> {
+    const HEADER_LENGTH: usize = 12;
+    const ALPHA_FLAG: u8 = 0x10;
+    const EXIF_FLAG: u8 = 0x08;
+    // The extended header's canvas size fields are 24 bits.
+    const MAX_DIMENSION: u32 = 1 << 24;
+    if encoded.len() < HEADER_LENGTH + 8 || &encoded[0..4] != b"RIFF" || &encoded[8..12] != b"WEBP"
+    {
+        return Err(malformed("WebP"));
+    }
+    let mut body = encoded[HEADER_LENGTH..].to_vec();
+    if &body[0..4] == b"VP8X" {
+        if body.len() < 18 {
+            return Err(malformed("WebP"));
+        }
+        body[8] |= EXIF_FLAG;
+    } else {
+        let (Some(width_minus_one), Some(height_minus_one)) = (
+            width.checked_sub(1).filter(|&w| w < MAX_DIMENSION),
+            height.checked_sub(1).filter(|&h| h < MAX_DIMENSION),
+        ) else {
+            return Err(Error::InvalidInput(format!(
+                "A WebP can't be {width}x{height} pixels"
+            )));
+        };
+        let flags = if webp_has_alpha(&body) {
+            ALPHA_FLAG | EXIF_FLAG
+        } else {
+            EXIF_FLAG
+        };
+        let mut vp8x = Vec::with_capacity(18);
+        vp8x.extend_from_slice(b"VP8X");
+        vp8x.extend_from_slice(&10u32.to_le_bytes());
+        vp8x.extend_from_slice(&[flags, 0, 0, 0]);
+        vp8x.extend_from_slice(&width_minus_one.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&height_minus_one.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&body);
+        body = vp8x;
+    }
+    body.extend_from_slice(b"EXIF");
+    body.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+    body.extend_from_slice(exif);
+    if exif.len() % 2 == 1 {
+        body.push(0);
+    }
+
+    let mut output = Vec::with_capacity(HEADER_LENGTH + body.len());
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&((4 + body.len()) as u32).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&body);
+    Ok(output)
+}
+
+/// Records the physical resolution of an image already encoded in `format`,
+/// so that it prints at the intended size: a pHYs chunk for PNG and the JFIF
+/// density for JPEG. WebP has nowhere standard to put it, so it's left as is.
+pub fn embed_dpi(encoded: Vec<u8>, format: OutputFormat, dpi: f32) -> Result<Vec<u8>, Error> {
+    if !(dpi > 0.0 && dpi.is_finite()) {
+        return Err(Error::InvalidInput(format!(
+            "DPI must be positive, got {dpi}"
+        )));
+    }
+    match format {
+        OutputFormat::Png => {
+            let pixels_per_metre = (dpi as f64 / METRES_PER_INCH).round() as u32;
+            let mut phys = Vec::with_capacity(9);
+            phys.extend_from_slice(&pixels_per_metre.to_be_bytes());
+            phys.extend_from_slice(&pixels_per_metre.to_be_bytes());
+            // The unit is the metre.
+            phys.push(1);
+            insert_png_chunk(encoded, b"pHYs", &phys)
+        }
+        OutputFormat::Jpeg => set_jfif_density(encoded, dpi),
+        OutputFormat::Webp => Ok(encoded),
+    }
+}
+
+/// The density in a JPEG's leading JFIF APP0 segment, if it gives one.
+fn jfif_dpi(header: &[u8]) -> Option<f32> {
+    const UNITS_OFFSET: usize = 2 + 2 + 2 + 5 + 2;
+    if header.len() < UNITS_OFFSET + 3
+        || header[0..4] != [0xFF, 0xD8, 0xFF, 0xE0]
+        || &header[6..11] != b"JFIF\0"
+    {
+        return None;
+    }
+    let density = u16::from_be_bytes([header[UNITS_OFFSET + 1], header[UNITS_OFFSET + 2]]) as f32;
+    match header[UNITS_OFFSET] {
+        1 => Some(density),
+        2 => Some(density * 2.54),
+        // No unit, only an aspect ratio.
+        _ => None,
+    }
+}
+
+/// The resolution in a PNG's pHYs chunk, if there is one before the image
+/// data.
+fn png_dpi(header: &[u8]) -> Option<f32> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !header.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut offset = SIGNATURE.len();
+    while offset + 8 <= header.len() {
+        let length = u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap()) as usize;
+        let data = offset + 8;
+        match &header[offset + 4..data] {
+            b"IDAT" => return None,
+            b"pHYs" if length == 9 && data + 9 <= header.len() => {
+                let pixels_per_unit =
+                    u32::from_be_bytes(header[data..data + 4].try_into().unwrap());
+                // The only unit defined is the metre.
+                return (header[data + 8] == 1)
+                    .then_some((pixels_per_unit as f64 * METRES_PER_INCH) as f32);
+            }
+            _ => {}
+        }
+        // Length, type, data and CRC.
+        offset = data.checked_add(length)?.checked_add(4)?;
+    }
+    None
+}
+
+/// Overwrites the density in the JFIF APP0 segment the encoder writes first.
+fn set_jfif_density(mut encoded: Vec<u8>, dpi: f32) -> Result<Vec<u8>, Error> {
+    // Marker, length, identifier and version come before the units.
+    const UNITS_OFFSET: usize = 2 + 2 + 2 + 5 + 2;
+    if encoded.len() < UNITS_OFFSET + 5
+        || encoded[0..4] != [0xFF, 0xD8, 0xFF, 0xE0]
+        || &encoded[6..11] != b"JFIF\0"
+    {
+        return Err(malformed("JPEG"));
+    }
+    let density = (dpi.round() as u16).max(1).to_be_bytes();
+    // Dots per inch.
+    encoded[UNITS_OFFSET] = 1;
+    encoded[UNITS_OFFSET + 1..UNITS_OFFSET + 3].copy_from_slice(&density);
+    encoded[UNITS_OFFSET + 3..UNITS_OFFSET + 5].copy_from_slice(&density);
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::codecs::webp::WebPEncoder;
+    use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+    // Of odd length, so its chunk needs padding.
+    const EXIF: &[u8] = b"MM\0*\0";
+
+    fn webp(image: &DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_with_encoder(WebPEncoder::new_lossless(&mut bytes))
+            .unwrap();
+        bytes
+    }
+
+    /// A WebP's chunks, checking that the RIFF header's size is right and
+    /// the chunks (with their padding) exactly fill it.
+    fn chunks(webp: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(&webp[0..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
+        let riff_size = u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_size + 8, webp.len());
+        let mut rest = &webp[12..];
+        let mut chunks = Vec::new();
+        while !rest.is_empty() {
+            let length = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[0..4], &rest[8..8 + length]));
+            rest = &rest[8 + length + length % 2..];
+        }
+        chunks
+    }
+
+    fn embed(image: &DynamicImage) -> Vec<u8> {
+        let (width, height) = image.dimensions();
+        embed_exif(webp(image), OutputFormat::Webp, width, height, EXIF).unwrap()
+    }
+
+    #[test]
+    fn webp_exif_goes_in_an_extended_file() {
+        let image =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(7, 5, image::Rgb([9, 99, 200])));
+        let embedded = embed(&image);
+        let chunks = chunks(&embedded);
+        let names: Vec<&[u8]> = chunks.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, [&b"VP8X"[..], b"VP8L", b"EXIF"]);
+        let vp8x = chunks[0].1;
+        // Only EXIF: an opaque image has no alpha to flag.
+        assert_eq!(vp8x[0], 0x08);
+        assert_eq!(&vp8x[4..7], &[6, 0, 0]);
+        assert_eq!(&vp8x[7..10], &[4, 0, 0]);
+        assert_eq!(chunks[2].1, EXIF);
+        let decoded = image::load_from_memory(&embedded).unwrap();
+        assert_eq!(decoded.dimensions(), (7, 5));
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn webp_exif_flags_alpha_only_when_there_is_some() {
+        let mut pixels = RgbaImage::from_pixel(6, 4, Rgba([200, 10, 10, 255]));
+        pixels.put_pixel(2, 1, Rgba([0, 0, 0, 0]));
+        let embedded = embed(&DynamicImage::ImageRgba8(pixels));
+        let chunks = chunks(&embedded);
+        assert_eq!(chunks[0].0, b"VP8X");
+        assert_eq!(chunks[0].1[0], 0x10 | 0x08);
+        let decoded = image::load_from_memory(&embedded).unwrap();
+        assert_eq!(decoded.to_rgba8().get_pixel(2, 1)[3], 0);
+    }
+
+    #[test]
+    fn webp_exif_rejects_an_empty_canvas() {
+        let encoded = webp(&DynamicImage::ImageRgb8(image::RgbImage::new(3, 3)));
+        let error = embed_exif(encoded, OutputFormat::Webp, 0, 3, EXIF).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)), "{error:?}");
+    }
+}
@@ -0,0 +1,259 @@
+//! Naming the outputs of a batch, shared by the app and the command line.
+
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::encode::OutputFormat;
+use crate::Error;
+
+// How outputs are named unless a job says otherwise.
+pub const DEFAULT_TEMPLATE: &str = "{stem}_squared.{ext}";
+
+/// What to do when an output's name is already taken.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Collision {
+    /// Leave the existing file alone and don't write the output.
+    Skip,
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Add `-2`, `-3` and so on before the extension until the name is free.
+    AutoNumber,
+}
+
+/// How a job names its outputs. The template can contain:
+///
+/// - `{stem}`: the source file's name without its extension
+/// - `{index}`: the item's position in the job, counting from 1, padded to
+///   three digits
+/// - `{date}` and `{time}`: when the job started, as `2024-05-31` and
+///   `14-05-09`, in local time
+/// - `{ext}`: the output format's extension, added at the end if the
+///   template doesn't place it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OutputNaming {
+    pub template: String,
+    pub on_collision: Collision,
+}
+
+impl Default for OutputNaming {
+    fn default() -> Self {
+        OutputNaming {
+            template: String::from(DEFAULT_TEMPLATE),
+            on_collision: Collision::default(),
+        }
+    }
+}
+
+impl OutputNaming {
+    /// Checks the template names a file in the output folder, rather than
+    /// somewhere else.
+    pub fn validate(&self) -> Result<(), Error> {
+        let template = self.template.trim();
+        if template.is_empty() || template == "." || template == ".." {
+            return Err(Error::InvalidInput(format!(
+                "'{}' isn't a usable file name template",
+                self.template
+            )));
+        }
+        if template.contains(['/', '\\']) {
+            return Err(Error::InvalidInput(format!(
+                "File name template '{}' can't contain a path separator",
+                self.template
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Fills in a template's placeholders (see `OutputNaming`).
+pub fn file_name(
+    template: &str,
+    stem: &str,
+    index: usize,
+    started: &DateTime<Local>,
+    format: OutputFormat,
+) -> String {
+    let mut name = template
+        .replace("{stem}", stem)
+        .replace("{index}", &format!("{index:03}"))
+        .replace("{date}", &started.format("%Y-%m-%d").to_string())
+        .replace("{time}", &started.format("%H-%M-%S").to_string());
+    if !name.contains("{ext}") {
+        name.push_str(".{ext}");
+    }
+    name.replace("{ext}", format.extension())
+}
+
+/// `name` with `-<number>` added before its extension.
+pub fn numbered(name: &str, number: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => format!("{base}-{number}.{extension}"),
+        _ => format!("{name}-{number}"),
+    }
+}
+
+/// Names the outputs of one job, remembering which names it's given out so
+/// items processed in parallel don't auto-number onto the same free name.
+pub struct Namer {
+    naming: OutputNaming,
+    output_dir: PathBuf,
+    started: DateTime<Local>,
+    claimed: Mutex<HashSet<PathBuf>>,
+}
+
+impl Namer {
+    pub fn new(naming: OutputNaming, output_dir: &Path) -> Result<Namer, Error> {
+        naming.validate()?;
+        Ok(Namer {
+            naming,
+            output_dir: output_dir.to_path_buf(),
+            started: Local::now(),
+            claimed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Where the output for the `index`th input (counting from 1) goes, or
+    /// None if it should be skipped because the name is taken.
+    pub fn output_path(&self, input: &Path, index: usize, format: OutputFormat) -> Option<PathBuf> {
+        let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+        let name = file_name(&self.naming.template, &stem, index, &self.started, format);
+        let mut claimed = self.claimed.lock().unwrap();
+        let mut path = self.output_dir.join(&name);
+        let taken =
+            |path: &Path, claimed: &HashSet<PathBuf>| claimed.contains(path) || path.exists();
+        match self.naming.on_collision {
+            Collision::Skip if taken(&path, &claimed) => return None,
+            Collision::AutoNumber => {
+                let mut number = 2;
+                while taken(&path, &claimed) {
+                    path = self.output_dir.join(numbered(&name, number));
+                    number += 1;
+                }
+            }
+            _ => {}
+        }
+        claimed.insert(path.clone());
+        Some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    /// An empty directory of its own for each test.
+    fn output_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("squarer-naming-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn namer(template: &str, on_collision: Collision, dir: &Path) -> Namer {
+        let naming = OutputNaming {
+            template: String::from(template),
+            on_collision,
+        };
+        Namer::new(naming, dir).unwrap()
+    }
+
+    #[test]
+    fn file_name_fills_in_every_placeholder() {
+        let started = Local.with_ymd_and_hms(2024, 5, 31, 14, 5, 9).unwrap();
+        let name = file_name(
+            "{date}_{time}_{stem}_{index}.{ext}",
+            "receipt",
+            7,
+            &started,
+            OutputFormat::Jpeg,
+        );
+        assert_eq!(name, "2024-05-31_14-05-09_receipt_007.jpg");
+    }
+
+    #[test]
+    fn file_name_adds_the_extension_if_the_template_leaves_it_out() {
+        let started = Local::now();
+        let name = file_name("{stem}-flat", "scan", 1, &started, OutputFormat::Png);
+        assert_eq!(name, "scan-flat.png");
+    }
+
+    #[test]
+    fn numbered_goes_before_the_extension() {
+        assert_eq!(numbered("scan.png", 2), "scan-2.png");
+        assert_eq!(numbered(".hidden", 3), ".hidden-3");
+        assert_eq!(numbered("scan", 4), "scan-4");
+    }
+
+    #[test]
+    fn validate_rejects_templates_outside_the_folder() {
+        for template in ["", " ", ".", "..", "../{stem}", "out/{stem}", "out\\{stem}"] {
+            let naming = OutputNaming {
+                template: String::from(template),
+                on_collision: Collision::Overwrite,
+            };
+            assert!(naming.validate().is_err(), "{template:?}");
+        }
+        assert!(OutputNaming::default().validate().is_ok());
+    }
+
+    #[test]
+    fn overwrite_reuses_a_taken_name() {
+        let dir = output_dir("overwrite");
+        std::fs::write(dir.join("a_squared.png"), b"").unwrap();
+        let namer = namer(DEFAULT_TEMPLATE, Collision::Overwrite, &dir);
+        let path = namer.output_path(Path::new("in/a.jpg"), 1, OutputFormat::Png);
+        assert_eq!(path, Some(dir.join("a_squared.png")));
+    }
+
+    #[test]
+    fn skip_leaves_a_taken_name_alone() {
+        let dir = output_dir("skip");
+        std::fs::write(dir.join("a_squared.png"), b"").unwrap();
+        let namer = namer(DEFAULT_TEMPLATE, Collision::Skip, &dir);
+        assert_eq!(
+            namer.output_path(Path::new("in/a.jpg"), 1, OutputFormat::Png),
+            None
+        );
+        assert_eq!(
+            namer.output_path(Path::new("in/b.jpg"), 2, OutputFormat::Png),
+            Some(dir.join("b_squared.png"))
+        );
+    }
+
+    #[test]
+    fn skip_counts_names_already_given_out() {
+        let dir = output_dir("skip-claimed");
+        let namer = namer(DEFAULT_TEMPLATE, Collision::Skip, &dir);
+        assert!(namer
+            .output_path(Path::new("one/a.jpg"), 1, OutputFormat::Png)
+            .is_some());
+        assert_eq!(
+            namer.output_path(Path::new("two/a.jpg"), 2, OutputFormat::Png),
+            None
+        );
+    }
+
+    #[test]
+    fn auto_number_finds_the_next_free_name() {
+        let dir = output_dir("auto-number");
+        std::fs::write(dir.join("a_squared.png"), b"").unwrap();
+        std::fs::write(dir.join("a_squared-2.png"), b"").unwrap();
+        let namer = namer(DEFAULT_TEMPLATE, Collision::AutoNumber, &dir);
+        // Neither output is written, so only the names given out keep the
+        // second from landing on the first's.
+        let first = namer.output_path(Path::new("one/a.jpg"), 1, OutputFormat::Png);
+        let second = namer.output_path(Path::new("two/a.jpg"), 2, OutputFormat::Png);
+        assert_eq!(first, Some(dir.join("a_squared-3.png")));
+        assert_eq!(second, Some(dir.join("a_squared-4.png")));
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+use crate::decode::{DecodeLimits, ImageInfo, SourceImage};
+use crate::Error;
+
+// Extensions of the camera RAW formats rawloader understands.
+pub const RAW_EXTENSIONS: &[&str] = &[
+    "dng", "cr2", "crw", "nef", "nrw", "arw", "srf", "sr2", "orf", "rw2", "raf", "pef", "srw",
+    "mrw", "3fr", "erf", "kdc", "dcr", "mef", "mos", "iiq",
+];
+
+pub fn has_raw_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| RAW_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+fn is_tiff(header: &[u8]) -> bool {
+    header.starts_with(b"II*\0") || header.starts_with(b"MM\0*")
+}
+
+/// Whether `header` could start a RAW file: most are TIFF-based, and the
+/// rest have signatures of their own.
+pub fn might_be_raw(header: &[u8]) -> bool {
+    is_tiff(header)
+        || [&b"IIRO"[..], b"IIRS", b"IIU\0", b"FUJIFILM", b"\0MRM"]
+            .iter()
+            .any(|signature| header.starts_with(signature))
+}
+
+#[cfg(feature = "raw")]
+mod rawloader_decode {
+    use super::*;
+    use image::error::{DecodingError, ImageFormatHint};
+    use image::{DynamicImage, ImageBuffer, ImageError};
+
+    use std::io::Cursor;
+
+    // Working memory per pixel in imagepipe's floating-point pipeline.
+    const PIPELINE_BYTES_PER_PIXEL: u64 = 16;
+
+    fn decoding_error(message: String) -> Error {
+        Error::Image(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name(String::from("RAW")),
+            message,
+        )))
+    }
+
+    /// Demosaics a RAW file into a 16-bit sRGB image, with the camera's
+    /// orientation applied. Returns None if rawloader doesn't recognize it.
+    pub fn try_read(bytes: &[u8], limits: &DecodeLimits) -> Result<Option<SourceImage>, Error> {
+        let Ok(raw) = rawloader::decode(&mut Cursor::new(bytes)) else {
+            return Ok(None);
+        };
+        let (width, height) = (raw.width as u32, raw.height as u32);
+        let bytes_needed = width as u64 * height as u64 * PIPELINE_BYTES_PER_PIXEL;
+        limits.check(width, height, bytes_needed)?;
+        let mut pipeline = imagepipe::Pipeline::new_from_source(imagepipe::ImageSource::Raw(raw))
+            .map_err(decoding_error)?;
+        let developed = pipeline.output_16bit(None).map_err(decoding_error)?;
+        let image = ImageBuffer::from_raw(
+            developed.width as u32,
+            developed.height as u32,
+            developed.data,
+        )
+        .map(DynamicImage::ImageRgb16)
+        .ok_or_else(|| decoding_error(String::from("RAW image has unexpected layout")))?;
+        // TIFF-based RAW files are their own EXIF block.
+        let exif = is_tiff(bytes).then(|| bytes.to_vec());
+        Ok(Some(SourceImage { image, exif }))
+    }
+
+    /// Reads a RAW file's metadata without decoding its sensor data. Returns
+    /// None if rawloader doesn't recognize it.
+    pub fn try_probe(bytes: &[u8]) -> Option<ImageInfo> {
+        let raw = rawloader::decode_dummy(&mut Cursor::new(bytes)).ok()?;
+        // The size once developed: cropped, then turned upright.
+        let [top, right, bottom, left] = raw.crops;
+        let width = raw.width.saturating_sub(left + right) as u32;
+        let height = raw.height.saturating_sub(top + bottom) as u32;
+        let (transposed, _, _) = raw.orientation.to_flips();
+        let (width, height) = if transposed {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        let exif = is_tiff(bytes).then_some(bytes);
+        Some(ImageInfo {
+            width,
+            height,
+            format: String::from("raw"),
+            color_type: String::from("rgb16"),
+            // Enough bits for the sensor's white level.
+            bit_depth: (u16::BITS - raw.whitelevels[0].leading_zeros()) as u16,
+            has_alpha: false,
+            // Applied while developing, as when reading.
+            orientation: 1,
+            dpi: crate::metadata::read_dpi(&[], exif),
+        })
+    }
+}
+
+#[cfg(feature = "raw")]
+pub use rawloader_decode::{try_probe, try_read};
+
+#[cfg(not(feature = "raw"))]
+pub fn try_read(_bytes: &[u8], _limits: &DecodeLimits) -> Result<Option<SourceImage>, Error> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "raw"))]
+pub fn try_probe(_bytes: &[u8]) -> Option<ImageInfo> {
+    None
+}
+
+/// Decodes a file that's known to be RAW (going by its extension).
+pub fn read(bytes: &[u8], limits: &DecodeLimits) -> Result<SourceImage, Error> {
+    try_read(bytes, limits)?.ok_or_else(|| {
+        Error::Unsupported(String::from(if cfg!(feature = "raw") {
+            "This camera's RAW format isn't supported"
+        } else {
+            "RAW images need a build with the `raw` feature"
+        }))
+    })
+}
+
+/// Reads the metadata of a file that's known to be RAW (going by its
+/// extension).
+pub fn probe(bytes: &[u8]) -> Result<ImageInfo, Error> {
+    try_probe(bytes).ok_or_else(|| {
+        Error::Unsupported(String::from(if cfg!(feature = "raw") {
+            "This camera's RAW format isn't supported"
+        } else {
+            "RAW images need a build with the `raw` feature"
+        }))
+    })
+}
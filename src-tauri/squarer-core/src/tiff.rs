@@ -0,0 +1,277 @@
+use ::tiff::encoder::compression::{Compression, Deflate, Uncompressed};
+use ::tiff::encoder::{colortype, Rational, TiffEncoder, TiffKindStandard};
+use ::tiff::tags::{CompressionMethod, PhotometricInterpretation, ResolutionUnit, Tag};
+use ::tiff::TiffError;
+use fax::encoder::Encoder;
+use fax::{Color, VecWriter};
+use image::error::{EncodingError, ImageFormatHint};
+use image::{DynamicImage, GrayImage, ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use std::io::{Cursor, Seek, Write};
+
+use crate::encode::remove_alpha;
+use crate::Error;
+
+// NewSubfileType value marking each image as one page of a multi-page document.
+const SUBFILE_PAGE: u32 = 2;
+// T6Options isn't among the tiff crate's known tags.
+const T6_OPTIONS: Tag = Tag::Unknown(293);
+const PAGE_NUMBER: Tag = Tag::Unknown(297);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TiffCompression {
+    /// CCITT Group 4 for bilevel pages (e.g. from `CleanupMode::Document`),
+    /// which is what archival scanning workflows expect, and Deflate for the
+    /// rest.
+    #[default]
+    Auto,
+    Deflate,
+    None,
+}
+
+fn tiff_error(error: TiffError) -> Error {
+    Error::Image(ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Tiff),
+        error,
+    )))
+}
+
+/// The image as black (0) and white (255) pixels, if that's all it has.
+fn bilevel(image: &DynamicImage) -> Option<GrayImage> {
+    let is_bilevel = |value: u8| value == 0 || value == 255;
+    match image {
+        DynamicImage::ImageLuma8(gray) => gray
+            .iter()
+            .all(|&value| is_bilevel(value))
+            .then(|| gray.clone()),
+        DynamicImage::ImageRgb8(rgb) => rgb
+            .pixels()
+            .all(|p| p[0] == p[1] && p[1] == p[2] && is_bilevel(p[0]))
+            .then(|| image.to_luma8()),
+        _ => None,
+    }
+}
+
+/// `dpi` as a TIFF rational, to a hundredth of a dot per inch.
+fn resolution(dpi: f32) -> Rational {
+    Rational {
+        n: (dpi * 100.0).round() as u32,
+        d: 100,
+    }
+}
+
+fn encode_g4(image: &GrayImage) -> Vec<u8> {
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in image.rows() {
+        let pels = row.map(|p| {
+            if p[0] == 0 {
+                Color::Black
+            } else {
+                Color::White
+            }
+        });
+        let Ok(()) = encoder.encode_line(pels, image.width());
+    }
+    let Ok(writer) = encoder.finish();
+    writer.finish()
+}
+
+/// Writes a 1-bit G4 page as a single strip.
+fn write_g4_page<W: Write + Seek>(
+    encoder: &mut TiffEncoder<W, TiffKindStandard>,
+    image: &GrayImage,
+    page: [u16; 2],
+    dpi: Option<f32>,
+) -> Result<(), TiffError> {
+    let data = encode_g4(image);
+    let mut directory = encoder.new_directory()?;
+    let offset = directory.write_data(&data[..])?;
+    directory.write_tag(Tag::NewSubfileType, SUBFILE_PAGE)?;
+    directory.write_tag(Tag::ImageWidth, image.width())?;
+    directory.write_tag(Tag::ImageLength, image.height())?;
+    directory.write_tag(Tag::BitsPerSample, 1u16)?;
+    directory.write_tag(Tag::Compression, CompressionMethod::Fax4.to_u16())?;
+    // G4 codes black as 1.
+    directory.write_tag(
+        Tag::PhotometricInterpretation,
+        PhotometricInterpretation::WhiteIsZero.to_u16(),
+    )?;
+    directory.write_tag(Tag::StripOffsets, offset as u32)?;
+    directory.write_tag(Tag::SamplesPerPixel, 1u16)?;
+    directory.write_tag(Tag::RowsPerStrip, image.height())?;
+    directory.write_tag(Tag::StripByteCounts, data.len() as u32)?;
+    directory.write_tag(T6_OPTIONS, 0u32)?;
+    directory.write_tag(PAGE_NUMBER, &page[..])?;
+    if let Some(dpi) = dpi {
+        directory.write_tag(Tag::XResolution, resolution(dpi))?;
+        directory.write_tag(Tag::YResolution, resolution(dpi))?;
+        directory.write_tag(Tag::ResolutionUnit, ResolutionUnit::Inch.to_u16())?;
+    }
+    directory.finish()
+}
+
+fn write_page<W: Write + Seek, D: Compression>(
+    encoder: &mut TiffEncoder<W, TiffKindStandard>,
+    image: &DynamicImage,
+    page: [u16; 2],
+    dpi: Option<f32>,
+    compression: D,
+) -> Result<(), TiffError> {
+    let (width, height) = (image.width(), image.height());
+    macro_rules! write {
+        ($color:ty, $samples:expr) => {{
+            let mut page_encoder =
+                encoder.new_image_with_compression::<$color, D>(width, height, compression)?;
+            page_encoder
+                .encoder()
+                .write_tag(Tag::NewSubfileType, SUBFILE_PAGE)?;
+            page_encoder.encoder().write_tag(PAGE_NUMBER, &page[..])?;
+            if let Some(dpi) = dpi {
+                page_encoder.resolution(ResolutionUnit::Inch, resolution(dpi));
+            }
+            page_encoder.write_data($samples)
+        }};
+    }
+    match image {
+        DynamicImage::ImageLuma8(gray) => write!(colortype::Gray8, gray.as_raw()),
+        DynamicImage::ImageLuma16(gray) => write!(colortype::Gray16, gray.as_raw()),
+        DynamicImage::ImageRgb8(rgb) => write!(colortype::RGB8, rgb.as_raw()),
+        _ => write!(colortype::RGB16, image.to_rgb16().as_raw()),
+    }
+}
+
+/// Encodes `pages` as one multi-page TIFF, each flattened onto `background`.
+/// Grayscale pages stay grayscale and 16-bit pages 16-bit. `dpi`, if given,
+/// is recorded as every page's resolution.
+pub fn encode_pages<'a>(
+    pages: impl IntoIterator<Item = &'a DynamicImage>,
+    compression: TiffCompression,
+    background: [u8; 3],
+    dpi: Option<f32>,
+) -> Result<Vec<u8>, Error> {
+    if let Some(dpi) = dpi.filter(|dpi| !(*dpi > 0.0 && dpi.is_finite())) {
+        return Err(Error::InvalidInput(format!(
+            "DPI must be positive, got {dpi}"
+        )));
+    }
+    let pages: Vec<_> = pages.into_iter().collect();
+    let page_count = u16::try_from(pages.len())
+        .map_err(|_| Error::InvalidInput(String::from("A TIFF can have at most 65535 pages")))?;
+    let mut bytes = Cursor::new(Vec::new());
+    {
+        let mut encoder = TiffEncoder::new(&mut bytes).map_err(tiff_error)?;
+        for (index, page) in pages.into_iter().enumerate() {
+            let page_number = [index as u16, page_count];
+            let flattened = remove_alpha(page.clone(), background);
+            let written = match compression {
+                TiffCompression::Auto => match bilevel(&flattened) {
+                    Some(gray) => write_g4_page(&mut encoder, &gray, page_number, dpi),
+                    None => write_page(
+                        &mut encoder,
+                        &flattened,
+                        page_number,
+                        dpi,
+                        Deflate::default(),
+                    ),
+                },
+                TiffCompression::Deflate => write_page(
+                    &mut encoder,
+                    &flattened,
+                    page_number,
+                    dpi,
+                    Deflate::default(),
+                ),
+                TiffCompression::None => {
+                    write_page(&mut encoder, &flattened, page_number, dpi, Uncompressed)
+                }
+            };
+            written.map_err(tiff_error)?;
+        }
+    }
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::tiff::decoder::Decoder;
+    use image::Luma;
+
+    /// A bilevel page: a ring, some stripes of varying width, and runs that
+    /// end exactly at the right edge.
+    fn bilevel_page(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let (dx, dy) = (x as f32 - 60.0, y as f32 - 50.0);
+            let ring = (25.0..35.0).contains(&dx.hypot(dy));
+            let stripe = y > 100 && (x / (1 + y % 7)) % 2 == 0;
+            let edge = x + 10 >= width && y % 3 == 0;
+            Luma([if ring || stripe || edge { 0 } else { 255 }])
+        })
+    }
+
+    /// Each page's compression and, if that's G4, its pixels; the tiff crate
+    /// reads the tags but can't decode G4, so the strip goes through fax's
+    /// decoder.
+    fn decode_g4_pages(bytes: &[u8]) -> Vec<(u16, Option<GrayImage>)> {
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let mut pages = Vec::new();
+        loop {
+            let compression = decoder.get_tag_unsigned::<u16>(Tag::Compression).unwrap();
+            let page = (compression == CompressionMethod::Fax4.to_u16()).then(|| {
+                let (width, height) = decoder.dimensions().unwrap();
+                let offset = decoder.get_tag_u32(Tag::StripOffsets).unwrap() as usize;
+                let length = decoder.get_tag_u32(Tag::StripByteCounts).unwrap() as usize;
+                let mut pixels = Vec::with_capacity((width * height) as usize);
+                fax::decoder::decode_g4(
+                    bytes[offset..offset + length].iter().copied(),
+                    width,
+                    Some(height),
+                    |transitions| {
+                        pixels.extend(fax::decoder::pels(transitions, width).map(
+                            |color| match color {
+                                Color::Black => 0,
+                                Color::White => 255,
+                            },
+                        ))
+                    },
+                )
+                .unwrap();
+                GrayImage::from_raw(width, height, pixels).unwrap()
+            });
+            pages.push((compression, page));
+            if !decoder.more_images() {
+                return pages;
+            }
+            decoder.next_image().unwrap();
+        }
+    }
+
+    #[test]
+    fn bilevel_pages_decode_to_the_same_pixels() {
+        let first = bilevel_page(173, 141);
+        let second = GrayImage::from_fn(64, 48, |x, y| {
+            Luma([if (x + y) % 5 == 0 { 0 } else { 255 }])
+        });
+        let gray = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 48, |x, _| Luma([x as u8 * 4])));
+        let bytes = encode_pages(
+            &[
+                DynamicImage::ImageLuma8(first.clone()),
+                gray,
+                DynamicImage::ImageLuma8(second.clone()),
+            ],
+            TiffCompression::Auto,
+            crate::encode::DEFAULT_BACKGROUND,
+            Some(300.0),
+        )
+        .unwrap();
+        let pages = decode_g4_pages(&bytes);
+        assert_eq!(pages.len(), 3);
+        assert_eq!(pages[0].1.as_ref(), Some(&first));
+        // Gray pages aren't bilevel, so fall back to Deflate.
+        assert_eq!(pages[1].0, CompressionMethod::Deflate.to_u16());
+        assert_eq!(pages[2].1.as_ref(), Some(&second));
+    }
+}
@@ -0,0 +1,218 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::State;
+use tiny_http::{Method, Request, Server};
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use crate::http::{self, HttpResponse};
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::{square_image_bytes, ErrorWrapper};
+
+// Where the API listens unless told otherwise.
+const DEFAULT_PORT: u16 = 7318;
+// Bigger than any photo worth squaring, small enough not to be a way to fill
+// memory.
+const MAX_REQUEST_BYTES: u64 = 200 * 1024 * 1024;
+// How many requests are handled at once; more wait their turn, so a burst
+// of large uploads can't hold unbounded image data in memory.
+const MAX_WORKERS: usize = 2;
+
+const USAGE: &str = "Squarer API
+
+All paths start with the token the app shows alongside the URL.
+
+POST /<token>/square with a multipart/form-data body of:
+  image    the image file
+  corners  JSON array of {\"x\": ..., \"y\": ...} control points: the four
+           corners, three of them for a parallelogram, two along a line to
+           level, or four or more with a \"target\" each
+  options  (optional) JSON processing options; the app's settings otherwise
+and the squared image comes back in the response body.
+";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiServer {
+    /// Includes the token.
+    url: String,
+    token: String,
+}
+
+struct Running {
+    server: Arc<Server>,
+    url: String,
+    token: String,
+}
+
+/// The API's listener, if it's running, kept in managed state.
+#[derive(Default)]
+pub struct Api {
+    running: Mutex<Option<Running>>,
+}
+
+fn json_field<T: DeserializeOwned>(name: &str, bytes: &[u8]) -> Result<T, ErrorWrapper> {
+    serde_json::from_slice(bytes)
+        .map_err(|e| ErrorWrapper::InvalidInput(format!("Invalid `{name}` field: {e}")))
+}
+
+/// Squares the image in a `POST /square` request, returning it encoded along
+/// with its MIME type.
+fn square(
+    request: &mut Request,
+    settings: &Settings,
+    lens_profiles: &LensProfiles,
+) -> Result<(Vec<u8>, &'static str), ErrorWrapper> {
+    let content_type = http::request_header(request, "Content-Type")
+        .unwrap_or_default()
+        .to_owned();
+    let body = http::read_body(request, MAX_REQUEST_BYTES)?;
+    let fields = http::form_fields(&content_type, &body)?;
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| *value)
+    };
+    let missing = |name: &str| ErrorWrapper::InvalidInput(format!("Missing `{name}` field"));
+    let image = field("image").ok_or_else(|| missing("image"))?;
+    let control_points: Vec<ControlPoint> = json_field(
+        "corners",
+        field("corners").ok_or_else(|| missing("corners"))?,
+    )?;
+    let options: ProcessingOptions = match field("options") {
+        Some(options) => json_field("options", options)?,
+        None => settings.processing_options(),
+    };
+    let mut output = Vec::new();
+    square_image_bytes(
+        image.to_vec(),
+        control_points,
+        options,
+        &settings.decode_limits(),
+        lens_profiles,
+        &CancellationToken::default(),
+        &mut output,
+    )?;
+    // Animations keep their own format, so go by what was written.
+    let mime_type = image::guess_format(&output)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream");
+    Ok((output, mime_type))
+}
+
+/// Whether the request was made to this server by name, and not from a page
+/// in a browser on another site: loopback alone doesn't stop a web page from
+/// reaching the API, or a DNS rebinding from passing it off as the page's
+/// own.
+fn is_local(request: &Request, port: u16) -> bool {
+    let hosts = [format!("127.0.0.1:{port}"), format!("localhost:{port}")];
+    let host_ok =
+        http::request_header(request, "Host").is_some_and(|host| hosts.iter().any(|h| h == host));
+    let origin_ok = http::request_header(request, "Origin").is_none_or(|origin| {
+        origin
+            .strip_prefix("http://")
+            .is_some_and(|origin| hosts.iter().any(|h| h == origin))
+    });
+    host_ok && origin_ok
+}
+
+fn respond(
+    mut request: Request,
+    port: u16,
+    token: &str,
+    settings: &Settings,
+    lens_profiles: &LensProfiles,
+) {
+    if !is_local(&request, port) {
+        let _ = request.respond(http::text(403, "Forbidden"));
+        return;
+    }
+    let path = request.url().split('?').next().unwrap_or_default();
+    let route = path
+        .strip_prefix('/')
+        .and_then(|path| path.strip_prefix(token))
+        .map(str::to_owned);
+    let response: HttpResponse = match (request.method(), route.as_deref()) {
+        (Method::Get, Some("/")) => http::text(200, USAGE),
+        (Method::Post, Some("/square")) => {
+            match crate::catch_panic(|| square(&mut request, settings, lens_profiles)) {
+                Ok((bytes, mime_type)) => HttpResponse::from_data(bytes)
+                    .with_header(http::header("Content-Type", mime_type)),
+                Err(error) => http::error(&error),
+            }
+        }
+        (_, Some("/square")) => http::text(405, "Use POST"),
+        _ => http::text(404, "Not found"),
+    };
+    // The client may have gone away; there's no one else to tell.
+    let _ = request.respond(response);
+}
+
+/// Starts serving the API on `port` (7318 by default) on the loopback
+/// interface only, so tools and scripts on this machine can square images
+/// with the app's pipeline and settings while it runs: see `USAGE`. Every
+/// request needs the token returned here, new each time the API starts, and
+/// requests from web pages are refused. Two are handled at a time. If the
+/// API is already running, its URL and token are returned again.
+#[tauri::command]
+pub fn start_api_server(
+    api: State<Api>,
+    settings: State<Settings>,
+    lens_profiles: State<LensProfiles>,
+    port: Option<u16>,
+) -> Result<ApiServer, ErrorWrapper> {
+    let mut running = api.running.lock().unwrap();
+    if let Some(running) = running.as_ref() {
+        return Ok(ApiServer {
+            url: running.url.clone(),
+            token: running.token.clone(),
+        });
+    }
+    let server = Server::http((Ipv4Addr::LOCALHOST, port.unwrap_or(DEFAULT_PORT)))
+        .map_err(|e| ErrorWrapper::Io(std::io::Error::other(e)))?;
+    let address = server
+        .server_addr()
+        .to_ip()
+        .ok_or_else(|| ErrorWrapper::Io(std::io::Error::other("Not listening on a port")))?;
+    let token = http::random_token()?;
+    let url = format!("http://{address}/{token}/");
+    let server = Arc::new(server);
+    for _ in 0..MAX_WORKERS {
+        let listener = server.clone();
+        let token = token.clone();
+        let settings = settings.inner().clone();
+        let lens_profiles = lens_profiles.inner().clone();
+        std::thread::spawn(move || {
+            // Ends once `stop_api_server` unblocks this worker.
+            while let Ok(request) = listener.recv() {
+                respond(request, address.port(), &token, &settings, &lens_profiles);
+            }
+        });
+    }
+    *running = Some(Running {
+        server,
+        url: url.clone(),
+        token: token.clone(),
+    });
+    Ok(ApiServer { url, token })
+}
+
+/// Stops serving the API. Returns false if it wasn't running.
+#[tauri::command]
+pub fn stop_api_server(api: State<Api>) -> bool {
+    match api.running.lock().unwrap().take() {
+        Some(running) => {
+            // Each unblock wakes one worker.
+            for _ in 0..MAX_WORKERS {
+                running.server.unblock();
+            }
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,130 @@
+use serde::Deserialize;
+use squarer_core::encode::OutputFormat;
+use squarer_core::naming;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::State;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cache::ImageCache;
+use crate::jobs::{JobId, JobRegistry};
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::{run_blocking, square_image_source, ErrorWrapper, ImageSource};
+
+// How archived files are named unless the frontend says otherwise.
+const DEFAULT_NAME_TEMPLATE: &str = "{stem}_{index}.{ext}";
+// Stands in for `{stem}` when the image didn't come from a file.
+const DEFAULT_STEM: &str = "page";
+
+/// An image to square into an archive.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivePage {
+    image: ImageSource,
+    control_points: Vec<ControlPoint>,
+}
+
+/// Writes each `(name, bytes)` pair as a file in a zip at `output_path`.
+/// Encoded images hardly compress any further, so they're stored as is.
+pub fn write_zip(
+    files: impl IntoIterator<Item = Result<(String, Vec<u8>), ErrorWrapper>>,
+    output_path: &Path,
+) -> Result<(), ErrorWrapper> {
+    let mut zip = ZipWriter::new(std::fs::File::create(output_path)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for file in files {
+        let (name, bytes) = file?;
+        zip.start_file(name, options)
+            .map_err(std::io::Error::other)?;
+        zip.write_all(&bytes)?;
+    }
+    zip.finish().map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// Squares every page with the same `options` and writes the results into a
+/// single zip at `path`, as `format` (by default the options' format). Files
+/// are named per `name_template` (see `naming::OutputNaming`),
+/// `{stem}_{index}.{ext}` by default, where `{stem}` is `page` for images
+/// that weren't files. Files that would share a name are auto-numbered.
+/// Can be cancelled with `cancel_job` if given a `job_id`.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn export_archive(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    cache: State<'_, ImageCache>,
+    pages: Vec<ArchivePage>,
+    path: PathBuf,
+    format: Option<OutputFormat>,
+    options: Option<ProcessingOptions>,
+    name_template: Option<String>,
+    job_id: Option<JobId>,
+) -> Result<(), ErrorWrapper> {
+    if pages.is_empty() {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "An archive needs at least one page",
+        )));
+    }
+    let mut options = options.unwrap_or_else(|| settings.processing_options());
+    if let Some(format) = format {
+        options.output_format = format;
+    }
+    let naming = naming::OutputNaming {
+        template: name_template.unwrap_or_else(|| String::from(DEFAULT_NAME_TEMPLATE)),
+        on_collision: naming::Collision::AutoNumber,
+    };
+    naming.validate()?;
+    let started = chrono::Local::now();
+    let job = jobs.register(job_id)?;
+    let limits = settings.decode_limits();
+    let lens_profiles = lens_profiles.inner().clone();
+    let cache = cache.inner().clone();
+    run_blocking(move || {
+        let cancel = job.token();
+        let mut taken = HashSet::new();
+        let files = pages.into_iter().enumerate().map(|(index, page)| {
+            cancel.check()?;
+            let stem = match &page.image {
+                ImageSource::Path(path) => path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned()),
+                _ => None,
+            };
+            let stem = stem.as_deref().unwrap_or(DEFAULT_STEM);
+            let name = naming::file_name(
+                &naming.template,
+                stem,
+                index + 1,
+                &started,
+                options.output_format,
+            );
+            let mut unique = name.clone();
+            let mut number = 2;
+            while !taken.insert(unique.clone()) {
+                unique = naming::numbered(&name, number);
+                number += 1;
+            }
+            let mut bytes = Vec::new();
+            square_image_source(
+                page.image,
+                &cache,
+                page.control_points,
+                options.clone(),
+                &limits,
+                &lens_profiles,
+                cancel,
+                &mut bytes,
+            )?;
+            Ok((unique, bytes))
+        });
+        write_zip(files, &path)
+    })
+    .await
+}
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, State};
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+
+use crate::history::History;
+use crate::jobs::{JobRegistry, JobRequest};
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::decode::DecodeLimits;
+use squarer_core::naming::{Namer, OutputNaming};
+use squarer_core::{ControlPoint, ProcessingOptions};
+
+/// Event emitted after each item of a batch finishes, successfully or not.
+const PROGRESS_EVENT: &str = "batch-progress";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItem {
+    path: PathBuf,
+    control_points: Vec<ControlPoint>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchItemResult {
+    index: usize,
+    path: PathBuf,
+    output_path: Option<PathBuf>,
+    /// Set when the output's name was taken and the naming said to skip it.
+    skipped: bool,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchProgress<'a> {
+    completed: usize,
+    total: usize,
+    item: &'a BatchItemResult,
+}
+
+/// What squaring a batch's items takes, shared by its jobs.
+struct Batch {
+    namer: Namer,
+    options: ProcessingOptions,
+    limits: DecodeLimits,
+    lens_profiles: LensProfiles,
+    history: History,
+    write_sidecar: bool,
+}
+
+impl Batch {
+    /// Squares `item` into the path the namer gives it, if it gives one, the
+    /// same way `process_image_file` would.
+    fn process_item(
+        &self,
+        item: &BatchItem,
+        index: usize,
+        cancel: &CancellationToken,
+    ) -> Result<Option<PathBuf>, ErrorWrapper> {
+        let Some(output_path) =
+            self.namer
+                .output_path(&item.path, index + 1, self.options.output_format)
+        else {
+            return Ok(None);
+        };
+        crate::square_file(
+            &item.path,
+            item.control_points.clone(),
+            &output_path,
+            self.options.clone(),
+            &self.limits,
+            &self.lens_profiles,
+            cancel,
+        )?;
+        crate::record_export(
+            &self.history,
+            self.write_sidecar,
+            &item.path,
+            &item.control_points,
+            &self.options,
+            &output_path,
+        );
+        Ok(Some(output_path))
+    }
+}
+
+/// Squares each image and writes the result into `output_dir`, emitting a
+/// `batch-progress` event as each one completes. Each image is a job on the
+/// queue `submit_job` uses, so at most two run at a time and each can be
+/// stopped with `cancel_job`. A failed or cancelled item doesn't stop the
+/// rest of the batch; its error is reported in its result instead. Outputs
+/// are named per `naming`, by default `<stem>_squared.<ext>` overwriting
+/// whatever's there.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn process_batch(
+    app: AppHandle,
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    history: State<'_, History>,
+    items: Vec<BatchItem>,
+    output_dir: PathBuf,
+    options: Option<ProcessingOptions>,
+    naming: Option<OutputNaming>,
+) -> Result<Vec<BatchItemResult>, ErrorWrapper> {
+    std::fs::create_dir_all(&output_dir)?;
+    let batch = Arc::new(Batch {
+        namer: Namer::new(naming.unwrap_or_default(), &output_dir)?,
+        options: options.unwrap_or_else(|| settings.processing_options()),
+        limits: settings.decode_limits(),
+        lens_profiles: lens_profiles.inner().clone(),
+        history: history.inner().clone(),
+        write_sidecar: settings.write_sidecars(),
+    });
+    let jobs = jobs.inner().clone();
+    crate::run_blocking(move || {
+        let total = items.len();
+        let paths: Vec<PathBuf> = items.iter().map(|item| item.path.clone()).collect();
+        let completed = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = mpsc::channel();
+        for (index, item) in items.into_iter().enumerate() {
+            let request = JobRequest::BatchItem {
+                path: item.path.clone(),
+                output_dir: output_dir.clone(),
+            };
+            let (batch, completed, sender) = (batch.clone(), completed.clone(), sender.clone());
+            let progress_app = app.clone();
+            jobs.submit(
+                app.clone(),
+                request,
+                Box::new(move |cancel| {
+                    // A panic is reported as this item's error like any other.
+                    let outcome = crate::catch_panic(|| batch.process_item(&item, index, cancel));
+                    let result = BatchItemResult {
+                        index,
+                        path: item.path,
+                        output_path: outcome.as_ref().ok().cloned().flatten(),
+                        skipped: matches!(outcome, Ok(None)),
+                        error: outcome.as_ref().err().map(|e| e.to_string()),
+                    };
+                    let progress = BatchProgress {
+                        completed: completed.fetch_add(1, Ordering::SeqCst) + 1,
+                        total,
+                        item: &result,
+                    };
+                    // Progress is informational; don't fail the batch over it.
+                    let _ = progress_app.emit(PROGRESS_EVENT, progress);
+                    let _ = sender.send(result);
+                    outcome.map(|_| ())
+                }),
+            );
+        }
+        // Ends the results once every job has sent its own, or been dropped
+        // unstarted.
+        drop(sender);
+        let mut results: Vec<Option<BatchItemResult>> = vec![None; total];
+        for result in receiver {
+            let index = result.index;
+            results[index] = Some(result);
+        }
+        Ok(results
+            .into_iter()
+            .zip(paths)
+            .enumerate()
+            .map(|(index, (result, path))| {
+                result.unwrap_or_else(|| BatchItemResult {
+                    index,
+                    path,
+                    output_path: None,
+                    skipped: false,
+                    error: Some(ErrorWrapper::Cancelled.to_string()),
+                })
+            })
+            .collect())
+    })
+    .await
+}
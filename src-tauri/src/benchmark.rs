@@ -0,0 +1,214 @@
+use image::{DynamicImage, GenericImageView, RgbImage};
+use serde::Serialize;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::decode::{self, DecodeLimits};
+use squarer_core::encode::{self, OutputFormat};
+use squarer_core::{
+    encode_output, quad_from_points, square_quad, ControlPoint, InterpolationMode,
+    ProcessingOptions,
+};
+use tauri::State;
+
+use std::time::Instant;
+
+use crate::jobs::{JobId, JobRegistry};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+// Widths of the synthetic photos when none are given; they're 4:3.
+const DEFAULT_SIZES: [u32; 3] = [1024, 2048, 4096];
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 8192;
+const DEFAULT_ITERATIONS: u32 = 3;
+const MAX_ITERATIONS: u32 = 100;
+const INTERPOLATIONS: [InterpolationMode; 3] = [
+    InterpolationMode::Nearest,
+    InterpolationMode::Bilinear,
+    InterpolationMode::Bicubic,
+];
+const FORMATS: [OutputFormat; 3] = [OutputFormat::Png, OutputFormat::Jpeg, OutputFormat::Webp];
+// Quality of the synthetic photos, about what a phone camera saves.
+const PHOTO_QUALITY: u8 = 90;
+
+/// How the warp runs.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarpPath {
+    /// On one thread.
+    Cpu,
+    /// Across rayon's thread pool, as the app normally does.
+    Rayon,
+    /// On the GPU, in builds with the `gpu` feature. Without a usable
+    /// adapter this falls back to rayon, so the two come out alike.
+    Gpu,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BenchmarkResult {
+    /// Width of the synthetic photo.
+    size: u32,
+    path: WarpPath,
+    interpolation: InterpolationMode,
+    format: OutputFormat,
+    iterations: u32,
+    /// The mean time to decode, square and encode the photo once.
+    mean_ms: f64,
+    /// Megapixels of the photo squared per second.
+    megapixels_per_second: f64,
+}
+
+/// Scrambles a pixel's coordinates into pseudo-random bits, so the photos
+/// are the same on every run.
+fn noise(x: u32, y: u32) -> u32 {
+    let mut hash = x.wrapping_mul(0x9e37_79b9) ^ y.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0x2c1b_3c6d);
+    hash ^ (hash >> 12)
+}
+
+/// A stand-in for a photo of a document `width` pixels wide: a page of
+/// dark "words" on a noisy table, encoded as a JPEG as a camera would, with
+/// the corners to square it by.
+fn synthetic_photo(width: u32) -> Result<(Vec<u8>, Vec<ControlPoint>), ErrorWrapper> {
+    let height = width * 3 / 4;
+    let page = |x: u32, y: u32| {
+        x > width / 10 && x < width * 9 / 10 && y > height / 12 && y < height * 11 / 12
+    };
+    let image = RgbImage::from_fn(width, height, |x, y| {
+        let grain = (noise(x, y) % 12) as u8;
+        let value = if !page(x, y) {
+            84 + grain
+        } else if !(y / 12).is_multiple_of(2)
+            && !(x / 7).is_multiple_of(9)
+            && !noise(x / 63, y / 12).is_multiple_of(4)
+        {
+            36 + grain
+        } else {
+            238 + grain
+        };
+        image::Rgb([value, value, value.saturating_sub(6)])
+    });
+    let bytes = encode::encode(
+        &DynamicImage::ImageRgb8(image),
+        OutputFormat::Jpeg,
+        PHOTO_QUALITY,
+        encode::DEFAULT_BACKGROUND,
+    )?;
+    let (width, height) = (width as f64, height as f64);
+    // Off square, as if taken at an angle.
+    let corners = vec![
+        ControlPoint::new(width * 0.12, height * 0.10),
+        ControlPoint::new(width * 0.88, height * 0.06),
+        ControlPoint::new(width * 0.93, height * 0.94),
+        ControlPoint::new(width * 0.07, height * 0.90),
+    ];
+    Ok((bytes, corners))
+}
+
+/// Decodes, squares and encodes the photo `iterations` times, returning the
+/// mean time each took in milliseconds.
+fn time_pipeline(
+    photo: &[u8],
+    corners: &[ControlPoint],
+    options: &ProcessingOptions,
+    limits: &DecodeLimits,
+    iterations: u32,
+    cancel: &CancellationToken,
+) -> Result<f64, ErrorWrapper> {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let source = decode::read_image_bytes(photo.to_vec(), limits)?;
+        let quad = quad_from_points(corners.to_vec(), source.image.dimensions())?;
+        let squared = square_quad(&source.image, quad, options, cancel)?;
+        encode_output(&squared, options)?;
+        cancel.check()?;
+    }
+    Ok(start.elapsed().as_secs_f64() * 1000.0 / iterations as f64)
+}
+
+/// Squares synthetic photos `sizes` pixels wide (1024, 2048 and 4096 by
+/// default) `iterations` times each (3 by default) with every interpolation,
+/// output format and warp path this build has, and reports the throughput
+/// of each combination, for comparing how the paths fare on this machine.
+/// The other options come from the settings. Takes a while, so it can be
+/// cancelled with `cancel_job` if given a `job_id`.
+#[tauri::command]
+pub async fn run_benchmark(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    sizes: Option<Vec<u32>>,
+    iterations: Option<u32>,
+    job_id: Option<JobId>,
+) -> Result<Vec<BenchmarkResult>, ErrorWrapper> {
+    let sizes = sizes.unwrap_or_else(|| DEFAULT_SIZES.to_vec());
+    if let Some(size) = sizes
+        .iter()
+        .find(|size| !(MIN_SIZE..=MAX_SIZE).contains(*size))
+    {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Sizes must be between {MIN_SIZE} and {MAX_SIZE}, got {size}"
+        )));
+    }
+    let iterations = iterations.unwrap_or(DEFAULT_ITERATIONS);
+    if !(1..=MAX_ITERATIONS).contains(&iterations) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Iterations must be between 1 and {MAX_ITERATIONS}, got {iterations}"
+        )));
+    }
+    let job = jobs.register(job_id)?;
+    let base = settings.processing_options();
+    let limits = settings.decode_limits();
+    crate::run_blocking(move || {
+        let single_thread = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .map_err(|e| ErrorWrapper::Io(std::io::Error::other(e)))?;
+        let mut paths = vec![WarpPath::Cpu, WarpPath::Rayon];
+        if cfg!(feature = "gpu") {
+            paths.push(WarpPath::Gpu);
+        }
+        let mut results = Vec::new();
+        for &size in &sizes {
+            let (photo, corners) = synthetic_photo(size)?;
+            let megapixels = size as f64 * (size * 3 / 4) as f64 / 1e6;
+            for &path in &paths {
+                for interpolation in INTERPOLATIONS {
+                    for format in FORMATS {
+                        let options = ProcessingOptions {
+                            interpolation,
+                            output_format: format,
+                            use_gpu: matches!(path, WarpPath::Gpu),
+                            ..base.clone()
+                        };
+                        let run = || {
+                            time_pipeline(
+                                &photo,
+                                &corners,
+                                &options,
+                                &limits,
+                                iterations,
+                                job.token(),
+                            )
+                        };
+                        let mean_ms = match path {
+                            WarpPath::Cpu => single_thread.install(run)?,
+                            WarpPath::Rayon | WarpPath::Gpu => run()?,
+                        };
+                        results.push(BenchmarkResult {
+                            size,
+                            path,
+                            interpolation,
+                            format,
+                            iterations,
+                            mean_ms,
+                            megapixels_per_second: megapixels / (mean_ms / 1000.0),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(results)
+    })
+    .await
+}
@@ -0,0 +1,126 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use lru::LruCache;
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use crate::ErrorWrapper;
+
+/// How many decoded images to keep around at once. Full-resolution photos are
+/// large, so this is intentionally small.
+pub const CACHE_CAPACITY: usize = 8;
+// Longest edge of the downscaled copies used for live previews.
+const PREVIEW_MAX_DIMENSION: u32 = 1024;
+
+pub type ImageHandle = u64;
+
+/// Decoded images kept in managed state between commands, so interactive
+/// adjustments don't have to resend and re-decode the source every time.
+/// The least recently used image is evicted once the cache is full. Clones
+/// share the same underlying cache.
+#[derive(Clone)]
+pub struct ImageCache {
+    inner: Arc<Mutex<CacheInner>>,
+}
+
+struct CacheEntry {
+    image: Arc<DynamicImage>,
+    // Downscaled copy for live previews, created on first use.
+    preview: Option<Arc<DynamicImage>>,
+}
+
+struct CacheInner {
+    images: LruCache<ImageHandle, CacheEntry>,
+    next_handle: ImageHandle,
+}
+
+impl ImageCache {
+    pub fn new() -> Self {
+        ImageCache {
+            inner: Arc::new(Mutex::new(CacheInner {
+                images: LruCache::new(NonZeroUsize::new(CACHE_CAPACITY).unwrap()),
+                next_handle: 1,
+            })),
+        }
+    }
+
+    pub fn insert(&self, image: DynamicImage) -> ImageHandle {
+        self.inner.lock().unwrap().insert(image)
+    }
+
+    /// Inserts the images together, returning their handles in the same
+    /// order, so that no other insert lands in between to evict one of them.
+    /// Only the last `CACHE_CAPACITY` of them can be kept.
+    pub fn insert_all(&self, images: Vec<DynamicImage>) -> Vec<ImageHandle> {
+        let mut inner = self.inner.lock().unwrap();
+        images
+            .into_iter()
+            .map(|image| inner.insert(image))
+            .collect()
+    }
+
+    pub fn get(&self, handle: ImageHandle) -> Result<Arc<DynamicImage>, ErrorWrapper> {
+        self.inner
+            .lock()
+            .unwrap()
+            .images
+            .get(&handle)
+            .map(|entry| entry.image.clone())
+            .ok_or_else(|| unknown_handle(handle))
+    }
+
+    /// Returns a copy of the image scaled to fit within `PREVIEW_MAX_DIMENSION`
+    /// (or the image itself if it's already small enough).
+    pub fn get_preview(&self, handle: ImageHandle) -> Result<Arc<DynamicImage>, ErrorWrapper> {
+        let image = {
+            let mut inner = self.inner.lock().unwrap();
+            let entry = inner
+                .images
+                .get(&handle)
+                .ok_or_else(|| unknown_handle(handle))?;
+            if let Some(preview) = &entry.preview {
+                return Ok(preview.clone());
+            }
+            entry.image.clone()
+        };
+        // Resize without holding the lock; at worst two callers both do it.
+        let preview =
+            if image.width() > PREVIEW_MAX_DIMENSION || image.height() > PREVIEW_MAX_DIMENSION {
+                Arc::new(image.resize(
+                    PREVIEW_MAX_DIMENSION,
+                    PREVIEW_MAX_DIMENSION,
+                    FilterType::Triangle,
+                ))
+            } else {
+                image
+            };
+        if let Some(entry) = self.inner.lock().unwrap().images.get_mut(&handle) {
+            entry.preview = Some(preview.clone());
+        }
+        Ok(preview)
+    }
+
+    pub fn remove(&self, handle: ImageHandle) -> bool {
+        self.inner.lock().unwrap().images.pop(&handle).is_some()
+    }
+}
+
+impl CacheInner {
+    fn insert(&mut self, image: DynamicImage) -> ImageHandle {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.images.put(
+            handle,
+            CacheEntry {
+                image: Arc::new(image),
+                preview: None,
+            },
+        );
+        handle
+    }
+}
+
+fn unknown_handle(handle: ImageHandle) -> ErrorWrapper {
+    ErrorWrapper::InvalidInput(format!("Unknown image handle {handle}"))
+}
@@ -0,0 +1,131 @@
+use image::DynamicImage;
+use serde::Serialize;
+use tauri::{AppHandle, State};
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+use squarer_core::{decode, detect, ControlPoint};
+
+/// A photo taken by `capture_and_detect` (or a scan from `acquire_scan`),
+/// already decoded and cached.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Capture {
+    handle: ImageHandle,
+    width: u32,
+    height: u32,
+    /// Suggested corners, or None if no quadrilateral was found.
+    control_points: Option<Vec<ControlPoint>>,
+    confidence: Option<f64>,
+}
+
+impl Capture {
+    /// Looks for a document in `image` and caches it.
+    pub fn detect(cache: &ImageCache, image: DynamicImage) -> Capture {
+        let detection = detect::detect(&image);
+        let (width, height) = (image.width(), image.height());
+        Capture {
+            handle: cache.insert(image),
+            width,
+            height,
+            control_points: detection.as_ref().map(|d| {
+                d.corners
+                    .iter()
+                    .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+                    .collect()
+            }),
+            confidence: detection.map(|d| d.confidence),
+        }
+    }
+
+    pub fn handle(&self) -> ImageHandle {
+        self.handle
+    }
+
+    /// The suggested corners, or by default the corners of the whole image.
+    pub fn corners(&self) -> Vec<ControlPoint> {
+        self.control_points.clone().unwrap_or_else(|| {
+            let (width, height) = (self.width as f64, self.height as f64);
+            vec![
+                ControlPoint::new(0.0, 0.0),
+                ControlPoint::new(width, 0.0),
+                ControlPoint::new(width, height),
+                ControlPoint::new(0.0, height),
+            ]
+        })
+    }
+}
+
+#[cfg(target_os = "android")]
+mod android {
+    use serde::Deserialize;
+    use tauri::plugin::{Builder, PluginHandle, TauriPlugin};
+    use tauri::{AppHandle, Manager, Wry};
+
+    use std::path::PathBuf;
+
+    use crate::ErrorWrapper;
+
+    /// The Kotlin side, in `gen/android/.../CameraPlugin.kt`.
+    struct Camera(PluginHandle<Wry>);
+
+    #[derive(Deserialize)]
+    struct Photo {
+        path: PathBuf,
+    }
+
+    pub fn init() -> TauriPlugin<Wry> {
+        Builder::new("camera")
+            .setup(|app, api| {
+                let handle = api.register_android_plugin("com.revfad.squarer", "CameraPlugin")?;
+                app.manage(Camera(handle));
+                Ok(())
+            })
+            .build()
+    }
+
+    /// Opens the system camera and waits for the user to take a photo,
+    /// returning the encoded image.
+    pub fn take_picture(app: &AppHandle) -> Result<Vec<u8>, ErrorWrapper> {
+        let photo: Photo = app
+            .state::<Camera>()
+            .0
+            .run_mobile_plugin("takePicture", ())
+            .map_err(|e| ErrorWrapper::Camera(e.to_string()))?;
+        let bytes = std::fs::read(&photo.path)?;
+        // The photo is a temporary file; it lives on in the cache from here.
+        let _ = std::fs::remove_file(&photo.path);
+        Ok(bytes)
+    }
+}
+
+#[cfg(target_os = "android")]
+pub use android::init;
+#[cfg(target_os = "android")]
+use android::take_picture;
+
+#[cfg(not(target_os = "android"))]
+fn take_picture(_app: &AppHandle) -> Result<Vec<u8>, ErrorWrapper> {
+    Err(ErrorWrapper::Unsupported(String::from(
+        "Camera capture is only available on Android",
+    )))
+}
+
+/// Takes a photo with the device camera, caches it, and looks for a
+/// document in it, all in one round trip.
+#[tauri::command]
+pub async fn capture_and_detect(
+    app: AppHandle,
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+) -> Result<Capture, ErrorWrapper> {
+    let limits = settings.decode_limits();
+    let cache = cache.inner().clone();
+    crate::run_blocking(move || {
+        let bytes = take_picture(&app)?;
+        let image = decode::read_image_bytes(bytes, &limits)?.image;
+        Ok(Capture::detect(&cache, image))
+    })
+    .await
+}
@@ -0,0 +1,79 @@
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use tauri::State;
+
+use std::borrow::Cow;
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{quad_from_points, square_quad, ControlPoint, ProcessingOptions};
+
+fn clipboard_error(error: arboard::Error) -> ErrorWrapper {
+    ErrorWrapper::Clipboard(error.to_string())
+}
+
+/// The image on the clipboard, e.g. a screenshot.
+pub fn read_image() -> Result<DynamicImage, ErrorWrapper> {
+    let pasted = arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_image())
+        .map_err(clipboard_error)?;
+    let rgba = RgbaImage::from_raw(
+        pasted.width as u32,
+        pasted.height as u32,
+        pasted.bytes.into_owned(),
+    )
+    .ok_or_else(|| ErrorWrapper::Clipboard(String::from("The clipboard image is malformed")))?;
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// Puts an image on the clipboard. The OS decides the clipboard format; it's
+/// usually PNG.
+pub fn write_image(rgba: RgbaImage) -> Result<(), ErrorWrapper> {
+    let data = arboard::ImageData {
+        width: rgba.width() as usize,
+        height: rgba.height() as usize,
+        bytes: Cow::Owned(rgba.into_raw()),
+    };
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_image(data))
+        .map_err(clipboard_error)
+}
+
+/// Caches the image on the clipboard (e.g. a screenshot) and returns its
+/// handle.
+#[tauri::command]
+pub async fn paste_image_from_clipboard(
+    cache: State<'_, ImageCache>,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let image = crate::run_blocking(read_image).await?;
+    Ok(cache.insert(image))
+}
+
+/// Puts the cached image on the clipboard, squared first if `control_points`
+/// are given.
+#[tauri::command]
+pub async fn copy_result_to_clipboard(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    control_points: Option<Vec<ControlPoint>>,
+    options: Option<ProcessingOptions>,
+) -> Result<(), ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    crate::run_blocking(move || {
+        let rgba = match control_points {
+            Some(control_points) => {
+                let control_points = options
+                    .coordinate_space
+                    .to_pixels(control_points, image.dimensions());
+                let quad = quad_from_points(control_points, image.dimensions())?;
+                square_quad(&image, quad, &options, &CancellationToken::default())?.to_rgba8()
+            }
+            None => image.to_rgba8(),
+        };
+        write_image(rgba)
+    })
+    .await
+}
@@ -0,0 +1,48 @@
+use serde::Serialize;
+
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Decode,
+    Detect,
+    Warp,
+    Encode,
+}
+
+/// How long each stage of squaring an image took, for commands given the
+/// `diagnostics` flag, so slow or pathological inputs can be spotted from
+/// the UI or a bug report. Stages that didn't run are None. An animation's
+/// frames are encoded as they're squared, so that counts as warping.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timings {
+    decode_ms: Option<f64>,
+    detect_ms: Option<f64>,
+    warp_ms: Option<f64>,
+    encode_ms: Option<f64>,
+    /// The most decoded and encoded image data held at once, in bytes.
+    peak_buffer_bytes: u64,
+}
+
+impl Timings {
+    /// Runs `f`, adding the time it takes to `stage`'s.
+    pub fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+        let total = match stage {
+            Stage::Decode => &mut self.decode_ms,
+            Stage::Detect => &mut self.detect_ms,
+            Stage::Warp => &mut self.warp_ms,
+            Stage::Encode => &mut self.encode_ms,
+        };
+        *total.get_or_insert(0.0) += elapsed;
+        result
+    }
+
+    /// Notes that `bytes` of image data are held at once.
+    pub fn hold(&mut self, bytes: usize) {
+        self.peak_buffer_bytes = self.peak_buffer_bytes.max(bytes as u64);
+    }
+}
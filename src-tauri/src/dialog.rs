@@ -0,0 +1,66 @@
+use tauri::{AppHandle, State};
+use tauri_plugin_dialog::{DialogExt, FileDialogBuilder, FilePath};
+
+use std::path::{Path, PathBuf};
+
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+use squarer_core::decode;
+use squarer_core::encode::OutputFormat;
+
+/// A file dialog that starts in the last-used directory.
+fn file_dialog(app: &AppHandle, settings: &Settings) -> FileDialogBuilder<tauri::Wry> {
+    let dialog = app.dialog().file();
+    match settings.preferences().last_directory {
+        Some(directory) => dialog.set_directory(directory),
+        None => dialog,
+    }
+}
+
+/// The chosen file's path, or None if the dialog was cancelled. Its directory
+/// becomes the last-used one.
+fn remember_choice(
+    settings: &Settings,
+    file: Option<FilePath>,
+) -> Result<Option<PathBuf>, ErrorWrapper> {
+    let Some(file) = file else {
+        return Ok(None);
+    };
+    let path = file.into_path().map_err(|e| {
+        ErrorWrapper::InvalidInput(format!("The chosen file has no local path: {e}"))
+    })?;
+    if let Some(directory) = path.parent() {
+        settings
+            .update(|preferences| preferences.last_directory = Some(directory.to_path_buf()))?;
+    }
+    Ok(Some(path))
+}
+
+/// Shows an open dialog for any image this build can decode.
+#[tauri::command]
+pub async fn choose_input_file(
+    app: AppHandle,
+    settings: State<'_, Settings>,
+) -> Result<Option<PathBuf>, ErrorWrapper> {
+    let extensions = decode::supported_extensions();
+    let dialog = file_dialog(&app, &settings).add_filter("Images", &extensions);
+    let settings = settings.inner().clone();
+    crate::run_blocking(move || remember_choice(&settings, dialog.blocking_pick_file())).await
+}
+
+/// Shows a save dialog for `format`, suggesting `default_name` with that
+/// format's extension.
+#[tauri::command]
+pub async fn choose_output_file(
+    app: AppHandle,
+    settings: State<'_, Settings>,
+    default_name: String,
+    format: OutputFormat,
+) -> Result<Option<PathBuf>, ErrorWrapper> {
+    let file_name = Path::new(&default_name).with_extension(format.extension());
+    let dialog = file_dialog(&app, &settings)
+        .add_filter(format.extension().to_ascii_uppercase(), format.extensions())
+        .set_file_name(file_name.to_string_lossy());
+    let settings = settings.inner().clone();
+    crate::run_blocking(move || remember_choice(&settings, dialog.blocking_save_file())).await
+}
@@ -0,0 +1,61 @@
+use squarer_core::encode::OutputFormat;
+use tauri::{AppHandle, State};
+use tauri_plugin_opener::OpenerExt;
+
+use std::path::PathBuf;
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+fn opener_error(error: tauri_plugin_opener::Error) -> ErrorWrapper {
+    ErrorWrapper::Io(std::io::Error::other(error))
+}
+
+/// Encodes a cached image, e.g. a squared result, per the settings (as
+/// `format` if given) into a temp file and opens that in the OS's default
+/// app for the format, for viewing or touching up in an image editor.
+/// Returns the temp file's path.
+#[tauri::command]
+pub async fn open_result_externally(
+    app: AppHandle,
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    format: Option<OutputFormat>,
+) -> Result<PathBuf, ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let mut options = settings.processing_options();
+    if let Some(format) = format {
+        options.output_format = format;
+    }
+    crate::run_blocking(move || {
+        let bytes = squarer_core::encode_output(&image, &options)?;
+        // Named per handle, so opening the same result again reuses the file
+        // rather than filling the temp directory.
+        let path = std::env::temp_dir().join(format!(
+            "squarer-{}-{handle}.{}",
+            std::process::id(),
+            options.output_format.extension()
+        ));
+        std::fs::write(&path, bytes)?;
+        app.opener()
+            .open_path(path.to_string_lossy(), None::<&str>)
+            .map_err(opener_error)?;
+        Ok(path)
+    })
+    .await
+}
+
+/// Opens the folder holding `path` in the file manager with the file
+/// selected, e.g. once an export has finished. Desktop only.
+#[tauri::command]
+pub fn reveal_in_folder(app: AppHandle, path: PathBuf) -> Result<(), ErrorWrapper> {
+    if !path.exists() {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "{} doesn't exist",
+            path.display()
+        )));
+    }
+    app.opener().reveal_item_in_dir(path).map_err(opener_error)
+}
@@ -0,0 +1,102 @@
+use serde::Serialize;
+use squarer_core::decode;
+use tauri::{AppHandle, Emitter, Manager};
+
+use std::path::{Path, PathBuf};
+
+use crate::cache::ImageCache;
+use crate::camera::Capture;
+use crate::pages::{PageId, Pages};
+use crate::settings::Settings;
+use crate::{decode_image_file, protocol, ErrorWrapper};
+
+/// Event emitted with a `ReadyFile` for each image dropped on a window.
+const READY_EVENT: &str = "file-ready";
+/// Event emitted with a `RejectedFile` for each dropped file that couldn't
+/// be opened.
+const REJECTED_EVENT: &str = "file-rejected";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyFile {
+    path: PathBuf,
+    /// The decoded image and the document found in it, as
+    /// `capture_and_detect` returns.
+    #[serde(flatten)]
+    capture: Capture,
+    /// Where an `<img>` can show the image from.
+    preview_url: String,
+    /// The page it was added as, when several files were dropped at once.
+    page_id: Option<PageId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedFile {
+    path: PathBuf,
+    error: String,
+}
+
+/// Checks a dropped file is an image this build can open, then decodes and
+/// caches it and looks for the document in it.
+fn open_file(app: &AppHandle, path: &Path) -> Result<Capture, ErrorWrapper> {
+    if !decode::is_supported_path(path) {
+        return Err(ErrorWrapper::Unsupported(format!(
+            "{} isn't an image Squarer can open",
+            path.display()
+        )));
+    }
+    // Reading the headers is enough to turn away files that aren't what
+    // their extension says, or are too big, before decoding anything.
+    let limits = app.state::<Settings>().decode_limits();
+    let info = decode::probe_image_file(path)?;
+    limits.check(
+        info.width,
+        info.height,
+        u64::from(info.width) * u64::from(info.height) * 4,
+    )?;
+    let image = decode_image_file(path, &limits)?;
+    Ok(Capture::detect(&app.state::<ImageCache>(), image))
+}
+
+/// Opens files dropped on a window off the main thread, emitting a
+/// `file-ready` event for each image ready to edit and a `file-rejected`
+/// event for each file that isn't one. Dropping several files at once adds
+/// the images to the project's pages too, in the order given, with the
+/// corners found in each (or its whole frame).
+pub fn ingest(app: &AppHandle, paths: Vec<PathBuf>) {
+    let app = app.clone();
+    std::thread::spawn(move || {
+        let as_pages = paths.len() > 1;
+        for path in paths {
+            let _ = match open_file(&app, &path) {
+                Ok(capture) => {
+                    let page_id = as_pages
+                        .then(|| {
+                            let image = app.state::<ImageCache>().get(capture.handle()).ok()?;
+                            Some(app.state::<Pages>().push(
+                                image,
+                                capture.handle(),
+                                capture.corners(),
+                            ))
+                        })
+                        .flatten();
+                    let ready = ReadyFile {
+                        path,
+                        preview_url: protocol::preview_url(capture.handle()),
+                        capture,
+                        page_id,
+                    };
+                    app.emit(READY_EVENT, ready)
+                }
+                Err(error) => app.emit(
+                    REJECTED_EVENT,
+                    RejectedFile {
+                        path,
+                        error: error.to_string(),
+                    },
+                ),
+            };
+        }
+    });
+}
@@ -0,0 +1,244 @@
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::{run_blocking, ErrorWrapper};
+
+// Where the history is kept, in the app's data directory.
+pub const HISTORY_FILE: &str = "history.sqlite3";
+// How many items `get_history` returns by default.
+const DEFAULT_HISTORY_LIMIT: u32 = 100;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY,
+    source_path TEXT NOT NULL,
+    source_hash TEXT NOT NULL,
+    control_points TEXT NOT NULL,
+    options TEXT NOT NULL,
+    output_path TEXT NOT NULL,
+    processed_at INTEGER NOT NULL
+)";
+const COLUMNS: &str =
+    "id, source_path, source_hash, control_points, options, output_path, processed_at";
+
+/// A file that was squared, with everything needed to square it again.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryItem {
+    id: i64,
+    source_path: PathBuf,
+    /// SHA-256 of the source file, in hex, for telling whether it's changed.
+    source_hash: String,
+    control_points: Vec<ControlPoint>,
+    options: ProcessingOptions,
+    output_path: PathBuf,
+    /// When it was processed, in seconds since the Unix epoch.
+    processed_at: i64,
+}
+
+fn database_error(error: rusqlite::Error) -> ErrorWrapper {
+    ErrorWrapper::Io(std::io::Error::other(format!("History database: {error}")))
+}
+
+fn hash_file(path: &Path) -> Result<String, ErrorWrapper> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn json_column<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let json: String = row.get(index)?;
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn read_item(row: &Row) -> rusqlite::Result<HistoryItem> {
+    Ok(HistoryItem {
+        id: row.get(0)?,
+        source_path: PathBuf::from(row.get::<_, String>(1)?),
+        source_hash: row.get(2)?,
+        control_points: json_column(row, 3)?,
+        options: json_column(row, 4)?,
+        output_path: PathBuf::from(row.get::<_, String>(5)?),
+        processed_at: row.get(6)?,
+    })
+}
+
+/// The record of processed files, kept in managed state. Clones share the
+/// same database. Without one (if it can't be opened) nothing is recorded.
+#[derive(Clone)]
+pub struct History {
+    connection: Option<Arc<Mutex<Connection>>>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it if need be. Without a path
+    /// it's kept in memory only.
+    pub fn open(path: Option<PathBuf>) -> History {
+        let connection = match path {
+            Some(path) => {
+                if let Some(directory) = path.parent() {
+                    let _ = std::fs::create_dir_all(directory);
+                }
+                Connection::open(path)
+            }
+            None => Connection::open_in_memory(),
+        };
+        let connection = connection
+            .and_then(|connection| connection.execute(SCHEMA, []).map(|_| connection))
+            .ok();
+        History {
+            connection: connection.map(|connection| Arc::new(Mutex::new(connection))),
+        }
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, ErrorWrapper> {
+        match &self.connection {
+            Some(connection) => Ok(connection.lock().unwrap()),
+            None => Err(ErrorWrapper::Unsupported(String::from(
+                "The history database couldn't be opened",
+            ))),
+        }
+    }
+
+    /// Records that `source` was squared into `output`. Failing to record it
+    /// doesn't undo the export, so callers can ignore the result.
+    pub fn record(
+        &self,
+        source: &Path,
+        control_points: &[ControlPoint],
+        options: &ProcessingOptions,
+        output: &Path,
+    ) -> Result<i64, ErrorWrapper> {
+        let hash = hash_file(source)?;
+        let source = std::fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let output = std::fs::canonicalize(output).unwrap_or_else(|_| output.to_path_buf());
+        let processed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let connection = self.connection()?;
+        connection
+            .execute(
+                "INSERT INTO history (source_path, source_hash, control_points, options, output_path, processed_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    source.to_string_lossy(),
+                    hash,
+                    serde_json::to_string(control_points).map_err(std::io::Error::other)?,
+                    serde_json::to_string(options).map_err(std::io::Error::other)?,
+                    output.to_string_lossy(),
+                    processed_at,
+                ],
+            )
+            .map_err(database_error)?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// The most recently processed items first.
+    pub fn items(&self, limit: u32, offset: u32) -> Result<Vec<HistoryItem>, ErrorWrapper> {
+        let connection = self.connection()?;
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT {COLUMNS} FROM history ORDER BY id DESC LIMIT ?1 OFFSET ?2"
+            ))
+            .map_err(database_error)?;
+        statement
+            .query_map(params![limit, offset], read_item)
+            .and_then(Iterator::collect)
+            .map_err(database_error)
+    }
+
+    pub fn item(&self, id: i64) -> Result<Option<HistoryItem>, ErrorWrapper> {
+        let connection = self.connection()?;
+        connection
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM history WHERE id = ?1"),
+                params![id],
+                read_item,
+            )
+            .optional()
+            .map_err(database_error)
+    }
+}
+
+/// The processed files on record, newest first: `limit` of them (100 by
+/// default), skipping the first `offset`.
+#[tauri::command]
+pub async fn get_history(
+    history: State<'_, History>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<Vec<HistoryItem>, ErrorWrapper> {
+    let history = history.inner().clone();
+    run_blocking(move || {
+        history.items(
+            limit.unwrap_or(DEFAULT_HISTORY_LIMIT),
+            offset.unwrap_or_default(),
+        )
+    })
+    .await
+}
+
+/// Squares the source of a history item again with the same control points,
+/// with `options` if given instead of the ones used then. The result goes to
+/// `output_path`, or by default replaces the old export (under the new
+/// format's extension). Fails if the source has changed since, since the
+/// control points would no longer fit it. Returns where the result went.
+#[tauri::command]
+pub async fn reprocess_history_item(
+    history: State<'_, History>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    id: i64,
+    options: Option<ProcessingOptions>,
+    output_path: Option<PathBuf>,
+) -> Result<PathBuf, ErrorWrapper> {
+    let history = history.inner().clone();
+    let limits = settings.decode_limits();
+    let write_sidecar = settings.write_sidecars();
+    let lens_profiles = lens_profiles.inner().clone();
+    run_blocking(move || {
+        let item = history
+            .item(id)?
+            .ok_or_else(|| ErrorWrapper::InvalidInput(format!("No history item with ID {id}")))?;
+        if hash_file(&item.source_path)? != item.source_hash {
+            return Err(ErrorWrapper::InvalidInput(format!(
+                "{} has changed since it was processed",
+                item.source_path.display()
+            )));
+        }
+        let options = options.unwrap_or(item.options);
+        let output_path = output_path
+            .unwrap_or_else(|| crate::replacement_output(&item.output_path, options.output_format));
+        crate::square_file(
+            &item.source_path,
+            item.control_points.clone(),
+            &output_path,
+            options.clone(),
+            &limits,
+            &lens_profiles,
+            &CancellationToken::default(),
+        )?;
+        crate::record_export(
+            &history,
+            write_sidecar,
+            &item.source_path,
+            &item.control_points,
+            &options,
+            &output_path,
+        );
+        Ok(output_path)
+    })
+    .await
+}
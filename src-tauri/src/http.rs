@@ -0,0 +1,123 @@
+use tiny_http::{Header, Request, Response};
+
+use std::io::{Cursor, Read};
+
+use crate::ErrorWrapper;
+
+pub type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// 128 bits from the OS's secure random number generator, in hex, for URLs
+/// and headers no one else should be able to guess.
+pub fn random_token() -> Result<String, ErrorWrapper> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| ErrorWrapper::Io(std::io::Error::other(e)))?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+pub fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Reads the whole body, refusing one longer than `max_bytes` rather than
+/// buffering it.
+pub fn read_body(request: &mut Request, max_bytes: u64) -> Result<Vec<u8>, ErrorWrapper> {
+    let too_large =
+        || ErrorWrapper::InvalidInput(format!("Uploads are limited to {max_bytes} bytes"));
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > max_bytes)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_bytes + 1)
+        .read_to_end(&mut body)?;
+    if body.len() as u64 > max_bytes {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+pub fn text(status: u16, message: &str) -> HttpResponse {
+    Response::from_string(message)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "text/plain; charset=utf-8"))
+}
+
+/// A client error for what the client sent, since that's theirs to fix, and
+/// a server error otherwise.
+pub fn error(error: &ErrorWrapper) -> HttpResponse {
+    let status = match error {
+        ErrorWrapper::InvalidInput(_)
+        | ErrorWrapper::Squaring(_)
+        | ErrorWrapper::Image(_)
+        | ErrorWrapper::ImageTooLarge(_) => 400,
+        ErrorWrapper::Unsupported(_) => 415,
+        _ => 500,
+    };
+    text(status, &error.to_string())
+}
+
+/// The value of a request header, if it was sent.
+pub fn request_header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str())
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+/// Splits a `multipart/form-data` body into its named fields, given the
+/// request's `Content-Type` (which holds the boundary between them).
+pub fn form_fields<'a>(
+    content_type: &str,
+    body: &'a [u8],
+) -> Result<Vec<(String, &'a [u8])>, ErrorWrapper> {
+    let malformed =
+        || ErrorWrapper::InvalidInput(String::from("Malformed multipart/form-data body"));
+    let boundary = content_type
+        .split(';')
+        .find_map(|parameter| parameter.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .ok_or_else(|| {
+            ErrorWrapper::InvalidInput(String::from("Expected a multipart/form-data body"))
+        })?;
+    let delimiter = format!("--{boundary}").into_bytes();
+    let mut fields = Vec::new();
+    let mut start = find(body, &delimiter, 0).ok_or_else(malformed)? + delimiter.len();
+    // The last delimiter is followed by `--`.
+    while !body[start..].starts_with(b"--") {
+        let end = find(body, &delimiter, start).ok_or_else(malformed)?;
+        let part = &body[start..end];
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let headers_end = find(part, b"\r\n\r\n", 0).ok_or_else(malformed)?;
+        let headers = String::from_utf8_lossy(&part[..headers_end]);
+        let name = headers
+            .lines()
+            .filter(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .find_map(|line| {
+                line.split(';')
+                    .find_map(|parameter| parameter.trim().strip_prefix("name="))
+                    .map(|name| name.trim_matches('"').to_owned())
+            });
+        if let Some(name) = name {
+            fields.push((name, &part[headers_end + 4..]));
+        }
+        start = end + delimiter.len();
+    }
+    Ok(fields)
+}
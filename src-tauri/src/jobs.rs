@@ -0,0 +1,373 @@
+use serde::{Deserialize, Serialize};
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::{AppHandle, Emitter, State};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::history::History;
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+/// Chosen by the frontend, so it can cancel a job before the command returns,
+/// or given out by `submit_job`.
+pub type JobId = u64;
+
+/// Event emitted with a queued job's `JobInfo` whenever its state changes.
+const STATUS_EVENT: &str = "job-status";
+// Queued jobs run at most this many at a time. Each one already spreads its
+// warp across every core, so more would only multiply the memory in use.
+const MAX_WORKERS: usize = 2;
+// Finished jobs are remembered for `job_status` until there are more than
+// this many, then the oldest are forgotten.
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// What a queued job does.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "snake_case",
+    rename_all_fields = "camelCase"
+)]
+pub enum JobRequest {
+    /// Squares an image file and writes the result to disk, as
+    /// `process_image_file` does.
+    ProcessFile {
+        path: PathBuf,
+        control_points: Vec<ControlPoint>,
+        output_path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        options: Option<ProcessingOptions>,
+    },
+    /// One image of a `process_batch`, squared into `output_dir`.
+    #[serde(skip_deserializing)]
+    BatchItem { path: PathBuf, output_dir: PathBuf },
+    /// An image that appeared in the watched folder (see
+    /// `configure_watch_folder`).
+    #[serde(skip_deserializing)]
+    WatchFile { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    id: JobId,
+    state: JobState,
+    request: JobRequest,
+    error: Option<String>,
+}
+
+pub type Work = Box<dyn FnOnce(&CancellationToken) -> Result<(), ErrorWrapper> + Send>;
+
+struct QueuedJob {
+    info: JobInfo,
+    token: CancellationToken,
+    // Taken by the worker that runs the job.
+    work: Option<Work>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    // Cancellation tokens of the commands currently running with an ID.
+    running: HashMap<JobId, CancellationToken>,
+    queued: BTreeMap<JobId, QueuedJob>,
+    pending: VecDeque<JobId>,
+    workers: usize,
+    next_id: JobId,
+}
+
+impl Jobs {
+    fn in_use(&self, id: JobId) -> bool {
+        self.running.contains_key(&id) || self.queued.contains_key(&id)
+    }
+
+    /// Forgets the oldest finished jobs beyond `MAX_FINISHED_JOBS`.
+    fn prune(&mut self) {
+        let finished: Vec<JobId> = self
+            .queued
+            .iter()
+            .filter(|(_, job)| job.info.state.is_finished())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &finished[..finished.len().saturating_sub(MAX_FINISHED_JOBS)] {
+            self.queued.remove(id);
+        }
+    }
+}
+
+/// The cancellation tokens of running commands, and the queue of jobs
+/// submitted to run in the background, kept in managed state. Clones share
+/// the same jobs.
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+impl JobRegistry {
+    /// Registers a job for the lifetime of the returned guard, which can be
+    /// moved to whichever thread does the work. Work without an ID still gets a
+    /// token, it just can't be cancelled. Fails if the ID is already in use.
+    pub fn register(&self, id: Option<JobId>) -> Result<JobGuard, ErrorWrapper> {
+        let token = CancellationToken::default();
+        if let Some(id) = id {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.in_use(id) {
+                return Err(ErrorWrapper::InvalidInput(format!(
+                    "Job ID {id} is already in use"
+                )));
+            }
+            jobs.running.insert(id, token.clone());
+        }
+        Ok(JobGuard {
+            jobs: self.jobs.clone(),
+            id,
+            token,
+        })
+    }
+
+    /// Asks a running command or queued job to stop; a job that hasn't
+    /// started yet is cancelled straight away. Returns false if no such job
+    /// is running or waiting.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(token) = jobs.running.get(&id) {
+            token.cancel();
+            return true;
+        }
+        let Some(job) = jobs.queued.get_mut(&id) else {
+            return false;
+        };
+        match job.info.state {
+            JobState::Queued => {
+                job.token.cancel();
+                job.info.state = JobState::Cancelled;
+                job.work = None;
+                jobs.pending.retain(|&pending| pending != id);
+                jobs.prune();
+                true
+            }
+            JobState::Running => {
+                job.token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.queued.get(&id).map(|job| job.info.clone())
+    }
+
+    /// Every queued job that's waiting, running or recently finished, oldest
+    /// first.
+    pub fn list(&self) -> Vec<JobInfo> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.queued.values().map(|job| job.info.clone()).collect()
+    }
+
+    /// Queues `work` to run on one of the workers, starting another if
+    /// fewer than `MAX_WORKERS` are busy. Work that's cancelled before it
+    /// starts is dropped without running.
+    pub fn submit(&self, app: AppHandle, request: JobRequest, work: Work) -> JobId {
+        let (id, info, start_worker) = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let mut id = jobs.next_id.max(1);
+            while jobs.in_use(id) {
+                id += 1;
+            }
+            jobs.next_id = id + 1;
+            let info = JobInfo {
+                id,
+                state: JobState::Queued,
+                request,
+                error: None,
+            };
+            jobs.queued.insert(
+                id,
+                QueuedJob {
+                    info: info.clone(),
+                    token: CancellationToken::default(),
+                    work: Some(work),
+                },
+            );
+            jobs.pending.push_back(id);
+            let start_worker = jobs.workers < MAX_WORKERS;
+            if start_worker {
+                jobs.workers += 1;
+            }
+            (id, info, start_worker)
+        };
+        let _ = app.emit(STATUS_EVENT, &info);
+        if start_worker {
+            let registry = self.clone();
+            std::thread::spawn(move || registry.work(&app));
+        }
+        id
+    }
+
+    /// Runs queued jobs one after another until there are none left.
+    fn work(&self, app: &AppHandle) {
+        loop {
+            let (id, work, token, info) = {
+                let mut jobs = self.jobs.lock().unwrap();
+                let Some(id) = jobs.pending.pop_front() else {
+                    jobs.workers -= 1;
+                    return;
+                };
+                let job = jobs.queued.get_mut(&id).unwrap();
+                job.info.state = JobState::Running;
+                (id, job.work.take(), job.token.clone(), job.info.clone())
+            };
+            let _ = app.emit(STATUS_EVENT, &info);
+            let result = match work {
+                // A panic would otherwise end the worker, leaving the job
+                // running forever and the rest of the queue unserved.
+                Some(work) => crate::catch_panic(|| work(&token)),
+                None => Ok(()),
+            };
+            let info = {
+                let mut jobs = self.jobs.lock().unwrap();
+                let Some(job) = jobs.queued.get_mut(&id) else {
+                    continue;
+                };
+                (job.info.state, job.info.error) = match result {
+                    Ok(()) => (JobState::Completed, None),
+                    Err(ErrorWrapper::Cancelled) => (JobState::Cancelled, None),
+                    Err(e) => (JobState::Failed, Some(e.to_string())),
+                };
+                let info = job.info.clone();
+                jobs.prune();
+                info
+            };
+            let _ = app.emit(STATUS_EVENT, &info);
+        }
+    }
+}
+
+pub struct JobGuard {
+    jobs: Arc<Mutex<Jobs>>,
+    id: Option<JobId>,
+    token: CancellationToken,
+}
+
+impl JobGuard {
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.jobs.lock().unwrap().running.remove(&id);
+        }
+    }
+}
+
+/// Queues the request to run in the background and returns its ID at once.
+/// Its progress is reported with `job-status` events, and can be checked
+/// with `job_status` or stopped with `cancel_job`. At most `MAX_WORKERS`
+/// jobs run at a time, so submitting many exports at once doesn't run out
+/// of memory.
+#[tauri::command]
+pub fn submit_job(
+    app: AppHandle,
+    jobs: State<JobRegistry>,
+    settings: State<Settings>,
+    lens_profiles: State<LensProfiles>,
+    history: State<History>,
+    request: JobRequest,
+) -> JobId {
+    let limits = settings.decode_limits();
+    let lens_profiles = lens_profiles.inner().clone();
+    let history = history.inner().clone();
+    let write_sidecar = settings.write_sidecars();
+    let work: Work = match request.clone() {
+        JobRequest::ProcessFile {
+            path,
+            control_points,
+            output_path,
+            options,
+        } => {
+            let options = options.unwrap_or_else(|| settings.processing_options());
+            Box::new(move |cancel| {
+                crate::square_file(
+                    &path,
+                    control_points.clone(),
+                    &output_path,
+                    options.clone(),
+                    &limits,
+                    &lens_profiles,
+                    cancel,
+                )?;
+                crate::record_export(
+                    &history,
+                    write_sidecar,
+                    &path,
+                    &control_points,
+                    &options,
+                    &output_path,
+                );
+                Ok(())
+            })
+        }
+        // These are only queued by the app itself.
+        JobRequest::BatchItem { .. } | JobRequest::WatchFile { .. } => Box::new(|_| {
+            Err(ErrorWrapper::InvalidInput(String::from(
+                "Batch and watch-folder jobs can't be submitted directly",
+            )))
+        }),
+    };
+    jobs.submit(app, request, work)
+}
+
+/// Asks a running command or queued job to stop. Returns false if no such
+/// job is running or waiting.
+#[tauri::command]
+pub fn cancel_job(app: AppHandle, jobs: State<JobRegistry>, job_id: JobId) -> bool {
+    let cancelled = jobs.cancel(job_id);
+    // A job that hadn't started is cancelled already; one that had reports
+    // it once it stops.
+    if let Some(info) = jobs
+        .status(job_id)
+        .filter(|info| info.state == JobState::Cancelled)
+    {
+        let _ = app.emit(STATUS_EVENT, &info);
+    }
+    cancelled
+}
+
+/// The state of a job from `submit_job`, or null if there's no such job (or
+/// it finished long enough ago to have been forgotten).
+#[tauri::command]
+pub fn job_status(jobs: State<JobRegistry>, job_id: JobId) -> Option<JobInfo> {
+    jobs.status(job_id)
+}
+
+#[tauri::command]
+pub fn list_jobs(jobs: State<JobRegistry>) -> Vec<JobInfo> {
+    jobs.list()
+}
@@ -0,0 +1,147 @@
+use serde::Deserialize;
+use squarer_core::lens::{self, LensDistortion, LensProfile};
+use squarer_core::ProcessingOptions;
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::ErrorWrapper;
+
+// Where imported profiles are kept, in the app's config directory.
+pub const LENS_PROFILES_FILE: &str = "lens_profiles.json";
+
+/// The lens profiles the user has imported, kept in managed state and saved
+/// to disk whenever they change. Clones share the same profiles.
+#[derive(Clone)]
+pub struct LensProfiles {
+    profiles: Arc<RwLock<Vec<LensProfile>>>,
+    path: Option<PathBuf>,
+}
+
+/// A profile file holds either one profile or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProfileFile {
+    One(LensProfile),
+    Many(Vec<LensProfile>),
+}
+
+impl LensProfiles {
+    /// Loads the profiles saved at `path`, if there are any (and they can be
+    /// read). Without a path they're kept in memory only.
+    pub fn load(path: Option<PathBuf>) -> LensProfiles {
+        let profiles = path
+            .as_deref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        LensProfiles {
+            profiles: Arc::new(RwLock::new(profiles)),
+            path,
+        }
+    }
+
+    pub fn profiles(&self) -> Vec<LensProfile> {
+        self.profiles.read().unwrap().clone()
+    }
+
+    /// The correction for a photo with the given EXIF block: `options`' own
+    /// if it has one (which may be no correction at all), otherwise that of
+    /// the profile for the photo's camera.
+    pub fn distortion_for(
+        &self,
+        options: &ProcessingOptions,
+        exif: Option<&[u8]>,
+    ) -> Option<LensDistortion> {
+        if options.lens_distortion.is_some() {
+            return options.lens_distortion;
+        }
+        let profiles = self.profiles.read().unwrap();
+        lens::find_profile(&profiles, exif?).map(|profile| profile.distortion)
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<LensProfile>)) -> Result<(), ErrorWrapper> {
+        let profiles = {
+            let mut profiles = self.profiles.write().unwrap();
+            change(&mut profiles);
+            profiles.clone()
+        };
+        match &self.path {
+            Some(path) => save(path, &profiles),
+            None => Ok(()),
+        }
+    }
+}
+
+fn save(path: &Path, profiles: &[LensProfile]) -> Result<(), ErrorWrapper> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let json = serde_json::to_vec_pretty(profiles).map_err(std::io::Error::other)?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+fn same_camera(a: &LensProfile, b: &LensProfile) -> bool {
+    let same = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+    same(&a.model, &b.model)
+        && match (&a.make, &b.make) {
+            (Some(a), Some(b)) => same(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+}
+
+#[tauri::command]
+pub fn list_lens_profiles(profiles: State<LensProfiles>) -> Vec<LensProfile> {
+    profiles.profiles()
+}
+
+/// Imports the profile (or list of profiles) in the JSON file at `path`,
+/// replacing any already kept for the same cameras, and returns them all.
+/// Photos from those cameras are then corrected automatically unless their
+/// options give a `lensDistortion` of their own.
+#[tauri::command]
+pub fn import_lens_profiles(
+    profiles: State<LensProfiles>,
+    path: PathBuf,
+) -> Result<Vec<LensProfile>, ErrorWrapper> {
+    let json = std::fs::read(&path)?;
+    let imported = match serde_json::from_slice(&json)
+        .map_err(|e| ErrorWrapper::InvalidInput(format!("Not a valid lens profile file: {e}")))?
+    {
+        ProfileFile::One(profile) => vec![profile],
+        ProfileFile::Many(profiles) => profiles,
+    };
+    for profile in &imported {
+        profile.validate()?;
+    }
+    profiles.update(|current| {
+        current.retain(|profile| !imported.iter().any(|new| same_camera(profile, new)));
+        current.extend(imported);
+    })?;
+    Ok(profiles.profiles())
+}
+
+/// Forgets the profile for the camera with the given make and model.
+/// Returns false if there wasn't one.
+#[tauri::command]
+pub fn remove_lens_profile(
+    profiles: State<LensProfiles>,
+    make: Option<String>,
+    model: String,
+) -> Result<bool, ErrorWrapper> {
+    let target = LensProfile {
+        make,
+        model,
+        distortion: LensDistortion::default(),
+    };
+    let mut removed = false;
+    profiles.update(|current| {
+        let before = current.len();
+        current.retain(|profile| !same_camera(profile, &target));
+        removed = current.len() < before;
+    })?;
+    Ok(removed)
+}
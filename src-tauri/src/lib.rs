@@ -1,33 +1,76 @@
+mod api;
+mod archive;
+mod batch;
+mod benchmark;
+mod cache;
+mod camera;
+#[cfg(desktop)]
+mod clipboard;
+mod diagnostics;
+mod dialog;
+mod external;
+mod file_drop;
+mod history;
+mod http;
+mod jobs;
+mod lenses;
+mod logs;
+mod ocr;
+mod open;
+mod pages;
+mod pdf;
+mod pdf_input;
+mod print;
+mod project;
+mod protocol;
+mod receive;
+mod scanner;
+#[cfg(desktop)]
+mod screenshot;
+mod session;
+mod settings;
+mod share;
+mod stream;
+mod tiff;
+#[cfg(desktop)]
+mod tray;
+mod watch;
+
+use api::Api;
+use cache::{ImageCache, ImageHandle};
 use data_url::DataUrl;
-use image::ImageReader;
-use imageproc::geometric_transformations;
-use imageproc::geometric_transformations::Projection;
-use imageproc::point::Point;
+use diagnostics::{Stage, Timings};
+use history::History;
+use image::{DynamicImage, GenericImageView};
+use jobs::{JobId, JobRegistry};
+use lenses::LensProfiles;
+use logs::Logs;
+use open::OpenedFiles;
+use pages::Pages;
+use rayon::prelude::*;
+use receive::Receiver;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tauri::ipc::Response;
+use session::Autosave;
+use settings::Settings;
+use squarer_core::align::{self, StackBlend};
+use squarer_core::animation;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::decode::{self, DecodeLimits, ImageInfo, SourceImage};
+use squarer_core::encode::OutputFormat;
+use squarer_core::lens::{self, LensDistortion};
+use squarer_core::{
+    book, detect, encode, encode_output, encode_output_with_metadata, mesh, quad_from_points,
+    square_quad, warp_geometry, write_output, ControlPoint, CoordinateSpace, ImageSquaringError,
+    MapDirection, ProcessingOptions, WarpGeometry, WarpQuality,
+};
+use tauri::ipc::{InvokeBody, Request, Response};
+use tauri::{Manager, State};
 use thiserror::Error;
+use watch::WatchFolder;
 
-use std::fmt;
-use std::io::Cursor;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ControlPoint {
-    x: i32,
-    y: i32,
-}
-
-#[derive(Debug)]
-pub struct ImageSquaringError {
-    message: String,
-}
-
-impl fmt::Display for ImageSquaringError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{0}", self.message)
-    }
-}
-
-impl std::error::Error for ImageSquaringError {}
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Error)]
 pub enum ErrorWrapper {
@@ -41,6 +84,145 @@ pub enum ErrorWrapper {
     Base64(#[from] data_url::forgiving_base64::InvalidBase64),
     #[error(transparent)]
     Squaring(#[from] ImageSquaringError),
+    #[error(transparent)]
+    Tauri(#[from] tauri::Error),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Exif(#[from] exif::Error),
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("OCR failed: {0}")]
+    Ocr(String),
+    #[error("Camera failed: {0}")]
+    Camera(String),
+    #[error("Clipboard error: {0}")]
+    Clipboard(String),
+    #[error("Screen capture failed: {0}")]
+    ScreenCapture(String),
+    #[error("Image too large: {0}")]
+    ImageTooLarge(String),
+    /// A bug: something panicked while handling the command.
+    #[error("Internal error: {0}")]
+    Internal(String),
+    #[error(transparent)]
+    Watch(#[from] notify::Error),
+    #[error("Non-convex quadrilateral: control point {point} is inside the other three")]
+    Concave { point: usize },
+    #[error(
+        "Self-intersecting quadrilateral: the edge from control point {} to {} crosses the edge from {} to {}",
+        edges[0][0], edges[0][1], edges[1][0], edges[1][1]
+    )]
+    SelfIntersecting { edges: [[usize; 2]; 2] },
+}
+
+/// Stable identifiers for each kind of error, so the frontend can pick its own
+/// (localized) messaging instead of parsing `message`.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Io,
+    Image,
+    DataUrl,
+    Base64,
+    Squaring,
+    Tauri,
+    InvalidInput,
+    Cancelled,
+    Exif,
+    Unsupported,
+    Ocr,
+    Camera,
+    Clipboard,
+    ScreenCapture,
+    ImageTooLarge,
+    Internal,
+    Watch,
+    Concave,
+    SelfIntersecting,
+}
+
+impl From<squarer_core::Error> for ErrorWrapper {
+    fn from(error: squarer_core::Error) -> Self {
+        match error {
+            squarer_core::Error::Io(e) => ErrorWrapper::Io(e),
+            squarer_core::Error::Image(e) => ErrorWrapper::Image(e),
+            squarer_core::Error::Squaring(e) => ErrorWrapper::Squaring(e),
+            squarer_core::Error::InvalidInput(message) => ErrorWrapper::InvalidInput(message),
+            squarer_core::Error::Cancelled => ErrorWrapper::Cancelled,
+            squarer_core::Error::Exif(e) => ErrorWrapper::Exif(e),
+            squarer_core::Error::ImageTooLarge(message) => ErrorWrapper::ImageTooLarge(message),
+            squarer_core::Error::Unsupported(message) => ErrorWrapper::Unsupported(message),
+            squarer_core::Error::Concave { point } => ErrorWrapper::Concave { point },
+            squarer_core::Error::SelfIntersecting { edges } => {
+                ErrorWrapper::SelfIntersecting { edges }
+            }
+        }
+    }
+}
+
+impl ErrorWrapper {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ErrorWrapper::Io(_) => ErrorCode::Io,
+            ErrorWrapper::Image(_) => ErrorCode::Image,
+            ErrorWrapper::DataUrl(_) => ErrorCode::DataUrl,
+            ErrorWrapper::Base64(_) => ErrorCode::Base64,
+            ErrorWrapper::Squaring(_) => ErrorCode::Squaring,
+            ErrorWrapper::Tauri(_) => ErrorCode::Tauri,
+            ErrorWrapper::InvalidInput(_) => ErrorCode::InvalidInput,
+            ErrorWrapper::Cancelled => ErrorCode::Cancelled,
+            ErrorWrapper::Exif(_) => ErrorCode::Exif,
+            ErrorWrapper::Unsupported(_) => ErrorCode::Unsupported,
+            ErrorWrapper::Ocr(_) => ErrorCode::Ocr,
+            ErrorWrapper::Camera(_) => ErrorCode::Camera,
+            ErrorWrapper::Clipboard(_) => ErrorCode::Clipboard,
+            ErrorWrapper::ScreenCapture(_) => ErrorCode::ScreenCapture,
+            ErrorWrapper::ImageTooLarge(_) => ErrorCode::ImageTooLarge,
+            ErrorWrapper::Internal(_) => ErrorCode::Internal,
+            ErrorWrapper::Watch(_) => ErrorCode::Watch,
+            ErrorWrapper::Concave { .. } => ErrorCode::Concave,
+            ErrorWrapper::SelfIntersecting { .. } => ErrorCode::SelfIntersecting,
+        }
+    }
+
+    /// A finer-grained classification within the error's code, where there is one.
+    fn details(&self) -> Option<String> {
+        match self {
+            ErrorWrapper::Io(e) => Some(format!("{:?}", e.kind())),
+            ErrorWrapper::Image(e) => Some(String::from(match e {
+                image::ImageError::Decoding(_) => "decoding",
+                image::ImageError::Encoding(_) => "encoding",
+                image::ImageError::Parameter(_) => "parameter",
+                image::ImageError::Limits(_) => "limits",
+                image::ImageError::Unsupported(_) => "unsupported",
+                image::ImageError::IoError(_) => "io",
+            })),
+            _ => None,
+        }
+    }
+
+    /// The indices of the control points the error is about, where it's
+    /// about particular ones: the reflex corner of a concave quad, or the
+    /// ends of a self-intersecting quad's crossing edges.
+    fn points(&self) -> Option<Vec<usize>> {
+        match self {
+            ErrorWrapper::Concave { point } => Some(vec![*point]),
+            ErrorWrapper::SelfIntersecting { edges } => Some(edges.concat()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorPayload {
+    code: ErrorCode,
+    message: String,
+    details: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    points: Option<Vec<usize>>,
 }
 
 impl Serialize for ErrorWrapper {
@@ -48,119 +230,1435 @@ impl Serialize for ErrorWrapper {
     where
         S: serde::ser::Serializer,
     {
-        serializer.serialize_str(self.to_string().as_ref())
-    }
-}
-
-fn scaled_control_points_to_projection(points: &Vec<(f32, f32)>) -> Option<Projection> {
-    // From Oleksandr Kaleniuk's "Geometry for Programmers", pp. 118 - 119.
-    match points.as_slice() {
-        [(xt1, yt1), (xt2, yt2), (xt3, yt3), (xt4, yt4)] => {
-            let g =
-                (xt1 * yt3 - xt1 * yt4 - xt2 * yt3 + xt2 * yt4 - xt3 * yt1 + xt3 * yt2 + xt4 * yt1
-                    - xt4 * yt2)
-                    / (xt2 * yt3 - xt2 * yt4 - xt3 * yt2 + xt3 * yt4 + xt4 * yt2 - xt4 * yt3);
-            let h =
-                (xt1 * yt2 - xt1 * yt3 - xt2 * yt1 + xt2 * yt4 + xt3 * yt1 - xt3 * yt4 - xt4 * yt2
-                    + xt4 * yt3)
-                    / (xt2 * yt3 - xt2 * yt4 - xt3 * yt2 + xt3 * yt4 + xt4 * yt2 - xt4 * yt3);
-            let e = h * yt4 - yt1 + yt4;
-            let d = g * yt2 - yt1 + yt2;
-            let b = h * xt4 - xt1 + xt4;
-            let a = g * xt2 - xt1 + xt2;
-            let c = 0.0 + xt1;
-            let f = 0.0 + yt1;
-            let i: f32 = 1.0;
-            Projection::from_matrix([a, b, c, d, e, f, g, h, i])
+        ErrorPayload {
+            code: self.code(),
+            message: self.to_string(),
+            details: self.details(),
+            points: self.points(),
         }
-        _ => None,
+        .serialize(serializer)
     }
 }
 
-#[tauri::command]
-fn process_image(
+fn data_uri_bytes(image_data_uri: &str) -> Result<Vec<u8>, ErrorWrapper> {
+    let url = DataUrl::process(image_data_uri)?;
+    let (body, _) = url.decode_to_vec()?;
+    Ok(body)
+}
+
+/// The image sent as the raw body of a request, which spares encoding it as a
+/// base64 data URI (a third bigger) and decoding it again here.
+fn request_bytes(request: &Request) -> Result<Vec<u8>, ErrorWrapper> {
+    match request.body() {
+        InvokeBody::Raw(bytes) => Ok(bytes.clone()),
+        InvokeBody::Json(_) => Err(ErrorWrapper::InvalidInput(String::from(
+            "Expected the image as the request's binary body",
+        ))),
+    }
+}
+
+/// An argument sent alongside a binary body, as JSON in the header of that
+/// name (percent-encoded, e.g. with `encodeURIComponent`, if it isn't plain
+/// ASCII). None if the header isn't there.
+fn request_header<T: DeserializeOwned>(
+    request: &Request,
+    name: &str,
+) -> Result<Option<T>, ErrorWrapper> {
+    let Some(value) = request.headers().get(name) else {
+        return Ok(None);
+    };
+    let invalid = |reason: &dyn std::fmt::Display| {
+        ErrorWrapper::InvalidInput(format!("Invalid {name} header: {reason}"))
+    };
+    let json = percent_decode(value.as_bytes()).ok_or_else(|| invalid(&"bad percent-encoding"))?;
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|e| invalid(&e))
+}
+
+fn percent_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'%' {
+            let mut digit = || (*bytes.next()? as char).to_digit(16);
+            decoded.push((digit()? * 16 + digit()?) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+fn read_image_data_uri(
+    image_data_uri: &str,
+    limits: &DecodeLimits,
+) -> Result<SourceImage, ErrorWrapper> {
+    Ok(decode::read_image_bytes(
+        data_uri_bytes(image_data_uri)?,
+        limits,
+    )?)
+}
+
+/// Where a command should get an image from, for commands that accept any of
+/// a cached handle, a file, or the image itself.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ImageSource {
+    Handle(ImageHandle),
+    Path(PathBuf),
+    DataUri(String),
+    Bytes(Vec<u8>),
+}
+
+impl ImageSource {
+    fn load(
+        self,
+        cache: &ImageCache,
+        limits: &DecodeLimits,
+    ) -> Result<Arc<DynamicImage>, ErrorWrapper> {
+        match self {
+            ImageSource::Handle(handle) => cache.get(handle),
+            ImageSource::Path(path) => Ok(Arc::new(decode_image_file(&path, limits)?)),
+            ImageSource::DataUri(uri) => Ok(Arc::new(decode_image_data_uri(&uri, limits)?)),
+            ImageSource::Bytes(bytes) => {
+                Ok(Arc::new(decode::read_image_bytes(bytes, limits)?.image))
+            }
+        }
+    }
+
+    /// The image's metadata, read from its headers rather than decoded. A
+    /// cached image has already been decoded, so only its pixels are known.
+    fn probe(self, cache: &ImageCache) -> Result<ImageInfo, ErrorWrapper> {
+        Ok(match self {
+            ImageSource::Handle(handle) => {
+                let image = cache.get(handle)?;
+                let color = image.color();
+                ImageInfo {
+                    width: image.width(),
+                    height: image.height(),
+                    format: String::new(),
+                    color_type: format!("{color:?}").to_lowercase(),
+                    bit_depth: color.bits_per_pixel() / color.channel_count() as u16,
+                    has_alpha: color.has_alpha(),
+                    orientation: 1,
+                    dpi: None,
+                }
+            }
+            ImageSource::Path(path) => decode::probe_image_file(&path)?,
+            ImageSource::DataUri(uri) => decode::probe_image_bytes(&data_uri_bytes(&uri)?)?,
+            ImageSource::Bytes(bytes) => decode::probe_image_bytes(&bytes)?,
+        })
+    }
+}
+
+fn decode_image_data_uri(
     image_data_uri: &str,
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, ErrorWrapper> {
+    Ok(read_image_data_uri(image_data_uri, limits)?.image)
+}
+
+fn decode_image_file(path: &Path, limits: &DecodeLimits) -> Result<DynamicImage, ErrorWrapper> {
+    Ok(decode::read_image_file(path, limits)?.image)
+}
+
+/// What this build supports, fixed by the cargo features it was compiled
+/// with.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Capabilities {
+    /// Extensions (in lowercase) of the images that can be opened.
+    input_formats: Vec<&'static str>,
+    output_formats: Vec<OutputFormat>,
+    heif: bool,
+    raw: bool,
+    ocr: bool,
+    /// Whether warping can use the GPU; without a usable adapter it still
+    /// runs on the CPU.
+    gpu: bool,
+    /// Whether PDF pages can be opened as images.
+    pdf_input: bool,
+    /// Whether `list_scanners` and `acquire_scan` can reach a scanner.
+    scanner: bool,
+    /// Whether `capture_screen` and the screenshot shortcut work.
+    screenshot: bool,
+}
+
+/// Lists the formats and optional features compiled into this build, so the
+/// frontend can hide what isn't available rather than fail when it's used.
+#[tauri::command]
+fn get_capabilities() -> Capabilities {
+    Capabilities {
+        input_formats: decode::supported_extensions(),
+        output_formats: vec![OutputFormat::Png, OutputFormat::Jpeg, OutputFormat::Webp],
+        heif: cfg!(feature = "heif"),
+        raw: cfg!(feature = "raw"),
+        ocr: cfg!(feature = "ocr"),
+        gpu: cfg!(feature = "gpu"),
+        pdf_input: cfg!(feature = "pdfium"),
+        scanner: scanner::AVAILABLE,
+        screenshot: cfg!(all(desktop, feature = "screenshot")),
+    }
+}
+
+/// Reads an image's size, format, color type, bit depth, EXIF orientation and
+/// DPI without decoding its pixels (except for RAW files, whose headers need
+/// parsing in full), so the canvas can be set up, and unsupported or
+/// oversized files flagged, before anything is uploaded.
+#[tauri::command]
+async fn probe_image(
+    cache: State<'_, ImageCache>,
+    image: ImageSource,
+) -> Result<ImageInfo, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    run_blocking(move || image.probe(&cache)).await
+}
+
+#[tauri::command]
+async fn detect_quad(
+    settings: State<'_, Settings>,
+    image_data_uri: String,
+) -> Result<Vec<ControlPoint>, ErrorWrapper> {
+    let limits = settings.decode_limits();
+    run_blocking(move || {
+        let image = decode_image_data_uri(&image_data_uri, &limits)?;
+        match detect::detect_quad(&image) {
+            Some(quad) => Ok(quad
+                .into_iter()
+                .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+                .collect()),
+            None => Err(ErrorWrapper::Squaring(ImageSquaringError::new(
+                "No quadrilateral found",
+            ))),
+        }
+    })
+    .await
+}
+
+/// Estimates the camera lens's distortion from how the edges of the document
+/// outlined by `control_points` (e.g. as detected) bow away from straight, for
+/// `ProcessingOptions::lens_distortion`. Null if the edges can't be made out.
+#[tauri::command]
+async fn estimate_lens_distortion(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    image: ImageSource,
     control_points: Vec<ControlPoint>,
-) -> Result<Response, ErrorWrapper> {
-    assert!(control_points.len() == 4);
-    let points: Vec<Point<i32>> = control_points
+) -> Result<Option<LensDistortion>, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    run_blocking(move || {
+        let image = image.load(&cache, &limits)?;
+        let quad = quad_from_points(control_points, image.dimensions())?;
+        Ok(lens::estimate_distortion(&image, &quad))
+    })
+    .await
+}
+
+// Most candidates `detect_candidates` returns unless told otherwise.
+const DEFAULT_MAX_CANDIDATES: usize = 5;
+
+/// A quad found by `detect_candidates`; see `detect::Candidate` for the
+/// scores.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct QuadCandidate {
+    control_points: Vec<ControlPoint>,
+    area: f64,
+    rectangularity: f64,
+    contrast: f64,
+    edge_support: f64,
+    score: f64,
+}
+
+/// Like `detect_quad`, but returns every plausible quad, best first, so the
+/// user can cycle through them when the photo has several documents (or
+/// windows, screens and so on) in it. The list is empty if none were found.
+#[tauri::command]
+async fn detect_candidates(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    image: ImageSource,
+    max_candidates: Option<usize>,
+) -> Result<Vec<QuadCandidate>, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    run_blocking(move || {
+        let image = image.load(&cache, &limits)?;
+        let max_candidates = max_candidates.unwrap_or(DEFAULT_MAX_CANDIDATES);
+        Ok(detect::detect_candidates(&image, max_candidates)
+            .into_iter()
+            .map(|candidate| QuadCandidate {
+                control_points: candidate
+                    .corners
+                    .iter()
+                    .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+                    .collect(),
+                area: candidate.area,
+                rectangularity: candidate.rectangularity,
+                contrast: candidate.contrast,
+                edge_support: candidate.edge_support,
+                score: candidate.score,
+            })
+            .collect())
+    })
+    .await
+}
+
+// Most candidates `detect_and_process_all` looks through in one photo, and
+// how much of a candidate's outline must run along edges for it to count as
+// a document.
+const MAX_DOCUMENT_CANDIDATES: usize = 20;
+const MIN_DOCUMENT_EDGE_SUPPORT: f64 = 0.6;
+// Most documents `detect_and_process_all` squares: as many as the cache can
+// hold alongside the photo, so none of the handles it returns is evicted
+// before the frontend gets to them.
+const MAX_DOCUMENTS: usize = cache::CACHE_CAPACITY - 1;
+
+/// One of the documents squared by `detect_and_process_all`, cached.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProcessedDocument {
+    handle: ImageHandle,
+    width: u32,
+    height: u32,
+    /// Where the document was found in the photo.
+    control_points: Vec<ControlPoint>,
+    score: f64,
+    quality: WarpQuality,
+    /// With `diagnostics`, how long loading the photo and finding the
+    /// documents in it took, and squaring this one.
+    timings: Option<Timings>,
+}
+
+/// Squares the best of the documents found in `image` and caches them all at
+/// once, best first.
+fn square_documents(
+    cache: &ImageCache,
+    image: &DynamicImage,
+    options: &ProcessingOptions,
+    diagnostics: bool,
+    mut timings: Timings,
+) -> Result<Vec<ProcessedDocument>, ErrorWrapper> {
+    let candidates: Vec<_> = timings
+        .time(Stage::Detect, || {
+            detect::detect_candidates(image, MAX_DOCUMENT_CANDIDATES)
+        })
         .into_iter()
-        .map(|cp| Point::<i32>::new(cp.x, cp.y))
+        .filter(|candidate| candidate.edge_support >= MIN_DOCUMENT_EDGE_SUPPORT)
+        .take(MAX_DOCUMENTS)
         .collect();
-    let mut convex_hull: Vec<Point<i32>> = imageproc::geometry::convex_hull(points);
-    if convex_hull.len() != 4 {
-        return Err(ErrorWrapper::Squaring(ImageSquaringError {
-            message: String::from("Non-convex quadrilateral"),
-        }));
-    }
-    let url = DataUrl::process(image_data_uri)?;
-    let (body, _) = url.decode_to_vec()?;
-    let image = ImageReader::new(Cursor::new(body))
-        .with_guessed_format()?
-        .decode()?;
-    let mut first_point = 0;
-    // Both in JavaScript and these Rust image packages, (0, 0) = top-left corner
-    // and increasing y goes *down* the page.
-    let mut min_mid_y = image.height() as i32;
-    let mut min_x = image.width() as i32;
-    let mut max_x = -1 as i32;
-    let mut min_y = image.height() as i32;
-    let mut max_y = -1 as i32;
-    for i in 0..4 {
-        let x = convex_hull[i].x;
-        let y = convex_hull[i].y;
-        min_x = std::cmp::min(x, min_x);
-        max_x = std::cmp::max(x, max_x);
-        min_y = std::cmp::min(y, min_y);
-        max_y = std::cmp::max(y, max_y);
-        let mid_y = (y + convex_hull[(i + 1) % 4].y) / 2;
-        if mid_y < min_mid_y {
-            min_mid_y = mid_y;
-            first_point = if x < convex_hull[(i + 1) % 4].x {
-                i
-            } else {
-                (i + 1) % 4
+    let squared = candidates
+        .into_par_iter()
+        .map(|candidate| {
+            let control_points: Vec<ControlPoint> = candidate
+                .corners
+                .iter()
+                .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+                .collect();
+            let quad = quad_from_points(control_points.clone(), image.dimensions())?;
+            let quality = warp_geometry(image.dimensions(), &quad, options)?.quality;
+            let mut timings = timings.clone();
+            let squared = timings.time(Stage::Warp, || {
+                square_quad(image, quad, options, &CancellationToken::default())
+            })?;
+            timings.hold(image.as_bytes().len() + squared.as_bytes().len());
+            let document = ProcessedDocument {
+                handle: 0,
+                width: squared.width(),
+                height: squared.height(),
+                control_points,
+                score: candidate.score,
+                quality,
+                timings: diagnostics.then_some(timings),
             };
+            Ok((document, squared))
+        })
+        .collect::<Result<Vec<_>, ErrorWrapper>>()?;
+    let (mut documents, images): (Vec<_>, Vec<_>) = squared.into_iter().unzip();
+    for (document, handle) in documents.iter_mut().zip(cache.insert_all(images)) {
+        document.handle = handle;
+    }
+    Ok(documents)
+}
+
+/// Finds the plausible documents in the photo (e.g. several receipts laid
+/// out on a table), up to seven of them, and squares each one, returning
+/// handles to the results, best candidate first.
+#[tauri::command]
+async fn detect_and_process_all(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    image: ImageSource,
+    options: Option<ProcessingOptions>,
+    diagnostics: Option<bool>,
+) -> Result<Vec<ProcessedDocument>, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    let diagnostics = diagnostics.unwrap_or(false);
+    run_blocking(move || {
+        let mut timings = Timings::default();
+        let image = timings.time(Stage::Decode, || image.load(&cache, &limits))?;
+        square_documents(&cache, &image, &options, diagnostics, timings)
+    })
+    .await
+}
+
+/// Runs `work`, turning a panic in it into `ErrorWrapper::Internal` with the
+/// panic's message, so that a bug tripped by one input fails that command
+/// instead of taking down the thread it runs on, and with it the IPC
+/// channel. The imaging code holds no locks while it works, so nothing is
+/// left poisoned.
+pub(crate) fn catch_panic<T>(
+    work: impl FnOnce() -> Result<T, ErrorWrapper>,
+) -> Result<T, ErrorWrapper> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(work)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("Unknown panic"));
+        Err(ErrorWrapper::Internal(message))
+    })
+}
+
+/// Runs decoding/warping/encoding on the blocking thread pool, so that the IPC
+/// thread (and with it the UI and other commands) stays responsive. Panics
+/// come back as `ErrorWrapper::Internal`.
+async fn run_blocking<T, F>(work: F) -> Result<T, ErrorWrapper>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ErrorWrapper> + Send + 'static,
+{
+    let result = tauri::async_runtime::spawn_blocking(move || catch_panic(work)).await?;
+    if let Err(error) = &result {
+        tracing::warn!("{error}");
+    }
+    result
+}
+
+/// The projection that squaring would use, without touching any pixels, so
+/// that the frontend can draw overlays and map positions between the source
+/// (of the given size) and the output.
+#[tauri::command]
+fn compute_projection(
+    settings: State<Settings>,
+    control_points: Vec<ControlPoint>,
+    width: u32,
+    height: u32,
+    options: Option<ProcessingOptions>,
+) -> Result<WarpGeometry, ErrorWrapper> {
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    catch_panic(|| {
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, (width, height));
+        let quad = quad_from_points(control_points, (width, height))?;
+        Ok(warp_geometry((width, height), &quad, &options)?)
+    })
+}
+
+/// Whether control points would be accepted, per `validate_points`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PointsValidity {
+    valid: bool,
+    /// Why they wouldn't be, as it would be reported by `process_image`.
+    error: Option<ErrorWrapper>,
+}
+
+/// Checks control points against an image of the given size as squaring it
+/// would, but without any image, so that it's cheap enough to run while the
+/// corners are being dragged.
+#[tauri::command]
+fn validate_points(
+    points: Vec<ControlPoint>,
+    width: u32,
+    height: u32,
+    coordinate_space: Option<CoordinateSpace>,
+) -> PointsValidity {
+    let points = coordinate_space
+        .unwrap_or_default()
+        .to_pixels(points, (width, height));
+    match catch_panic(|| Ok(squarer_core::validate_points(points, (width, height))?)) {
+        Ok(()) => PointsValidity {
+            valid: true,
+            error: None,
+        },
+        Err(error) => PointsValidity {
+            valid: false,
+            error: Some(error),
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Position {
+    x: f64,
+    y: f64,
+}
+
+/// Maps positions through the projection that squaring would use (see
+/// `compute_projection`), in either direction. Positions that don't map to
+/// anything come back as null.
+#[tauri::command]
+fn map_points(
+    settings: State<Settings>,
+    control_points: Vec<ControlPoint>,
+    width: u32,
+    height: u32,
+    points: Vec<Position>,
+    direction: MapDirection,
+    options: Option<ProcessingOptions>,
+) -> Result<Vec<Option<Position>>, ErrorWrapper> {
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    catch_panic(|| {
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, (width, height));
+        let quad = quad_from_points(control_points, (width, height))?;
+        let geometry = warp_geometry((width, height), &quad, &options)?;
+        Ok(points
+            .into_iter()
+            .map(|p| {
+                geometry
+                    .map_point((p.x, p.y), direction)
+                    .map(|(x, y)| Position { x, y })
+            })
+            .collect())
+    })
+}
+
+/// Squares the image and returns it encoded per `options`. If `job_id` is
+/// given, the job can be aborted while it runs with `cancel_job`. Animated
+/// GIFs and APNGs come back as animations in the same format, with every
+/// frame squared. Three control points give an affine correction and two
+/// straighten the image (see `quad_from_points`). Unless `options` say
+/// otherwise, the lens is corrected per the profile for the camera named in
+/// the photo's EXIF, if one has been imported.
+#[tauri::command]
+async fn process_image(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    image_data_uri: String,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+    job_id: Option<JobId>,
+) -> Result<Response, ErrorWrapper> {
+    let job = jobs.register(job_id)?;
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    let lens_profiles = lens_profiles.inner().clone();
+    run_blocking(move || {
+        let bytes = data_uri_bytes(&image_data_uri)?;
+        let mut squared = Vec::new();
+        square_image_bytes(
+            bytes,
+            control_points,
+            options,
+            &limits,
+            &lens_profiles,
+            job.token(),
+            &mut squared,
+        )?;
+        Ok(tauri::ipc::Response::new(squared))
+    })
+    .await
+}
+
+/// Like `process_image`, but takes the image as the raw body of the request
+/// (an `ArrayBuffer` or `Uint8Array` passed straight to `invoke`) rather than
+/// as a base64 data URI. The other arguments go in headers as JSON: the
+/// control points in `control-points`, and optionally `options` and `job-id`.
+#[tauri::command]
+async fn process_image_bytes(
+    request: Request<'_>,
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+) -> Result<Response, ErrorWrapper> {
+    let control_points: Vec<ControlPoint> = request_header(&request, "control-points")?
+        .ok_or_else(|| ErrorWrapper::InvalidInput(String::from("Missing control-points header")))?;
+    let options =
+        request_header(&request, "options")?.unwrap_or_else(|| settings.processing_options());
+    let job = jobs.register(request_header(&request, "job-id")?)?;
+    let bytes = request_bytes(&request)?;
+    let limits = settings.decode_limits();
+    let lens_profiles = lens_profiles.inner().clone();
+    run_blocking(move || {
+        let mut squared = Vec::new();
+        square_image_bytes(
+            bytes,
+            control_points,
+            options,
+            &limits,
+            &lens_profiles,
+            job.token(),
+            &mut squared,
+        )?;
+        Ok(tauri::ipc::Response::new(squared))
+    })
+    .await
+}
+
+/// Squares an encoded image (or every frame of an animation) and writes the
+/// encoded result to `output`, for `process_image` and its variants.
+fn square_image_bytes(
+    bytes: Vec<u8>,
+    control_points: Vec<ControlPoint>,
+    mut options: ProcessingOptions,
+    limits: &DecodeLimits,
+    lens_profiles: &LensProfiles,
+    cancel: &CancellationToken,
+    mut output: impl std::io::Write,
+) -> Result<(), ErrorWrapper> {
+    if let Some((format, frames)) = animation::decode_frames(&bytes, limits)? {
+        let size = frames[0].buffer().dimensions();
+        let control_points = options.coordinate_space.to_pixels(control_points, size);
+        let quad = quad_from_points(control_points, size)?;
+        let squared = animation::square_animation(format, frames, &quad, &options, cancel)?;
+        output.write_all(&squared)?;
+        return Ok(());
+    }
+    let source = decode::read_image_bytes(bytes, limits)?;
+    cancel.check()?;
+    let size = source.image.dimensions();
+    let control_points = options.coordinate_space.to_pixels(control_points, size);
+    let quad = quad_from_points(control_points, size)?;
+    options.lens_distortion = lens_profiles.distortion_for(&options, source.exif.as_deref());
+    let squared = square_quad(&source.image, quad.clone(), &options, cancel)?;
+    cancel.check()?;
+    write_output(&squared, &options, source.exif.as_deref(), &quad, output)?;
+    cancel.check()?;
+    Ok(())
+}
+
+/// Like `square_image_bytes`, for an image from any source. A cached image
+/// has already been decoded, so there's no animation or EXIF to consider.
+#[allow(clippy::too_many_arguments)]
+fn square_image_source(
+    image: ImageSource,
+    cache: &ImageCache,
+    control_points: Vec<ControlPoint>,
+    options: ProcessingOptions,
+    limits: &DecodeLimits,
+    lens_profiles: &LensProfiles,
+    cancel: &CancellationToken,
+    output: impl std::io::Write,
+) -> Result<(), ErrorWrapper> {
+    let bytes = match image {
+        ImageSource::Handle(handle) => {
+            let image = cache.get(handle)?;
+            let size = (image.width(), image.height());
+            let control_points = options.coordinate_space.to_pixels(control_points, size);
+            let quad = quad_from_points(control_points, size)?;
+            let squared = square_quad(&image, quad.clone(), &options, cancel)?;
+            return Ok(write_output(&squared, &options, None, &quad, output)?);
         }
+        ImageSource::Path(path) => std::fs::read(path)?,
+        ImageSource::DataUri(uri) => data_uri_bytes(&uri)?,
+        ImageSource::Bytes(bytes) => bytes,
+    };
+    square_image_bytes(
+        bytes,
+        control_points,
+        options,
+        limits,
+        lens_profiles,
+        cancel,
+        output,
+    )
+}
+
+/// Like `process_image`, but reads the input from and writes the result to
+/// disk, so large photos never pass through the IPC channel. The output format
+/// follows `output_path`'s extension when it's a recognized one, except that
+/// animations stay in their own format. The export is recorded in the history
+/// (see `get_history`), and in a sidecar if the preferences say to. With
+/// `diagnostics`, returns how long each stage took.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+async fn process_image_file(
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    history: State<'_, History>,
+    path: PathBuf,
+    control_points: Vec<ControlPoint>,
+    output_path: PathBuf,
+    options: Option<ProcessingOptions>,
+    diagnostics: Option<bool>,
+) -> Result<Option<Timings>, ErrorWrapper> {
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    let lens_profiles = lens_profiles.inner().clone();
+    let history = history.inner().clone();
+    let write_sidecar = settings.write_sidecars();
+    run_blocking(move || {
+        let timings = square_file(
+            &path,
+            control_points.clone(),
+            &output_path,
+            options.clone(),
+            &limits,
+            &lens_profiles,
+            &CancellationToken::default(),
+        )?;
+        record_export(
+            &history,
+            write_sidecar,
+            &path,
+            &control_points,
+            &options,
+            &output_path,
+        );
+        Ok(diagnostics.unwrap_or(false).then_some(timings))
+    })
+    .await
+}
+
+/// Keeps a record of how `output` was made from `source`: in the history,
+/// and in a sidecar next to it if `write_sidecar`. Neither failing undoes the
+/// export, so errors are ignored.
+fn record_export(
+    history: &History,
+    write_sidecar: bool,
+    source: &Path,
+    control_points: &[ControlPoint],
+    options: &ProcessingOptions,
+    output: &Path,
+) {
+    // The output's extension picks the format, as in `square_file`.
+    let mut options = options.clone();
+    if let Some(format) = OutputFormat::from_path(output) {
+        options.output_format = format;
     }
-    let new_width = (max_x - min_x) as f32;
-    let new_height = (max_y - min_y) as f32;
-    convex_hull.rotate_left(first_point);
-    let image = image.crop_imm(
-        min_x as u32,
-        min_y as u32,
-        new_width as u32,
-        new_height as u32,
-    );
-    let scaled_hull_vec: Vec<(f32, f32)> = convex_hull
-        .into_iter()
-        .map(|p: Point<i32>| -> (f32, f32) {
-            (
-                ((p.x - min_x) as f32) / new_width,
-                ((p.y - min_y) as f32) / new_height,
-            )
+    let _ = history.record(source, control_points, &options, output);
+    if write_sidecar {
+        let _ = project::write_sidecar(output, source, control_points, &options);
+    }
+}
+
+/// Where a redone export goes by default: in place of the old one, under the
+/// new format's extension if that's changed.
+fn replacement_output(previous: &Path, format: OutputFormat) -> PathBuf {
+    if OutputFormat::from_path(previous) == Some(format) {
+        previous.to_path_buf()
+    } else {
+        previous.with_extension(format.extension())
+    }
+}
+
+/// Squares the image file at `path` and writes the result to `output_path`,
+/// for `process_image_file` and queued jobs, timing each stage.
+fn square_file(
+    path: &Path,
+    control_points: Vec<ControlPoint>,
+    output_path: &Path,
+    mut options: ProcessingOptions,
+    limits: &DecodeLimits,
+    lens_profiles: &LensProfiles,
+    cancel: &CancellationToken,
+) -> Result<Timings, ErrorWrapper> {
+    let mut timings = Timings::default();
+    if let Some(format) = OutputFormat::from_path(output_path) {
+        options.output_format = format;
+    }
+    if matches!(
+        image::ImageFormat::from_path(path),
+        Ok(image::ImageFormat::Gif | image::ImageFormat::Png)
+    ) {
+        let bytes = std::fs::read(path)?;
+        if let Some((format, frames)) =
+            timings.time(Stage::Decode, || animation::decode_frames(&bytes, limits))?
+        {
+            let size = frames[0].buffer().dimensions();
+            let control_points = options.coordinate_space.to_pixels(control_points, size);
+            let quad = quad_from_points(control_points, size)?;
+            let squared = timings.time(Stage::Warp, || {
+                animation::square_animation(format, frames, &quad, &options, cancel)
+            })?;
+            timings.hold(bytes.len() + squared.len());
+            std::fs::write(output_path, squared)?;
+            return Ok(timings);
+        }
+    }
+    let source = timings.time(Stage::Decode, || decode::read_image_file(path, limits))?;
+    cancel.check()?;
+    let size = source.image.dimensions();
+    let control_points = options.coordinate_space.to_pixels(control_points, size);
+    let quad = quad_from_points(control_points, size)?;
+    options.lens_distortion = lens_profiles.distortion_for(&options, source.exif.as_deref());
+    let squared = timings.time(Stage::Warp, || {
+        square_quad(&source.image, quad.clone(), &options, cancel)
+    })?;
+    cancel.check()?;
+    let bytes = timings.time(Stage::Encode, || {
+        encode_output_with_metadata(&squared, &options, source.exif.as_deref(), &quad)
+    })?;
+    timings.hold(source.image.as_bytes().len() + squared.as_bytes().len() + bytes.len());
+    std::fs::write(output_path, bytes)?;
+    Ok(timings)
+}
+
+// Longest edge of thumbnails returned by `get_thumbnail` by default.
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+// JPEG quality for `preview_warp`; previews favor speed and size.
+pub(crate) const PREVIEW_QUALITY: u8 = 75;
+// Magnification and edge length (in output pixels) of `get_loupe` crops by
+// default, and the largest edge length allowed.
+const DEFAULT_LOUPE_ZOOM: f64 = 4.0;
+const DEFAULT_LOUPE_SIZE: u32 = 160;
+const MAX_LOUPE_SIZE: u32 = 1024;
+// How far `refine_corner` looks for a corner by default, and at most.
+const DEFAULT_SNAP_RADIUS: u32 = 24;
+const MAX_SNAP_RADIUS: u32 = 256;
+// Color of the edges drawn by `get_edge_overlay`, chosen to stand out against
+// most photos.
+const EDGE_OVERLAY_COLOR: [u8; 4] = [0, 255, 255, 255];
+
+/// Decodes the image once and keeps it in the cache, returning a handle for
+/// the other `*_handle` commands.
+#[tauri::command]
+async fn load_image(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    image_data_uri: String,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let limits = settings.decode_limits();
+    let image = run_blocking(move || decode_image_data_uri(&image_data_uri, &limits)).await?;
+    Ok(cache.insert(image))
+}
+
+/// Like `load_image`, but takes the image as the raw body of the request, as
+/// `process_image_bytes` does.
+#[tauri::command]
+async fn load_image_bytes(
+    request: Request<'_>,
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let bytes = request_bytes(&request)?;
+    let limits = settings.decode_limits();
+    let source = run_blocking(move || Ok(decode::read_image_bytes(bytes, &limits)?)).await?;
+    Ok(cache.insert(source.image))
+}
+
+#[tauri::command]
+async fn warp_handle(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) -> Result<Response, ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, image.dimensions());
+        let quad = quad_from_points(control_points, image.dimensions())?;
+        let squared = square_quad(&image, quad, &options, &CancellationToken::default())?;
+        Ok(tauri::ipc::Response::new(encode_output(
+            &squared, &options,
+        )?))
+    })
+    .await
+}
+
+/// A squared image, cached, with how well the source supported it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SquaredImage {
+    handle: ImageHandle,
+    width: u32,
+    height: u32,
+    quality: WarpQuality,
+    /// With `diagnostics`, how long squaring took.
+    timings: Option<Timings>,
+}
+
+/// Like `warp_handle`, but caches the result and returns a handle to it
+/// along with diagnostics (see `WarpQuality`), so the UI can advise
+/// retaking a photo shot at too steep an angle. The result can be shown
+/// straight from `squared://localhost/image/<handle>` (see `protocol`).
+#[tauri::command]
+async fn square_handle(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+    diagnostics: Option<bool>,
+) -> Result<SquaredImage, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let image = cache.get(handle)?;
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, image.dimensions());
+        let quad = quad_from_points(control_points, image.dimensions())?;
+        let quality = warp_geometry(image.dimensions(), &quad, &options)?.quality;
+        let mut timings = Timings::default();
+        let squared = timings.time(Stage::Warp, || {
+            square_quad(&image, quad, &options, &CancellationToken::default())
+        })?;
+        timings.hold(image.as_bytes().len() + squared.as_bytes().len());
+        Ok(SquaredImage {
+            width: squared.width(),
+            height: squared.height(),
+            handle: cache.insert(squared),
+            quality,
+            timings: diagnostics.unwrap_or(false).then_some(timings),
         })
-        .collect();
-    let projection = scaled_control_points_to_projection(&scaled_hull_vec).unwrap();
-    let projection = Projection::scale(1.0 / new_width, 1.0 / new_height)
-        .and_then(projection.invert())
-        .and_then(Projection::scale(new_width, new_height));
-    let mut bytes: Vec<u8> = Vec::new();
-    let squared = geometric_transformations::warp(
-        &image.to_rgba8(),
-        &projection,
-        geometric_transformations::Interpolation::Nearest,
-        image::Rgba([0, 0, 0, 0]),
-    );
-    squared.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)?;
-    Ok(tauri::ipc::Response::new(bytes))
+    })
+    .await
+}
+
+/// Flattens a curved subject in a cached image with a thin-plate spline
+/// through the control points, each of which needs a target (see
+/// `squarer_core::mesh::warp_mesh`), and returns it encoded per `options`.
+#[tauri::command]
+async fn warp_mesh(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) -> Result<Response, ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, image.dimensions());
+        let warped = mesh::warp_mesh(
+            &image,
+            &control_points,
+            &options,
+            &CancellationToken::default(),
+        )?;
+        Ok(tauri::ipc::Response::new(encode_output(&warped, &options)?))
+    })
+    .await
+}
+
+/// One page of a split spread, cached.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BookPage {
+    handle: ImageHandle,
+    width: u32,
+    height: u32,
+}
+
+/// Squares an open-book spread photographed whole, then splits it at the
+/// gutter into its left and right pages, returned in that order as handles
+/// (which `export_pdf` and `export_tiff` take as consecutive pages). Each page
+/// extends `overlap` (a fraction of the spread's width) past the gutter.
+#[tauri::command]
+async fn split_book_spread(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    image: ImageSource,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+    overlap: Option<f32>,
+) -> Result<Vec<BookPage>, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let image = image.load(&cache, &limits)?;
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, image.dimensions());
+        let quad = quad_from_points(control_points, image.dimensions())?;
+        let spread = square_quad(&image, quad, &options, &CancellationToken::default())?;
+        let pages = book::split_pages(&spread, overlap.unwrap_or(book::DEFAULT_OVERLAP))?;
+        Ok(pages
+            .into_iter()
+            .map(|page| BookPage {
+                width: page.width(),
+                height: page.height(),
+                handle: cache.insert(page),
+            })
+            .collect())
+    })
+    .await
+}
+
+/// Warps `overlay_image` into the quad of `base_image` outlined by
+/// `control_points` (the inverse of squaring), for mockups such as putting a
+/// flattened document or a screenshot back into a photographed scene.
+/// Returns the composited base, encoded as `options` say.
+#[tauri::command]
+async fn project_into_quad(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    base_image: ImageSource,
+    overlay_image: ImageSource,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) -> Result<Response, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let base = base_image.load(&cache, &limits)?;
+        let overlay = overlay_image.load(&cache, &limits)?;
+        let control_points = options
+            .coordinate_space
+            .to_pixels(control_points, base.dimensions());
+        let quad = quad_from_points(control_points, base.dimensions())?;
+        let composited = squarer_core::project_into_quad(
+            &base,
+            &overlay,
+            &quad,
+            &options,
+            &CancellationToken::default(),
+        )?;
+        Ok(tauri::ipc::Response::new(encode_output(
+            &composited,
+            &options,
+        )?))
+    })
+    .await
+}
+
+/// Aligns several cached photos of the same page to the first and blends
+/// them into one (cached), to reduce noise or (with the median, the default)
+/// remove a hand or glare that's only in some of them.
+#[tauri::command]
+async fn stack_align(
+    cache: State<'_, ImageCache>,
+    handles: Vec<ImageHandle>,
+    blend: Option<StackBlend>,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    run_blocking(move || {
+        let images = handles
+            .into_iter()
+            .map(|handle| cache.get(handle))
+            .collect::<Result<Vec<_>, _>>()?;
+        let images: Vec<&DynamicImage> = images.iter().map(|image| image.as_ref()).collect();
+        let stacked = align::stack_align(
+            &images,
+            blend.unwrap_or_default(),
+            &CancellationToken::default(),
+        )?;
+        Ok(cache.insert(stacked))
+    })
+    .await
+}
+
+/// Stitches cached photos of overlapping sections (e.g. of a whiteboard or
+/// poster too big for one shot, each squared first) into one image, cached.
+#[tauri::command]
+async fn stitch_images(
+    cache: State<'_, ImageCache>,
+    handles: Vec<ImageHandle>,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    run_blocking(move || {
+        let images = handles
+            .into_iter()
+            .map(|handle| cache.get(handle))
+            .collect::<Result<Vec<_>, _>>()?;
+        let images: Vec<&DynamicImage> = images.iter().map(|image| image.as_ref()).collect();
+        let stitched = align::stitch(&images, &CancellationToken::default())?;
+        Ok(cache.insert(stitched))
+    })
+    .await
+}
+
+/// Squares a downscaled copy of the cached image and returns it as a JPEG, fast
+/// enough to call while the user drags the corners around. The control points
+/// are in full-resolution coordinates, as for `warp_handle`.
+#[tauri::command]
+async fn preview_warp(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) -> Result<Response, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    run_blocking(move || {
+        let image = cache.get(handle)?;
+        let preview = cache.get_preview(handle)?;
+        let scale_x = preview.width() as f64 / image.width() as f64;
+        let scale_y = preview.height() as f64 / image.height() as f64;
+        let scaled_points = options
+            .coordinate_space
+            .to_pixels(control_points, image.dimensions())
+            .into_iter()
+            .map(|cp| ControlPoint {
+                x: cp.x * scale_x,
+                y: cp.y * scale_y,
+                role: cp.role,
+                target: cp.target,
+            })
+            .collect();
+        let quad = quad_from_points(scaled_points, preview.dimensions())?;
+        let squared = square_quad(&preview, quad, &options, &CancellationToken::default())?;
+        let bytes = encode::encode(
+            &squared,
+            OutputFormat::Jpeg,
+            PREVIEW_QUALITY,
+            options.background,
+        )?;
+        Ok(tauri::ipc::Response::new(bytes))
+    })
+    .await
+}
+
+/// Returns a JPEG of the cached image scaled to fit within `max_size` pixels.
+#[tauri::command]
+async fn get_thumbnail(
+    cache: State<'_, ImageCache>,
+    handle: ImageHandle,
+    max_size: Option<u32>,
+) -> Result<Response, ErrorWrapper> {
+    let image = cache.get(handle)?;
+    run_blocking(move || {
+        let max_size = max_size.unwrap_or(DEFAULT_THUMBNAIL_SIZE);
+        let thumbnail = image.thumbnail(max_size, max_size);
+        let bytes = encode::encode(
+            &thumbnail,
+            OutputFormat::Jpeg,
+            encode::DEFAULT_QUALITY,
+            encode::DEFAULT_BACKGROUND,
+        )?;
+        Ok(tauri::ipc::Response::new(bytes))
+    })
+    .await
+}
+
+/// Returns a PNG magnifying the full-resolution image around (`x`, `y`), for
+/// a magnifier while corners are fine-tuned. It's `size` pixels square and
+/// centered on the position, with each source pixel drawn `zoom` pixels wide
+/// (unsmoothed, so pixel edges stay visible); beyond the image it's
+/// transparent.
+#[tauri::command]
+async fn get_loupe(
+    cache: State<'_, ImageCache>,
+    handle: ImageHandle,
+    x: f64,
+    y: f64,
+    zoom: Option<f64>,
+    size: Option<u32>,
+) -> Result<Response, ErrorWrapper> {
+    let zoom = zoom.unwrap_or(DEFAULT_LOUPE_ZOOM);
+    let size = size.unwrap_or(DEFAULT_LOUPE_SIZE);
+    if !(zoom > 0.0 && zoom.is_finite() && x.is_finite() && y.is_finite()) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Can't magnify ({x}, {y}) by {zoom}"
+        )));
+    }
+    if !(1..=MAX_LOUPE_SIZE).contains(&size) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Loupe size must be between 1 and {MAX_LOUPE_SIZE}, got {size}"
+        )));
+    }
+    let image = cache.get(handle)?;
+    run_blocking(move || {
+        let (width, height) = (image.width() as f64, image.height() as f64);
+        let half = size as f64 / 2.0;
+        let loupe = image::RgbaImage::from_fn(size, size, |u, v| {
+            let source_x = (x + (u as f64 + 0.5 - half) / zoom).floor();
+            let source_y = (y + (v as f64 + 0.5 - half) / zoom).floor();
+            if (0.0..width).contains(&source_x) && (0.0..height).contains(&source_y) {
+                image.get_pixel(source_x as u32, source_y as u32)
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        });
+        let bytes = encode::encode(
+            &DynamicImage::ImageRgba8(loupe),
+            OutputFormat::Png,
+            encode::DEFAULT_QUALITY,
+            encode::DEFAULT_BACKGROUND,
+        )?;
+        Ok(tauri::ipc::Response::new(bytes))
+    })
+    .await
+}
+
+/// Snaps a roughly placed handle at (`x`, `y`) onto the strongest corner
+/// within `radius` pixels of it, such as where two document edges meet.
+/// Returns null if there's no corner nearby, in which case the handle should
+/// stay where it is.
+#[tauri::command]
+async fn refine_corner(
+    cache: State<'_, ImageCache>,
+    handle: ImageHandle,
+    x: f64,
+    y: f64,
+    radius: Option<u32>,
+) -> Result<Option<Position>, ErrorWrapper> {
+    let radius = radius.unwrap_or(DEFAULT_SNAP_RADIUS);
+    if !(1..=MAX_SNAP_RADIUS).contains(&radius) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Snap radius must be between 1 and {MAX_SNAP_RADIUS}, got {radius}"
+        )));
+    }
+    let image = cache.get(handle)?;
+    run_blocking(move || {
+        Ok(detect::refine_corner(&image, x, y, radius).map(|p| Position { x: p.x, y: p.y }))
+    })
+    .await
+}
+
+/// Returns a PNG, at the cached image's preview resolution, of the edges that
+/// detection sees: opaque where there's an edge and transparent elsewhere, to
+/// overlay on the photo so that the document's borders stand out. `low` and
+/// `high` are the Canny thresholds; the defaults are the ones detection uses.
+#[tauri::command]
+async fn get_edge_overlay(
+    cache: State<'_, ImageCache>,
+    handle: ImageHandle,
+    low: Option<f32>,
+    high: Option<f32>,
+) -> Result<Response, ErrorWrapper> {
+    let low = low.unwrap_or(detect::CANNY_LOW);
+    let high = high.unwrap_or(detect::CANNY_HIGH);
+    if !(low >= 0.0 && low <= high && high.is_finite()) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Edge thresholds must satisfy 0 <= low <= high, got {low} and {high}"
+        )));
+    }
+    let cache = cache.inner().clone();
+    run_blocking(move || {
+        let preview = cache.get_preview(handle)?;
+        let edges = detect::edge_map(&preview, low, high);
+        let overlay = image::RgbaImage::from_fn(edges.width(), edges.height(), |x, y| {
+            if edges.get_pixel(x, y)[0] > 0 {
+                image::Rgba(EDGE_OVERLAY_COLOR)
+            } else {
+                image::Rgba([0, 0, 0, 0])
+            }
+        });
+        let bytes = encode::encode(
+            &DynamicImage::ImageRgba8(overlay),
+            OutputFormat::Png,
+            encode::DEFAULT_QUALITY,
+            encode::DEFAULT_BACKGROUND,
+        )?;
+        Ok(tauri::ipc::Response::new(bytes))
+    })
+    .await
+}
+
+/// Drops the cached image. Returns false if the handle was unknown (e.g.
+/// already evicted).
+#[tauri::command]
+fn release_handle(cache: State<ImageCache>, handle: ImageHandle) -> bool {
+    cache.remove(handle)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
+    let builder = tauri::Builder::default();
+    // Registered first so a second launch is handed over before anything
+    // else starts.
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_single_instance::init(|app, args, cwd| {
+        open::open_paths(
+            app,
+            open::file_arguments(args.into_iter().skip(1), Path::new(&cwd)),
+        );
+    }));
+    let builder = builder
         .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![process_image])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_notification::init());
+    #[cfg(target_os = "android")]
+    let builder = builder.plugin(camera::init()).plugin(share::init());
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_global_shortcut::Builder::new().build());
+    builder
+        .register_asynchronous_uri_scheme_protocol(protocol::SCHEME, protocol::handle)
+        .manage(ImageCache::new())
+        .manage(JobRegistry::default())
+        .manage(WatchFolder::default())
+        .manage(Pages::default())
+        .manage(Receiver::default())
+        .manage(Api::default())
+        .manage(OpenedFiles::default())
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::DragDrop(tauri::DragDropEvent::Drop { paths, .. }) = event {
+                file_drop::ingest(window.app_handle(), paths.clone());
+            }
+        })
+        .setup(|app| {
+            let data_dir = app.path().app_data_dir().ok();
+            app.manage(Logs::init(
+                data_dir
+                    .as_ref()
+                    .map(|directory| directory.join(logs::LOG_DIR)),
+            ));
+            let config_dir = app.path().app_config_dir().ok();
+            let path = |file: &str| config_dir.as_ref().map(|directory| directory.join(file));
+            app.manage(Settings::load(path(settings::SETTINGS_FILE)));
+            app.manage(LensProfiles::load(path(lenses::LENS_PROFILES_FILE)));
+            let data_path = |file: &str| data_dir.as_ref().map(|directory| directory.join(file));
+            app.manage(History::open(data_path(history::HISTORY_FILE)));
+            let autosave = Autosave::load(data_path(session::SESSION_FILE));
+            autosave.start();
+            app.manage(autosave);
+            #[cfg(desktop)]
+            tray::init(app.handle())?;
+            #[cfg(desktop)]
+            screenshot::init(app.handle());
+            let cwd = std::env::current_dir().unwrap_or_default();
+            open::open_paths(
+                app.handle(),
+                open::file_arguments(std::env::args().skip(1), &cwd),
+            );
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            get_capabilities,
+            probe_image,
+            detect_quad,
+            detect_candidates,
+            estimate_lens_distortion,
+            detect_and_process_all,
+            process_image,
+            process_image_bytes,
+            stream::process_image_stream,
+            jobs::submit_job,
+            jobs::job_status,
+            jobs::list_jobs,
+            jobs::cancel_job,
+            compute_projection,
+            validate_points,
+            map_points,
+            process_image_file,
+            load_image,
+            load_image_bytes,
+            warp_handle,
+            square_handle,
+            warp_mesh,
+            split_book_spread,
+            project_into_quad,
+            stack_align,
+            stitch_images,
+            preview_warp,
+            get_thumbnail,
+            get_loupe,
+            refine_corner,
+            get_edge_overlay,
+            release_handle,
+            batch::process_batch,
+            benchmark::run_benchmark,
+            pdf::export_pdf,
+            print::print_result,
+            external::open_result_externally,
+            external::reveal_in_folder,
+            share::share_result,
+            archive::export_archive,
+            pages::add_page,
+            pages::update_page,
+            pages::move_page,
+            pages::remove_page,
+            pages::clear_pages,
+            pages::list_pages,
+            pages::export_pages,
+            tiff::export_tiff,
+            pdf_input::load_pdf_page,
+            ocr::ocr_result,
+            project::save_project,
+            project::open_project,
+            project::reprocess_from_sidecar,
+            history::get_history,
+            history::reprocess_history_item,
+            lenses::list_lens_profiles,
+            lenses::import_lens_profiles,
+            lenses::remove_lens_profile,
+            logs::get_recent_logs,
+            session::update_session,
+            session::clear_session,
+            session::recover_session,
+            settings::get_settings,
+            settings::set_settings,
+            settings::get_decode_limits,
+            settings::set_decode_limits,
+            watch::configure_watch_folder,
+            camera::capture_and_detect,
+            scanner::list_scanners,
+            scanner::acquire_scan,
+            receive::start_receive_session,
+            receive::stop_receive_session,
+            api::start_api_server,
+            api::stop_api_server,
+            open::take_opened_files,
+            dialog::choose_input_file,
+            dialog::choose_output_file,
+            #[cfg(desktop)]
+            clipboard::paste_image_from_clipboard,
+            #[cfg(desktop)]
+            clipboard::copy_result_to_clipboard,
+            #[cfg(desktop)]
+            screenshot::capture_screen
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| match event {
+            tauri::RunEvent::Exit => app.state::<Autosave>().finish(),
+            #[cfg(any(target_os = "macos", target_os = "ios"))]
+            tauri::RunEvent::Opened { urls } => open::open_paths(app, open::file_urls(urls)),
+            _ => {}
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgb, RgbImage};
+
+    /// Twelve light cards in a grid on a dark table, more than the cache can
+    /// hold squared.
+    fn photo_of_cards() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(800, 600, |x, y| {
+            if (30..170).contains(&(x % 200)) && (40..160).contains(&(y % 200)) {
+                Rgb([235, 235, 230])
+            } else {
+                Rgb([40, 45, 50])
+            }
+        }))
+    }
+
+    #[test]
+    fn square_documents_keeps_every_handle_it_returns() {
+        let cache = ImageCache::new();
+        // A few images from earlier, which may be evicted, and the photo,
+        // which mustn't be.
+        for _ in 0..cache::CACHE_CAPACITY {
+            cache.insert(DynamicImage::new_rgb8(1, 1));
+        }
+        let photo = cache.insert(photo_of_cards());
+        let image = cache.get(photo).unwrap();
+        let documents = square_documents(
+            &cache,
+            &image,
+            &ProcessingOptions::default(),
+            false,
+            Timings::default(),
+        )
+        .unwrap();
+        assert_eq!(documents.len(), MAX_DOCUMENTS);
+        assert!(cache.get(photo).is_ok());
+        for document in &documents {
+            let squared = cache.get(document.handle).unwrap();
+            assert_eq!(squared.dimensions(), (document.width, document.height));
+        }
+    }
 }
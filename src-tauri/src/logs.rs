@@ -0,0 +1,95 @@
+use tauri::State;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use std::path::PathBuf;
+
+use crate::ErrorWrapper;
+
+/// Directory under the app data directory the logs are written to.
+pub const LOG_DIR: &str = "logs";
+const LOG_PREFIX: &str = "squarer";
+const LOG_SUFFIX: &str = "log";
+// A new file is started each day, and a week's worth kept.
+const MAX_LOG_FILES: usize = 7;
+// How much of the log `get_recent_logs` returns by default.
+const DEFAULT_LINES: usize = 500;
+
+/// The log's directory, kept in managed state along with the guard that
+/// flushes the log file when the app exits.
+pub struct Logs {
+    dir: Option<PathBuf>,
+    _guard: Option<WorkerGuard>,
+}
+
+impl Logs {
+    /// Starts logging the pipeline's spans (decode, detect, warp and encode,
+    /// with how long each took) and any errors to daily files in `dir`. With
+    /// no directory, or one that can't be written, nothing is logged.
+    pub fn init(dir: Option<PathBuf>) -> Logs {
+        let appender = dir.as_ref().and_then(|dir| {
+            RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(LOG_PREFIX)
+                .filename_suffix(LOG_SUFFIX)
+                .max_log_files(MAX_LOG_FILES)
+                .build(dir)
+                .ok()
+        });
+        let Some(appender) = appender else {
+            return Logs {
+                dir: None,
+                _guard: None,
+            };
+        };
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        // Fails only if a subscriber was already set, which then stays.
+        let _ = tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .try_init();
+        tracing::info!(version = env!("CARGO_PKG_VERSION"), "Squarer started");
+        Logs {
+            dir,
+            _guard: Some(guard),
+        }
+    }
+}
+
+/// The last `lines` lines of the log (500 by default), oldest first, for
+/// attaching to a bug report.
+#[tauri::command]
+pub fn get_recent_logs(logs: State<Logs>, lines: Option<usize>) -> Result<String, ErrorWrapper> {
+    let Some(dir) = &logs.dir else {
+        return Ok(String::new());
+    };
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(LOG_PREFIX) && name.ends_with(LOG_SUFFIX))
+        })
+        .collect();
+    // The files are named by date, so newest sorts last.
+    files.sort();
+    let wanted = lines.unwrap_or(DEFAULT_LINES);
+    let mut recent: Vec<String> = Vec::new();
+    for file in files.iter().rev() {
+        if recent.len() >= wanted {
+            break;
+        }
+        let contents = std::fs::read_to_string(file)?;
+        recent.extend(
+            contents
+                .lines()
+                .rev()
+                .take(wanted - recent.len())
+                .map(str::to_owned),
+        );
+    }
+    recent.reverse();
+    Ok(recent.join("\n"))
+}
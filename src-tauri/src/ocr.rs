@@ -0,0 +1,100 @@
+use image::DynamicImage;
+use serde::Serialize;
+use tauri::State;
+
+use crate::cache::ImageCache;
+use crate::settings::Settings;
+use crate::{ErrorWrapper, ImageSource};
+
+pub const DEFAULT_LANGUAGE: &str = "eng";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OcrWord {
+    pub(crate) text: String,
+    /// Tesseract's confidence, 0 to 100.
+    pub(crate) confidence: f32,
+    pub(crate) x: i32,
+    pub(crate) y: i32,
+    pub(crate) width: i32,
+    pub(crate) height: i32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OcrResult {
+    pub(crate) text: String,
+    pub(crate) words: Vec<OcrWord>,
+}
+
+/// Parses the word rows (level 5) of Tesseract's TSV output.
+#[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+fn parse_tsv(tsv: &str) -> Vec<OcrWord> {
+    tsv.lines()
+        .filter_map(|line| {
+            // level page block paragraph line word left top width height conf text
+            let columns: Vec<&str> = line.splitn(12, '\t').collect();
+            if columns.len() != 12 || columns[0] != "5" || columns[11].trim().is_empty() {
+                return None;
+            }
+            Some(OcrWord {
+                text: columns[11].to_string(),
+                confidence: columns[10].parse().ok()?,
+                x: columns[6].parse().ok()?,
+                y: columns[7].parse().ok()?,
+                width: columns[8].parse().ok()?,
+                height: columns[9].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(feature = "ocr")]
+pub fn recognize(image: &DynamicImage, language: &str) -> Result<OcrResult, ErrorWrapper> {
+    use squarer_core::encode::{self, OutputFormat};
+
+    let ocr_error = |e: &dyn std::fmt::Display| ErrorWrapper::Ocr(e.to_string());
+    // Leptonica reads images from encoded bytes; PNG keeps it lossless.
+    let png = encode::encode(
+        image,
+        OutputFormat::Png,
+        encode::DEFAULT_QUALITY,
+        encode::DEFAULT_BACKGROUND,
+    )?;
+    let mut tesseract = leptess::LepTess::new(None, language).map_err(|e| ocr_error(&e))?;
+    tesseract
+        .set_image_from_mem(&png)
+        .map_err(|e| ocr_error(&e))?;
+    // The squared image has no meaningful DPI; this avoids Tesseract guessing.
+    tesseract.set_source_resolution(300);
+    let text = tesseract.get_utf8_text().map_err(|e| ocr_error(&e))?;
+    let tsv = tesseract.get_tsv_text(0).map_err(|e| ocr_error(&e))?;
+    Ok(OcrResult {
+        text,
+        words: parse_tsv(&tsv),
+    })
+}
+
+#[cfg(not(feature = "ocr"))]
+pub fn recognize(_image: &DynamicImage, _language: &str) -> Result<OcrResult, ErrorWrapper> {
+    Err(ErrorWrapper::Unsupported(String::from(
+        "This build doesn't include OCR support",
+    )))
+}
+
+/// Recognizes the text in an (already squared) image, with word bounding boxes.
+/// `lang` is a Tesseract language code such as "eng" or "deu+eng".
+#[tauri::command]
+pub async fn ocr_result(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    source: ImageSource,
+    lang: Option<String>,
+) -> Result<OcrResult, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    crate::run_blocking(move || {
+        let image = source.load(&cache, &limits)?;
+        recognize(&image, lang.as_deref().unwrap_or(DEFAULT_LANGUAGE))
+    })
+    .await
+}
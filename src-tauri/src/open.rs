@@ -0,0 +1,118 @@
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager, State};
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cache::ImageCache;
+use crate::camera::Capture;
+use crate::decode_image_file;
+use crate::settings::Settings;
+
+/// Event emitted with an `OpenedFile` for each file the platform asks the
+/// app to open once the frontend is listening.
+const OPENED_EVENT: &str = "file-opened";
+
+/// A file opened from outside the app, by "Open with" or dropping it on the
+/// app's icon.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenedFile {
+    path: PathBuf,
+    /// The decoded image and the document found in it, as
+    /// `capture_and_detect` returns, or None if it couldn't be read.
+    capture: Option<Capture>,
+    /// Why the file couldn't be read.
+    error: Option<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: Vec<OpenedFile>,
+    // Set once the frontend has taken the files opened at launch; from then
+    // on they're emitted as they come.
+    listening: bool,
+}
+
+/// Files opened from outside the app before the frontend was ready for them,
+/// kept in managed state.
+#[derive(Default)]
+pub struct OpenedFiles {
+    inner: Mutex<Inner>,
+}
+
+/// The files among a command line's arguments, resolved against `cwd`.
+/// Anything that isn't an existing file, such as a flag some launcher adds,
+/// is left out.
+pub fn file_arguments(args: impl IntoIterator<Item = String>, cwd: &Path) -> Vec<PathBuf> {
+    args.into_iter()
+        .filter(|arg| !arg.starts_with('-'))
+        .map(|arg| cwd.join(arg))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// The local files among the URLs of a platform open-file event.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn file_urls(urls: impl IntoIterator<Item = tauri::Url>) -> impl Iterator<Item = PathBuf> {
+    urls.into_iter()
+        .filter(|url| url.scheme() == "file")
+        .filter_map(|url| url.to_file_path().ok())
+}
+
+fn open_file(app: &AppHandle, path: PathBuf) -> OpenedFile {
+    let limits = app.state::<Settings>().decode_limits();
+    match decode_image_file(&path, &limits) {
+        Ok(image) => OpenedFile {
+            capture: Some(Capture::detect(&app.state::<ImageCache>(), image)),
+            path,
+            error: None,
+        },
+        Err(error) => OpenedFile {
+            path,
+            capture: None,
+            error: Some(error.to_string()),
+        },
+    }
+}
+
+/// Decodes and caches each of `paths` off the main thread, looks for the
+/// document in it, and hands it to the frontend: queued for
+/// `take_opened_files` until the frontend has called it, emitted as a
+/// `file-opened` event after. The main window is brought up to edit them.
+pub fn open_paths(app: &AppHandle, paths: impl IntoIterator<Item = PathBuf>) {
+    let paths: Vec<_> = paths.into_iter().collect();
+    if paths.is_empty() {
+        return;
+    }
+    if let Some(window) = app.webview_windows().values().next() {
+        let _ = window.show();
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+    let app = app.clone();
+    std::thread::spawn(move || {
+        for path in paths {
+            let file = open_file(&app, path);
+            let state = app.state::<OpenedFiles>();
+            let mut inner = state.inner.lock().unwrap();
+            if inner.listening {
+                drop(inner);
+                let _ = app.emit(OPENED_EVENT, file);
+            } else {
+                inner.pending.push(file);
+            }
+        }
+    });
+}
+
+/// The files opened from outside the app since launch, for the frontend to
+/// call once it has started listening for `file-opened` events, which carry
+/// the ones opened after. Files still being decoded when this is called
+/// arrive as events.
+#[tauri::command]
+pub fn take_opened_files(opened: State<OpenedFiles>) -> Vec<OpenedFile> {
+    let mut inner = opened.inner.lock().unwrap();
+    inner.listening = true;
+    std::mem::take(&mut inner.pending)
+}
@@ -0,0 +1,273 @@
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use squarer_core::{quad_from_points, square_quad, write_output, ControlPoint, ProcessingOptions};
+use tauri::State;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::archive;
+use crate::cache::{ImageCache, ImageHandle};
+use crate::jobs::{JobId, JobRegistry};
+use crate::pdf::{self, PdfOptions};
+use crate::settings::Settings;
+use crate::{run_blocking, ErrorWrapper};
+
+pub type PageId = u64;
+
+struct Page {
+    id: PageId,
+    // The page holds on to its image itself, so it survives being evicted
+    // from the cache while the rest of the document is scanned.
+    image: Arc<DynamicImage>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+}
+
+/// A page as the frontend sees it. `handle` is the cached image it was added
+/// from, which may have been evicted since.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageInfo {
+    id: PageId,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PagesFormat {
+    /// One PDF with a page per page, per the `PdfOptions`.
+    Pdf,
+    /// A zip with an image per page, each encoded per its own options.
+    Zip,
+}
+
+#[derive(Default)]
+struct Inner {
+    pages: Vec<Page>,
+    next_id: PageId,
+}
+
+impl Inner {
+    fn position(&self, id: PageId) -> Result<usize, ErrorWrapper> {
+        self.pages
+            .iter()
+            .position(|page| page.id == id)
+            .ok_or_else(|| ErrorWrapper::InvalidInput(format!("No page with ID {id}")))
+    }
+
+    fn add(
+        &mut self,
+        image: Arc<DynamicImage>,
+        handle: ImageHandle,
+        control_points: Vec<ControlPoint>,
+        options: Option<ProcessingOptions>,
+        index: Option<usize>,
+    ) -> PageId {
+        let index = index.unwrap_or(self.pages.len()).min(self.pages.len());
+        self.next_id = self.next_id.max(1);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pages.insert(
+            index,
+            Page {
+                id,
+                image,
+                handle,
+                control_points,
+                options,
+            },
+        );
+        id
+    }
+
+    fn info(&self) -> Vec<PageInfo> {
+        self.pages
+            .iter()
+            .map(|page| PageInfo {
+                id: page.id,
+                handle: page.handle,
+                control_points: page.control_points.clone(),
+                options: page.options.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The pages of the document being scanned, in order, kept in managed
+/// state. Clones share the same pages.
+#[derive(Clone, Default)]
+pub struct Pages {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Pages {
+    /// Changes the pages with `f` and returns them as they then are.
+    fn update(
+        &self,
+        f: impl FnOnce(&mut Inner) -> Result<(), ErrorWrapper>,
+    ) -> Result<Vec<PageInfo>, ErrorWrapper> {
+        let mut inner = self.inner.lock().unwrap();
+        f(&mut inner)?;
+        Ok(inner.info())
+    }
+
+    /// Adds `image`, cached as `handle`, after the last page.
+    pub fn push(
+        &self,
+        image: Arc<DynamicImage>,
+        handle: ImageHandle,
+        control_points: Vec<ControlPoint>,
+    ) -> PageId {
+        let mut inner = self.inner.lock().unwrap();
+        inner.add(image, handle, control_points, None, None)
+    }
+}
+
+/// Adds a cached image as a page, squared through `control_points` with
+/// `options` (or the settings' defaults at export time), at `index` or by
+/// default after the last page. Returns the pages as they now are.
+#[tauri::command]
+pub fn add_page(
+    pages: State<Pages>,
+    cache: State<ImageCache>,
+    handle: ImageHandle,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+    index: Option<usize>,
+) -> Result<Vec<PageInfo>, ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let mut inner = pages.inner.lock().unwrap();
+    inner.add(image, handle, control_points, options, index);
+    Ok(inner.info())
+}
+
+/// Replaces a page's control points and options, e.g. after its corners
+/// have been adjusted.
+#[tauri::command]
+pub fn update_page(
+    pages: State<Pages>,
+    page_id: PageId,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) -> Result<Vec<PageInfo>, ErrorWrapper> {
+    pages.update(|inner| {
+        let index = inner.position(page_id)?;
+        let page = &mut inner.pages[index];
+        page.control_points = control_points;
+        page.options = options;
+        Ok(())
+    })
+}
+
+/// Moves a page to `index`, shifting the ones in between; past the end
+/// means last.
+#[tauri::command]
+pub fn move_page(
+    pages: State<Pages>,
+    page_id: PageId,
+    index: usize,
+) -> Result<Vec<PageInfo>, ErrorWrapper> {
+    pages.update(|inner| {
+        let page = inner.pages.remove(inner.position(page_id)?);
+        let index = index.min(inner.pages.len());
+        inner.pages.insert(index, page);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn remove_page(pages: State<Pages>, page_id: PageId) -> Result<Vec<PageInfo>, ErrorWrapper> {
+    pages.update(|inner| {
+        inner.pages.remove(inner.position(page_id)?);
+        Ok(())
+    })
+}
+
+/// Removes every page, e.g. once the document has been exported.
+#[tauri::command]
+pub fn clear_pages(pages: State<Pages>) {
+    pages.inner.lock().unwrap().pages.clear();
+}
+
+#[tauri::command]
+pub fn list_pages(pages: State<Pages>) -> Vec<PageInfo> {
+    pages.inner.lock().unwrap().info()
+}
+
+/// Squares every page, in order, and writes them to `output_path` as a
+/// single PDF (per `pdf_options`) or a zip of images named `page-001.jpg`
+/// and so on. Pages without options of their own use the settings'
+/// defaults. Can be cancelled with `cancel_job` if given a `job_id`.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn export_pages(
+    pages: State<'_, Pages>,
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    format: PagesFormat,
+    output_path: PathBuf,
+    pdf_options: Option<PdfOptions>,
+    job_id: Option<JobId>,
+) -> Result<(), ErrorWrapper> {
+    let pdf_options = pdf_options.unwrap_or_default();
+    pdf_options.validate()?;
+    let pages: Vec<(Arc<DynamicImage>, Vec<ControlPoint>, ProcessingOptions)> = {
+        let inner = pages.inner.lock().unwrap();
+        inner
+            .pages
+            .iter()
+            .map(|page| {
+                (
+                    page.image.clone(),
+                    page.control_points.clone(),
+                    page.options
+                        .clone()
+                        .unwrap_or_else(|| settings.processing_options()),
+                )
+            })
+            .collect()
+    };
+    if pages.is_empty() {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "There are no pages to export",
+        )));
+    }
+    let job = jobs.register(job_id)?;
+    run_blocking(move || {
+        let cancel = job.token();
+        let squared = pages.into_iter().map(|(image, control_points, options)| {
+            let size = (image.width(), image.height());
+            let control_points = options.coordinate_space.to_pixels(control_points, size);
+            let quad = quad_from_points(control_points, size)?;
+            let squared = square_quad(&image, quad.clone(), &options, cancel)?;
+            Ok::<_, ErrorWrapper>((squared, options, quad))
+        });
+        match format {
+            PagesFormat::Pdf => {
+                let images = squared
+                    .map(|result| result.map(|(squared, _, _)| Arc::new(squared)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                pdf::write_pdf(&images, &pdf_options, &output_path)
+            }
+            PagesFormat::Zip => archive::write_zip(
+                squared.enumerate().map(|(index, result)| {
+                    let (squared, options, quad) = result?;
+                    let mut bytes = Vec::new();
+                    write_output(&squared, &options, None, &quad, &mut bytes)?;
+                    let name = format!(
+                        "page-{:03}.{}",
+                        index + 1,
+                        options.output_format.extension()
+                    );
+                    Ok((name, bytes))
+                }),
+                &output_path,
+            ),
+        }
+    })
+    .await
+}
@@ -0,0 +1,283 @@
+use image::DynamicImage;
+use pdf_writer::types::TextRenderingMode;
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref, Str};
+use serde::Deserialize;
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::cache::ImageCache;
+use crate::ocr::{self, OcrWord};
+use crate::settings::Settings;
+use crate::{ErrorWrapper, ImageSource};
+use squarer_core::encode::{self, OutputFormat};
+
+const POINTS_PER_INCH: f32 = 72.0;
+// Resolution at which `PageSize::Fit` pages are sized to their image when
+// the options don't give one.
+const FIT_DPI: f32 = 150.0;
+// JPEG quality of the PDFs made for printing.
+const PRINT_QUALITY: u8 = 95;
+// Blank border around images on fixed-size pages.
+const MARGIN_POINTS: f32 = 18.0;
+// Rough average Helvetica glyph width, in units of the font size, used to
+// stretch each invisible word over the width of the word in the image.
+const AVERAGE_GLYPH_WIDTH: f32 = 0.5;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageSize {
+    #[default]
+    A4,
+    Letter,
+    /// Each page is exactly the size of its image.
+    Fit,
+}
+
+impl PageSize {
+    /// Portrait dimensions in points, or None for `Fit`.
+    fn dimensions(self) -> Option<(f32, f32)> {
+        match self {
+            PageSize::A4 => Some((595.28, 841.89)),
+            PageSize::Letter => Some((612.0, 792.0)),
+            PageSize::Fit => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PdfOptions {
+    page_size: PageSize,
+    /// JPEG quality (1..=100) used to compress each page's image.
+    quality: u8,
+    /// Run OCR on each page and put the text, invisibly, over the image so
+    /// that the PDF can be searched and its text selected.
+    searchable: bool,
+    /// Tesseract language code for `searchable`.
+    ocr_language: Option<String>,
+    /// Resolution of the images, which sets their printed size: `Fit` pages
+    /// are sized to match, and on fixed-size pages images are only shrunk
+    /// if they don't fit. Without it, images fill fixed-size pages and `Fit`
+    /// pages assume 150 dpi.
+    dpi: Option<f32>,
+}
+
+impl Default for PdfOptions {
+    fn default() -> Self {
+        PdfOptions {
+            page_size: PageSize::default(),
+            quality: encode::DEFAULT_QUALITY,
+            searchable: false,
+            ocr_language: None,
+            dpi: None,
+        }
+    }
+}
+
+impl PdfOptions {
+    /// Options for printing a single image on `page_size` paper at `dpi`,
+    /// with JPEG quality high enough not to show on paper.
+    pub fn for_print(page_size: PageSize, dpi: Option<f32>) -> PdfOptions {
+        PdfOptions {
+            page_size,
+            quality: PRINT_QUALITY,
+            dpi,
+            ..PdfOptions::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ErrorWrapper> {
+        match self.dpi.filter(|dpi| !(*dpi > 0.0 && dpi.is_finite())) {
+            Some(dpi) => Err(ErrorWrapper::InvalidInput(format!(
+                "DPI must be positive, got {dpi}"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Converts text to WinAnsiEncoding (close to Latin-1) for the standard
+/// Helvetica font; characters it can't represent become '?'.
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            code @ (0x20..=0x7E | 0xA0..=0xFF) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Writes each word invisibly (render mode 3) at its position in the image,
+/// where the image is drawn `scale` points per pixel with its bottom-left
+/// corner at `origin`.
+fn write_text_layer(
+    content: &mut Content,
+    font_name: Name,
+    words: &[OcrWord],
+    image_height: f32,
+    scale: f32,
+    origin: (f32, f32),
+) {
+    content.begin_text();
+    content.set_text_rendering_mode(TextRenderingMode::Invisible);
+    for word in words.iter().filter(|w| w.width > 0 && w.height > 0) {
+        let text = win_ansi(&word.text);
+        let size = word.height as f32 * scale;
+        let natural_width = text.len() as f32 * AVERAGE_GLYPH_WIDTH * size;
+        content.set_font(font_name, size);
+        content.set_horizontal_scaling(100.0 * word.width as f32 * scale / natural_width);
+        // PDF's y axis points up; the baseline goes at the bottom of the box.
+        content.set_text_matrix([
+            1.0,
+            0.0,
+            0.0,
+            1.0,
+            origin.0 + word.x as f32 * scale,
+            origin.1 + (image_height - (word.y + word.height) as f32) * scale,
+        ]);
+        content.show(Str(&text));
+    }
+    content.end_text();
+}
+
+/// Writes `pages` into a PDF, one image per page, at `output_path`.
+pub fn write_pdf(
+    pages: &[Arc<DynamicImage>],
+    options: &PdfOptions,
+    output_path: &Path,
+) -> Result<(), ErrorWrapper> {
+    let mut pdf = Pdf::new();
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let mut next_id = 3;
+    let mut alloc = || {
+        let id = Ref::new(next_id);
+        next_id += 1;
+        id
+    };
+    pdf.catalog(catalog_id).pages(page_tree_id);
+
+    let image_name = Name(b"Im");
+    let font_name = Name(b"F1");
+    let font_id = options.searchable.then(&mut alloc);
+    if let Some(font_id) = font_id {
+        pdf.type1_font(font_id)
+            .base_font(Name(b"Helvetica"))
+            .encoding_predefined(Name(b"WinAnsiEncoding"));
+    }
+    let language = options
+        .ocr_language
+        .as_deref()
+        .unwrap_or(ocr::DEFAULT_LANGUAGE);
+
+    let mut page_ids = Vec::with_capacity(pages.len());
+    for image in pages {
+        let (page_id, image_id, content_id) = (alloc(), alloc(), alloc());
+        page_ids.push(page_id);
+
+        let (pixel_width, pixel_height) = (image.width() as f32, image.height() as f32);
+        let landscape = pixel_width > pixel_height;
+        let (page_width, page_height, margin) = match options.page_size.dimensions() {
+            Some((w, h)) if landscape => (h, w, MARGIN_POINTS),
+            Some((w, h)) => (w, h, MARGIN_POINTS),
+            None => {
+                let dpi = options.dpi.unwrap_or(FIT_DPI);
+                (
+                    pixel_width * POINTS_PER_INCH / dpi,
+                    pixel_height * POINTS_PER_INCH / dpi,
+                    0.0,
+                )
+            }
+        };
+        // Scale to fit within the margins, centered, keeping proportions.
+        let fit_scale = f32::min(
+            (page_width - 2.0 * margin) / pixel_width,
+            (page_height - 2.0 * margin) / pixel_height,
+        );
+        let scale = match options.dpi {
+            Some(dpi) => fit_scale.min(POINTS_PER_INCH / dpi),
+            None => fit_scale,
+        };
+        let (draw_width, draw_height) = (pixel_width * scale, pixel_height * scale);
+        let (x, y) = (
+            (page_width - draw_width) / 2.0,
+            (page_height - draw_height) / 2.0,
+        );
+
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0.0, 0.0, page_width, page_height));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        let mut resources = page.resources();
+        resources.x_objects().pair(image_name, image_id);
+        if let Some(font_id) = font_id {
+            resources.fonts().pair(font_name, font_id);
+        }
+        resources.finish();
+        page.finish();
+
+        let jpeg = encode::encode(
+            image,
+            OutputFormat::Jpeg,
+            options.quality,
+            encode::DEFAULT_BACKGROUND,
+        )?;
+        let mut xobject = pdf.image_xobject(image_id, &jpeg);
+        xobject.filter(Filter::DctDecode);
+        xobject.width(image.width() as i32);
+        xobject.height(image.height() as i32);
+        if image.color().has_color() {
+            xobject.color_space().device_rgb();
+        } else {
+            xobject.color_space().device_gray();
+        }
+        xobject.bits_per_component(8);
+        xobject.finish();
+
+        let mut content = Content::new();
+        content.save_state();
+        content.transform([draw_width, 0.0, 0.0, draw_height, x, y]);
+        content.x_object(image_name);
+        content.restore_state();
+        if options.searchable {
+            let words = ocr::recognize(image, language)?.words;
+            write_text_layer(&mut content, font_name, &words, pixel_height, scale, (x, y));
+        }
+        pdf.stream(content_id, &content.finish());
+    }
+    let page_count = page_ids.len() as i32;
+    pdf.pages(page_tree_id).kids(page_ids).count(page_count);
+
+    std::fs::write(output_path, pdf.finish())?;
+    Ok(())
+}
+
+/// Combines already-squared images into a single multi-page PDF.
+#[tauri::command]
+pub async fn export_pdf(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    pages: Vec<ImageSource>,
+    output_path: PathBuf,
+    options: Option<PdfOptions>,
+) -> Result<(), ErrorWrapper> {
+    if pages.is_empty() {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "A PDF needs at least one page",
+        )));
+    }
+    let options = options.unwrap_or_default();
+    options.validate()?;
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    crate::run_blocking(move || {
+        let images = pages
+            .into_iter()
+            .map(|page| page.load(&cache, &limits))
+            .collect::<Result<Vec<_>, _>>()?;
+        write_pdf(&images, &options, &output_path)
+    })
+    .await
+}
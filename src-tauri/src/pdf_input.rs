@@ -0,0 +1,111 @@
+use image::DynamicImage;
+use squarer_core::decode::DecodeLimits;
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+// Resolution for `load_pdf_page` when none is given; enough to read and
+// correct a scanned page.
+const DEFAULT_DPI: f32 = 200.0;
+
+#[cfg(feature = "pdfium")]
+mod pdfium {
+    use super::*;
+    use image::RgbaImage;
+    use pdfium_render::prelude::{PdfRenderConfig, Pdfium, PdfiumError};
+
+    use std::sync::OnceLock;
+
+    const POINTS_PER_INCH: f32 = 72.0;
+
+    fn pdf_error(error: PdfiumError) -> ErrorWrapper {
+        ErrorWrapper::InvalidInput(format!("Couldn't read PDF: {error}"))
+    }
+
+    /// The pdfium library, bound on first use: one next to the executable if
+    /// there is one, otherwise the system's.
+    fn library() -> Result<&'static Pdfium, ErrorWrapper> {
+        static PDFIUM: OnceLock<Result<Pdfium, String>> = OnceLock::new();
+        PDFIUM
+            .get_or_init(|| {
+                let local = std::env::current_exe().ok().and_then(|exe| {
+                    exe.parent()
+                        .map(Pdfium::pdfium_platform_library_name_at_path)
+                });
+                local
+                    .map_or_else(Pdfium::bind_to_system_library, |path| {
+                        Pdfium::bind_to_library(path).or_else(|_| Pdfium::bind_to_system_library())
+                    })
+                    .map(Pdfium::new)
+                    .map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|e| ErrorWrapper::Unsupported(format!("pdfium isn't available: {e}")))
+    }
+
+    pub fn rasterize(
+        path: &Path,
+        page_index: u16,
+        dpi: f32,
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, ErrorWrapper> {
+        let document = library()?
+            .load_pdf_from_file(path, None)
+            .map_err(pdf_error)?;
+        let page = document.pages().get(page_index).map_err(pdf_error)?;
+        let scale = dpi / POINTS_PER_INCH;
+        let width = (page.width().value * scale).round() as u32;
+        let height = (page.height().value * scale).round() as u32;
+        limits.check(width, height, width as u64 * height as u64 * 4)?;
+        let bitmap = page
+            .render_with_config(&PdfRenderConfig::new().scale_page_by_factor(scale))
+            .map_err(pdf_error)?;
+        let rgba = RgbaImage::from_raw(
+            bitmap.width() as u32,
+            bitmap.height() as u32,
+            bitmap.as_rgba_bytes(),
+        )
+        .ok_or_else(|| ErrorWrapper::InvalidInput(String::from("pdfium returned a bad bitmap")))?;
+        Ok(DynamicImage::ImageRgba8(rgba))
+    }
+}
+
+#[cfg(feature = "pdfium")]
+use pdfium::rasterize;
+
+#[cfg(not(feature = "pdfium"))]
+fn rasterize(
+    _path: &Path,
+    _page_index: u16,
+    _dpi: f32,
+    _limits: &DecodeLimits,
+) -> Result<DynamicImage, ErrorWrapper> {
+    Err(ErrorWrapper::Unsupported(String::from(
+        "PDF input needs a build with the `pdfium` feature",
+    )))
+}
+
+/// Renders a page of a PDF (e.g. a keystoned scan) at `dpi` and caches it,
+/// returning a handle as `load_image` does. Pages are numbered from 0.
+#[tauri::command]
+pub async fn load_pdf_page(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    path: PathBuf,
+    page_index: u16,
+    dpi: Option<f32>,
+) -> Result<ImageHandle, ErrorWrapper> {
+    let dpi = dpi.unwrap_or(DEFAULT_DPI);
+    if !(dpi > 0.0 && dpi.is_finite()) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "DPI must be positive, got {dpi}"
+        )));
+    }
+    let limits = settings.decode_limits();
+    let image = crate::run_blocking(move || rasterize(&path, page_index, dpi, &limits)).await?;
+    Ok(cache.insert(image))
+}
@@ -0,0 +1,119 @@
+use serde::Deserialize;
+use tauri::State;
+
+use std::path::Path;
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::pdf::{self, PageSize, PdfOptions};
+use crate::ErrorWrapper;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PrintOptions {
+    /// The paper to print on; `fit` isn't a paper size, so it's rejected.
+    paper_size: PageSize,
+    /// Resolution to print at, which sets the printed size (shrunk only if
+    /// it doesn't fit the paper). Without it the image fills the paper.
+    dpi: Option<f32>,
+    /// The printer to use, by the name the OS knows it by, or the default
+    /// printer.
+    printer: Option<String>,
+}
+
+/// Runs a command to completion, or fails with what it wrote to stderr.
+#[cfg(desktop)]
+fn run(command: &mut std::process::Command) -> Result<(), ErrorWrapper> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let message = String::from_utf8_lossy(&output.stderr);
+    Err(ErrorWrapper::Io(std::io::Error::other(format!(
+        "Printing failed: {}",
+        message.trim()
+    ))))
+}
+
+/// CUPS, through its `lp` tool, on Linux and macOS alike. The file has been
+/// spooled once `lp` returns, so it's removed then.
+#[cfg(all(desktop, unix))]
+mod backend {
+    use super::*;
+
+    use std::process::Command;
+
+    pub fn print(path: &Path, printer: Option<&str>) -> Result<(), ErrorWrapper> {
+        let mut command = Command::new("lp");
+        if let Some(printer) = printer {
+            command.arg("-d").arg(printer);
+        }
+        let result = run(command.arg("--").arg(path));
+        let _ = std::fs::remove_file(path);
+        result
+    }
+}
+
+/// The shell's print verb, which hands the PDF to whatever app is
+/// registered to print PDFs. That app reads the file after this returns, so
+/// it's left in the temp directory. The path and printer are passed in the
+/// environment so they're never parsed as script.
+#[cfg(windows)]
+mod backend {
+    use super::*;
+
+    use std::process::Command;
+
+    const PRINT_SCRIPT: &str = r#"
+$ErrorActionPreference = 'Stop'
+if ($env:SQUARER_PRINTER) {
+    Start-Process -FilePath $env:SQUARER_FILE -Verb PrintTo -ArgumentList "`"$env:SQUARER_PRINTER`""
+} else {
+    Start-Process -FilePath $env:SQUARER_FILE -Verb Print
+}
+"#;
+
+    pub fn print(path: &Path, printer: Option<&str>) -> Result<(), ErrorWrapper> {
+        run(Command::new("powershell")
+            .args(["-NoProfile", "-NonInteractive", "-Command", PRINT_SCRIPT])
+            .env("SQUARER_FILE", path)
+            .env("SQUARER_PRINTER", printer.unwrap_or_default()))
+    }
+}
+
+#[cfg(mobile)]
+mod backend {
+    use super::*;
+
+    pub fn print(_path: &Path, _printer: Option<&str>) -> Result<(), ErrorWrapper> {
+        Err(ErrorWrapper::Unsupported(String::from(
+            "Printing isn't supported on mobile yet",
+        )))
+    }
+}
+
+/// Prints a cached image, e.g. a squared result, on one page of
+/// `options.paper_size` paper at `options.dpi`, by way of a PDF handed to
+/// the OS print system, so there's no need to export it first.
+#[tauri::command]
+pub async fn print_result(
+    cache: State<'_, ImageCache>,
+    handle: ImageHandle,
+    options: Option<PrintOptions>,
+) -> Result<(), ErrorWrapper> {
+    let options = options.unwrap_or_default();
+    if let PageSize::Fit = options.paper_size {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "Printing needs a paper size",
+        )));
+    }
+    let pdf_options = PdfOptions::for_print(options.paper_size, options.dpi);
+    pdf_options.validate()?;
+    let image = cache.get(handle)?;
+    crate::run_blocking(move || {
+        let path =
+            std::env::temp_dir().join(format!("squarer-print-{}-{handle}.pdf", std::process::id()));
+        pdf::write_pdf(&[image], &pdf_options, &path)?;
+        backend::print(&path, options.printer.as_deref())
+    })
+    .await
+}
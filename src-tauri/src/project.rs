@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+
+use crate::history::History;
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::{run_blocking, ErrorWrapper};
+
+// Bumped whenever the file format changes incompatibly.
+const PROJECT_VERSION: u32 = 1;
+// Appended to an export's file name to name its sidecar.
+const SIDECAR_SUFFIX: &str = ".squarer.json";
+
+/// Everything needed to pick up where the user left off: which image, where
+/// its corners are, and how it's exported. Saved as JSON, conventionally with
+/// a `.squarer` extension.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Project {
+    /// The source image. In the file it's relative to the project when it's
+    /// in the same directory tree, so the two can be moved together.
+    source: PathBuf,
+    control_points: Vec<ControlPoint>,
+    #[serde(default)]
+    options: ProcessingOptions,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProjectFile {
+    version: u32,
+    #[serde(flatten)]
+    project: Project,
+}
+
+fn project_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new(""))
+}
+
+/// Saves `project` to `path`.
+#[tauri::command]
+pub fn save_project(path: PathBuf, project: Project) -> Result<(), ErrorWrapper> {
+    write_project(&path, project)
+}
+
+/// Loads a project saved by `save_project`, with its source path resolved
+/// against the project's location.
+#[tauri::command]
+pub fn open_project(path: PathBuf) -> Result<Project, ErrorWrapper> {
+    read_project(&path)
+}
+
+fn write_project(path: &Path, mut project: Project) -> Result<(), ErrorWrapper> {
+    if let Ok(relative) = project.source.strip_prefix(project_dir(path)) {
+        project.source = relative.to_path_buf();
+    }
+    let file = ProjectFile {
+        version: PROJECT_VERSION,
+        project,
+    };
+    let json = serde_json::to_vec_pretty(&file)
+        .map_err(|e| ErrorWrapper::InvalidInput(format!("Couldn't save project: {e}")))?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+fn read_project(path: &Path) -> Result<Project, ErrorWrapper> {
+    let json = std::fs::read(path)?;
+    let file: ProjectFile = serde_json::from_slice(&json)
+        .map_err(|e| ErrorWrapper::InvalidInput(format!("Not a valid project file: {e}")))?;
+    if file.version > PROJECT_VERSION {
+        return Err(ErrorWrapper::Unsupported(format!(
+            "Project file version {} is newer than this version of Squarer supports",
+            file.version
+        )));
+    }
+    let mut project = file.project;
+    project.source = project_dir(path).join(&project.source);
+    Ok(project)
+}
+
+/// Where the sidecar for an export goes: next to it, named after it.
+fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    output.with_file_name(name)
+}
+
+/// Writes a sidecar recording how `output` was made. It's a project file, so
+/// it can also be opened with `open_project` to adjust the corners.
+pub fn write_sidecar(
+    output: &Path,
+    source: &Path,
+    control_points: &[ControlPoint],
+    options: &ProcessingOptions,
+) -> Result<(), ErrorWrapper> {
+    let source = std::fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+    write_project(
+        &sidecar_path(output),
+        Project {
+            source,
+            control_points: control_points.to_vec(),
+            options: options.clone(),
+        },
+    )
+}
+
+/// Squares an image again as recorded in the sidecar (or project file) at
+/// `path`, with `options` if given instead of the recorded ones, e.g. for a
+/// different format or a higher quality. The result goes to `output_path`,
+/// or by default replaces the export the sidecar belongs to. Returns where
+/// the result went.
+#[tauri::command]
+pub async fn reprocess_from_sidecar(
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    history: State<'_, History>,
+    path: PathBuf,
+    options: Option<ProcessingOptions>,
+    output_path: Option<PathBuf>,
+) -> Result<PathBuf, ErrorWrapper> {
+    let limits = settings.decode_limits();
+    let write_sidecar = settings.write_sidecars();
+    let lens_profiles = lens_profiles.inner().clone();
+    let history = history.inner().clone();
+    run_blocking(move || {
+        let project = read_project(&path)?;
+        let options = options.unwrap_or(project.options);
+        let output_path = match output_path {
+            Some(output_path) => output_path,
+            None => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let Some(output_name) = name.strip_suffix(SIDECAR_SUFFIX) else {
+                    return Err(ErrorWrapper::InvalidInput(format!(
+                        "{} isn't a sidecar, so an output path is needed",
+                        path.display()
+                    )));
+                };
+                crate::replacement_output(&path.with_file_name(output_name), options.output_format)
+            }
+        };
+        crate::square_file(
+            &project.source,
+            project.control_points.clone(),
+            &output_path,
+            options.clone(),
+            &limits,
+            &lens_profiles,
+            &CancellationToken::default(),
+        )?;
+        crate::record_export(
+            &history,
+            write_sidecar,
+            &project.source,
+            &project.control_points,
+            &options,
+            &output_path,
+        );
+        Ok(output_path)
+    })
+    .await
+}
@@ -0,0 +1,203 @@
+use sha2::{Digest, Sha256};
+use squarer_core::encode::{self, OutputFormat};
+use squarer_core::ProcessingOptions;
+use tauri::http::{header, Request, Response, StatusCode};
+use tauri::{Manager, Runtime, UriSchemeContext, UriSchemeResponder};
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+/// The URI scheme that serves cached images, so the frontend can point an
+/// `<img>` at a result instead of having it sent back over IPC.
+pub const SCHEME: &str = "squared";
+
+// Cached images never change and handles aren't reused, and previews are
+// always encoded the same way, so the webview can keep the ones it's fetched.
+const PREVIEW_CACHE_CONTROL: &str = "max-age=31536000, immutable";
+// Full images are encoded per the settings, which can change, so the webview
+// checks back each time; the ETag spares re-encoding an unchanged one.
+const IMAGE_CACHE_CONTROL: &str = "no-cache";
+
+/// The URL the webview loads the cache's preview of `handle` from.
+pub fn preview_url(handle: ImageHandle) -> String {
+    if cfg!(any(windows, target_os = "android")) {
+        format!("http://{SCHEME}.localhost/preview/{handle}")
+    } else {
+        format!("{SCHEME}://localhost/preview/{handle}")
+    }
+}
+
+/// What a request asks for.
+enum Resource {
+    /// The full image, encoded per the settings or the `format` query
+    /// parameter.
+    Image(ImageHandle, Option<OutputFormat>),
+    /// The cache's downscaled preview, as a JPEG.
+    Preview(ImageHandle),
+}
+
+/// Parses `squared://localhost/image/<handle>` or `.../preview/<handle>`.
+/// Windows and Android spell that `http://squared.localhost/...` for the
+/// webview, and `squared://image/<handle>` is accepted too.
+fn parse(request: &Request<Vec<u8>>) -> Option<Resource> {
+    let uri = request.uri();
+    let host = uri.host().unwrap_or_default();
+    let mut segments: Vec<&str> = uri.path().split('/').filter(|s| !s.is_empty()).collect();
+    if !host.is_empty() && host != "localhost" && !host.ends_with(".localhost") {
+        segments.insert(0, host);
+    }
+    let [kind, handle] = segments[..] else {
+        return None;
+    };
+    let handle = handle.parse().ok()?;
+    match kind {
+        "image" => {
+            let format = match uri.query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("format="))
+            }) {
+                Some(format) => Some(OutputFormat::from_extension(format)?),
+                None => None,
+            };
+            Some(Resource::Image(handle, format))
+        }
+        "preview" => Some(Resource::Preview(handle)),
+        _ => None,
+    }
+}
+
+/// What answers a request.
+enum Served {
+    Encoded {
+        bytes: Vec<u8>,
+        format: OutputFormat,
+        cache_control: &'static str,
+        etag: Option<String>,
+    },
+    /// The webview's copy, with this ETag, is still current.
+    NotModified(String),
+}
+
+/// Tags an image as encoded with `options`: the pixels behind a handle never
+/// change, so that's all that can.
+fn etag(handle: ImageHandle, options: &ProcessingOptions) -> Result<String, ErrorWrapper> {
+    let options =
+        serde_json::to_vec(options).map_err(|e| ErrorWrapper::Io(std::io::Error::other(e)))?;
+    Ok(format!("\"{handle}-{:x}\"", Sha256::digest(options)))
+}
+
+fn serve<R: Runtime>(
+    app: &tauri::AppHandle<R>,
+    resource: Resource,
+    if_none_match: Option<&str>,
+) -> Result<Served, ErrorWrapper> {
+    let cache = app.state::<ImageCache>();
+    match resource {
+        Resource::Image(handle, format) => {
+            let image = cache.get(handle)?;
+            let mut options = app
+                .try_state::<Settings>()
+                .map(|settings| settings.processing_options())
+                .unwrap_or_default();
+            if let Some(format) = format {
+                options.output_format = format;
+            }
+            let etag = etag(handle, &options)?;
+            if if_none_match == Some(etag.as_str()) {
+                return Ok(Served::NotModified(etag));
+            }
+            let bytes = squarer_core::encode_output(&image, &options)?;
+            Ok(Served::Encoded {
+                bytes,
+                format: options.output_format,
+                cache_control: IMAGE_CACHE_CONTROL,
+                etag: Some(etag),
+            })
+        }
+        Resource::Preview(handle) => {
+            let preview = cache.get_preview(handle)?;
+            let bytes = encode::encode(
+                &preview,
+                OutputFormat::Jpeg,
+                crate::PREVIEW_QUALITY,
+                encode::DEFAULT_BACKGROUND,
+            )?;
+            Ok(Served::Encoded {
+                bytes,
+                format: OutputFormat::Jpeg,
+                cache_control: PREVIEW_CACHE_CONTROL,
+                etag: None,
+            })
+        }
+    }
+}
+
+fn error_response(status: StatusCode, message: String) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .body(message.into_bytes())
+        .unwrap()
+}
+
+/// Answers a request for a cached image, encoding it on the blocking thread
+/// pool. Unknown handles get a 404, so a stale `src` fails like any missing
+/// image, and a full image the webview already has, encoded with the same
+/// settings, gets a 304.
+pub fn handle<R: Runtime>(
+    context: UriSchemeContext<'_, R>,
+    request: Request<Vec<u8>>,
+    responder: UriSchemeResponder,
+) {
+    let Some(resource) = parse(&request) else {
+        responder.respond(error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Unrecognized {SCHEME} URI: {}", request.uri()),
+        ));
+        return;
+    };
+    let if_none_match = request
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let app = context.app_handle().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        // Respond even if serving panics, or the request never finishes.
+        let served = crate::catch_panic(|| serve(&app, resource, if_none_match.as_deref()));
+        let response = match served {
+            Ok(Served::Encoded {
+                bytes,
+                format,
+                cache_control,
+                etag,
+            }) => {
+                let mut response = Response::builder()
+                    .header(header::CONTENT_TYPE, format.mime_type())
+                    .header(header::CACHE_CONTROL, cache_control)
+                    // Lets the frontend draw the image to a canvas and read
+                    // it back without the canvas being tainted.
+                    .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+                if let Some(etag) = etag {
+                    response = response.header(header::ETAG, etag);
+                }
+                response.body(bytes).unwrap()
+            }
+            Ok(Served::NotModified(etag)) => Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .header(header::CACHE_CONTROL, IMAGE_CACHE_CONTROL)
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(Vec::new())
+                .unwrap(),
+            Err(error @ ErrorWrapper::InvalidInput(_)) => {
+                error_response(StatusCode::NOT_FOUND, error.to_string())
+            }
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        };
+        responder.respond(response);
+    });
+}
@@ -0,0 +1,275 @@
+use serde::Serialize;
+use squarer_core::decode;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tiny_http::{Method, Request, Server};
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cache::ImageCache;
+use crate::camera::Capture;
+use crate::http::{self, HttpResponse};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+/// Event emitted with the `Capture` of each photo uploaded from a phone.
+const RECEIVED_EVENT: &str = "photo-received";
+/// Event emitted when a session ends by itself, having gone idle.
+const ENDED_EVENT: &str = "receive-session-ended";
+// How long a session waits for the next upload before it stops listening,
+// so a forgotten one doesn't stay open to the network.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// Bigger than any phone photo, small enough not to be a way to fill memory.
+const MAX_UPLOAD_BYTES: u64 = 100 * 1024 * 1024;
+
+/// What the phone's browser is shown: a button that opens the camera (or the
+/// photo library) and uploads each photo chosen.
+const UPLOAD_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Send to Squarer</title>
+<style>
+body { font-family: system-ui, sans-serif; margin: 2em; text-align: center; }
+label { display: inline-block; padding: 1em 2em; border-radius: 0.5em; background: #2463eb; color: white; font-size: 1.2em; }
+input { display: none; }
+</style>
+</head>
+<body>
+<h1>Send to Squarer</h1>
+<label>Take or choose photos<input type="file" accept="image/*" capture="environment" multiple></label>
+<p id="status"></p>
+<script>
+const input = document.querySelector("input");
+const status = document.getElementById("status");
+input.addEventListener("change", async () => {
+  const files = [...input.files];
+  for (const [index, file] of files.entries()) {
+    status.textContent = `Sending ${index + 1} of ${files.length}…`;
+    const response = await fetch("upload", { method: "POST", body: file });
+    if (!response.ok) {
+      status.textContent = `Couldn't send ${file.name}: ${await response.text()}`;
+      return;
+    }
+  }
+  status.textContent = `Sent ${files.length} photo${files.length == 1 ? "" : "s"}.`;
+  input.value = "";
+});
+</script>
+</body>
+</html>
+"#;
+
+/// Where a phone on the same network can upload photos, for showing as a
+/// QR code, and the other addresses this device has there, for choosing
+/// from if the phone can't reach that one.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiveSession {
+    url: String,
+    addresses: Vec<IpAddr>,
+}
+
+struct Running {
+    server: Arc<Server>,
+    port: u16,
+    token: String,
+}
+
+impl Running {
+    fn session(&self, address: IpAddr, addresses: Vec<IpAddr>) -> ReceiveSession {
+        ReceiveSession {
+            url: format!(
+                "http://{}/{}/",
+                SocketAddr::new(address, self.port),
+                self.token
+            ),
+            addresses,
+        }
+    }
+}
+
+/// The receive session's listener, if one is running, kept in managed state.
+#[derive(Default)]
+pub struct Receiver {
+    running: Mutex<Option<Running>>,
+}
+
+fn reachable(address: &IpAddr) -> bool {
+    !address.is_unspecified() && !address.is_loopback()
+}
+
+/// The address on the interface the default route goes through, if there's
+/// a default route.
+fn routed_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    // Connecting a UDP socket sends nothing; it only picks the interface.
+    socket.connect((Ipv4Addr::new(8, 8, 8, 8), 80)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// The IPv4 addresses of every interface that's up.
+#[cfg(unix)]
+fn interface_addresses() -> Vec<IpAddr> {
+    use nix::ifaddrs::getifaddrs;
+    use nix::net::if_::InterfaceFlags;
+
+    let Ok(interfaces) = getifaddrs() else {
+        return Vec::new();
+    };
+    interfaces
+        .filter(|interface| interface.flags.contains(InterfaceFlags::IFF_UP))
+        .filter_map(|interface| Some(IpAddr::V4(interface.address?.as_sockaddr_in()?.ip())))
+        .collect()
+}
+
+/// Listing interfaces needs platform APIs std doesn't wrap; elsewhere, the
+/// routed address (or one the user gives) has to do.
+#[cfg(not(unix))]
+fn interface_addresses() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// The addresses other devices on the local network may reach this one at,
+/// best first: the one the default route goes through, then those of the
+/// other interfaces, for networks with no way out (such as a phone's
+/// hotspot or a router without internet).
+fn local_addresses() -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for address in routed_address().into_iter().chain(interface_addresses()) {
+        if reachable(&address) && !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+/// Decodes and caches an uploaded photo, looks for the document in it, and
+/// passes it on to the frontend.
+fn receive(
+    request: &mut Request,
+    app: &AppHandle,
+    cache: &ImageCache,
+    settings: &Settings,
+) -> Result<(), ErrorWrapper> {
+    let body = http::read_body(request, MAX_UPLOAD_BYTES)?;
+    let image = decode::read_image_bytes(body, &settings.decode_limits())?.image;
+    app.emit(RECEIVED_EVENT, Capture::detect(cache, image))?;
+    Ok(())
+}
+
+fn respond(
+    mut request: Request,
+    token: &str,
+    app: &AppHandle,
+    cache: &ImageCache,
+    settings: &Settings,
+) {
+    let path = request.url().split('?').next().unwrap_or_default();
+    let route = path
+        .strip_prefix('/')
+        .and_then(|path| path.strip_prefix(token))
+        .map(str::to_owned);
+    let response: HttpResponse = match (request.method(), route.as_deref()) {
+        (Method::Get, Some("/")) => HttpResponse::from_string(UPLOAD_PAGE)
+            .with_header(http::header("Content-Type", "text/html; charset=utf-8")),
+        (Method::Post, Some("/upload")) => {
+            match crate::catch_panic(|| receive(&mut request, app, cache, settings)) {
+                Ok(()) => http::text(200, "Received"),
+                Err(error) => http::error(&error),
+            }
+        }
+        _ => http::text(404, "Not found"),
+    };
+    // The phone may have gone away; there's no one else to tell.
+    let _ = request.respond(response);
+}
+
+/// Starts listening on the local network for photos uploaded from a phone's
+/// browser, and returns the URL to open there (e.g. by scanning it as a QR
+/// code), at `address` if given, else the one most likely to be reachable.
+/// Each photo is decoded, cached and checked for a document, then emitted as
+/// a `photo-received` event with the same fields `capture_and_detect`
+/// returns. If a session is already running, its URL is returned again (at
+/// `address`, if given, since the session listens on every interface). The
+/// session stops by itself, with a `receive-session-ended` event, after ten
+/// minutes without an upload.
+#[tauri::command]
+pub fn start_receive_session(
+    app: AppHandle,
+    receiver: State<Receiver>,
+    cache: State<ImageCache>,
+    settings: State<Settings>,
+    address: Option<IpAddr>,
+) -> Result<ReceiveSession, ErrorWrapper> {
+    let addresses = local_addresses();
+    let address = match address {
+        Some(address) if !reachable(&address) => {
+            return Err(ErrorWrapper::InvalidInput(format!(
+                "A phone can't reach this device at {address}"
+            )));
+        }
+        Some(address) => address,
+        None => *addresses
+            .first()
+            .ok_or_else(|| ErrorWrapper::Unsupported(String::from("Not connected to a network")))?,
+    };
+    let mut running = receiver.running.lock().unwrap();
+    if let Some(running) = running.as_ref() {
+        return Ok(running.session(address, addresses));
+    }
+    let server = Server::http((Ipv4Addr::UNSPECIFIED, 0))
+        .map_err(|e| ErrorWrapper::Io(std::io::Error::other(e)))?;
+    let port = server
+        .server_addr()
+        .to_ip()
+        .map(|address| address.port())
+        .ok_or_else(|| ErrorWrapper::Io(std::io::Error::other("Not listening on a port")))?;
+    // In the URL, so it can't be guessed by anyone else on the network.
+    let token = http::random_token()?;
+    let server = Arc::new(server);
+    let listener = server.clone();
+    let cache = cache.inner().clone();
+    let settings = settings.inner().clone();
+    let listener_token = token.clone();
+    std::thread::spawn(move || {
+        // Ends once `stop_receive_session` unblocks the server, or no upload
+        // has come for `IDLE_TIMEOUT`.
+        while let Ok(Some(request)) = listener.recv_timeout(IDLE_TIMEOUT) {
+            respond(request, &listener_token, &app, &cache, &settings);
+        }
+        let receiver = app.state::<Receiver>();
+        let mut running = receiver.running.lock().unwrap();
+        // Unless the session was stopped, or another has started since.
+        if running
+            .as_ref()
+            .is_some_and(|running| Arc::ptr_eq(&running.server, &listener))
+        {
+            *running = None;
+            drop(running);
+            let _ = app.emit(ENDED_EVENT, ());
+        }
+    });
+    let started = Running {
+        server,
+        port,
+        token,
+    };
+    let session = started.session(address, addresses);
+    *running = Some(started);
+    Ok(session)
+}
+
+/// Stops listening for uploads. Returns false if no session was running.
+#[tauri::command]
+pub fn stop_receive_session(receiver: State<Receiver>) -> bool {
+    match receiver.running.lock().unwrap().take() {
+        Some(running) => {
+            running.server.unblock();
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,199 @@
+use serde::Serialize;
+use squarer_core::decode;
+use tauri::State;
+
+use crate::cache::ImageCache;
+use crate::camera::Capture;
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+// Resolution for `acquire_scan` when none is given.
+const DEFAULT_DPI: u32 = 300;
+// Beyond what any flatbed offers, and a scan of A4 at this resolution is
+// already about 90 megapixels.
+const MAX_DPI: u32 = 2400;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scanner {
+    /// What to pass to `acquire_scan`.
+    id: String,
+    /// The make and model, for showing to the user.
+    name: String,
+}
+
+/// Runs a command to completion, returning what it wrote to stdout or, if it
+/// failed, an error with what it wrote to stderr.
+#[cfg(any(
+    all(feature = "sane", target_os = "linux"),
+    all(feature = "wia", windows)
+))]
+fn run(command: &mut std::process::Command) -> Result<Vec<u8>, ErrorWrapper> {
+    let output = command.output()?;
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    let message = String::from_utf8_lossy(&output.stderr);
+    Err(ErrorWrapper::Io(std::io::Error::other(format!(
+        "Scanning failed: {}",
+        message.trim()
+    ))))
+}
+
+/// SANE, through its `scanimage` tool, which comes with the SANE backends
+/// every distribution packages.
+#[cfg(all(feature = "sane", target_os = "linux"))]
+mod backend {
+    use super::*;
+
+    use std::process::Command;
+
+    pub fn list() -> Result<Vec<Scanner>, ErrorWrapper> {
+        let output =
+            run(Command::new("scanimage").args(["--formatted-device-list", "%d\t%v %m%n"]))?;
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(id, name)| Scanner {
+                id: id.to_owned(),
+                name: name.trim().to_owned(),
+            })
+            .collect())
+    }
+
+    pub fn scan(device: &str, dpi: u32) -> Result<Vec<u8>, ErrorWrapper> {
+        run(Command::new("scanimage")
+            .arg(format!("--device-name={device}"))
+            .arg(format!("--resolution={dpi}"))
+            .arg("--format=png"))
+    }
+}
+
+/// Windows Image Acquisition, through its scripting interface from
+/// PowerShell. The device and resolution are passed in the environment so
+/// they're never parsed as script.
+#[cfg(all(feature = "wia", windows))]
+mod backend {
+    use super::*;
+
+    use std::process::Command;
+
+    const LIST_SCRIPT: &str = r#"
+$manager = New-Object -ComObject WIA.DeviceManager
+foreach ($info in $manager.DeviceInfos) {
+    if ($info.Type -eq 1) {
+        "$($info.DeviceID)`t$($info.Properties.Item('Name').Value)"
+    }
+}
+"#;
+    // 6147 and 6148 are WIA_IPS_XRES and WIA_IPS_YRES; the GUID is
+    // WiaImgFmt_PNG.
+    const SCAN_SCRIPT: &str = r#"
+$ErrorActionPreference = 'Stop'
+$manager = New-Object -ComObject WIA.DeviceManager
+$device = $null
+foreach ($info in $manager.DeviceInfos) {
+    if ($info.DeviceID -eq $env:SQUARER_SCANNER) { $device = $info.Connect() }
+}
+if ($device -eq $null) { throw "No scanner with ID $env:SQUARER_SCANNER" }
+$item = $device.Items.Item(1)
+foreach ($property in $item.Properties) {
+    if ($property.PropertyID -eq 6147 -or $property.PropertyID -eq 6148) {
+        $property.Value = [int]$env:SQUARER_DPI
+    }
+}
+$image = $item.Transfer('{B96B3CAF-0728-11D3-9D7B-0000F81EF32E}')
+$bytes = $image.FileData.BinaryData
+$stdout = [Console]::OpenStandardOutput()
+$stdout.Write($bytes, 0, $bytes.Length)
+$stdout.Flush()
+"#;
+
+    fn powershell(script: &str) -> Command {
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-NonInteractive", "-Command", script]);
+        command
+    }
+
+    pub fn list() -> Result<Vec<Scanner>, ErrorWrapper> {
+        let output = run(&mut powershell(LIST_SCRIPT))?;
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(id, name)| Scanner {
+                id: id.to_owned(),
+                name: name.trim().to_owned(),
+            })
+            .collect())
+    }
+
+    pub fn scan(device: &str, dpi: u32) -> Result<Vec<u8>, ErrorWrapper> {
+        run(powershell(SCAN_SCRIPT)
+            .env("SQUARER_SCANNER", device)
+            .env("SQUARER_DPI", dpi.to_string()))
+    }
+}
+
+/// Neither backend is built in. There's no macOS one yet: Image Capture
+/// can only be driven through ImageCaptureCore, which has no command-line
+/// or scripting interface to call out to.
+#[cfg(not(any(
+    all(feature = "sane", target_os = "linux"),
+    all(feature = "wia", windows)
+)))]
+mod backend {
+    use super::*;
+
+    fn unsupported() -> ErrorWrapper {
+        ErrorWrapper::Unsupported(String::from(
+            "Scanning needs a build with the `sane` feature on Linux or `wia` on Windows",
+        ))
+    }
+
+    pub fn list() -> Result<Vec<Scanner>, ErrorWrapper> {
+        Err(unsupported())
+    }
+
+    pub fn scan(_device: &str, _dpi: u32) -> Result<Vec<u8>, ErrorWrapper> {
+        Err(unsupported())
+    }
+}
+
+/// Whether this build can scan at all.
+pub const AVAILABLE: bool = cfg!(any(
+    all(feature = "sane", target_os = "linux"),
+    all(feature = "wia", windows)
+));
+
+/// The scanners attached or on the network that the platform's scanning
+/// service can see. Slow (SANE probes the network), so call it on demand.
+#[tauri::command]
+pub async fn list_scanners() -> Result<Vec<Scanner>, ErrorWrapper> {
+    crate::run_blocking(backend::list).await
+}
+
+/// Scans a page from `device` (an ID from `list_scanners`) at `dpi`, 300 by
+/// default, caches it and looks for the document in it, as
+/// `capture_and_detect` does, ready to be squared.
+#[tauri::command]
+pub async fn acquire_scan(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    device: String,
+    dpi: Option<u32>,
+) -> Result<Capture, ErrorWrapper> {
+    let dpi = dpi.unwrap_or(DEFAULT_DPI);
+    if !(1..=MAX_DPI).contains(&dpi) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "DPI must be between 1 and {MAX_DPI}, got {dpi}"
+        )));
+    }
+    let limits = settings.decode_limits();
+    let cache = cache.inner().clone();
+    crate::run_blocking(move || {
+        let bytes = backend::scan(&device, dpi)?;
+        let image = decode::read_image_bytes(bytes, &limits)?.image;
+        Ok(Capture::detect(&cache, image))
+    })
+    .await
+}
@@ -0,0 +1,158 @@
+use image::DynamicImage;
+use serde::Deserialize;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_global_shortcut::{Code, GlobalShortcutExt, Modifiers, Shortcut, ShortcutState};
+
+use std::time::Duration;
+
+use crate::cache::ImageCache;
+use crate::camera::Capture;
+use crate::ErrorWrapper;
+
+/// Event emitted with the `Capture` of each screenshot the shortcut takes,
+/// for the frontend to open in the corner editor.
+const CAPTURED_EVENT: &str = "screenshot-captured";
+/// Event emitted with the error message when the shortcut's screenshot fails.
+const FAILED_EVENT: &str = "screenshot-failed";
+// Time for the window to disappear before the screen is captured.
+const HIDE_DELAY: Duration = Duration::from_millis(250);
+
+/// Part of the screen, in physical pixels in the desktop's coordinates (the
+/// same space as the cursor position).
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(not(feature = "screenshot"), allow(dead_code))]
+pub struct ScreenRegion {
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+}
+
+#[cfg(feature = "screenshot")]
+mod backend {
+    use super::*;
+    use xcap::Monitor;
+
+    fn capture_error(error: xcap::XCapError) -> ErrorWrapper {
+        ErrorWrapper::ScreenCapture(error.to_string())
+    }
+
+    /// Captures `region`, or by default the whole monitor at `point`.
+    pub fn capture(
+        point: (i32, i32),
+        region: Option<ScreenRegion>,
+    ) -> Result<DynamicImage, ErrorWrapper> {
+        let (x, y) = region.map_or(point, |region| (region.x, region.y));
+        let monitor = Monitor::from_point(x, y).map_err(capture_error)?;
+        let image = match region {
+            Some(region) => {
+                let left = monitor.x().map_err(capture_error)?;
+                let top = monitor.y().map_err(capture_error)?;
+                // A region starting left of or above its monitor can't be
+                // on it.
+                let (Ok(x), Ok(y)) = (u32::try_from(x - left), u32::try_from(y - top)) else {
+                    return Err(ErrorWrapper::InvalidInput(String::from(
+                        "The region isn't on a monitor",
+                    )));
+                };
+                monitor
+                    .capture_region(x, y, region.width, region.height)
+                    .map_err(capture_error)?
+            }
+            None => monitor.capture_image().map_err(capture_error)?,
+        };
+        Ok(DynamicImage::ImageRgba8(image))
+    }
+}
+
+#[cfg(not(feature = "screenshot"))]
+mod backend {
+    use super::*;
+
+    pub fn capture(
+        _point: (i32, i32),
+        _region: Option<ScreenRegion>,
+    ) -> Result<DynamicImage, ErrorWrapper> {
+        Err(ErrorWrapper::Unsupported(String::from(
+            "Screen capture needs a build with the `screenshot` feature",
+        )))
+    }
+}
+
+/// Captures the screen with Squarer's own windows out of the way, caches
+/// the result and looks for a document in it.
+fn capture(
+    app: &AppHandle,
+    cache: &ImageCache,
+    region: Option<ScreenRegion>,
+) -> Result<Capture, ErrorWrapper> {
+    let visible: Vec<_> = app
+        .webview_windows()
+        .into_values()
+        .filter(|window| window.is_visible().unwrap_or(false))
+        .collect();
+    for window in &visible {
+        let _ = window.hide();
+    }
+    if !visible.is_empty() {
+        std::thread::sleep(HIDE_DELAY);
+    }
+    let point = app
+        .cursor_position()
+        .map_or((0, 0), |position| (position.x as i32, position.y as i32));
+    let image = backend::capture(point, region);
+    for window in &visible {
+        let _ = window.show();
+    }
+    Ok(Capture::detect(cache, image?))
+}
+
+/// Registers the global shortcut (Ctrl+Alt+Shift+S, or Cmd+Option+Shift+S
+/// on macOS) that captures the monitor under the cursor, emits a
+/// `screenshot-captured` event (or `screenshot-failed`) and brings the main
+/// window up to edit the result. Another app already holding the shortcut
+/// isn't fatal; the capture can still be started with `capture_screen`.
+pub fn init(app: &AppHandle) {
+    let command = if cfg!(target_os = "macos") {
+        Modifiers::SUPER
+    } else {
+        Modifiers::CONTROL
+    };
+    let shortcut = Shortcut::new(
+        Some(command | Modifiers::ALT | Modifiers::SHIFT),
+        Code::KeyS,
+    );
+    let _ = app
+        .global_shortcut()
+        .on_shortcut(shortcut, |app, _, event| {
+            if event.state != ShortcutState::Pressed {
+                return;
+            }
+            let app = app.clone();
+            std::thread::spawn(move || {
+                let result = capture(&app, &app.state::<ImageCache>(), None);
+                if let Some(window) = app.webview_windows().into_values().next() {
+                    let _ = window.unminimize();
+                    let _ = window.set_focus();
+                }
+                let _ = match result {
+                    Ok(capture) => app.emit(CAPTURED_EVENT, capture),
+                    Err(error) => app.emit(FAILED_EVENT, error.to_string()),
+                };
+            });
+        });
+}
+
+/// Captures `region` of the screen, or by default the monitor under the
+/// cursor, with the app's windows hidden for the moment it takes. Returns
+/// what `capture_and_detect` does.
+#[tauri::command]
+pub async fn capture_screen(
+    app: AppHandle,
+    cache: State<'_, ImageCache>,
+    region: Option<ScreenRegion>,
+) -> Result<Capture, ErrorWrapper> {
+    let cache = cache.inner().clone();
+    crate::run_blocking(move || capture(&app, &cache, region)).await
+}
@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::{decode_image_file, run_blocking, ErrorWrapper};
+
+// Where the work in progress is kept, in the app's data directory, until the
+// app exits normally.
+pub const SESSION_FILE: &str = "session.json";
+// How often changes to the session are written out.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The image being worked on and where its corners have been put so far.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    source: PathBuf,
+    control_points: Vec<ControlPoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    options: Option<ProcessingOptions>,
+}
+
+/// A session restored by `recover_session`, with its image decoded again
+/// if it's still there.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveredSession {
+    #[serde(flatten)]
+    session: Session,
+    handle: Option<ImageHandle>,
+}
+
+#[derive(Default)]
+struct Inner {
+    current: Option<Session>,
+    // Whether `current` has changed since it was last written out.
+    dirty: bool,
+    // What the autosave held at launch: the session of a run that didn't
+    // exit normally.
+    recovered: Option<Session>,
+}
+
+/// The work in progress, kept in managed state and written to disk every
+/// `AUTOSAVE_INTERVAL` while it changes, so it survives a crash. Clones share
+/// the same session.
+#[derive(Clone)]
+pub struct Autosave {
+    inner: Arc<Mutex<Inner>>,
+    path: Option<PathBuf>,
+}
+
+impl Autosave {
+    /// Picks up whatever a previous run left at `path`. Without a path
+    /// nothing is saved.
+    pub fn load(path: Option<PathBuf>) -> Autosave {
+        let recovered = path
+            .as_deref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok());
+        Autosave {
+            inner: Arc::new(Mutex::new(Inner {
+                recovered,
+                ..Inner::default()
+            })),
+            path,
+        }
+    }
+
+    fn set(&self, session: Option<Session>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.current = session;
+        inner.dirty = true;
+    }
+
+    /// Writes the session out if it's changed, or removes the file if it's
+    /// been cleared.
+    pub fn save(&self) -> Result<(), ErrorWrapper> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let session = {
+            let mut inner = self.inner.lock().unwrap();
+            if !inner.dirty {
+                return Ok(());
+            }
+            inner.dirty = false;
+            inner.current.clone()
+        };
+        match session {
+            Some(session) => write(path, &session),
+            None => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Saves every `AUTOSAVE_INTERVAL` on a thread of its own, for the rest
+    /// of the run.
+    pub fn start(&self) {
+        let autosave = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(AUTOSAVE_INTERVAL);
+            let _ = autosave.save();
+        });
+    }
+
+    /// The previous run's session, if there was one and it hasn't been taken
+    /// yet. Unless this run's session has been recorded since, the file is
+    /// cleared on the next save, so it isn't offered again next time.
+    fn take_recovered(&self) -> Option<Session> {
+        let mut inner = self.inner.lock().unwrap();
+        let recovered = inner.recovered.take()?;
+        inner.dirty = true;
+        Some(recovered)
+    }
+
+    /// Forgets the session on a normal exit, so nothing is offered for
+    /// recovery next time.
+    pub fn finish(&self) {
+        self.set(None);
+        let _ = self.save();
+    }
+}
+
+/// Writes via a temporary file, so a crash mid-write can't leave a truncated
+/// session behind.
+fn write(path: &Path, session: &Session) -> Result<(), ErrorWrapper> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let json = serde_json::to_vec_pretty(session).map_err(std::io::Error::other)?;
+    let temporary = path.with_extension("json.tmp");
+    std::fs::write(&temporary, json)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Records the image being worked on and its control points so far, to be
+/// offered by `recover_session` if the app doesn't exit normally. Cheap
+/// enough to call on every change; it's written out every few seconds.
+#[tauri::command]
+pub fn update_session(
+    autosave: State<Autosave>,
+    source: PathBuf,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+) {
+    autosave.set(Some(Session {
+        source,
+        control_points,
+        options,
+    }));
+}
+
+/// Forgets the session, e.g. once its image has been exported or closed.
+#[tauri::command]
+pub fn clear_session(autosave: State<Autosave>) {
+    autosave.set(None);
+}
+
+/// The session the last run was in the middle of when it crashed or was
+/// killed, if any, with its image loaded into the cache again (unless it's
+/// gone or can't be decoded). It's only offered once.
+#[tauri::command]
+pub async fn recover_session(
+    autosave: State<'_, Autosave>,
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+) -> Result<Option<RecoveredSession>, ErrorWrapper> {
+    let Some(session) = autosave.take_recovered() else {
+        return Ok(None);
+    };
+    let limits = settings.decode_limits();
+    let source = session.source.clone();
+    let image = run_blocking(move || decode_image_file(&source, &limits)).await;
+    Ok(Some(RecoveredSession {
+        session,
+        handle: image.ok().map(|image| cache.insert(image)),
+    }))
+}
@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+use squarer_core::decode::DecodeLimits;
+use squarer_core::encode::{self, OutputFormat};
+use squarer_core::{Fill, InterpolationMode, ProcessingOptions};
+use tauri::State;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::ErrorWrapper;
+
+// Where the preferences are kept, in the app's config directory.
+pub const SETTINGS_FILE: &str = "settings.json";
+
+/// The user's preferences, which persist between runs. Commands called
+/// without options take their defaults from here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Preferences {
+    pub output_format: OutputFormat,
+    pub quality: u8,
+    pub interpolation: InterpolationMode,
+    pub fill: Fill,
+    /// The directory a file was last opened from or saved to.
+    pub last_directory: Option<PathBuf>,
+    pub decode_limits: DecodeLimits,
+    /// Whether exports to disk get a `.squarer.json` sidecar recording how
+    /// they were made (see `reprocess_from_sidecar`).
+    pub write_sidecars: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            output_format: OutputFormat::default(),
+            quality: encode::DEFAULT_QUALITY,
+            interpolation: InterpolationMode::default(),
+            fill: Fill::default(),
+            last_directory: None,
+            decode_limits: DecodeLimits::default(),
+            write_sidecars: false,
+        }
+    }
+}
+
+/// The preferences, kept in managed state and saved to disk whenever they
+/// change. Clones share the same preferences.
+#[derive(Clone)]
+pub struct Settings {
+    preferences: Arc<RwLock<Preferences>>,
+    path: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Loads the preferences saved at `path`, falling back to the defaults if
+    /// there are none (or they can't be read). Without a path they're kept in
+    /// memory only.
+    pub fn load(path: Option<PathBuf>) -> Settings {
+        let preferences = path
+            .as_deref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        Settings {
+            preferences: Arc::new(RwLock::new(preferences)),
+            path,
+        }
+    }
+
+    pub fn preferences(&self) -> Preferences {
+        self.preferences.read().unwrap().clone()
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        self.preferences.read().unwrap().decode_limits
+    }
+
+    pub fn write_sidecars(&self) -> bool {
+        self.preferences.read().unwrap().write_sidecars
+    }
+
+    /// Processing options for commands that weren't given any.
+    pub fn processing_options(&self) -> ProcessingOptions {
+        let preferences = self.preferences.read().unwrap();
+        ProcessingOptions {
+            output_format: preferences.output_format,
+            quality: preferences.quality,
+            interpolation: preferences.interpolation,
+            fill: preferences.fill,
+            ..ProcessingOptions::default()
+        }
+    }
+
+    /// Changes the preferences and saves them.
+    pub fn update(&self, change: impl FnOnce(&mut Preferences)) -> Result<(), ErrorWrapper> {
+        let preferences = {
+            let mut preferences = self.preferences.write().unwrap();
+            change(&mut preferences);
+            preferences.clone()
+        };
+        match &self.path {
+            Some(path) => save(path, &preferences),
+            None => Ok(()),
+        }
+    }
+}
+
+fn save(path: &Path, preferences: &Preferences) -> Result<(), ErrorWrapper> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let json = serde_json::to_vec_pretty(preferences).map_err(std::io::Error::other)?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+fn check_decode_limits(limits: &DecodeLimits) -> Result<(), ErrorWrapper> {
+    if limits.max_width == 0 || limits.max_height == 0 || limits.max_alloc == 0 {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "Decode limits must be greater than zero",
+        )));
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_settings(settings: State<Settings>) -> Preferences {
+    settings.preferences()
+}
+
+/// Replaces the preferences, saving them for next time.
+#[tauri::command]
+pub fn set_settings(
+    settings: State<Settings>,
+    preferences: Preferences,
+) -> Result<(), ErrorWrapper> {
+    if !(1..=100).contains(&preferences.quality) {
+        return Err(ErrorWrapper::InvalidInput(format!(
+            "Quality must be between 1 and 100, got {}",
+            preferences.quality
+        )));
+    }
+    check_decode_limits(&preferences.decode_limits)?;
+    settings.update(|current| *current = preferences)
+}
+
+/// The caps on the size of images that will be decoded.
+#[tauri::command]
+pub fn get_decode_limits(settings: State<Settings>) -> DecodeLimits {
+    settings.decode_limits()
+}
+
+/// Changes the caps on the size of images that will be decoded; images over
+/// them fail with an `image_too_large` error.
+#[tauri::command]
+pub fn set_decode_limits(
+    settings: State<Settings>,
+    limits: DecodeLimits,
+) -> Result<(), ErrorWrapper> {
+    check_decode_limits(&limits)?;
+    settings.update(|preferences| preferences.decode_limits = limits)
+}
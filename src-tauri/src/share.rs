@@ -0,0 +1,109 @@
+use tauri::{AppHandle, Manager, State};
+
+use std::path::Path;
+
+use crate::cache::{ImageCache, ImageHandle};
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+
+#[cfg(target_os = "android")]
+mod android {
+    use serde::{Deserialize, Serialize};
+    use tauri::ipc::{Channel, InvokeResponseBody};
+    use tauri::plugin::{Builder, PluginHandle, TauriPlugin};
+    use tauri::{AppHandle, Manager, Wry};
+
+    use std::path::{Path, PathBuf};
+
+    use crate::{open, ErrorWrapper};
+
+    /// The Kotlin side, in `gen/android/.../SharePlugin.kt`.
+    struct Share(PluginHandle<Wry>);
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ShareFile<'a> {
+        path: &'a Path,
+        mime_type: &'a str,
+    }
+
+    #[derive(Serialize)]
+    struct Receiver {
+        handler: Channel,
+    }
+
+    /// Images shared to the app, copied into its cache directory.
+    #[derive(Deserialize)]
+    struct Shared {
+        paths: Vec<PathBuf>,
+    }
+
+    pub fn init() -> TauriPlugin<Wry> {
+        Builder::new("share")
+            .setup(|app, api| {
+                let handle = api.register_android_plugin("com.revfad.squarer", "SharePlugin")?;
+                let receiving_app = app.clone();
+                // Images shared to Squarer open as if with "Open with".
+                let handler = Channel::new(move |body| {
+                    if let InvokeResponseBody::Json(json) = body {
+                        if let Ok(shared) = serde_json::from_str::<Shared>(&json) {
+                            open::open_paths(&receiving_app, shared.paths);
+                        }
+                    }
+                    Ok(())
+                });
+                handle
+                    .run_mobile_plugin::<serde_json::Value>("setReceiver", Receiver { handler })?;
+                app.manage(Share(handle));
+                Ok(())
+            })
+            .build()
+    }
+
+    /// Opens the system share sheet for the file at `path`.
+    pub fn share(app: &AppHandle, path: &Path, mime_type: &str) -> Result<(), ErrorWrapper> {
+        app.state::<Share>()
+            .0
+            .run_mobile_plugin::<serde_json::Value>("share", ShareFile { path, mime_type })
+            .map_err(|e| ErrorWrapper::Io(std::io::Error::other(format!("Sharing failed: {e}"))))?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "android")]
+pub use android::init;
+#[cfg(target_os = "android")]
+use android::share;
+
+/// There's no iOS plugin yet. Images shared to Squarer there come in through
+/// the file associations, as open-file events.
+#[cfg(not(target_os = "android"))]
+fn share(_app: &AppHandle, _path: &Path, _mime_type: &str) -> Result<(), ErrorWrapper> {
+    Err(ErrorWrapper::Unsupported(String::from(
+        "Sharing is only available on Android",
+    )))
+}
+
+/// Encodes a cached image, e.g. a squared result, per the settings into the
+/// app's cache directory and offers it to other apps through the system
+/// share sheet.
+#[tauri::command]
+pub async fn share_result(
+    app: AppHandle,
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    handle: ImageHandle,
+) -> Result<(), ErrorWrapper> {
+    let image = cache.get(handle)?;
+    let options = settings.processing_options();
+    let directory = app.path().app_cache_dir()?;
+    crate::run_blocking(move || {
+        let bytes = squarer_core::encode_output(&image, &options)?;
+        std::fs::create_dir_all(&directory)?;
+        let format = options.output_format;
+        let path = directory.join(format!("squarer-{handle}.{}", format.extension()));
+        std::fs::write(&path, bytes)?;
+        share(&app, &path, format.mime_type())
+    })
+    .await
+}
@@ -0,0 +1,112 @@
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{ControlPoint, ProcessingOptions};
+use tauri::ipc::{Channel, InvokeResponseBody};
+use tauri::State;
+
+use std::io::{self, Write};
+
+use crate::cache::ImageCache;
+use crate::jobs::{JobId, JobRegistry};
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::{run_blocking, square_image_source, ErrorWrapper, ImageSource};
+
+// Bytes collected before they're sent on as one message; big enough that the
+// per-message overhead doesn't matter, small enough that memory stays flat.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Sends what's written to it over an IPC channel in chunks of `CHUNK_SIZE`,
+/// failing the write once the job is cancelled so the encoder stops early.
+struct ChannelWriter<'a> {
+    channel: Channel,
+    buffer: Vec<u8>,
+    cancel: &'a CancellationToken,
+    written: u64,
+}
+
+impl ChannelWriter<'_> {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.channel
+            .send(InvokeResponseBody::Raw(chunk))
+            .map_err(io::Error::other)
+    }
+}
+
+impl Write for ChannelWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.cancel.check().is_err() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled"));
+        }
+        let taken = bytes.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&bytes[..taken]);
+        self.written += taken as u64;
+        if self.buffer.len() == CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            self.send()
+        }
+    }
+}
+
+/// Like `process_image`, but for exports too big to return in one piece:
+/// the encoded result is sent over `on_chunk` as raw byte chunks while it's
+/// being encoded, ending with an empty chunk, so the frontend can start
+/// writing it out straight away. Returns the result's total size in bytes.
+///
+/// The file is only streamed as it's encoded when nothing has to be patched
+/// into it afterwards (see `write_output`); animations, and results with a
+/// DPI, size budget or copied metadata, are encoded first and then sent in
+/// chunks.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+pub async fn process_image_stream(
+    jobs: State<'_, JobRegistry>,
+    settings: State<'_, Settings>,
+    lens_profiles: State<'_, LensProfiles>,
+    cache: State<'_, ImageCache>,
+    image: ImageSource,
+    control_points: Vec<ControlPoint>,
+    options: Option<ProcessingOptions>,
+    job_id: Option<JobId>,
+    on_chunk: Channel,
+) -> Result<u64, ErrorWrapper> {
+    let job = jobs.register(job_id)?;
+    let limits = settings.decode_limits();
+    let options = options.unwrap_or_else(|| settings.processing_options());
+    let lens_profiles = lens_profiles.inner().clone();
+    let cache = cache.inner().clone();
+    run_blocking(move || {
+        let cancel = job.token();
+        let mut writer = ChannelWriter {
+            channel: on_chunk,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            cancel,
+            written: 0,
+        };
+        let result = square_image_source(
+            image,
+            &cache,
+            control_points,
+            options,
+            &limits,
+            &lens_profiles,
+            cancel,
+            &mut writer,
+        );
+        // A write failing because the job was cancelled is a cancellation.
+        cancel.check()?;
+        result?;
+        writer.flush()?;
+        writer.send()?;
+        Ok(writer.written)
+    })
+    .await
+}
@@ -0,0 +1,46 @@
+use tauri::State;
+
+use std::path::PathBuf;
+
+use crate::cache::ImageCache;
+use crate::settings::Settings;
+use crate::{ErrorWrapper, ImageSource};
+use squarer_core::encode;
+use squarer_core::tiff::{self, TiffCompression};
+
+/// Combines already-squared images into a single multi-page TIFF, the usual
+/// format for archived scans. Pages cleaned up with `CleanupMode::Document`
+/// are stored as 1-bit CCITT G4 unless `compression` says otherwise. `dpi`
+/// sets the pages' printed size.
+#[tauri::command]
+pub async fn export_tiff(
+    cache: State<'_, ImageCache>,
+    settings: State<'_, Settings>,
+    pages: Vec<ImageSource>,
+    output_path: PathBuf,
+    compression: Option<TiffCompression>,
+    dpi: Option<f32>,
+) -> Result<(), ErrorWrapper> {
+    if pages.is_empty() {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "A TIFF needs at least one page",
+        )));
+    }
+    let cache = cache.inner().clone();
+    let limits = settings.decode_limits();
+    crate::run_blocking(move || {
+        let images = pages
+            .into_iter()
+            .map(|page| page.load(&cache, &limits))
+            .collect::<Result<Vec<_>, _>>()?;
+        let bytes = tiff::encode_pages(
+            images.iter().map(|image| &**image),
+            compression.unwrap_or_default(),
+            encode::DEFAULT_BACKGROUND,
+            dpi,
+        )?;
+        std::fs::write(output_path, bytes)?;
+        Ok(())
+    })
+    .await
+}
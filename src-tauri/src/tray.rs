@@ -0,0 +1,121 @@
+use image::GenericImageView;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::{detect, quad_from_points, square_quad, ControlPoint};
+use tauri::menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+use tauri::tray::TrayIconBuilder;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_opener::OpenerExt;
+
+use crate::clipboard;
+use crate::settings::Settings;
+use crate::watch::WatchFolder;
+use crate::ErrorWrapper;
+
+const SQUARE_CLIPBOARD: &str = "square-clipboard";
+const OPEN_WATCH_FOLDER: &str = "open-watch-folder";
+const SHOW_WINDOW: &str = "show-window";
+const QUIT: &str = "quit";
+
+/// Tells the user how a quick action went, since there may be no window to
+/// show it in.
+fn toast(app: &AppHandle, message: &str) {
+    let _ = app
+        .notification()
+        .builder()
+        .title("Squarer")
+        .body(message)
+        .show();
+}
+
+/// Finds the document in the image on the clipboard and replaces it with the
+/// squared result.
+fn square_clipboard(settings: &Settings) -> Result<(), ErrorWrapper> {
+    let image = clipboard::read_image()?;
+    let corners = detect::detect_quad(&image).ok_or_else(|| {
+        ErrorWrapper::InvalidInput(String::from("No document found in the clipboard image"))
+    })?;
+    let control_points = corners
+        .into_iter()
+        .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+        .collect();
+    let quad = quad_from_points(control_points, image.dimensions())?;
+    let squared = square_quad(
+        &image,
+        quad,
+        &settings.processing_options(),
+        &CancellationToken::default(),
+    )?;
+    clipboard::write_image(squared.to_rgba8())
+}
+
+fn on_menu_event(app: &AppHandle, event: MenuEvent) {
+    match event.id().as_ref() {
+        SQUARE_CLIPBOARD => {
+            let app = app.clone();
+            std::thread::spawn(move || {
+                let message = match square_clipboard(&app.state::<Settings>()) {
+                    Ok(()) => String::from("The squared image is on the clipboard"),
+                    Err(error) => format!("Couldn't square the clipboard image: {error}"),
+                };
+                toast(&app, &message);
+            });
+        }
+        OPEN_WATCH_FOLDER => match app.state::<WatchFolder>().folder() {
+            Some(folder) => {
+                if let Err(error) = app
+                    .opener()
+                    .open_path(folder.to_string_lossy(), None::<&str>)
+                {
+                    toast(app, &format!("Couldn't open the watch folder: {error}"));
+                }
+            }
+            None => toast(app, "No folder is being watched"),
+        },
+        SHOW_WINDOW => {
+            if let Some(window) = app.webview_windows().values().next() {
+                let _ = window.show();
+                let _ = window.unminimize();
+                let _ = window.set_focus();
+            }
+        }
+        QUIT => app.exit(0),
+        _ => {}
+    }
+}
+
+/// Adds the tray icon and its menu of quick actions, which work without the
+/// main window.
+pub fn init(app: &AppHandle) -> Result<(), ErrorWrapper> {
+    let menu = Menu::with_items(
+        app,
+        &[
+            &MenuItem::with_id(
+                app,
+                SQUARE_CLIPBOARD,
+                "Square Image from Clipboard",
+                true,
+                None::<&str>,
+            )?,
+            &MenuItem::with_id(
+                app,
+                OPEN_WATCH_FOLDER,
+                "Open Watch Folder",
+                true,
+                None::<&str>,
+            )?,
+            &PredefinedMenuItem::separator(app)?,
+            &MenuItem::with_id(app, SHOW_WINDOW, "Show Squarer", true, None::<&str>)?,
+            &MenuItem::with_id(app, QUIT, "Quit", true, None::<&str>)?,
+        ],
+    )?;
+    let mut tray = TrayIconBuilder::new()
+        .tooltip("Squarer")
+        .menu(&menu)
+        .on_menu_event(on_menu_event);
+    if let Some(icon) = app.default_window_icon() {
+        tray = tray.icon(icon.clone());
+    }
+    tray.build(app)?;
+    Ok(())
+}
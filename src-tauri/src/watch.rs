@@ -0,0 +1,246 @@
+use image::GenericImageView;
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, State};
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::jobs::{JobRegistry, JobRequest};
+use crate::lenses::LensProfiles;
+use crate::settings::Settings;
+use crate::ErrorWrapper;
+use squarer_core::cancel::CancellationToken;
+use squarer_core::naming::{Namer, OutputNaming};
+use squarer_core::{decode, detect, ControlPoint, ImageSquaringError, ProcessingOptions};
+
+/// Event emitted after each image dropped into the watched folder is handled.
+const PROCESSED_EVENT: &str = "watch-processed";
+// Detections less confident than this are flagged for review by default.
+const DEFAULT_MIN_CONFIDENCE: f64 = 0.6;
+// How often, and how many times, to check whether a new file is still being
+// written before giving up on it settling.
+const SETTLE_INTERVAL: Duration = Duration::from_millis(250);
+const SETTLE_ATTEMPTS: u32 = 40;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchConfig {
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    /// The settings' processing options otherwise.
+    #[serde(default)]
+    options: Option<ProcessingOptions>,
+    /// Detections with a lower confidence (0 to 1) are still processed, but
+    /// flagged with `needsReview`.
+    #[serde(default = "default_min_confidence")]
+    min_confidence: f64,
+    /// How outputs are named; `index` counts the files processed since
+    /// watching started.
+    #[serde(default)]
+    naming: OutputNaming,
+}
+
+fn default_min_confidence() -> f64 {
+    DEFAULT_MIN_CONFIDENCE
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchResult {
+    path: PathBuf,
+    output_path: Option<PathBuf>,
+    control_points: Option<Vec<ControlPoint>>,
+    confidence: Option<f64>,
+    /// Set when the corners were found with low confidence, or not at all.
+    needs_review: bool,
+    /// Set when the output's name was taken and the naming said to skip it.
+    skipped: bool,
+    error: Option<String>,
+}
+
+/// The folder being watched, if any, kept in managed state. Dropping the
+/// watcher stops it.
+#[derive(Default)]
+pub struct WatchFolder {
+    // The watcher, and the folder it's watching.
+    watcher: Mutex<Option<(RecommendedWatcher, PathBuf)>>,
+}
+
+impl WatchFolder {
+    /// The folder being watched, if any.
+    pub fn folder(&self) -> Option<PathBuf> {
+        let watcher = self.watcher.lock().unwrap();
+        watcher.as_ref().map(|(_, folder)| folder.clone())
+    }
+}
+
+/// Waits for `path` to stop growing, since files usually show up in the
+/// folder before whatever is copying them in has finished.
+fn wait_until_written(path: &Path) -> Result<(), ErrorWrapper> {
+    let mut last_len = std::fs::metadata(path)?.len();
+    for _ in 0..SETTLE_ATTEMPTS {
+        std::thread::sleep(SETTLE_INTERVAL);
+        let len = std::fs::metadata(path)?.len();
+        if len == last_len && len > 0 {
+            return Ok(());
+        }
+        last_len = len;
+    }
+    Err(ErrorWrapper::InvalidInput(format!(
+        "{} is still being written",
+        path.display()
+    )))
+}
+
+/// What processing a watched folder's new files takes, fixed when watching
+/// starts.
+struct Watched {
+    namer: Namer,
+    options: ProcessingOptions,
+    min_confidence: f64,
+    settings: Settings,
+    lens_profiles: LensProfiles,
+}
+
+impl Watched {
+    fn process(&self, path: &Path, index: usize, cancel: &CancellationToken) -> WatchResult {
+        let Watched {
+            namer,
+            options,
+            min_confidence,
+            settings,
+            lens_profiles,
+        } = self;
+        let mut result = WatchResult {
+            path: path.to_path_buf(),
+            output_path: None,
+            control_points: None,
+            confidence: None,
+            needs_review: true,
+            skipped: false,
+            error: None,
+        };
+        let Some(output_path) = namer.output_path(path, index, options.output_format) else {
+            result.skipped = true;
+            result.needs_review = false;
+            return result;
+        };
+        // A panic is reported in the result like any other error, and doesn't
+        // take the watcher down.
+        let outcome = crate::catch_panic(|| {
+            wait_until_written(path)?;
+            let source = decode::read_image_file(path, &settings.decode_limits())?;
+            let detection = detect::detect(&source.image).ok_or_else(|| {
+                ErrorWrapper::Squaring(ImageSquaringError::new("No quadrilateral found"))
+            })?;
+            let control_points: Vec<ControlPoint> = detection
+                .corners
+                .iter()
+                .map(|p| ControlPoint::new(p.x as f64, p.y as f64))
+                .collect();
+            result.confidence = Some(detection.confidence);
+            result.needs_review = detection.confidence < *min_confidence;
+            result.control_points = Some(control_points.clone());
+            let quad = squarer_core::quad_from_points(control_points, source.image.dimensions())?;
+            let options = ProcessingOptions {
+                lens_distortion: lens_profiles.distortion_for(options, source.exif.as_deref()),
+                ..options.clone()
+            };
+            let squared = squarer_core::square_quad(&source.image, quad.clone(), &options, cancel)?;
+            let bytes = squarer_core::encode_output_with_metadata(
+                &squared,
+                &options,
+                source.exif.as_deref(),
+                &quad,
+            )?;
+            std::fs::write(&output_path, bytes)?;
+            Ok(())
+        });
+        match outcome {
+            Ok(()) => result.output_path = Some(output_path),
+            Err(e) => result.error = Some(e.to_string()),
+        }
+        result
+    }
+}
+
+/// Files that an event says have newly appeared in the folder.
+fn new_files(event: Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Create(CreateKind::File | CreateKind::Any)
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event.paths,
+        _ => Vec::new(),
+    }
+}
+
+/// Starts watching `config.inputDir`: each image that appears in it has its
+/// corners detected, is squared into `config.outputDir`, and is reported with
+/// a `watch-processed` event. Outputs are named per `config.naming`. Each
+/// image is a job on the queue `submit_job` uses, in the order they appear.
+/// Replaces any folder already being watched; passing no config just
+/// stops watching.
+#[tauri::command]
+pub fn configure_watch_folder(
+    app: AppHandle,
+    watch_folder: State<WatchFolder>,
+    jobs: State<JobRegistry>,
+    settings: State<Settings>,
+    lens_profiles: State<LensProfiles>,
+    config: Option<WatchConfig>,
+) -> Result<(), ErrorWrapper> {
+    let mut current = watch_folder.watcher.lock().unwrap();
+    *current = None;
+    let Some(config) = config else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(&config.output_dir)?;
+    if config.input_dir.canonicalize()? == config.output_dir.canonicalize()? {
+        return Err(ErrorWrapper::InvalidInput(String::from(
+            "The output folder must be different from the watched folder",
+        )));
+    }
+    let watched = Arc::new(Watched {
+        namer: Namer::new(config.naming.clone(), &config.output_dir)?,
+        options: config
+            .options
+            .clone()
+            .unwrap_or_else(|| settings.processing_options()),
+        min_confidence: config.min_confidence,
+        settings: settings.inner().clone(),
+        lens_profiles: lens_profiles.inner().clone(),
+    });
+    let jobs = jobs.inner().clone();
+    let processed = AtomicUsize::new(0);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        // Errors from the watcher itself aren't about any one file.
+        let Ok(event) = event else {
+            return;
+        };
+        for path in new_files(event) {
+            // HEIC and RAW files included, in builds that can open them.
+            if !decode::is_supported_path(&path) {
+                continue;
+            }
+            let index = processed.fetch_add(1, Ordering::SeqCst) + 1;
+            let request = JobRequest::WatchFile { path: path.clone() };
+            let (watched, result_app) = (watched.clone(), app.clone());
+            jobs.submit(
+                app.clone(),
+                request,
+                Box::new(move |cancel| {
+                    let result = watched.process(&path, index, cancel);
+                    // Results are informational; keep watching regardless.
+                    let _ = result_app.emit(PROCESSED_EVENT, result);
+                    Ok(cancel.check()?)
+                }),
+            );
+        }
+    })?;
+    watcher.watch(&config.input_dir, RecursiveMode::NonRecursive)?;
+    *current = Some((watcher, config.input_dir));
+    Ok(())
+}
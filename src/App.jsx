@@ -41,6 +41,15 @@ function App() {
     setTransformedImageData(dataUri);
   }
 
+  async function detectCorners() {
+    try {
+      const points = await invoke("detect_quad", { imageDataUri });
+      setControlPoints(points.map((p) => [p.x, p.y]));
+    } catch (e) {
+      setErrorMessage(e.message ?? e.toString());
+    }
+  }
+
   function imageFileSelected(e) {
     const file = e.target.files[0];
     if (file) {
@@ -64,7 +73,7 @@ function App() {
     const xOffset = e.clientX - containerRect.left;
     const yOffset = e.clientY - containerRect.top;
     if (controlPoints.length < 4) {
-      controlPoints.push([parseInt(xOffset), parseInt(yOffset)]);
+      controlPoints.push([xOffset, yOffset]);
       // Clone the array so that React will recognize the state change.
       setControlPoints([...controlPoints]);
     }
@@ -92,7 +101,7 @@ function App() {
     try {
       await processImage();
     } catch (e) {
-      setErrorMessage(e.toString());
+      setErrorMessage(e.message ?? e.toString());
     }
   }
 
@@ -105,6 +114,7 @@ function App() {
         <label>
           Select an image: <input type="file" accept="image/jpeg" onChange={imageFileSelected} />
         </label>
+        <button type="button" title="Find the corners automatically" disabled={!imageDataUri} onClick={detectCorners}>Detect corners</button>
         <button type="submit" title="Select the 4 corners of the rectangle" disabled={controlPoints.length < 4}>Process</button>
         {
           errorMessage ?